@@ -0,0 +1,371 @@
+use std::{ env, fmt::Display, str::FromStr, thread };
+
+use tracing::Level;
+use url::Url;
+
+use crate::{
+    enums::{ LogFormat, StorageBackendKind },
+    state::models::{ CacheControlConfig, FeatureFlags },
+    utils::cors_utils::AllowedOrigins,
+};
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Missing(&'static str),
+    Invalid(&'static str, String),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Missing(name) => write!(f, "{} IS NOT SET", name),
+            ConfigError::Invalid(name, reason) => write!(f, "{} IS INVALID - {}", name, reason),
+        }
+    }
+}
+
+// Everything read from the environment, loaded once at startup
+pub struct Config {
+    pub port: u16,
+    pub database_url: String,
+    // Read replica for listings and thumbnail lookups, those use the primary when unset
+    pub database_read_url: Option<String>,
+    pub log_format: LogFormat,
+    // Events below it are dropped, e.g. debug, info or warn
+    pub log_level: Level,
+    pub storage_backend: StorageBackendKind,
+    // Only the settings of the backend in use are required, the others are left empty
+    pub spaces_endpoint: String,
+    pub spaces_key: String,
+    pub spaces_secret: String,
+    pub spaces_region: String,
+    // Used for projects without a storage_targets row and objects outside projects
+    pub default_bucket: String,
+    pub local_storage_path: String,
+    // Public URL of this service, local objects are served from <url>/storage/...
+    pub local_storage_url: String,
+    // Signs the presigned URLs of local objects
+    pub local_storage_secret: String,
+    // Origins the app's routes answer with credentials, the client URLs unless set
+    pub cors_origins: AllowedOrigins,
+    // The extension and Foundry modules authenticate with API keys and run on origins that
+    // aren't known up front, so any origin can use their routes unless set
+    pub extension_cors_origins: AllowedOrigins,
+    pub foundry_cors_origins: AllowedOrigins,
+    pub auth_service_url: String,
+    // How long verified claims are reused before asking the auth service again, 0 disables it
+    pub auth_cache_ttl_secs: u64,
+    pub thumbnail_service_url: String,
+    pub thumbnail_secret: String,
+    // Part of the thumbnail ETags, bump it with the secret so clients drop URLs signed with the
+    // old one
+    pub thumbnail_secret_version: String,
+    // Seconds clients and CDNs may reuse a thumbnail URL, presigned ones at most PRESIGN_DURATION
+    pub thumbnail_max_age: u64,
+    pub avatar_fallback_url: String,
+    // DNS-over-HTTPS endpoint (JSON API) custom domain TXT records are looked up with
+    pub dns_resolver_url: String,
+    // Uploads to public projects are reviewed here before they're published, empty disables it
+    pub moderation_service_url: String,
+    pub moderation_secret: String,
+    pub admin_api_key: String,
+    // Shared with the gateway, which signs its entity uploads with it
+    pub gateway_secret: String,
+    pub storage_price_per_gb: f64,
+    pub egress_price_per_gb: f64,
+    pub cache_control: CacheControlConfig,
+    pub feature_flags: FeatureFlags,
+    // Uploads whose header announces more pixels than this are rejected before being decoded
+    pub max_image_pixels: u64,
+    // Image tasks (decodes, resizes, encodes) running at once, the rest wait for their turn.
+    // One per core unless set.
+    pub max_concurrent_encodes: usize,
+    // pub discord_service_url: String,
+    // pub discord_service_api_key: String,
+}
+
+fn required(name: &'static str) -> Result<String, ConfigError> {
+    match env::var(name) {
+        Ok(value) if !value.trim().is_empty() => Ok(value),
+        _ => Err(ConfigError::Missing(name)),
+    }
+}
+
+fn optional(name: &'static str, default: &str) -> String {
+    env::var(name).unwrap_or(default.to_owned())
+}
+
+// Required when `in_use`, empty otherwise
+fn required_if(in_use: bool, name: &'static str) -> Result<String, ConfigError> {
+    match in_use {
+        true => required(name),
+        false => Ok(optional(name, "")),
+    }
+}
+
+fn parsed<T: FromStr>(name: &'static str, value: String) -> Result<T, ConfigError>
+    where T::Err: Display
+{
+    value.trim().parse().map_err(|err: T::Err| ConfigError::Invalid(name, err.to_string()))
+}
+
+// Service and client URLs are used as prefixes, so they're stored without a trailing slash
+fn url(name: &'static str, value: String) -> Result<String, ConfigError> {
+    let parsed = Url::parse(&value);
+
+    if parsed.is_err() {
+        return Err(ConfigError::Invalid(name, parsed.err().unwrap().to_string()));
+    }
+
+    if !matches!(parsed.unwrap().scheme(), "http" | "https") {
+        return Err(ConfigError::Invalid(name, "EXPECTED AN HTTP(S) URL".to_owned()));
+    }
+
+    Ok(value.trim_end_matches('/').to_owned())
+}
+
+fn origins(name: &'static str, value: String) -> Result<AllowedOrigins, ConfigError> {
+    parsed(name, value)
+}
+
+// Keeps the value when it's valid, records the error otherwise so every problem is reported at once
+fn check<T>(errors: &mut Vec<ConfigError>, res: Result<T, ConfigError>) -> Option<T> {
+    match res {
+        Ok(value) => Some(value),
+        Err(err) => {
+            errors.push(err);
+            None
+        }
+    }
+}
+
+// Checked up front, otherwise a bad URL only shows up once the pool tries to connect
+fn postgres_url(name: &'static str, value: String) -> Result<String, ConfigError> {
+    let parsed = value.parse::<tokio_postgres::Config>();
+
+    if parsed.is_err() {
+        return Err(ConfigError::Invalid(name, parsed.err().unwrap().to_string()));
+    }
+
+    Ok(value)
+}
+
+impl Config {
+    pub fn from_env() -> Result<Config, Vec<ConfigError>> {
+        let mut errors = Vec::new();
+
+        let storage_backend = check(
+            &mut errors,
+            parsed("STORAGE_BACKEND", optional("STORAGE_BACKEND", "s3"))
+        );
+        // Checks the S3 settings when the backend is invalid, those are the default
+        let s3 = storage_backend.is_none_or(|backend| backend == StorageBackendKind::S3);
+        let local = storage_backend == Some(StorageBackendKind::Local);
+
+        let spaces_endpoint = match s3 {
+            true =>
+                check(
+                    &mut errors,
+                    required("DO_SPACES_ENDPOINT").and_then(|value| url("DO_SPACES_ENDPOINT", value))
+                ),
+            false => Some(optional("DO_SPACES_ENDPOINT", "")),
+        };
+        let local_storage_url = match local {
+            true =>
+                check(
+                    &mut errors,
+                    required("LOCAL_STORAGE_URL").and_then(|value| url("LOCAL_STORAGE_URL", value))
+                ),
+            false => Some(optional("LOCAL_STORAGE_URL", "")),
+        };
+        let moderation_service_url = match env::var("MODERATION_SERVICE_URL") {
+            Ok(value) if !value.is_empty() => {
+                check(&mut errors, url("MODERATION_SERVICE_URL", value))
+            }
+            _ => Some(String::new()),
+        };
+        let moderation = moderation_service_url.as_ref().is_none_or(|service_url| !service_url.is_empty());
+        // e.g. https://editor.example.com,https://*.example.com
+        let cors_origins = match env::var("CORS_ORIGINS") {
+            Ok(value) if !value.trim().is_empty() => {
+                check(&mut errors, origins("CORS_ORIGINS", value))
+            }
+            _ => {
+                let client_urls: Vec<Option<String>> = [
+                    "EDITOR_CLIENT_URL",
+                    "WIKI_CLIENT_URL",
+                    "GATEWAY_CLIENT_URL",
+                ]
+                    .into_iter()
+                    .map(|name| check(&mut errors, required(name).and_then(|value| url(name, value))))
+                    .collect();
+                let client_urls: Option<Vec<String>> = client_urls.into_iter().collect();
+
+                match client_urls {
+                    Some(client_urls) => {
+                        check(&mut errors, origins("CORS_ORIGINS", client_urls.join(",")))
+                    }
+                    None => None,
+                }
+            }
+        };
+
+        // Browsers refuse credentials with a wildcard origin
+        if matches!(cors_origins, Some(AllowedOrigins::Any)) {
+            errors.push(
+                ConfigError::Invalid("CORS_ORIGINS", "CREDENTIALS CAN'T BE USED WITH *".to_owned())
+            );
+        }
+
+        // e.g. {"avif": {"projects": ["..."], "rollout_percent": 10}}
+        let feature_flags = match env::var("FEATURE_FLAGS") {
+            Ok(flags) =>
+                check(
+                    &mut errors,
+                    serde_json
+                        ::from_str(&flags)
+                        .map_err(|err| ConfigError::Invalid("FEATURE_FLAGS", err.to_string()))
+                ),
+            Err(_) => Some(FeatureFlags::default()),
+        };
+
+        let port = check(
+            &mut errors,
+            required("PORT").and_then(|value| parsed("PORT", value))
+        );
+        let database_url = check(
+            &mut errors,
+            required("DATABASE_URL").and_then(|value| postgres_url("DATABASE_URL", value))
+        );
+        let database_read_url = match env::var("DATABASE_READ_URL") {
+            Ok(value) if !value.trim().is_empty() => {
+                check(&mut errors, postgres_url("DATABASE_READ_URL", value)).map(Some)
+            }
+            _ => Some(None),
+        };
+        let log_format = check(&mut errors, parsed("LOG_FORMAT", optional("LOG_FORMAT", "text")));
+        let log_level = check(&mut errors, parsed("LOG_LEVEL", optional("LOG_LEVEL", "info")));
+        let spaces_key = check(&mut errors, required_if(s3, "DO_SPACES_KEY"));
+        let spaces_secret = check(&mut errors, required_if(s3, "DO_SPACES_SECRET"));
+        // Becomes a directory under LOCAL_STORAGE_PATH on local disk
+        let default_bucket = match s3 {
+            true => check(&mut errors, required("DO_SPACES_NAME")),
+            false => Some(optional("DO_SPACES_NAME", "arkive")),
+        };
+        let local_storage_secret = check(&mut errors, required_if(local, "LOCAL_STORAGE_SECRET"));
+        let extension_cors_origins = check(
+            &mut errors,
+            origins("EXTENSION_CORS_ORIGINS", optional("EXTENSION_CORS_ORIGINS", "*"))
+        );
+        let foundry_cors_origins = check(
+            &mut errors,
+            origins("FOUNDRY_CORS_ORIGINS", optional("FOUNDRY_CORS_ORIGINS", "*"))
+        );
+        let auth_service_url = check(
+            &mut errors,
+            required("AUTH_SERVICE_URL").and_then(|value| url("AUTH_SERVICE_URL", value))
+        );
+        let auth_cache_ttl_secs = check(
+            &mut errors,
+            parsed("AUTH_CACHE_TTL_SECS", optional("AUTH_CACHE_TTL_SECS", "30"))
+        );
+        let thumbnail_service_url = check(
+            &mut errors,
+            required("THUMBNAIL_SERVICE").and_then(|value| url("THUMBNAIL_SERVICE", value))
+        );
+        let thumbnail_secret = check(&mut errors, required("THUMBNAIL_SECRET"));
+        let thumbnail_max_age = check(
+            &mut errors,
+            parsed("THUMBNAIL_MAX_AGE", optional("THUMBNAIL_MAX_AGE", "3600"))
+        );
+        let avatar_fallback_url = check(
+            &mut errors,
+            url(
+                "AVATAR_FALLBACK_URL",
+                optional("AVATAR_FALLBACK_URL", "https://www.gravatar.com/avatar")
+            )
+        );
+        let dns_resolver_url = check(
+            &mut errors,
+            url(
+                "DNS_RESOLVER_URL",
+                optional("DNS_RESOLVER_URL", "https://cloudflare-dns.com/dns-query")
+            )
+        );
+        let moderation_secret = check(&mut errors, required_if(moderation, "MODERATION_SECRET"));
+        let gateway_secret = check(&mut errors, required("GATEWAY_SECRET"));
+        let storage_price_per_gb = check(
+            &mut errors,
+            parsed("STORAGE_PRICE_PER_GB", optional("STORAGE_PRICE_PER_GB", "0.02"))
+        );
+        let egress_price_per_gb = check(
+            &mut errors,
+            parsed("EGRESS_PRICE_PER_GB", optional("EGRESS_PRICE_PER_GB", "0.01"))
+        );
+        let max_image_pixels = check(
+            &mut errors,
+            parsed("MAX_IMAGE_PIXELS", optional("MAX_IMAGE_PIXELS", "100000000"))
+        );
+        let max_concurrent_encodes = check(
+            &mut errors,
+            parsed(
+                "MAX_CONCURRENT_ENCODES",
+                optional(
+                    "MAX_CONCURRENT_ENCODES",
+                    &thread::available_parallelism().map_or(1, |cores| cores.get()).to_string()
+                )
+            )
+        );
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        // Every value is set past this point, a None would have left an error behind
+        Ok(Config {
+            port: port.unwrap(),
+            database_url: database_url.unwrap(),
+            database_read_url: database_read_url.unwrap(),
+            log_format: log_format.unwrap(),
+            log_level: log_level.unwrap(),
+            storage_backend: storage_backend.unwrap(),
+            spaces_endpoint: spaces_endpoint.unwrap(),
+            spaces_key: spaces_key.unwrap(),
+            spaces_secret: spaces_secret.unwrap(),
+            spaces_region: optional("DO_SPACES_REGION", "us-east-1"),
+            default_bucket: default_bucket.unwrap(),
+            local_storage_path: optional("LOCAL_STORAGE_PATH", "./storage"),
+            local_storage_url: local_storage_url.unwrap(),
+            local_storage_secret: local_storage_secret.unwrap(),
+            cors_origins: cors_origins.unwrap(),
+            extension_cors_origins: extension_cors_origins.unwrap(),
+            foundry_cors_origins: foundry_cors_origins.unwrap(),
+            auth_service_url: auth_service_url.unwrap(),
+            auth_cache_ttl_secs: auth_cache_ttl_secs.unwrap(),
+            thumbnail_service_url: thumbnail_service_url.unwrap(),
+            thumbnail_secret: thumbnail_secret.unwrap(),
+            thumbnail_secret_version: optional("THUMBNAIL_SECRET_VERSION", "1"),
+            thumbnail_max_age: thumbnail_max_age.unwrap(),
+            avatar_fallback_url: avatar_fallback_url.unwrap(),
+            dns_resolver_url: dns_resolver_url.unwrap(),
+            moderation_service_url: moderation_service_url.unwrap(),
+            moderation_secret: moderation_secret.unwrap(),
+            admin_api_key: optional("ADMIN_API_KEY", ""),
+            gateway_secret: gateway_secret.unwrap(),
+            storage_price_per_gb: storage_price_per_gb.unwrap(),
+            egress_price_per_gb: egress_price_per_gb.unwrap(),
+            cache_control: CacheControlConfig {
+                images: optional("CACHE_CONTROL_IMAGES", "max-age=600"),
+                map_images: optional("CACHE_CONTROL_MAP_IMAGES", "max-age=600"),
+                avatars: optional("CACHE_CONTROL_AVATARS", "max-age=600"),
+                renditions: optional(
+                    "CACHE_CONTROL_RENDITIONS",
+                    "public, max-age=31536000, immutable"
+                ),
+            },
+            feature_flags: feature_flags.unwrap(),
+            max_image_pixels: max_image_pixels.unwrap(),
+            max_concurrent_encodes: max_concurrent_encodes.unwrap(),
+        })
+    }
+}
@@ -1,11 +1,18 @@
-use std::fmt::Display;
+use std::{ fmt::Display, str::FromStr };
 
 use axum::{ response::{ IntoResponse, Response }, Json };
+use image::ImageFormat;
 use postgres_types::{ FromSql, ToSql };
 use reqwest::StatusCode;
 use serde::{ Deserialize, Serialize };
 use serde_json::Value;
-#[derive(Deserialize, Debug, ToSql, FromSql)]
+use utoipa::ToSchema;
+
+use crate::{
+    storage::circuit_breaker::STORAGE_UNAVAILABLE,
+    utils::request_id_utils::current_request_id,
+};
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, ToSql, FromSql, ToSchema)]
 #[serde(rename_all = "snake_case")]
 #[postgres(name = "ImageType")]
 pub enum ImageType {
@@ -25,7 +32,411 @@ impl Display for ImageType {
     }
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, ToSql, FromSql)]
+#[serde(rename_all = "snake_case")]
+#[postgres(name = "AssetKind")]
+pub enum AssetKind {
+    #[postgres(name = "image")]
+    Image,
+    #[postgres(name = "audio")]
+    Audio,
+    #[postgres(name = "document")]
+    Document,
+    #[postgres(name = "video")]
+    Video,
+}
+
+impl Display for AssetKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let output = match self {
+            &AssetKind::Image => "image",
+            &AssetKind::Audio => "audio",
+            &AssetKind::Document => "document",
+            &AssetKind::Video => "video",
+        };
+        write!(f, "{}", output)
+    }
+}
+
+#[derive(Debug, ToSql, FromSql)]
+#[postgres(name = "HotlinkAction")]
+pub enum HotlinkAction {
+    #[postgres(name = "block")]
+    Block,
+    #[postgres(name = "placeholder")]
+    Placeholder,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, ToSql, FromSql)]
+#[serde(rename_all = "snake_case")]
+#[postgres(name = "AssetVisibility")]
+pub enum AssetVisibility {
+    #[postgres(name = "public")]
+    Public,
+    #[postgres(name = "private")]
+    Private,
+}
+
+impl AssetVisibility {
+    pub fn acl(&self) -> aws_sdk_s3::types::ObjectCannedAcl {
+        match self {
+            &AssetVisibility::Public => aws_sdk_s3::types::ObjectCannedAcl::PublicRead,
+            &AssetVisibility::Private => aws_sdk_s3::types::ObjectCannedAcl::Private,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, ToSql, FromSql)]
+#[serde(rename_all = "snake_case")]
+#[postgres(name = "AssetJobOperation")]
+pub enum AssetJobOperation {
+    #[postgres(name = "delete_object")]
+    DeleteObject,
+    // Removes everything under the target, used for rendition folders
+    #[postgres(name = "delete_prefix")]
+    DeletePrefix,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, ToSql, FromSql)]
+#[serde(rename_all = "snake_case")]
+#[postgres(name = "AssetJobStatus")]
+pub enum AssetJobStatus {
+    #[postgres(name = "pending")]
+    Pending,
+    // Gave up after the maximum number of attempts
+    #[postgres(name = "failed")]
+    Failed,
+}
+
+// Review by the moderation service, NULL on assets that don't need one
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, ToSql, FromSql)]
+#[serde(rename_all = "snake_case")]
+#[postgres(name = "ModerationStatus")]
+pub enum ModerationStatus {
+    #[postgres(name = "pending")]
+    Pending,
+    #[postgres(name = "approved")]
+    Approved,
+    #[postgres(name = "rejected")]
+    Rejected,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, ToSql, FromSql)]
+#[postgres(name = "WebhookEvent")]
+pub enum WebhookEvent {
+    #[serde(rename = "asset.uploaded")]
+    #[postgres(name = "asset_uploaded")]
+    Uploaded,
+    #[serde(rename = "asset.updated")]
+    #[postgres(name = "asset_updated")]
+    Updated,
+    // Sent when an asset is trashed and when it's deleted without going through the trash
+    #[serde(rename = "asset.deleted")]
+    #[postgres(name = "asset_deleted")]
+    Deleted,
+}
+
+impl WebhookEvent {
+    pub const ALL: [WebhookEvent; 3] = [
+        WebhookEvent::Uploaded,
+        WebhookEvent::Updated,
+        WebhookEvent::Deleted,
+    ];
+
+    // Matches the serialized name
+    pub fn name(&self) -> &'static str {
+        match self {
+            &WebhookEvent::Uploaded => "asset.uploaded",
+            &WebhookEvent::Updated => "asset.updated",
+            &WebhookEvent::Deleted => "asset.deleted",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, ToSql, FromSql)]
+#[serde(rename_all = "snake_case")]
+#[postgres(name = "WebhookDeliveryStatus")]
+pub enum WebhookDeliveryStatus {
+    #[postgres(name = "pending")]
+    Pending,
+    #[postgres(name = "delivered")]
+    Delivered,
+    // Gave up after the maximum number of attempts
+    #[postgres(name = "failed")]
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, ToSql, FromSql)]
+#[serde(rename_all = "snake_case")]
+#[postgres(name = "AuditAction")]
+pub enum AuditAction {
+    #[postgres(name = "upload")]
+    Upload,
+    #[postgres(name = "update")]
+    Update,
+    // Trashing as well as deleting for good
+    #[postgres(name = "delete")]
+    Delete,
+    #[postgres(name = "download")]
+    Download,
+}
+
+// Where an audited request came from
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, ToSql, FromSql)]
+#[serde(rename_all = "snake_case")]
+#[postgres(name = "AuditSource")]
+pub enum AuditSource {
+    #[postgres(name = "ui")]
+    Ui,
+    #[postgres(name = "extension")]
+    Extension,
+    #[postgres(name = "gateway")]
+    Gateway,
+    #[postgres(name = "foundry")]
+    Foundry,
+}
+
+// What a project API key may be used for
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, ToSql, FromSql)]
+#[serde(rename_all = "snake_case")]
+#[postgres(name = "ApiKeyScope")]
+pub enum ApiKeyScope {
+    // Listing assets and reading their URLs and usage
+    #[postgres(name = "read")]
+    Read,
+    #[postgres(name = "upload")]
+    Upload,
+    // Renaming and deleting assets
+    #[postgres(name = "manage")]
+    Manage,
+}
+
+// Where a Foundry instance keeps uploaded files, the `source` of its file picker
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, ToSql, FromSql)]
+#[serde(rename_all = "snake_case")]
+#[postgres(name = "FoundrySource")]
+pub enum FoundrySource {
+    // The instance's own user data folder
+    #[postgres(name = "data")]
+    Data,
+    // The S3 compatible bucket the instance is configured with
+    #[postgres(name = "s3")]
+    S3,
+}
+
+impl Display for FoundrySource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let output = match self {
+            &FoundrySource::Data => "data",
+            &FoundrySource::S3 => "s3",
+        };
+        write!(f, "{}", output)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, ToSql, FromSql)]
+#[serde(rename_all = "snake_case")]
+#[postgres(name = "FoundrySyncStatus")]
+pub enum FoundrySyncStatus {
+    #[postgres(name = "pending")]
+    Pending,
+    #[postgres(name = "synced")]
+    Synced,
+    #[postgres(name = "failed")]
+    Failed,
+}
+
+// What a route does to the asset in its path, checked by the permission middleware
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RequiredPermission {
+    Read,
+    Update,
+    Delete,
+    Upload,
+}
+
+impl RequiredPermission {
+    // Name of the permission in the auth service
+    pub fn name(&self) -> &'static str {
+        match self {
+            &RequiredPermission::Read => "read_images",
+            &RequiredPermission::Update => "update_images",
+            &RequiredPermission::Delete => "delete_images",
+            &RequiredPermission::Upload => "upload_images",
+        }
+    }
+}
+
 #[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PlaceholderKind {
+    Images,
+    MapImages,
+    Avatars,
+}
+
+impl PlaceholderKind {
+    pub fn dimensions(&self) -> (u32, u32) {
+        match self {
+            &PlaceholderKind::Images => (512, 512),
+            &PlaceholderKind::MapImages => (1280, 720),
+            &PlaceholderKind::Avatars => (256, 256),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Feature {
+    Avif,
+    SmartCrop,
+    Dedupe,
+}
+
+impl Feature {
+    pub const ALL: [Feature; 3] = [Feature::Avif, Feature::SmartCrop, Feature::Dedupe];
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum UploadStage {
+    Receiving,
+    Decoding,
+    Encoding,
+    Storing,
+    Completed,
+}
+
+// Sent to progress subscribers as each file of an upload gets through a step
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum UploadEventKind {
+    Received,
+    // Images only, other kinds are stored as uploaded
+    Encoded,
+    // Also sent for deduplicated images, which reuse an object that is already stored
+    Stored,
+    DbInserted,
+    Failed,
+    // The whole upload is done, sent without a file
+    Completed,
+}
+
+impl UploadEventKind {
+    // Matches the serialized name, used as the SSE event name
+    pub fn name(&self) -> &'static str {
+        match self {
+            &UploadEventKind::Received => "received",
+            &UploadEventKind::Encoded => "encoded",
+            &UploadEventKind::Stored => "stored",
+            &UploadEventKind::DbInserted => "db_inserted",
+            &UploadEventKind::Failed => "failed",
+            &UploadEventKind::Completed => "completed",
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum UploadResultStatus {
+    Uploaded,
+    Failed,
+    Skipped,
+    RolledBack,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    Webp,
+    Avif,
+    Png,
+    Jpeg,
+}
+
+impl OutputFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            &OutputFormat::Webp => "webp",
+            &OutputFormat::Avif => "avif",
+            &OutputFormat::Png => "png",
+            &OutputFormat::Jpeg => "jpeg",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            &OutputFormat::Webp => "image/webp",
+            &OutputFormat::Avif => "image/avif",
+            &OutputFormat::Png => "image/png",
+            &OutputFormat::Jpeg => "image/jpeg",
+        }
+    }
+
+    pub fn from_content_type(content_type: &str) -> Option<OutputFormat> {
+        match content_type {
+            "image/webp" => Some(OutputFormat::Webp),
+            "image/avif" => Some(OutputFormat::Avif),
+            "image/png" => Some(OutputFormat::Png),
+            "image/jpeg" => Some(OutputFormat::Jpeg),
+            _ => None,
+        }
+    }
+
+    // PNG and JPEG are only produced for downloads, stored images are WebP or AVIF
+    pub fn is_storage_format(&self) -> bool {
+        matches!(self, &OutputFormat::Webp | &OutputFormat::Avif)
+    }
+}
+
+// STORAGE_BACKEND
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StorageBackendKind {
+    S3,
+    Local,
+}
+
+impl FromStr for StorageBackendKind {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "s3" => Ok(StorageBackendKind::S3),
+            "local" => Ok(StorageBackendKind::Local),
+            _ => Err(format!("EXPECTED s3 OR local, GOT {}", value)),
+        }
+    }
+}
+
+// LOG_FORMAT, JSON for log collectors, text for reading logs in a terminal
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("EXPECTED text OR json, GOT {}", value)),
+        }
+    }
+}
+
+// Which pool a query may run on. Reads can lag behind writes on a replica, so anything that
+// reads back what the same request just wrote uses Write.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DbAccess {
+    Read,
+    Write,
+}
+
+// Source formats images are accepted in, everything else is turned down before decoding
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum SupportedImageType {
     Jpeg,
@@ -34,11 +445,52 @@ pub enum SupportedImageType {
     Webp,
     Avif,
     Gif,
+    Bmp,
+}
+
+impl SupportedImageType {
+    // From the magic bytes. Jpg is only an alias of Jpeg for declared types and is never detected.
+    pub fn from_magic(head: &[u8]) -> Option<SupportedImageType> {
+        match image::guess_format(head) {
+            Ok(ImageFormat::Jpeg) => Some(SupportedImageType::Jpeg),
+            Ok(ImageFormat::Png) => Some(SupportedImageType::Png),
+            Ok(ImageFormat::WebP) => Some(SupportedImageType::Webp),
+            Ok(ImageFormat::Avif) => Some(SupportedImageType::Avif),
+            Ok(ImageFormat::Gif) => Some(SupportedImageType::Gif),
+            Ok(ImageFormat::Bmp) => Some(SupportedImageType::Bmp),
+            _ => None,
+        }
+    }
+
+    pub fn from_mime_type(mime_type: &str) -> Option<SupportedImageType> {
+        match mime_type {
+            "image/jpeg" => Some(SupportedImageType::Jpeg),
+            "image/jpg" => Some(SupportedImageType::Jpg),
+            "image/png" => Some(SupportedImageType::Png),
+            "image/webp" => Some(SupportedImageType::Webp),
+            "image/avif" => Some(SupportedImageType::Avif),
+            "image/gif" => Some(SupportedImageType::Gif),
+            "image/bmp" => Some(SupportedImageType::Bmp),
+            _ => None,
+        }
+    }
+
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            &SupportedImageType::Jpeg | &SupportedImageType::Jpg => "image/jpeg",
+            &SupportedImageType::Png => "image/png",
+            &SupportedImageType::Webp => "image/webp",
+            &SupportedImageType::Avif => "image/avif",
+            &SupportedImageType::Gif => "image/gif",
+            &SupportedImageType::Bmp => "image/bmp",
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum SuccessActions {
-    // Create,
+    Create,
+    Read,
     Download,
     Update,
     Delete,
@@ -48,6 +500,8 @@ pub enum SuccessActions {
 impl Display for SuccessActions {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let output = match self {
+            &SuccessActions::Create => "created",
+            &SuccessActions::Read => "retrieved",
             &SuccessActions::Download => "downloaded",
             &SuccessActions::Update => "updated",
             &SuccessActions::Delete => "deleted",
@@ -62,22 +516,44 @@ pub enum AppResponse {
     Success(String, SuccessActions),
     SuccessData(String, SuccessActions, Value),
     Error(String),
+    // Request is valid but blocked by the current state of the listed entities
+    Conflict(String, Value),
+    // Upload broke the rules of its image type, the data lists every rule that failed
+    Invalid(String, Value),
+    // Object storage is down or uploads are paused for maintenance, the request can be retried
+    // later. The message is shown to the client.
+    Unavailable(String),
     Auth,
     Unauthorized,
 }
 
+// Body of every AppResponse, `data` carries the entity of SuccessData, Conflict and Invalid
+#[derive(Serialize, ToSchema)]
+pub struct ResponsePayload {
+    ok: bool,
+    message: String,
+    role_access: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<Value>,
+    // The x-request-id of the request, to look it up in the logs of every service
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+}
+
 impl IntoResponse for AppResponse {
     fn into_response(self) -> Response {
-        #[derive(Serialize)]
-        struct ResponsePayload {
-            ok: bool,
-            message: String,
-            role_access: bool,
-            #[serde(skip_serializing_if = "Option::is_none")]
-            data: Option<Value>,
-        }
+        // Storage errors are passed around as strings, one from a tripped breaker is still a 503
+        let response = match self {
+            AppResponse::Error(err) if err.contains(STORAGE_UNAVAILABLE) => {
+                tracing::warn!("UNAVAILABLE - {}", err);
+                AppResponse::Unavailable(
+                    "Storage is temporarily unavailable, try again shortly.".to_owned()
+                )
+            }
+            response => response,
+        };
 
-        let (status, res) = match self {
+        let (status, res) = match response {
             AppResponse::Success(entity, action) => {
                 (
                     StatusCode::OK,
@@ -86,6 +562,7 @@ impl IntoResponse for AppResponse {
                         message: format!("{} successfully {}.", entity, action),
                         role_access: true,
                         data: None,
+                        request_id: current_request_id(),
                     }),
                 )
             }
@@ -97,6 +574,7 @@ impl IntoResponse for AppResponse {
                         ok: true,
                         message: format!("{} successfully {}.", entity, action),
                         role_access: true,
+                        request_id: current_request_id(),
                     }),
                 )
             }
@@ -109,6 +587,43 @@ impl IntoResponse for AppResponse {
                         message: "There was an error with your request.".to_owned(),
                         role_access: true,
                         data: None,
+                        request_id: current_request_id(),
+                    }),
+                )
+            }
+            AppResponse::Unavailable(message) => {
+                (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    Json(ResponsePayload {
+                        ok: false,
+                        message,
+                        role_access: true,
+                        data: None,
+                        request_id: current_request_id(),
+                    }),
+                )
+            }
+            AppResponse::Conflict(message, data) => {
+                (
+                    StatusCode::CONFLICT,
+                    Json(ResponsePayload {
+                        ok: false,
+                        message,
+                        role_access: true,
+                        data: Some(data),
+                        request_id: current_request_id(),
+                    }),
+                )
+            }
+            AppResponse::Invalid(message, data) => {
+                (
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    Json(ResponsePayload {
+                        ok: false,
+                        message,
+                        role_access: true,
+                        data: Some(data),
+                        request_id: current_request_id(),
                     }),
                 )
             }
@@ -120,6 +635,7 @@ impl IntoResponse for AppResponse {
                         message: "You do not have permission to perform this action.".to_owned(),
                         role_access: false,
                         data: None,
+                        request_id: current_request_id(),
                     }),
                 )
             }
@@ -131,6 +647,7 @@ impl IntoResponse for AppResponse {
                         message: "UNAUTHORIZED".to_owned(),
                         role_access: false,
                         data: None,
+                        request_id: current_request_id(),
                     }),
                 )
             }
@@ -0,0 +1,249 @@
+use deadpool_postgres::Object;
+use futures::{ stream, StreamExt };
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{
+    enums::{ AppResponse, AssetVisibility },
+    state::models::AppState,
+    storage::{ resolve_target, StorageTarget },
+    utils::db_utils::get_client,
+};
+
+const ACL_BATCH_SIZE: usize = 16;
+
+#[derive(Clone, Serialize)]
+pub struct AclReport {
+    pub visibility: AssetVisibility,
+    pub total: usize,
+    pub checked: usize,
+    pub public: usize,
+    pub private: usize,
+    pub updated: usize,
+    pub mismatched: Vec<String>,
+    pub failed: Vec<String>,
+    pub finished: bool,
+}
+
+impl AclReport {
+    fn new(visibility: AssetVisibility) -> Self {
+        AclReport {
+            visibility,
+            total: 0,
+            checked: 0,
+            public: 0,
+            private: 0,
+            updated: 0,
+            mismatched: vec![],
+            failed: vec![],
+            finished: false,
+        }
+    }
+}
+
+pub async fn get_project_visibility(
+    state: &AppState,
+    project_id: &Uuid
+) -> Result<AssetVisibility, AppResponse> {
+    let client = get_client(&state.pool).await?;
+
+    let row = client.query_opt(
+        "SELECT asset_visibility FROM projects WHERE id = $1;",
+        &[&project_id]
+    ).await;
+
+    if row.is_err() {
+        return Err(AppResponse::Error(row.err().unwrap().to_string()));
+    }
+
+    let row = row.unwrap();
+
+    if row.is_none() {
+        return Err(AppResponse::Error(format!("PROJECT NOT FOUND - {}", project_id)));
+    }
+
+    let visibility: Option<AssetVisibility> = row.unwrap().get("asset_visibility");
+
+    Ok(visibility.unwrap_or(AssetVisibility::Public))
+}
+
+// The visibility the object of an asset should have. Ids without a row (e.g. gateway entity
+// images) get the project's.
+pub async fn get_asset_visibility(
+    client: &Object,
+    project_id: &Uuid,
+    id: &Uuid
+) -> Result<AssetVisibility, AppResponse> {
+    let row = client.query_opt(
+        "SELECT asset_visibility, (SELECT pending FROM images WHERE images.id = $2) AS pending
+         FROM projects WHERE id = $1;",
+        &[&project_id, &id]
+    ).await;
+
+    if row.is_err() {
+        return Err(AppResponse::Error(row.err().unwrap().to_string()));
+    }
+
+    let row = row.unwrap();
+
+    if row.is_none() {
+        return Err(AppResponse::Error(format!("PROJECT NOT FOUND - {}", project_id)));
+    }
+
+    let row = row.unwrap();
+    let pending: Option<bool> = row.get("pending");
+    let visibility: Option<AssetVisibility> = row.get("asset_visibility");
+
+    if pending.unwrap_or(false) {
+        return Ok(AssetVisibility::Private);
+    }
+
+    Ok(visibility.unwrap_or(AssetVisibility::Public))
+}
+
+// Renditions and thumbnails are always private and served through the API, so they are never remediated.
+pub async fn list_project_asset_keys(
+    target: &StorageTarget,
+    project_id: &Uuid
+) -> Result<Vec<String>, AppResponse> {
+    let keys = target.list(&format!("assets/{}/", project_id)).await;
+
+    if keys.is_err() {
+        return Err(AppResponse::Error(keys.err().unwrap()));
+    }
+
+    let keys = keys.unwrap();
+
+    Ok(
+        keys
+            .into_iter()
+            .filter(|key| {
+                !key.contains("/renditions/") &&
+                    !key.contains("/thumbs/") &&
+                    !key.contains("/variants/")
+            })
+            .collect()
+    )
+}
+
+pub async fn get_object_visibility(
+    target: &StorageTarget,
+    key: &str
+) -> Result<AssetVisibility, AppResponse> {
+    target.get_visibility(key).await.map_err(AppResponse::Error)
+}
+
+fn update_report(state: &AppState, project_id: &Uuid, apply: impl FnOnce(&mut AclReport)) {
+    let mut reports = state.acl_reports.lock().unwrap();
+
+    if let Some(report) = reports.get_mut(project_id) {
+        apply(report);
+    }
+}
+
+// Checks every object of the project and, unless dry_run is set, rewrites the ones whose
+// ACL doesn't match the project's visibility. Progress is published to state.acl_reports.
+pub async fn run_acl_remediation(
+    state: AppState,
+    project_id: Uuid,
+    visibility: AssetVisibility,
+    dry_run: bool
+) {
+    let target = resolve_target(&state, &project_id).await;
+
+    if target.is_err() {
+        tracing::error!("ACL REMEDIATION FAILED FOR {} - {:?}", project_id, target.err().unwrap());
+        update_report(&state, &project_id, |report| {
+            report.finished = true;
+        });
+        return;
+    }
+
+    let target = target.unwrap();
+    let keys = list_project_asset_keys(&target, &project_id).await;
+
+    if keys.is_err() {
+        tracing::error!("ACL REMEDIATION FAILED FOR {} - {:?}", project_id, keys.err().unwrap());
+        update_report(&state, &project_id, |report| {
+            report.finished = true;
+        });
+        return;
+    }
+
+    let keys = keys.unwrap();
+
+    update_report(&state, &project_id, |report| {
+        report.total = keys.len();
+    });
+
+    stream
+        ::iter(keys)
+        .for_each_concurrent(ACL_BATCH_SIZE, |key| {
+            let state = state.clone();
+            let target = &target;
+
+            async move {
+                let current = get_object_visibility(target, &key).await;
+
+                if current.is_err() {
+                    tracing::error!("{:?}", current.err().unwrap());
+                    update_report(&state, &project_id, |report| {
+                        report.checked += 1;
+                        report.failed.push(key);
+                    });
+                    return;
+                }
+
+                let current = current.unwrap();
+
+                update_report(&state, &project_id, |report| {
+                    report.checked += 1;
+                    match current {
+                        AssetVisibility::Public => {
+                            report.public += 1;
+                        }
+                        AssetVisibility::Private => {
+                            report.private += 1;
+                        }
+                    }
+                    if current != visibility {
+                        report.mismatched.push(key.clone());
+                    }
+                });
+
+                if current == visibility || dry_run {
+                    return;
+                }
+
+                let res = target.set_visibility(&key, visibility).await;
+
+                if res.is_err() {
+                    tracing::error!("ACL UPDATE FAILED FOR {} - {}", key, res.err().unwrap());
+                    update_report(&state, &project_id, |report| report.failed.push(key));
+                    return;
+                }
+
+                update_report(&state, &project_id, |report| {
+                    report.updated += 1;
+                });
+            }
+        }).await;
+
+    update_report(&state, &project_id, |report| {
+        report.finished = true;
+    });
+
+    tracing::info!("ACL REMEDIATION FINISHED FOR {}", project_id);
+}
+
+pub fn start_acl_report(state: &AppState, project_id: Uuid, visibility: AssetVisibility) -> bool {
+    let mut reports = state.acl_reports.lock().unwrap();
+
+    if reports.get(&project_id).is_some_and(|report| !report.finished) {
+        return false;
+    }
+
+    reports.insert(project_id, AclReport::new(visibility));
+
+    true
+}
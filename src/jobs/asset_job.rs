@@ -0,0 +1,305 @@
+use std::{ collections::HashSet, time::Duration };
+
+use deadpool_postgres::GenericClient;
+use tokio_postgres::Row;
+use uuid::Uuid;
+
+use crate::{
+    enums::{ AppResponse, AssetJobOperation, AssetJobStatus, AssetKind, ImageType },
+    state::models::AppState,
+    storage::{ resolve_target, StorageTarget },
+    utils::{
+        asset_utils::asset_key,
+        db_utils::get_client,
+        dedup_utils::{ referenced_objects, OBJECT_ID },
+        s3_utils::rendition_prefix,
+        sprite_utils::sprite_manifest_key,
+        thumbnail_utils::thumbnail_prefix,
+        trash_utils::trash_key,
+        variant_utils::{ clear_variants, variant_prefix },
+    },
+    JOB_POLL_INTERVAL,
+};
+
+const JOB_BATCH_SIZE: i64 = 50;
+const JOB_MAX_ATTEMPTS: i32 = 10;
+const JOB_BASE_BACKOFF: Duration = Duration::from_secs(30);
+const JOB_MAX_BACKOFF: Duration = Duration::from_secs(21600); // 6 hours
+
+pub struct AssetJob {
+    pub operation: AssetJobOperation,
+    pub target: String,
+}
+
+struct ClaimedJob {
+    id: Uuid,
+    operation: AssetJobOperation,
+    target: String,
+    attempts: i32,
+}
+
+// Columns deleting queries return for deletion_jobs
+pub fn deleted_asset_columns() -> String {
+    format!("project_id, type, kind, mime_type, {}, deleted_at IS NOT NULL AS trashed", OBJECT_ID)
+}
+
+// The stored object, its sprite manifest and every cached rendition, thumbnail and variant of
+// it. A trashed asset's object is only in the trash if no live row shared it at the time, so
+// both keys go.
+pub fn asset_deletion_jobs(
+    project_id: &Uuid,
+    image_type: &ImageType,
+    kind: &AssetKind,
+    id: &Uuid,
+    mime_type: &str,
+    trashed: bool
+) -> Vec<AssetJob> {
+    let key = asset_key(project_id, image_type, kind, id, mime_type);
+
+    let mut jobs = vec![
+        AssetJob {
+            operation: AssetJobOperation::DeletePrefix,
+            target: rendition_prefix(project_id, image_type, id),
+        },
+        AssetJob {
+            operation: AssetJobOperation::DeletePrefix,
+            target: thumbnail_prefix(project_id, image_type, id),
+        },
+        AssetJob {
+            operation: AssetJobOperation::DeletePrefix,
+            target: variant_prefix(project_id, image_type, id),
+        }
+    ];
+
+    if let Some(manifest) = sprite_manifest_key(project_id, image_type, kind, id, mime_type) {
+        if trashed {
+            jobs.push(AssetJob {
+                operation: AssetJobOperation::DeleteObject,
+                target: trash_key(&manifest),
+            });
+        }
+
+        jobs.push(AssetJob { operation: AssetJobOperation::DeleteObject, target: manifest });
+    }
+
+    if trashed {
+        jobs.push(AssetJob {
+            operation: AssetJobOperation::DeleteObject,
+            target: trash_key(&key),
+        });
+    }
+
+    jobs.push(AssetJob { operation: AssetJobOperation::DeleteObject, target: key });
+
+    jobs
+}
+
+// Jobs for rows that were just deleted (see deleted_asset_columns). Objects another row
+// still points at are kept, shared objects are only queued once.
+pub async fn deletion_jobs(
+    client: &impl GenericClient,
+    rows: &[Row]
+) -> Result<Vec<AssetJob>, AppResponse> {
+    let object_ids: Vec<Uuid> = rows
+        .iter()
+        .map(|row| row.get("object_id"))
+        .collect();
+
+    let mut skipped: HashSet<Uuid> = referenced_objects(client, &object_ids).await?;
+    let mut jobs: Vec<AssetJob> = vec![];
+    let mut deleted: Vec<Uuid> = vec![];
+
+    for row in rows {
+        let object_id: Uuid = row.get("object_id");
+
+        if !skipped.insert(object_id) {
+            continue;
+        }
+
+        deleted.push(object_id);
+
+        let image_type: ImageType = row.get("type");
+        let kind: AssetKind = row.get("kind");
+        let mime_type: String = row.get("mime_type");
+
+        jobs.extend(
+            asset_deletion_jobs(
+                &row.get("project_id"),
+                &image_type,
+                &kind,
+                &object_id,
+                &mime_type,
+                row.get("trashed")
+            )
+        );
+    }
+
+    // The variant files are queued above, their rows go with the asset rows
+    clear_variants(client, &deleted).await?;
+
+    Ok(jobs)
+}
+
+// Takes any client so the jobs can be recorded in the same transaction that removes the rows
+pub async fn enqueue_jobs(client: &impl GenericClient, jobs: &[AssetJob]) -> Result<(), AppResponse> {
+    if jobs.is_empty() {
+        return Ok(());
+    }
+
+    let operations: Vec<AssetJobOperation> = jobs
+        .iter()
+        .map(|job| job.operation)
+        .collect();
+    let targets: Vec<&str> = jobs
+        .iter()
+        .map(|job| job.target.as_str())
+        .collect();
+
+    let res = client.execute(
+        "INSERT INTO asset_jobs (operation, target)
+         SELECT * FROM UNNEST($1::\"AssetJobOperation\"[], $2::TEXT[]);",
+        &[&operations, &targets]
+    ).await;
+
+    if res.is_err() {
+        return Err(AppResponse::Error(res.err().unwrap().to_string()));
+    }
+
+    Ok(())
+}
+
+// Wakes the worker so freshly queued jobs run right away instead of on the next poll
+pub fn notify_job_worker(state: &AppState) {
+    state.job_notify.notify_one();
+}
+
+fn backoff(attempts: i32) -> Duration {
+    let factor = 2_u32.saturating_pow(attempts.max(0) as u32);
+
+    JOB_BASE_BACKOFF.saturating_mul(factor).min(JOB_MAX_BACKOFF)
+}
+
+// Every job target is an assets/<project_id>/... key, possibly under the trash prefix
+fn job_project_id(target: &str) -> Option<Uuid> {
+    let key = target.strip_prefix("trash/").unwrap_or(target);
+
+    key.strip_prefix("assets/")?.split('/').next()?.parse().ok()
+}
+
+async fn job_storage_target(state: &AppState, job: &ClaimedJob) -> Result<StorageTarget, String> {
+    match job_project_id(&job.target) {
+        Some(project_id) =>
+            resolve_target(state, &project_id).await.map_err(|err| format!("{:?}", err)),
+        None => Ok(state.storage.default_target()),
+    }
+}
+
+async fn execute_job(state: &AppState, job: &ClaimedJob) -> Result<(), String> {
+    let target = job_storage_target(state, job).await?;
+
+    match job.operation {
+        AssetJobOperation::DeleteObject => {
+            // Deleting a missing key succeeds, so retries are safe
+            target.delete(&job.target).await
+        }
+        AssetJobOperation::DeletePrefix => {
+            target.delete_prefix(&job.target).await?;
+
+            Ok(())
+        }
+    }
+}
+
+pub async fn process_due_jobs(state: &AppState) -> Result<usize, AppResponse> {
+    let client = get_client(&state.pool).await?;
+
+    // Claimed jobs are pushed back by the max backoff, so a crashed worker's jobs get picked up later
+    let rows = client.query(
+        "UPDATE asset_jobs SET attempts = attempts + 1, run_after = NOW() + make_interval(secs => $2)
+         WHERE id IN (
+            SELECT id FROM asset_jobs
+            WHERE status = 'pending' AND run_after <= NOW()
+            ORDER BY run_after
+            LIMIT $1
+            FOR UPDATE SKIP LOCKED
+         )
+         RETURNING id, operation, target, attempts;",
+        &[&JOB_BATCH_SIZE, &(JOB_MAX_BACKOFF.as_secs() as f64)]
+    ).await;
+
+    if rows.is_err() {
+        return Err(AppResponse::Error(rows.err().unwrap().to_string()));
+    }
+
+    let jobs: Vec<ClaimedJob> = rows
+        .unwrap()
+        .iter()
+        .map(|row| ClaimedJob {
+            id: row.get("id"),
+            operation: row.get("operation"),
+            target: row.get("target"),
+            attempts: row.get("attempts"),
+        })
+        .collect();
+
+    let count = jobs.len();
+
+    for job in jobs {
+        let res = execute_job(state, &job).await;
+
+        let update = if res.is_ok() {
+            client.execute("DELETE FROM asset_jobs WHERE id = $1;", &[&job.id]).await
+        } else {
+            let error = res.err().unwrap();
+            let status = if job.attempts >= JOB_MAX_ATTEMPTS {
+                AssetJobStatus::Failed
+            } else {
+                AssetJobStatus::Pending
+            };
+
+            tracing::error!("ASSET JOB {} FAILED (ATTEMPT {}) - {}", job.id, job.attempts, error);
+
+            client.execute(
+                "UPDATE asset_jobs SET status = $2, last_error = $3, run_after = NOW() + make_interval(secs => $4)
+                 WHERE id = $1;",
+                &[&job.id, &status, &error, &(backoff(job.attempts).as_secs() as f64)]
+            ).await
+        };
+
+        if update.is_err() {
+            tracing::error!("ERROR UPDATING ASSET JOB {} - {}", job.id, update.err().unwrap());
+        }
+    }
+
+    Ok(count)
+}
+
+pub async fn run_asset_job_worker(state: AppState) {
+    let mut interval = tokio::time::interval(JOB_POLL_INTERVAL);
+
+    loop {
+        // A batch that's already running is finished, whatever is left stays queued
+        // for the next instance
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = state.job_notify.notified() => {}
+            _ = state.shutdown.cancelled() => {
+                return;
+            }
+        }
+
+        // Keep draining while full batches come back
+        loop {
+            let res = process_due_jobs(&state).await;
+
+            if res.is_err() {
+                tracing::error!("{:?}", res.err().unwrap());
+                break;
+            }
+
+            if res.unwrap() < (JOB_BATCH_SIZE as usize) {
+                break;
+            }
+        }
+    }
+}
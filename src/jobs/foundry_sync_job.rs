@@ -0,0 +1,243 @@
+use std::time::Duration;
+
+use axum::body::Bytes;
+use deadpool_postgres::Object;
+use reqwest::{ header::COOKIE, multipart::{ Form, Part } };
+use serde::Deserialize;
+use tokio_postgres::Row;
+use uuid::Uuid;
+
+use crate::{
+    enums::{ AppResponse, AssetKind, FoundrySource, FoundrySyncStatus, ImageType },
+    state::models::AppState,
+    storage::resolve_target,
+    utils::{
+        asset_utils::{ asset_key, extension_for_mime },
+        db_utils::get_client,
+        dedup_utils::OBJECT_ID,
+        s3_utils::get_object_bytes,
+    },
+};
+
+// Maps are large and instances often run on home connections
+const FOUNDRY_UPLOAD_TIMEOUT: Duration = Duration::from_secs(120);
+const MAX_LOGGED_RESPONSE: usize = 500;
+
+pub struct FoundryConnection {
+    pub url: String,
+    // Value of the `session` cookie of a Foundry user allowed to upload files
+    pub session: String,
+    pub source: FoundrySource,
+    // Only used with the S3 source
+    pub bucket: Option<String>,
+    // Folder of the source the maps are uploaded to
+    pub target_path: String,
+}
+
+impl FoundryConnection {
+    fn from_row(row: &Row) -> Self {
+        FoundryConnection {
+            url: row.get("url"),
+            session: row.get("session"),
+            source: row.get("source"),
+            bucket: row.get("bucket"),
+            target_path: row.get("target_path"),
+        }
+    }
+}
+
+pub async fn get_foundry_connection(
+    client: &Object,
+    project_id: &Uuid
+) -> Result<Option<FoundryConnection>, AppResponse> {
+    let row = client.query_opt(
+        "SELECT url, session, source, bucket, target_path FROM foundry_connections
+         WHERE project_id = $1;",
+        &[&project_id]
+    ).await;
+
+    if row.is_err() {
+        return Err(AppResponse::Error(row.err().unwrap().to_string()));
+    }
+
+    Ok(row.unwrap().as_ref().map(FoundryConnection::from_row))
+}
+
+#[derive(Deserialize)]
+struct FoundryUploadResponse {
+    status: Option<String>,
+    path: Option<String>,
+    message: Option<String>,
+    error: Option<String>,
+}
+
+// Posts to the endpoint Foundry's own file picker uploads through. Returns the path the world
+// refers to the file by.
+async fn upload_to_foundry(
+    state: &AppState,
+    connection: &FoundryConnection,
+    file_name: String,
+    mime_type: &str,
+    data: Bytes
+) -> Result<String, String> {
+    let part = Part::bytes(data.to_vec())
+        .file_name(file_name)
+        .mime_str(mime_type)
+        .map_err(|err| err.to_string())?;
+
+    let mut form = Form::new()
+        .text("source", connection.source.to_string())
+        .text("target", connection.target_path.clone())
+        .part("upload", part);
+
+    if let Some(bucket) = &connection.bucket {
+        form = form.text("bucket", bucket.clone());
+    }
+
+    let res = state.reqwest_client
+        .post(format!("{}/upload", connection.url.trim_end_matches('/')))
+        .timeout(FOUNDRY_UPLOAD_TIMEOUT)
+        .header(COOKIE, format!("session={}", connection.session))
+        .multipart(form)
+        .send().await;
+
+    if res.is_err() {
+        return Err(res.err().unwrap().to_string());
+    }
+
+    let res = res.unwrap();
+    let status = res.status();
+    let text = res.text().await.unwrap_or_default();
+
+    if !status.is_success() {
+        return Err(
+            format!("{} - {}", status, text.chars().take(MAX_LOGGED_RESPONSE).collect::<String>())
+        );
+    }
+
+    // Foundry answers rejected uploads (e.g. a missing permission) with a 200 as well
+    let body = serde_json::from_str::<FoundryUploadResponse>(&text);
+
+    if body.is_err() {
+        return Err(format!("UNEXPECTED FOUNDRY RESPONSE - {}", body.err().unwrap()));
+    }
+
+    let body = body.unwrap();
+
+    match (body.status.as_deref(), body.path) {
+        (Some("success"), Some(path)) => Ok(path),
+        _ =>
+            Err(
+                body.error
+                    .or(body.message)
+                    .unwrap_or_else(|| "FOUNDRY REJECTED THE UPLOAD".to_owned())
+            ),
+    }
+}
+
+async fn record_sync(client: &Object, id: &Uuid, result: Result<String, String>) {
+    let (status, path, error) = match result {
+        Ok(path) => (FoundrySyncStatus::Synced, Some(path), None),
+        Err(err) => (FoundrySyncStatus::Failed, None, Some(err)),
+    };
+
+    let res = client.execute(
+        "UPDATE foundry_syncs
+         SET status = $2, path = COALESCE($3, path), error = $4,
+            synced_at = CASE WHEN $2 = 'synced' THEN NOW() ELSE synced_at END
+         WHERE image_id = $1;",
+        &[&id, &status, &path, &error]
+    ).await;
+
+    if res.is_err() {
+        tracing::error!("ERROR RECORDING FOUNDRY SYNC OF {} - {}", id, res.err().unwrap());
+    }
+}
+
+async fn sync_row(
+    state: &AppState,
+    connection: &FoundryConnection,
+    project_id: &Uuid,
+    row: &Row
+) -> Result<String, String> {
+    let object_id: Uuid = row.get("object_id");
+    let mime_type: String = row.get("mime_type");
+
+    let target = resolve_target(state, project_id).await.map_err(|err| format!("{:?}", err))?;
+    let key = asset_key(
+        project_id,
+        &ImageType::MapImages,
+        &AssetKind::Image,
+        &object_id,
+        &mime_type
+    );
+    let data = get_object_bytes(&target, &key).await.map_err(|err| format!("{:?}", err))?;
+
+    // Named after the asset rather than its title, so syncing again replaces the file in
+    // place and scenes that use it pick up the new version
+    let id: Uuid = row.get("id");
+    let file_name = format!("{}.{}", id, extension_for_mime(&mime_type));
+
+    upload_to_foundry(state, connection, file_name, &mime_type, data).await
+}
+
+// Pushes the map images to the project's Foundry instance one by one and records the outcome
+// of each in foundry_syncs. The rows are expected to be marked pending by the caller.
+pub async fn run_foundry_sync(state: AppState, project_id: Uuid, ids: Vec<Uuid>) {
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        tracing::error!("{:?}", client.err().unwrap());
+        return;
+    }
+
+    let client = client.unwrap();
+    let connection = get_foundry_connection(&client, &project_id).await;
+
+    let connection = match connection {
+        Ok(Some(connection)) => connection,
+        Ok(None) => {
+            for id in &ids {
+                record_sync(&client, id, Err("PROJECT HAS NO FOUNDRY CONNECTION".to_owned())).await;
+            }
+            return;
+        }
+        Err(err) => {
+            tracing::error!("{:?}", err);
+            return;
+        }
+    };
+
+    let rows = client.query(
+        &format!(
+            "SELECT id, mime_type, {} FROM images WHERE id = ANY($1) AND project_id = $2;",
+            OBJECT_ID
+        ),
+        &[&ids, &project_id]
+    ).await;
+
+    if rows.is_err() {
+        tracing::error!("FOUNDRY SYNC FAILED - {}", rows.err().unwrap());
+        return;
+    }
+
+    let rows = rows.unwrap();
+
+    for row in &rows {
+        let id: Uuid = row.get("id");
+        let result = sync_row(&state, &connection, &project_id, row).await;
+
+        if result.is_err() {
+            tracing::error!("FOUNDRY SYNC OF {} FAILED - {}", id, result.as_ref().err().unwrap());
+        }
+
+        record_sync(&client, &id, result).await;
+    }
+
+    // Deleted for good since they were queued, they would stay pending otherwise
+    for id in ids.iter().filter(|id| !rows.iter().any(|row| row.get::<_, Uuid>("id") == **id)) {
+        record_sync(&client, id, Err("ASSET NOT FOUND".to_owned())).await;
+    }
+
+    tracing::info!("FOUNDRY SYNC FINISHED FOR {}", project_id);
+}
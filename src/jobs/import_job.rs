@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::{
+    enums::{ AppResponse, ImageType, OutputFormat },
+    jobs::acl_job::get_project_visibility,
+    services::asset_service::{ store_asset, AssetBody, NewAsset },
+    state::models::AppState,
+    storage::resolve_target,
+    utils::{
+        db_utils::get_client,
+        image_utils::{ load_upload, run_image_task, EncodeOptions },
+    },
+};
+
+
+#[derive(Deserialize)]
+pub struct V3Asset {
+    pub id: Uuid,
+    pub title: String,
+    pub project_id: Uuid,
+    pub owner_id: Uuid,
+    pub key: String,
+    pub image_type: Option<ImageType>,
+}
+
+#[derive(Deserialize)]
+pub struct ImportV3Payload {
+    // Defaults to the default bucket when the v3 objects were never moved. Read with the
+    // default region's client, so it has to live in that region.
+    pub source_bucket: Option<String>,
+    pub source_prefix: Option<String>,
+    // v3 id -> v4 id, anything not listed keeps its original id
+    #[serde(default)]
+    pub project_mapping: HashMap<Uuid, Uuid>,
+    #[serde(default)]
+    pub user_mapping: HashMap<Uuid, Uuid>,
+    pub assets: Vec<V3Asset>,
+}
+
+// Goes through store_asset like every other upload, so imported rows get the same kind, MIME
+// type, dimensions, content hash and moderation status. Assets that were already imported are
+// skipped, re-running an import doesn't touch them.
+async fn import_v3_asset(
+    state: &AppState,
+    source_bucket: &str,
+    source_prefix: &str,
+    project_mapping: &HashMap<Uuid, Uuid>,
+    user_mapping: &HashMap<Uuid, Uuid>,
+    asset: &V3Asset
+) -> Result<(), AppResponse> {
+    let project_id = project_mapping.get(&asset.project_id).unwrap_or(&asset.project_id);
+    let owner_id = user_mapping.get(&asset.owner_id).unwrap_or(&asset.owner_id);
+    let image_type = asset.image_type.as_ref().unwrap_or(&ImageType::Images);
+
+    let client = get_client(&state.pool).await?;
+
+    let existing = client.query_opt("SELECT 1 FROM images WHERE id = $1;", &[&asset.id]).await;
+
+    if existing.is_err() {
+        return Err(AppResponse::Error(existing.err().unwrap().to_string()));
+    }
+
+    if existing.unwrap().is_some() {
+        return Ok(());
+    }
+
+    let source_key = format!("{}{}", source_prefix, &asset.key);
+    let target = resolve_target(state, project_id).await?;
+    let visibility = get_project_visibility(state, project_id).await?;
+    let source = state.storage.default_target();
+
+    let data = source.backend().get(source_bucket, &source_key).await;
+
+    if data.is_err() {
+        return Err(AppResponse::Error(data.err().unwrap()));
+    }
+
+    let data = data.unwrap();
+    // Cheap to clone, the decoder gets one handle and ImageMetadata the other
+    let head = data.clone();
+    let max_pixels = state.config.max_image_pixels;
+
+    let decoded = run_image_task(&state.encode_permits, "import", move || {
+        load_upload(&data, max_pixels)
+    }).await;
+
+    if decoded.is_err() {
+        return Err(AppResponse::Error(decoded.err().unwrap()));
+    }
+
+    let decoded = decoded.unwrap();
+
+    if decoded.is_err() {
+        return Err(AppResponse::Error(decoded.err().unwrap()));
+    }
+
+    let new = NewAsset {
+        id: asset.id,
+        key_id: None,
+        title: &asset.title,
+        project_id,
+        image_type,
+        owner_id,
+        pending: false,
+        visibility,
+        // v3 references assets by their id, so each keeps an object of its own
+        dedupe: false,
+        max_size_bytes: None,
+    };
+
+    let body = AssetBody::Image {
+        img: decoded.unwrap(),
+        head: &head,
+        format: OutputFormat::Webp,
+        options: &EncodeOptions::default(),
+    };
+
+    let stored = store_asset(state, &client, &target, &new, body, None).await;
+
+    if stored.is_err() {
+        return Err(stored.err().unwrap().into());
+    }
+
+    Ok(())
+}
+
+pub async fn run_v3_import(state: AppState, payload: ImportV3Payload) {
+    let source_bucket = payload.source_bucket.clone().unwrap_or(state.config.default_bucket.clone());
+    let source_prefix = payload.source_prefix.clone().unwrap_or_default();
+
+    let mut imported = 0;
+    let mut failed: Vec<Uuid> = vec![];
+
+    for asset in payload.assets.iter() {
+        let res = import_v3_asset(
+            &state,
+            &source_bucket,
+            &source_prefix,
+            &payload.project_mapping,
+            &payload.user_mapping,
+            asset
+        ).await;
+
+        if res.is_err() {
+            tracing::error!("V3 IMPORT FAILED FOR {} - {:?}", asset.id, res.err().unwrap());
+            failed.push(asset.id);
+            continue;
+        }
+
+        imported += 1;
+    }
+
+    tracing::info!("V3 IMPORT FINISHED - {} imported, {} failed {:?}", imported, failed.len(), failed);
+}
@@ -0,0 +1,11 @@
+pub mod sitemap_job;
+pub mod import_job;
+pub mod view_count_job;
+pub mod prewarm_job;
+pub mod acl_job;
+pub mod asset_job;
+pub mod trash_job;
+pub mod webhook_job;
+pub mod reconcile_job;
+pub mod moderation_job;
+pub mod foundry_sync_job;
\ No newline at end of file
@@ -0,0 +1,189 @@
+use std::{ sync::Arc, time::Duration };
+
+use deadpool_postgres::Object;
+use tokio_postgres::Row;
+use uuid::Uuid;
+
+use crate::{
+    enums::{ AppResponse, AssetKind, ImageType, ModerationStatus },
+    jobs::acl_job::get_project_visibility,
+    moderation::{ ModerationBackend, ModerationRequest },
+    state::models::AppState,
+    storage::resolve_target,
+    utils::{ asset_utils::asset_key, db_utils::get_client, dedup_utils::OBJECT_ID },
+    JOB_POLL_INTERVAL,
+    PRESIGN_DURATION,
+};
+
+const MODERATION_BATCH_SIZE: i64 = 20;
+// Claimed assets without a verdict by then are sent again, e.g. after the service failed
+const MODERATION_RETRY_AFTER: Duration = Duration::from_secs(600);
+
+// Lists and thumbnails only show assets that passed moderation or never needed it
+pub const MODERATION_VISIBLE: &str =
+    "(moderation_status IS NULL OR moderation_status = 'approved')";
+
+// Whether images uploaded to the project wait for the moderation service. Only public projects
+// are moderated. When the project can't be read the upload is held back rather than published.
+pub async fn requires_moderation(state: &AppState, client: &Object, project_id: &Uuid) -> bool {
+    if state.moderation.is_none() {
+        return false;
+    }
+
+    let row = client.query_opt(
+        "SELECT is_public FROM projects WHERE id = $1;",
+        &[&project_id]
+    ).await;
+
+    if row.is_err() {
+        tracing::error!("ERROR CHECKING MODERATION - {}", row.err().unwrap());
+        return true;
+    }
+
+    row.unwrap().is_some_and(|row| row.get::<_, Option<bool>>("is_public").unwrap_or(false))
+}
+
+pub async fn is_hidden_by_moderation(client: &Object, id: &Uuid) -> Result<bool, AppResponse> {
+    let row = client.query_opt(
+        &format!("SELECT NOT {} AS hidden FROM images WHERE id = $1;", MODERATION_VISIBLE),
+        &[&id]
+    ).await;
+
+    if row.is_err() {
+        return Err(AppResponse::Error(row.err().unwrap().to_string()));
+    }
+
+    // Ids without a row (e.g. gateway entity images) are never moderated
+    Ok(row.unwrap().is_some_and(|row| row.get("hidden")))
+}
+
+pub fn notify_moderation_worker(state: &AppState) {
+    state.moderation_notify.notify_one();
+}
+
+// Sends the object to the moderation service and records its verdict. Approved objects get
+// the project's visibility unless the upload still waits for the owner's approval.
+async fn moderate_row(
+    state: &AppState,
+    client: &Object,
+    backend: &Arc<dyn ModerationBackend>,
+    row: &Row
+) -> Result<(), String> {
+    let id: Uuid = row.get("id");
+    let project_id: Uuid = row.get("project_id");
+    let image_type: ImageType = row.get("type");
+    let kind: AssetKind = row.get("kind");
+    let mime_type: String = row.get("mime_type");
+    let object_id: Uuid = row.get("object_id");
+    let pending: bool = row.get("pending");
+
+    let target = resolve_target(state, &project_id).await.map_err(|err| format!("{:?}", err))?;
+    let key = asset_key(&project_id, &image_type, &kind, &object_id, &mime_type);
+    let url = target.presign_get(&key, PRESIGN_DURATION).await?;
+
+    let verdict = backend.moderate(
+        &(ModerationRequest {
+            id: &id,
+            project_id: &project_id,
+            url: &url,
+            mime_type: &mime_type,
+        })
+    ).await?;
+
+    if verdict.status == ModerationStatus::Approved && !pending {
+        let visibility = get_project_visibility(state, &project_id).await.map_err(|err|
+            format!("{:?}", err)
+        )?;
+
+        target.set_visibility(&key, visibility).await?;
+    }
+
+    let res = client.execute(
+        "UPDATE images SET moderation_status = $2, moderation_reason = $3
+         WHERE id = $1 AND moderation_status = 'pending';",
+        &[&id, &verdict.status, &verdict.reason]
+    ).await;
+
+    if res.is_err() {
+        return Err(res.err().unwrap().to_string());
+    }
+
+    Ok(())
+}
+
+async fn process_pending_moderation(
+    state: &AppState,
+    backend: &Arc<dyn ModerationBackend>
+) -> Result<usize, AppResponse> {
+    let client = get_client(&state.pool).await?;
+
+    // Like asset jobs, claimed rows are only picked up again once the claim is stale
+    let rows = client.query(
+        &format!(
+            "UPDATE images SET moderation_claimed_at = NOW()
+             WHERE id IN (
+                SELECT id FROM images
+                WHERE moderation_status = 'pending' AND awaiting_upload = FALSE AND deleted_at IS NULL
+                    AND (moderation_claimed_at IS NULL
+                        OR moderation_claimed_at <= NOW() - make_interval(secs => $2))
+                ORDER BY created_at
+                LIMIT $1
+                FOR UPDATE SKIP LOCKED
+             )
+             RETURNING id, project_id, type, kind, mime_type, pending, {};",
+            OBJECT_ID
+        ),
+        &[&MODERATION_BATCH_SIZE, &(MODERATION_RETRY_AFTER.as_secs() as f64)]
+    ).await;
+
+    if rows.is_err() {
+        return Err(AppResponse::Error(rows.err().unwrap().to_string()));
+    }
+
+    let rows = rows.unwrap();
+
+    for row in &rows {
+        let res = moderate_row(state, &client, backend, row).await;
+
+        if res.is_err() {
+            let id: Uuid = row.get("id");
+            tracing::error!("MODERATION OF {} FAILED - {}", id, res.err().unwrap());
+        }
+    }
+
+    Ok(rows.len())
+}
+
+pub async fn run_moderation_worker(state: AppState) {
+    let backend = state.moderation.clone();
+
+    if backend.is_none() {
+        return;
+    }
+
+    let backend = backend.unwrap();
+    let mut interval = tokio::time::interval(JOB_POLL_INTERVAL);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = state.moderation_notify.notified() => {}
+            _ = state.shutdown.cancelled() => {
+                return;
+            }
+        }
+
+        loop {
+            let res = process_pending_moderation(&state, &backend).await;
+
+            if res.is_err() {
+                tracing::error!("{:?}", res.err().unwrap());
+                break;
+            }
+
+            if res.unwrap() < (MODERATION_BATCH_SIZE as usize) {
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,80 @@
+use futures::{ stream, StreamExt };
+use uuid::Uuid;
+
+use crate::{
+    enums::ImageType,
+    state::models::AppState,
+    storage::resolve_target,
+    utils::{ dedup_utils::StoredObject, thumbnail_utils::{ sign_thumbnail_url, THUMBNAIL_PRESETS } },
+};
+
+const PREWARM_CONCURRENCY: usize = 8;
+
+// Requests every preset size through the signed thumbnail URLs so the thumbnail
+// service generates and caches them ahead of the first real visitor.
+pub async fn run_thumbnail_prewarm(
+    state: AppState,
+    project_id: Uuid,
+    assets: Vec<(StoredObject, ImageType)>
+) {
+    let target = resolve_target(&state, &project_id).await;
+
+    if target.is_err() {
+        tracing::error!("THUMBNAIL PREWARM FAILED FOR {} - {:?}", project_id, target.err().unwrap());
+        return;
+    }
+
+    let target = target.unwrap();
+
+    // Thumbnails of dedicated buckets are generated locally on first request instead
+    if !target.is_default() {
+        return;
+    }
+
+    let urls: Vec<String> = assets
+        .iter()
+        .flat_map(|(object, image_type)| {
+            THUMBNAIL_PRESETS.iter().map(|(width, height)|
+                sign_thumbnail_url(
+                    &state,
+                    &target,
+                    &project_id,
+                    image_type,
+                    &object.id,
+                    &object.mime_type,
+                    *width,
+                    *height
+                )
+            )
+        })
+        .collect();
+
+    let total = urls.len();
+
+    let failed = stream
+        ::iter(urls)
+        .map(|url| {
+            let reqwest_client = state.reqwest_client.clone();
+
+            async move {
+                let res = reqwest_client.get(&url).send().await;
+
+                match res {
+                    Ok(res) if res.status().is_success() => true,
+                    Ok(res) => {
+                        tracing::error!("PREWARM FAILED - {} {}", res.status(), url);
+                        false
+                    }
+                    Err(err) => {
+                        tracing::error!("PREWARM FAILED - {}", err);
+                        false
+                    }
+                }
+            }
+        })
+        .buffer_unordered(PREWARM_CONCURRENCY)
+        .filter(|ok| std::future::ready(!ok))
+        .count().await;
+
+    tracing::info!("THUMBNAIL PREWARM FINISHED FOR {} - {} requested, {} failed", project_id, total, failed);
+}
@@ -0,0 +1,254 @@
+use std::collections::HashSet;
+
+use axum::body::{ Body, Bytes };
+use serde_json::{ json, Value };
+use tokio::sync::mpsc::{ self, Receiver, Sender };
+use uuid::Uuid;
+
+use crate::{
+    enums::{ AppResponse, AssetJobOperation, AssetKind, ImageType },
+    jobs::{
+        acl_job::list_project_asset_keys,
+        asset_job::{ enqueue_jobs, notify_job_worker, AssetJob },
+    },
+    state::models::AppState,
+    storage::{ resolve_target, StorageTarget },
+    utils::{
+        asset_utils::asset_key,
+        db_utils::get_client,
+        dedup_utils::OBJECT_ID,
+        trash_utils::trash_key,
+        zip_utils::BodyChunk,
+    },
+};
+
+// Every line is a JSON object with an "event" field:
+// started, listed, orphaned_object, dangling_row, error and finally finished
+struct ReconcileReport {
+    sender: Sender<BodyChunk>,
+}
+
+impl ReconcileReport {
+    // A client that went away doesn't stop the run, the repairs are still applied
+    async fn send(&self, event: Value) {
+        let _ = self.sender.send(Ok(Bytes::from(format!("{}\n", event)))).await;
+    }
+
+    async fn error(&self, message: String) {
+        tracing::error!("RECONCILIATION FAILED - {}", message);
+        self.send(json!({ "event": "error", "message": message })).await;
+    }
+}
+
+// Spawns the reconciliation of a project and returns its progress as a JSON lines body
+pub fn start_reconciliation(state: &AppState, project_id: Uuid, repair: bool) -> Body {
+    let (sender, receiver): (Sender<BodyChunk>, Receiver<BodyChunk>) = mpsc::channel(16);
+
+    let report = ReconcileReport { sender };
+
+    state.tasks.spawn(run_reconciliation(state.clone(), project_id, repair, report));
+
+    let stream = futures::stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|line| (line, receiver))
+    });
+
+    Body::from_stream(stream)
+}
+
+// Id an object is stored under, taken from the file name. Sprite manifests share the id of
+// their sheet, so they match the sheet's row.
+fn object_id_of(key: &str) -> Option<Uuid> {
+    let name = key.rsplit('/').next().unwrap_or_default();
+
+    Uuid::parse_str(name.split('.').next().unwrap_or_default()).ok()
+}
+
+async fn list_objects(
+    target: &StorageTarget,
+    project_id: &Uuid
+) -> Result<Vec<String>, AppResponse> {
+    let mut keys = list_project_asset_keys(target, project_id).await?;
+    let trashed = target.list(&trash_key(&format!("assets/{}/", project_id))).await;
+
+    if trashed.is_err() {
+        return Err(AppResponse::Error(trashed.err().unwrap()));
+    }
+
+    keys.extend(
+        trashed
+            .unwrap()
+            .into_iter()
+            .filter(|key| {
+                !key.contains("/renditions/") &&
+                    !key.contains("/thumbs/") &&
+                    !key.contains("/variants/")
+            })
+    );
+
+    Ok(keys)
+}
+
+// Objects are listed before the rows are read, so an upload that finishes in between shows up
+// as a dangling row at worst. Orphans are checked against the table again before they're
+// deleted. Gateway entity images are stored under their entity's id instead of their row's,
+// so projects that use the gateway should be checked without repairing first.
+async fn run_reconciliation(
+    state: AppState,
+    project_id: Uuid,
+    repair: bool,
+    report: ReconcileReport
+) {
+    report.send(json!({ "event": "started", "project_id": project_id, "repair": repair })).await;
+
+    let target = resolve_target(&state, &project_id).await;
+
+    if target.is_err() {
+        report.error(format!("{:?}", target.err().unwrap())).await;
+        return;
+    }
+
+    let target = target.unwrap();
+    let keys = list_objects(&target, &project_id).await;
+
+    if keys.is_err() {
+        report.error(format!("{:?}", keys.err().unwrap())).await;
+        return;
+    }
+
+    let keys = keys.unwrap();
+
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        report.error(format!("{:?}", client.err().unwrap())).await;
+        return;
+    }
+    let client = client.unwrap();
+
+    // Rows of presigned and resumable uploads have no object until they are confirmed
+    let rows = client.query(
+        &format!(
+            "SELECT id, type, kind, mime_type, {} FROM images
+             WHERE project_id = $1 AND awaiting_upload = FALSE;",
+            OBJECT_ID
+        ),
+        &[&project_id]
+    ).await;
+
+    if rows.is_err() {
+        report.error(rows.err().unwrap().to_string()).await;
+        return;
+    }
+
+    let rows = rows.unwrap();
+
+    report.send(json!({ "event": "listed", "objects": keys.len(), "rows": rows.len() })).await;
+
+    let stored: HashSet<&str> = keys
+        .iter()
+        .map(|key| key.as_str())
+        .collect();
+    let mut known: HashSet<Uuid> = HashSet::new();
+    let mut dangling: Vec<Uuid> = vec![];
+
+    for row in rows {
+        let id: Uuid = row.get("id");
+        let object_id: Uuid = row.get("object_id");
+        let image_type: ImageType = row.get("type");
+        let kind: AssetKind = row.get("kind");
+        let mime_type: String = row.get("mime_type");
+
+        known.insert(object_id);
+
+        let key = asset_key(&project_id, &image_type, &kind, &object_id, &mime_type);
+
+        if !stored.contains(key.as_str()) && !stored.contains(trash_key(&key).as_str()) {
+            report.send(json!({ "event": "dangling_row", "id": id, "key": key })).await;
+            dangling.push(id);
+        }
+    }
+
+    let orphaned: Vec<&String> = keys
+        .iter()
+        .filter(|key| !object_id_of(key).is_some_and(|id| known.contains(&id)))
+        .collect();
+
+    for key in &orphaned {
+        report.send(json!({ "event": "orphaned_object", "key": key })).await;
+    }
+
+    let mut repaired_rows = 0;
+    let mut repaired_objects = 0;
+
+    if repair && !dangling.is_empty() {
+        let res = client.execute(
+            "UPDATE images SET object_missing_at = now()
+             WHERE id = ANY($1) AND object_missing_at IS NULL;",
+            &[&dangling]
+        ).await;
+
+        if res.is_err() {
+            report.error(res.err().unwrap().to_string()).await;
+            return;
+        }
+
+        repaired_rows = res.unwrap();
+    }
+
+    if repair && !orphaned.is_empty() {
+        let ids: Vec<Uuid> = orphaned
+            .iter()
+            .filter_map(|key| object_id_of(key))
+            .collect();
+
+        let claimed = client.query(
+            &format!(
+                "SELECT DISTINCT {} FROM images WHERE id = ANY($1) OR object_id = ANY($1);",
+                OBJECT_ID
+            ),
+            &[&ids]
+        ).await;
+
+        if claimed.is_err() {
+            report.error(claimed.err().unwrap().to_string()).await;
+            return;
+        }
+
+        let claimed: HashSet<Uuid> = claimed
+            .unwrap()
+            .iter()
+            .map(|row| row.get("object_id"))
+            .collect();
+
+        let jobs: Vec<AssetJob> = orphaned
+            .iter()
+            .filter(|key| !object_id_of(key).is_some_and(|id| claimed.contains(&id)))
+            .map(|key| AssetJob {
+                operation: AssetJobOperation::DeleteObject,
+                target: key.to_string(),
+            })
+            .collect();
+
+        let enqueued = enqueue_jobs(&client, &jobs).await;
+
+        if enqueued.is_err() {
+            report.error(format!("{:?}", enqueued.err().unwrap())).await;
+            return;
+        }
+
+        notify_job_worker(&state);
+        repaired_objects = jobs.len();
+    }
+
+    report.send(
+        json!({
+            "event": "finished",
+            "orphaned_objects": orphaned.len(),
+            "dangling_rows": dangling.len(),
+            "deleted_objects": repaired_objects,
+            "marked_rows": repaired_rows,
+        })
+    ).await;
+
+    tracing::info!("RECONCILIATION FINISHED FOR {}", project_id);
+}
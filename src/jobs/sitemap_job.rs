@@ -0,0 +1,201 @@
+use std::{ collections::HashSet, str::FromStr };
+
+use deadpool_postgres::Object;
+use uuid::Uuid;
+
+use crate::{
+    enums::{ AppResponse, AssetVisibility, ImageType },
+    jobs::moderation_job::MODERATION_VISIBLE,
+    state::models::AppState,
+    storage::{ backend::PutOptions, resolve_target },
+    utils::{
+        db_utils::get_client,
+        dedup_utils::OBJECT_ID,
+        domain_utils::{ asset_url, get_custom_domain },
+    },
+    SITEMAP_INTERVAL,
+};
+
+const SITEMAP_PREFIX: &str = "sitemaps/";
+
+// Sitemaps stay in the default bucket whatever the project's storage target
+pub fn sitemap_key(project_id: &Uuid) -> String {
+    format!("{}{}.xml", SITEMAP_PREFIX, project_id)
+}
+
+fn escape_xml(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+pub async fn generate_project_sitemap(
+    state: &AppState,
+    project_id: &Uuid
+) -> Result<(), AppResponse> {
+    let client = get_client(&state.pool).await?;
+
+    let rows = client.query(
+        &format!(
+            "SELECT {}, title, type, mime_type, to_char(updated_at, 'YYYY-MM-DD') AS lastmod FROM images
+             WHERE project_id = $1 AND is_public = TRUE AND pending = FALSE AND deleted_at IS NULL
+                AND {}
+             ORDER BY updated_at DESC;",
+            OBJECT_ID,
+            MODERATION_VISIBLE
+        ),
+        &[&project_id]
+    ).await;
+
+    if rows.is_err() {
+        return Err(AppResponse::Error(rows.err().unwrap().to_string()));
+    }
+
+    let domain = get_custom_domain(&client, project_id).await;
+    let target = resolve_target(state, project_id).await?;
+
+    let mut xml = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\" xmlns:image=\"http://www.google.com/schemas/sitemap-image/1.1\">\n"
+    );
+
+    for row in rows.unwrap() {
+        let object_id: Uuid = row.get("object_id");
+        let title: Option<String> = row.get("title");
+        let image_type: ImageType = row.get("type");
+        let mime_type: String = row.get("mime_type");
+        let lastmod: Option<String> = row.get("lastmod");
+
+        let loc = escape_xml(
+            &asset_url(&target, domain.as_deref(), project_id, &image_type, &object_id, &mime_type)
+        );
+
+        xml.push_str("  <url>\n");
+        xml.push_str(&format!("    <loc>{}</loc>\n", loc));
+        if let Some(lastmod) = lastmod {
+            xml.push_str(&format!("    <lastmod>{}</lastmod>\n", lastmod));
+        }
+        xml.push_str("    <image:image>\n");
+        xml.push_str(&format!("      <image:loc>{}</image:loc>\n", loc));
+        if let Some(title) = title {
+            xml.push_str(&format!("      <image:title>{}</image:title>\n", escape_xml(&title)));
+        }
+        xml.push_str("    </image:image>\n");
+        xml.push_str("  </url>\n");
+    }
+
+    xml.push_str("</urlset>\n");
+
+    let default_target = state.storage.default_target();
+
+    let upload = default_target.put(&sitemap_key(project_id), xml.into_bytes(), &PutOptions {
+        content_type: "application/xml",
+        cache_control: "max-age=3600",
+        visibility: AssetVisibility::Public,
+    }).await;
+
+    if upload.is_err() {
+        return Err(AppResponse::Error(upload.err().unwrap()));
+    }
+
+    Ok(())
+}
+
+// Sitemaps of projects that are no longer public (or no longer exist) would keep publishing
+// their asset titles, so they're deleted instead of just no longer regenerated
+async fn remove_stale_sitemaps(state: &AppState, client: &Object) -> Result<(), AppResponse> {
+    let target = state.storage.default_target();
+    let keys = target.list(SITEMAP_PREFIX).await;
+
+    if keys.is_err() {
+        return Err(AppResponse::Error(keys.err().unwrap()));
+    }
+
+    let keys = keys.unwrap();
+    let project_ids: Vec<Uuid> = keys
+        .iter()
+        .filter_map(|key| {
+            let name = key.strip_prefix(SITEMAP_PREFIX)?.strip_suffix(".xml")?;
+
+            Uuid::from_str(name).ok()
+        })
+        .collect();
+
+    if project_ids.is_empty() {
+        return Ok(());
+    }
+
+    let public = client.query(
+        "SELECT id FROM projects WHERE id = ANY($1) AND is_public = TRUE;",
+        &[&project_ids]
+    ).await;
+
+    if public.is_err() {
+        return Err(AppResponse::Error(public.err().unwrap().to_string()));
+    }
+
+    let public: HashSet<Uuid> = public
+        .unwrap()
+        .iter()
+        .map(|row| row.get("id"))
+        .collect();
+
+    for project_id in project_ids.iter().filter(|project_id| !public.contains(project_id)) {
+        let res = target.delete(&sitemap_key(project_id)).await;
+
+        if res.is_err() {
+            tracing::error!("COULD NOT DELETE SITEMAP OF {} - {}", project_id, res.err().unwrap());
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn regenerate_sitemaps(state: &AppState) -> Result<(), AppResponse> {
+    let client = get_client(&state.pool).await?;
+
+    let removed = remove_stale_sitemaps(state, &client).await;
+
+    if removed.is_err() {
+        tracing::error!("{:?}", removed.err().unwrap());
+    }
+
+    let projects = client.query("SELECT id FROM projects WHERE is_public = TRUE;", &[]).await;
+
+    if projects.is_err() {
+        return Err(AppResponse::Error(projects.err().unwrap().to_string()));
+    }
+
+    for project in projects.unwrap() {
+        let project_id: Uuid = project.get("id");
+
+        let res = generate_project_sitemap(state, &project_id).await;
+
+        if res.is_err() {
+            tracing::error!("SITEMAP GENERATION FAILED FOR {} - {:?}", project_id, res.err());
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn run_sitemap_job(state: AppState) {
+    let mut interval = tokio::time::interval(SITEMAP_INTERVAL);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = state.shutdown.cancelled() => {
+                return;
+            }
+        }
+
+        let res = regenerate_sitemaps(&state).await;
+
+        if res.is_err() {
+            tracing::error!("{:?}", res.err().unwrap());
+        }
+    }
+}
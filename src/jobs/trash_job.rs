@@ -0,0 +1,141 @@
+use uuid::Uuid;
+
+use crate::{
+    enums::{ AppResponse, AssetKind, ImageType },
+    jobs::asset_job::{ deleted_asset_columns, deletion_jobs, enqueue_jobs, notify_job_worker },
+    state::models::AppState,
+    storage::resolve_target,
+    utils::{ asset_utils::asset_key, db_utils::get_client },
+    TRASH_PURGE_INTERVAL,
+    TRASH_RETENTION_DAYS,
+};
+
+// Hard deletes trashed rows past the retention window, their objects go through the job queue
+pub async fn purge_trash(state: &AppState) -> Result<(), AppResponse> {
+    let mut client = get_client(&state.pool).await?;
+
+    let transaction = client.transaction().await;
+
+    if transaction.is_err() {
+        return Err(AppResponse::Error(transaction.err().unwrap().to_string()));
+    }
+    let transaction = transaction.unwrap();
+
+    let rows = transaction.query(
+        &format!(
+            "DELETE FROM images WHERE deleted_at < NOW() - make_interval(days => $1) RETURNING {};",
+            deleted_asset_columns()
+        ),
+        &[&TRASH_RETENTION_DAYS]
+    ).await;
+
+    if rows.is_err() {
+        return Err(AppResponse::Error(rows.err().unwrap().to_string()));
+    }
+
+    let rows = rows.unwrap();
+
+    if rows.is_empty() {
+        return Ok(());
+    }
+
+    let jobs = deletion_jobs(&transaction, &rows).await?;
+
+    enqueue_jobs(&transaction, &jobs).await?;
+
+    let committed = transaction.commit().await;
+
+    if committed.is_err() {
+        return Err(AppResponse::Error(committed.err().unwrap().to_string()));
+    }
+
+    tracing::info!("PURGED {} TRASHED ASSETS", rows.len());
+
+    notify_job_worker(state);
+
+    Ok(())
+}
+
+// Abandoned resumable uploads keep their parts in storage until the multipart upload is
+// aborted. Sessions whose abort fails are left for the next run.
+pub async fn purge_upload_sessions(state: &AppState) -> Result<(), AppResponse> {
+    let client = get_client(&state.pool).await?;
+
+    let rows = client.query(
+        "SELECT upload_sessions.id, upload_sessions.project_id, upload_sessions.upload_id,
+            images.type, images.kind, images.mime_type
+         FROM upload_sessions
+         JOIN images ON images.id = upload_sessions.id
+         WHERE upload_sessions.expires_at < NOW();",
+        &[]
+    ).await;
+
+    if rows.is_err() {
+        return Err(AppResponse::Error(rows.err().unwrap().to_string()));
+    }
+
+    let mut ids: Vec<Uuid> = vec![];
+
+    for row in rows.unwrap() {
+        let id: Uuid = row.get("id");
+        let project_id: Uuid = row.get("project_id");
+        let image_type: ImageType = row.get("type");
+        let kind: AssetKind = row.get("kind");
+        let mime_type: String = row.get("mime_type");
+        let upload_id: String = row.get("upload_id");
+
+        let target = resolve_target(state, &project_id).await?;
+        let key = asset_key(&project_id, &image_type, &kind, &id, &mime_type);
+        let res = target.abort_multipart(&key, &upload_id).await;
+
+        if res.is_err() {
+            tracing::error!("ERROR ABORTING UPLOAD SESSION {} - {}", id, res.err().unwrap());
+            continue;
+        }
+
+        ids.push(id);
+    }
+
+    if ids.is_empty() {
+        return Ok(());
+    }
+
+    let res = client.execute(
+        "WITH sessions AS (DELETE FROM upload_sessions WHERE id = ANY($1))
+         DELETE FROM images WHERE id = ANY($1) AND awaiting_upload = TRUE;",
+        &[&ids]
+    ).await;
+
+    if res.is_err() {
+        return Err(AppResponse::Error(res.err().unwrap().to_string()));
+    }
+
+    tracing::info!("PURGED {} EXPIRED UPLOAD SESSIONS", ids.len());
+
+    Ok(())
+}
+
+pub async fn run_trash_purge_job(state: AppState) {
+    let mut interval = tokio::time::interval(TRASH_PURGE_INTERVAL);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = state.shutdown.cancelled() => {
+                return;
+            }
+        }
+
+        let res = purge_trash(&state).await;
+
+        if res.is_err() {
+            tracing::error!("{:?}", res.err().unwrap());
+        }
+
+        let res = purge_upload_sessions(&state).await;
+
+        if res.is_err() {
+            tracing::error!("{:?}", res.err().unwrap());
+        }
+    }
+}
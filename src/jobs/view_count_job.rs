@@ -0,0 +1,67 @@
+use std::{ collections::HashMap, mem };
+
+use uuid::Uuid;
+
+use crate::{
+    enums::AppResponse,
+    state::models::AppState,
+    utils::db_utils::get_client,
+    VIEW_FLUSH_INTERVAL,
+};
+
+pub fn record_view(state: &AppState, image_id: Uuid) {
+    let mut counter = state.view_counter.lock().unwrap();
+    *counter.entry(image_id).or_insert(0) += 1;
+}
+
+pub async fn flush_view_counts(state: &AppState) -> Result<(), AppResponse> {
+    let counts: HashMap<Uuid, i64> = {
+        let mut counter = state.view_counter.lock().unwrap();
+        mem::take(&mut *counter)
+    };
+
+    if counts.is_empty() {
+        return Ok(());
+    }
+
+    let (ids, views): (Vec<Uuid>, Vec<i64>) = counts.into_iter().unzip();
+
+    let client = get_client(&state.pool).await?;
+
+    let res = client.execute(
+        "INSERT INTO asset_views (image_id, day, views)
+         SELECT counts.image_id, CURRENT_DATE, counts.views
+         FROM UNNEST($1::UUID[], $2::BIGINT[]) AS counts(image_id, views)
+         JOIN images ON images.id = counts.image_id
+         ON CONFLICT (image_id, day) DO UPDATE SET views = asset_views.views + EXCLUDED.views;",
+        &[&ids, &views]
+    ).await;
+
+    if res.is_err() {
+        return Err(AppResponse::Error(res.err().unwrap().to_string()));
+    }
+
+    Ok(())
+}
+
+pub async fn run_view_count_job(state: AppState) {
+    let mut interval = tokio::time::interval(VIEW_FLUSH_INTERVAL);
+
+    loop {
+        // Views recorded since the last tick are written one last time on shutdown
+        let shutting_down = tokio::select! {
+            _ = interval.tick() => false,
+            _ = state.shutdown.cancelled() => true,
+        };
+
+        let res = flush_view_counts(&state).await;
+
+        if res.is_err() {
+            tracing::error!("{:?}", res.err().unwrap());
+        }
+
+        if shutting_down {
+            return;
+        }
+    }
+}
@@ -0,0 +1,203 @@
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+
+use reqwest::header::CONTENT_TYPE;
+use serde_json::Value;
+use uuid::Uuid;
+
+use crate::{
+    enums::{ AppResponse, WebhookDeliveryStatus, WebhookEvent },
+    state::models::AppState,
+    utils::{
+        db_utils::get_client,
+        webhook_utils::{
+            sign_payload,
+            DELIVERY_HEADER,
+            EVENT_HEADER,
+            SIGNATURE_HEADER,
+            TIMESTAMP_HEADER,
+        },
+    },
+    JOB_POLL_INTERVAL,
+};
+
+const WEBHOOK_BATCH_SIZE: i64 = 50;
+const WEBHOOK_MAX_ATTEMPTS: i32 = 8;
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+const WEBHOOK_BASE_BACKOFF: Duration = Duration::from_secs(30);
+const WEBHOOK_MAX_BACKOFF: Duration = Duration::from_secs(21600); // 6 hours
+// Only the start of a failed response is kept in the delivery log
+const MAX_LOGGED_RESPONSE: usize = 512;
+
+struct ClaimedDelivery {
+    id: Uuid,
+    event: WebhookEvent,
+    payload: Value,
+    attempts: i32,
+    url: String,
+    secret: Option<String>,
+}
+
+fn backoff(attempts: i32) -> Duration {
+    let factor = 2_u32.saturating_pow(attempts.max(0) as u32);
+
+    WEBHOOK_BASE_BACKOFF.saturating_mul(factor).min(WEBHOOK_MAX_BACKOFF)
+}
+
+// Ok with the status code for 2xx responses, Err with the status code (when there was a
+// response) and the reason otherwise
+async fn deliver(state: &AppState, delivery: &ClaimedDelivery) -> Result<i32, (Option<i32>, String)> {
+    if delivery.secret.is_none() {
+        return Err((None, "PROJECT HAS NO WEBHOOK SECRET".to_owned()));
+    }
+
+    let body = delivery.payload.to_string();
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or(0);
+
+    let res = state.reqwest_client
+        .post(&delivery.url)
+        .timeout(WEBHOOK_TIMEOUT)
+        .header(CONTENT_TYPE, "application/json")
+        .header(EVENT_HEADER, delivery.event.name())
+        .header(DELIVERY_HEADER, delivery.id.to_string())
+        .header(TIMESTAMP_HEADER, timestamp.to_string())
+        .header(SIGNATURE_HEADER, sign_payload(delivery.secret.as_ref().unwrap(), timestamp, &body))
+        .body(body)
+        .send().await;
+
+    if res.is_err() {
+        return Err((None, res.err().unwrap().to_string()));
+    }
+
+    let res = res.unwrap();
+    let status = res.status();
+
+    if status.is_success() {
+        return Ok(status.as_u16() as i32);
+    }
+
+    let text = res.text().await.unwrap_or_default();
+
+    Err((
+        Some(status.as_u16() as i32),
+        format!("{} - {}", status, text.chars().take(MAX_LOGGED_RESPONSE).collect::<String>()),
+    ))
+}
+
+pub async fn process_due_deliveries(state: &AppState) -> Result<usize, AppResponse> {
+    let client = get_client(&state.pool).await?;
+
+    // Same claiming scheme as asset jobs, a crashed worker's deliveries get picked up later
+    let rows = client.query(
+        "WITH claimed AS (
+            UPDATE webhook_deliveries SET attempts = attempts + 1, run_after = NOW() + make_interval(secs => $2)
+            WHERE id IN (
+                SELECT id FROM webhook_deliveries
+                WHERE status = 'pending' AND run_after <= NOW()
+                ORDER BY run_after
+                LIMIT $1
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING id, webhook_id, event, payload, attempts
+         )
+         SELECT claimed.id, claimed.event, claimed.payload, claimed.attempts, webhooks.url, projects.webhook_secret
+         FROM claimed
+         JOIN webhooks ON webhooks.id = claimed.webhook_id
+         JOIN projects ON projects.id = webhooks.project_id;",
+        &[&WEBHOOK_BATCH_SIZE, &(WEBHOOK_MAX_BACKOFF.as_secs() as f64)]
+    ).await;
+
+    if rows.is_err() {
+        return Err(AppResponse::Error(rows.err().unwrap().to_string()));
+    }
+
+    let deliveries: Vec<ClaimedDelivery> = rows
+        .unwrap()
+        .iter()
+        .map(|row| ClaimedDelivery {
+            id: row.get("id"),
+            event: row.get("event"),
+            payload: row.get("payload"),
+            attempts: row.get("attempts"),
+            url: row.get("url"),
+            secret: row.get("webhook_secret"),
+        })
+        .collect();
+
+    let count = deliveries.len();
+
+    for delivery in deliveries {
+        let res = deliver(state, &delivery).await;
+
+        let update = if res.is_ok() {
+            client.execute(
+                "UPDATE webhook_deliveries
+                 SET status = $2, response_status = $3, last_error = NULL, delivered_at = NOW()
+                 WHERE id = $1;",
+                &[&delivery.id, &WebhookDeliveryStatus::Delivered, &res.unwrap()]
+            ).await
+        } else {
+            let (response_status, error) = res.err().unwrap();
+            let status = if delivery.attempts >= WEBHOOK_MAX_ATTEMPTS {
+                WebhookDeliveryStatus::Failed
+            } else {
+                WebhookDeliveryStatus::Pending
+            };
+
+            tracing::error!(
+                "WEBHOOK DELIVERY {} FAILED (ATTEMPT {}) - {}",
+                delivery.id,
+                delivery.attempts,
+                error
+            );
+
+            client.execute(
+                "UPDATE webhook_deliveries
+                 SET status = $2, response_status = $3, last_error = $4, run_after = NOW() + make_interval(secs => $5)
+                 WHERE id = $1;",
+                &[
+                    &delivery.id,
+                    &status,
+                    &response_status,
+                    &error,
+                    &(backoff(delivery.attempts).as_secs() as f64),
+                ]
+            ).await
+        };
+
+        if update.is_err() {
+            tracing::error!("ERROR UPDATING WEBHOOK DELIVERY {} - {}", delivery.id, update.err().unwrap());
+        }
+    }
+
+    Ok(count)
+}
+
+pub async fn run_webhook_worker(state: AppState) {
+    let mut interval = tokio::time::interval(JOB_POLL_INTERVAL);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = state.webhook_notify.notified() => {}
+            _ = state.shutdown.cancelled() => {
+                return;
+            }
+        }
+
+        loop {
+            let res = process_due_deliveries(&state).await;
+
+            if res.is_err() {
+                tracing::error!("{:?}", res.err().unwrap());
+                break;
+            }
+
+            if res.unwrap() < (WEBHOOK_BATCH_SIZE as usize) {
+                break;
+            }
+        }
+    }
+}
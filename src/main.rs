@@ -1,120 +1,468 @@
-use std::{ env, str::FromStr, time::Duration };
+use std::{ collections::HashMap, str::FromStr, sync::{ Arc, Mutex }, time::Duration };
 
 use aws_config::{ BehaviorVersion, Region };
-use aws_sdk_s3::config::Credentials;
+use aws_sdk_s3::config::{ retry::RetryConfig, timeout::TimeoutConfig, Credentials };
 use axum::{
-    extract::{ MatchedPath, Request },
+    extract::{ MatchedPath, Request, State },
     http::HeaderName,
-    response::IntoResponse,
+    middleware::from_fn,
+    response::{ IntoResponse, Response },
+    Json,
     Router,
     routing::get,
 };
-use deadpool_postgres::{ Config as DeadPoolConfig, ManagerConfig };
-use reqwest::{ header::CONTENT_TYPE, Method, StatusCode };
+use deadpool_postgres::{ Config as DeadPoolConfig, ManagerConfig, Pool };
+use metrics_exporter_prometheus::PrometheusHandle;
+use reqwest::{
+    header::{ ACCEPT_RANGES, CONTENT_RANGE, CONTENT_TYPE, ETAG, RANGE },
+    Method,
+    StatusCode,
+};
+use serde_json::{ json, Value };
 use routes::{
+    admin_routes::admin_routes,
+    api_key_routes::api_key_routes,
+    collection_routes::collection_routes,
+    library_routes::library_routes,
     crud_routes::crud_routes,
+    domain_routes::domain_routes,
     extension_routes::extension_routes,
     foundry_routes::foundry_routes,
+    openapi_routes::openapi_routes,
+    placeholder_routes::placeholder_routes,
+    public_routes::public_routes,
+    storage_routes::storage_routes,
     thumbnail_routes::thumbnail_routes,
     upload_routes::upload_routes,
+    user_routes::user_routes,
+    webhook_routes::webhook_routes,
+};
+use jobs::{
+    asset_job::run_asset_job_worker,
+    moderation_job::run_moderation_worker,
+    sitemap_job::run_sitemap_job,
+    trash_job::run_trash_purge_job,
+    view_count_job::run_view_count_job,
+    webhook_job::run_webhook_worker,
 };
+use config::Config;
+use enums::{ LogFormat, StorageBackendKind };
+use moderation::moderation_backend;
 use state::models::AppState;
-use tokio::net::TcpListener;
+use storage::Storage;
+use tokio::{ net::TcpListener, signal, sync::{ broadcast, Notify, Semaphore } };
 use tokio_postgres::NoTls;
-use tower_http::{ cors::{ AllowOrigin, CorsLayer }, trace::TraceLayer };
+use tokio_util::{ sync::CancellationToken, task::TaskTracker };
+use tower_http::{ cors::CorsLayer, trace::TraceLayer };
+use tracing::Span;
+use utils::{
+    auth_utils::run_auth_cache_sweep,
+    maintenance_utils::MaintenanceMode,
+    metrics_utils::{
+        install_recorder,
+        record_pool_status,
+        run_metrics_upkeep,
+        track_metrics,
+        S3MetricsInterceptor,
+    },
+    remote_utils::remote_client,
+    request_id_utils::{ request_id_middleware, REQUEST_ID_HEADER },
+};
 
+mod config;
 mod enums;
+mod jobs;
+mod moderation;
 mod routes;
+mod services;
 mod state;
+mod storage;
+#[cfg(test)]
+mod test_utils;
 mod utils;
 
 const PRESIGN_DURATION: Duration = Duration::from_secs(3600); // 60 mins
 const MAX_FILE_SIZE: usize = 20_000_000;
+const MAX_RESUMABLE_FILE_SIZE: i64 = 2_000_000_000;
+// Bounds both the uploaded archive and everything extracted from it
+const MAX_ARCHIVE_SIZE: usize = 500_000_000;
+const MAX_ARCHIVE_ENTRIES: usize = 1000;
+// Every part of a multipart upload but the last has to be at least 5MB
+const UPLOAD_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+const UPLOAD_SESSION_TTL_HOURS: i32 = 24;
+const DEFAULT_STORAGE_QUOTA: i64 = 5_000_000_000;
+const SITEMAP_INTERVAL: Duration = Duration::from_secs(21600); // 6 hours
+const VIEW_FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+const UPLOAD_STATUS_TTL: Duration = Duration::from_secs(600); // 10 mins
+// Progress events buffered per subscriber before the oldest ones are dropped
+const UPLOAD_EVENT_CAPACITY: usize = 1024;
+const JOB_POLL_INTERVAL: Duration = Duration::from_secs(30);
+const TRASH_PURGE_INTERVAL: Duration = Duration::from_secs(86400); // 24 hours
+const TRASH_RETENTION_DAYS: i32 = 30;
+// How long background work gets to finish after the server stopped taking requests
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(30);
+const READINESS_TIMEOUT: Duration = Duration::from_secs(3);
+// Longer than readiness, the first connections of a cold start can take a while
+const STARTUP_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+// Stored in plain text to look project API keys up, "ark_" and 8 random characters
+const API_KEY_PREFIX_LEN: usize = 12;
+// A slow or unreachable region fails the request instead of holding it open indefinitely
+const S3_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// Longest wait for the next bytes of a response
+const S3_READ_TIMEOUT: Duration = Duration::from_secs(30);
+// Covers every attempt and the backoff between them, 20MB uploads included
+const S3_OPERATION_TIMEOUT: Duration = Duration::from_secs(120);
+const S3_MAX_ATTEMPTS: u32 = 3;
 
 async fn health_check() -> impl IntoResponse {
     return (StatusCode::OK, "Ok");
 }
 
+fn create_pool(url: &str) -> Pool {
+    let mut cfg = DeadPoolConfig::new();
+    cfg.url = Some(url.to_owned());
+
+    cfg.manager = Some(ManagerConfig {
+        recycling_method: deadpool_postgres::RecyclingMethod::Fast,
+    });
+
+    cfg.create_pool(Some(deadpool_postgres::Runtime::Tokio1), NoTls).unwrap()
+}
+
+async fn check_database(pool: &Pool) -> Result<(), String> {
+    let client = pool.get().await.map_err(|err| err.to_string())?;
+
+    client.execute("SELECT 1;", &[]).await.map_err(|err| err.to_string())?;
+
+    Ok(())
+}
+
+async fn check_storage(state: &AppState) -> Result<(), String> {
+    state.storage.default_target().ping().await
+}
+
+fn dependency_status(res: Result<Result<(), String>, tokio::time::error::Elapsed>) -> Value {
+    match res {
+        Ok(Ok(())) => json!({ "ok": true }),
+        Ok(Err(err)) => json!({ "ok": false, "error": err }),
+        Err(_) => json!({ "ok": false, "error": "TIMED OUT" }),
+    }
+}
+
+fn startup_failure(
+    name: &str,
+    res: Result<Result<(), String>, tokio::time::error::Elapsed>
+) -> Option<String> {
+    match res {
+        Ok(Ok(())) => None,
+        Ok(Err(err)) => Some(format!("{} IS UNREACHABLE - {}", name, err)),
+        Err(_) => Some(format!("{} IS UNREACHABLE - TIMED OUT", name)),
+    }
+}
+
+// Runs before serving, so a deploy pointing at the wrong database or bucket fails right away
+// instead of on its first requests. Failures are named after the variables to fix.
+async fn startup_checks(state: &AppState) -> Vec<String> {
+    let check_read_database = async {
+        match &state.read_pool {
+            Some(read_pool) => {
+                Some(tokio::time::timeout(STARTUP_CHECK_TIMEOUT, check_database(read_pool)).await)
+            }
+            None => None,
+        }
+    };
+
+    let (database, read_database, storage) = tokio::join!(
+        tokio::time::timeout(STARTUP_CHECK_TIMEOUT, check_database(&state.pool)),
+        check_read_database,
+        tokio::time::timeout(STARTUP_CHECK_TIMEOUT, check_storage(state))
+    );
+
+    let storage_name = match state.config.storage_backend {
+        StorageBackendKind::S3 => "DO_SPACES_ENDPOINT",
+        StorageBackendKind::Local => "LOCAL_STORAGE_PATH",
+    };
+
+    return [
+        startup_failure("DATABASE_URL", database),
+        read_database.and_then(|res| startup_failure("DATABASE_READ_URL", res)),
+        startup_failure(storage_name, storage),
+    ]
+        .into_iter()
+        .flatten()
+        .collect();
+}
+
+fn startup_report(title: &str, failures: &[String]) -> String {
+    let lines: Vec<String> = failures
+        .iter()
+        .map(|failure| format!("  - {}", failure))
+        .collect();
+
+    return format!("{} ({})\n{}", title, failures.len(), lines.join("\n"));
+}
+
+// Unlike /health_check this fails when Postgres or Spaces can't be reached, and while the
+// instance is shutting down, so it can be taken out of rotation
+async fn readiness_check(State(state): State<AppState>) -> impl IntoResponse {
+    let check_read_database = async {
+        match &state.read_pool {
+            Some(read_pool) => {
+                Some(tokio::time::timeout(READINESS_TIMEOUT, check_database(read_pool)).await)
+            }
+            None => None,
+        }
+    };
+
+    let (database, read_database, storage) = tokio::join!(
+        tokio::time::timeout(READINESS_TIMEOUT, check_database(&state.pool)),
+        check_read_database,
+        tokio::time::timeout(READINESS_TIMEOUT, check_storage(&state))
+    );
+
+    let database = dependency_status(database);
+    // Null without a replica, reads go to the primary then
+    let read_database = read_database.map(dependency_status);
+    let storage = dependency_status(storage);
+    let shutting_down = state.shutdown.is_cancelled();
+
+    let ready =
+        database["ok"] == true &&
+        read_database.as_ref().is_none_or(|status| status["ok"] == true) &&
+        storage["ok"] == true &&
+        !shutting_down;
+    let status = match ready {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+
+    return (
+        status,
+        Json(
+            json!({
+                "ok": ready,
+                "shutting_down": shutting_down,
+                "database": database,
+                "read_database": read_database,
+                "storage": storage,
+            })
+        ),
+    );
+}
+
+fn app_state(
+    config: Arc<Config>,
+    storage: Storage,
+    metrics: PrometheusHandle,
+    pool: Pool,
+    read_pool: Option<Pool>
+) -> AppState {
+    return AppState {
+        storage: Arc::new(storage),
+        reqwest_client: reqwest::Client::new(),
+        remote_client: remote_client(),
+        moderation: moderation_backend(&config),
+        encode_permits: Arc::new(Semaphore::new(config.max_concurrent_encodes.max(1))),
+        config,
+        view_counter: Arc::new(Mutex::new(HashMap::new())),
+        upload_tracker: Arc::new(Mutex::new(HashMap::new())),
+        upload_events: broadcast::channel(UPLOAD_EVENT_CAPACITY).0,
+        auth_cache: Arc::new(Mutex::new(HashMap::new())),
+        acl_reports: Arc::new(Mutex::new(HashMap::new())),
+        job_notify: Arc::new(Notify::new()),
+        moderation_notify: Arc::new(Notify::new()),
+        webhook_notify: Arc::new(Notify::new()),
+        thumbnail_health: Arc::new(Mutex::new(None)),
+        maintenance: Arc::new(MaintenanceMode::default()),
+        tasks: TaskTracker::new(),
+        shutdown: CancellationToken::new(),
+        metrics,
+        pool,
+        read_pool,
+    };
+}
+
+async fn shutdown_signal(shutdown: CancellationToken) {
+    let ctrl_c = async {
+        signal::ctrl_c().await.expect("COULD NOT LISTEN FOR CTRL+C");
+    };
+
+    let terminate = async {
+        signal::unix
+            ::signal(signal::unix::SignalKind::terminate())
+            .expect("COULD NOT LISTEN FOR SIGTERM")
+            .recv().await;
+    };
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+
+    tracing::info!("SHUTTING DOWN, DRAINING CONNECTIONS");
+
+    shutdown.cancel();
+}
+
+async fn metrics_handler(State(state): State<AppState>) -> impl IntoResponse {
+    record_pool_status(&state);
+
+    return (
+        StatusCode::OK,
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics.render(),
+    );
+}
+
+// JSON output carries the fields of every span the event happened in, so each line of a
+// request has its request id, route and user
+fn init_tracing(config: &Config) {
+    let subscriber = tracing_subscriber::fmt().with_max_level(config.log_level);
+
+    match config.log_format {
+        LogFormat::Json => subscriber.json().with_current_span(false).with_span_list(true).init(),
+        LogFormat::Text => subscriber.init(),
+    }
+}
+
 #[tokio::main]
 async fn main() {
-    tracing_subscriber::fmt::init();
-
     dotenv::dotenv().ok();
 
-    let endpoint_url = env::var("DO_SPACES_ENDPOINT").unwrap();
-    let access_key_id = env::var("DO_SPACES_KEY").unwrap();
-    let secret_access_key = env::var("DO_SPACES_SECRET").unwrap();
-    let bucket = env::var("DO_SPACES_NAME").unwrap();
+    let config = Config::from_env();
 
-    let editor_client = env::var("EDITOR_CLIENT_URL").unwrap();
-    let wiki_client = env::var("WIKI_CLIENT_URL").unwrap();
-    let gateway_client = env::var("GATEWAY_CLIENT_URL").unwrap();
+    // Logging is configured by the config, so there's nowhere else to report this
+    if config.is_err() {
+        let errors: Vec<String> = config
+            .err()
+            .unwrap()
+            .iter()
+            .map(|err| err.to_string())
+            .collect();
 
-    let auth_service_url = env::var("AUTH_SERVICE_URL").unwrap();
-    let thumbnail_service_url = env::var("THUMBNAIL_SERVICE").unwrap();
-    // let discord_service_url = env::var("DISCORD_SERVICE_URL").unwrap();
+        eprintln!("{}", startup_report("INVALID CONFIGURATION", &errors));
+        std::process::exit(1);
+    }
 
-    let thumbnail_secret = env::var("THUMBNAIL_SECRET").unwrap();
-    // let discord_service_api_key = env::var("DISCORD_SERVICE_API_KEY").unwrap();
+    let config = Arc::new(config.unwrap());
 
-    let database_url = env::var("DATABASE_URL").expect("NO DB URL CONFIGURED");
+    init_tracing(&config);
 
-    let mut cfg = DeadPoolConfig::new();
-    cfg.url = Some(database_url);
+    let metrics = install_recorder();
 
-    cfg.manager = Some(ManagerConfig {
-        recycling_method: deadpool_postgres::RecyclingMethod::Fast,
-    });
-    let pool = cfg.create_pool(Some(deadpool_postgres::Runtime::Tokio1), NoTls).unwrap();
+    if metrics.is_err() {
+        tracing::error!("COULD NOT INSTALL METRICS RECORDER - {}", metrics.err().unwrap());
+        std::process::exit(1);
+    }
+
+    let metrics = metrics.unwrap();
 
-    let port = env::var("PORT").unwrap();
+    let pool = create_pool(&config.database_url);
+    let read_pool = config.database_read_url.as_deref().map(create_pool);
 
-    let creds = Credentials::new(access_key_id, secret_access_key, None, None, "");
-    let reqwest_client = reqwest::Client::new();
-    let config = aws_sdk_s3::config::Builder
+    let creds = Credentials::new(
+        config.spaces_key.clone(),
+        config.spaces_secret.clone(),
+        None,
+        None,
+        ""
+    );
+    let s3_config = aws_sdk_s3::config::Builder
         ::new()
         .behavior_version(BehaviorVersion::latest())
         .force_path_style(false)
-        .region(Region::new("us-east-1"))
-        .endpoint_url(endpoint_url)
+        .region(Region::new(config.spaces_region.clone()))
+        .endpoint_url(&config.spaces_endpoint)
         .credentials_provider(creds)
+        .timeout_config(
+            TimeoutConfig::builder()
+                .connect_timeout(S3_CONNECT_TIMEOUT)
+                .read_timeout(S3_READ_TIMEOUT)
+                .operation_timeout(S3_OPERATION_TIMEOUT)
+                .build()
+        )
+        // Throttling, 5xx and transient connection errors are retried with exponential backoff
+        .retry_config(RetryConfig::standard().with_max_attempts(S3_MAX_ATTEMPTS))
+        .interceptor(S3MetricsInterceptor)
         .build();
 
-    let client = aws_sdk_s3::Client::from_conf(config);
-
-    let listener = TcpListener::bind(format!("[::]:{}", port)).await.unwrap();
+    let storage = match config.storage_backend {
+        StorageBackendKind::S3 => Storage::s3(aws_sdk_s3::Client::from_conf(s3_config), &config),
+        StorageBackendKind::Local => Storage::local(&config),
+    };
 
-    let origins = AllowOrigin::list([
-        editor_client.parse().unwrap(),
-        gateway_client.parse().unwrap(),
-        wiki_client.parse().unwrap(),
-        "discord.com".parse().unwrap(),
-    ]);
+    let listener = TcpListener::bind(format!("[::]:{}", config.port)).await;
 
     let cors = CorsLayer::new()
-        .allow_methods([Method::GET, Method::POST, Method::DELETE, Method::OPTIONS])
+        // PUT is only used by presigned uploads to local storage
+        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE, Method::OPTIONS])
         .allow_credentials(true)
-        .allow_headers([HeaderName::from_str("module").unwrap(), CONTENT_TYPE])
-        .allow_origin(origins);
-
-    let state = AppState {
-        client,
-        bucket,
-        reqwest_client,
-        auth_service_url,
-        thumbnail_secret,
-        thumbnail_service_url,
-        // discord_service_url,
-        // discord_service_api_key,
-        pool,
-    };
+        .allow_headers([
+            HeaderName::from_str("module").unwrap(),
+            HeaderName::from_static(REQUEST_ID_HEADER),
+            CONTENT_TYPE,
+            RANGE,
+        ])
+        // Read by clients of the raw asset route
+        .expose_headers([
+            ACCEPT_RANGES,
+            CONTENT_RANGE,
+            ETAG,
+            HeaderName::from_static(REQUEST_ID_HEADER),
+        ])
+        .allow_origin(config.cors_origins.allow_origin());
+
+    let state = app_state(config.clone(), storage, metrics, pool, read_pool);
+
+    let mut failures = startup_checks(&state).await;
+
+    if listener.is_err() {
+        failures.insert(
+            0,
+            format!("PORT {} COULD NOT BE BOUND - {}", config.port, listener.as_ref().err().unwrap())
+        );
+    }
+
+    if !failures.is_empty() {
+        tracing::error!("{}", startup_report("STARTUP CHECKS FAILED", &failures));
+        std::process::exit(1);
+    }
+
+    let listener = listener.unwrap();
+
+    state.tasks.spawn(run_sitemap_job(state.clone()));
+    state.tasks.spawn(run_view_count_job(state.clone()));
+    state.tasks.spawn(run_asset_job_worker(state.clone()));
+    state.tasks.spawn(run_trash_purge_job(state.clone()));
+    state.tasks.spawn(run_webhook_worker(state.clone()));
+    state.tasks.spawn(run_moderation_worker(state.clone()));
+    state.tasks.spawn(run_metrics_upkeep(state.clone()));
+    state.tasks.spawn(run_auth_cache_sweep(state.clone()));
+
+    let tasks = state.tasks.clone();
+    let shutdown = state.shutdown.clone();
+    let pool = state.pool.clone();
+    let read_pool = state.read_pool.clone();
 
     let app = Router::new()
 
         .merge(crud_routes(state.clone()))
-        .merge(upload_routes())
-        .merge(thumbnail_routes())
+        .merge(upload_routes(state.clone()))
+        .merge(thumbnail_routes(state.clone()))
+        .merge(public_routes())
+        .merge(user_routes())
+        .merge(webhook_routes(state.clone()))
+        .merge(api_key_routes(state.clone()))
+        .merge(collection_routes(state.clone()))
+        .merge(library_routes(state.clone()))
+        .merge(storage_routes(state.clone()))
         .layer(cors)
+        .merge(extension_routes(state.clone()))
+        .merge(foundry_routes(state.clone()))
+        .merge(admin_routes(state.clone()))
+        .merge(placeholder_routes())
+        .merge(domain_routes())
+        .merge(openapi_routes())
         .layer(
             TraceLayer::new_for_http()
                 .make_span_with(|req: &Request| {
@@ -126,16 +474,47 @@ async fn main() {
                         .get::<MatchedPath>()
                         .map(|matched_path| matched_path.as_str());
 
-                    tracing::error_span!("Request error at", %method, %uri, matched_path)
+                    // user_id is filled in once the caller is authenticated
+                    tracing::info_span!(
+                        "http_request",
+                        %method,
+                        %uri,
+                        matched_path,
+                        status = tracing::field::Empty,
+                        latency_ms = tracing::field::Empty,
+                        user_id = tracing::field::Empty
+                    )
+                })
+                .on_response(|res: &Response, latency: Duration, span: &Span| {
+                    span.record("status", res.status().as_u16());
+                    span.record("latency_ms", latency.as_millis() as u64);
+
+                    tracing::info!("REQUEST FINISHED");
                 })
+                // Errors are logged where they happen, with more context than the status
                 .on_failure(())
         )
-        .merge(extension_routes())
-        .merge(foundry_routes())
+        .layer(from_fn(track_metrics))
+        .route("/ready", get(readiness_check))
+        .route("/metrics", get(metrics_handler))
         .with_state(state)
-        .route("/health_check", get(health_check));
+        .route("/health_check", get(health_check))
+        .layer(from_fn(request_id_middleware));
+
+    tracing::info!("RUNNING ON PORT {} 🚀", config.port);
+
+    axum::serve(listener, app).with_graceful_shutdown(shutdown_signal(shutdown)).await.unwrap();
+
+    // In-flight requests are done, let the jobs and spawned S3 work wrap up
+    tasks.close();
+
+    if tokio::time::timeout(SHUTDOWN_GRACE_PERIOD, tasks.wait()).await.is_err() {
+        tracing::error!("{} BACKGROUND TASK(S) DID NOT FINISH BEFORE SHUTDOWN", tasks.len());
+    }
 
-    println!("RUNNING ON PORT {} 🚀", port);
+    pool.close();
 
-    axum::serve(listener, app).await.unwrap();
+    if let Some(read_pool) = read_pool {
+        read_pool.close();
+    }
 }
@@ -0,0 +1,76 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use reqwest::{ header::AUTHORIZATION, Client };
+use serde::Deserialize;
+
+use crate::{
+    enums::ModerationStatus,
+    moderation::{ ModerationBackend, ModerationRequest, ModerationVerdict },
+};
+
+const MODERATION_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Verdict {
+    Approved,
+    Rejected,
+}
+
+#[derive(Deserialize)]
+struct ModerationResponse {
+    verdict: Verdict,
+    reason: Option<String>,
+}
+
+// POSTs the request as JSON and expects {"verdict": "approved" | "rejected", "reason": ...}
+pub struct HttpModeration {
+    client: Client,
+    url: String,
+    secret: String,
+}
+
+impl HttpModeration {
+    pub fn new(url: String, secret: String) -> Self {
+        HttpModeration { client: Client::new(), url, secret }
+    }
+}
+
+#[async_trait]
+impl ModerationBackend for HttpModeration {
+    async fn moderate(&self, request: &ModerationRequest<'_>) -> Result<ModerationVerdict, String> {
+        let res = self.client
+            .post(&self.url)
+            .header(AUTHORIZATION, format!("Bearer {}", self.secret))
+            .json(request)
+            .timeout(MODERATION_TIMEOUT)
+            .send().await;
+
+        if res.is_err() {
+            return Err(res.err().unwrap().to_string());
+        }
+
+        let res = res.unwrap();
+
+        if !res.status().is_success() {
+            return Err(format!("MODERATION SERVICE RESPONDED WITH {}", res.status()));
+        }
+
+        let data = res.json::<ModerationResponse>().await;
+
+        if data.is_err() {
+            return Err(data.err().unwrap().to_string());
+        }
+
+        let data = data.unwrap();
+
+        Ok(ModerationVerdict {
+            status: match data.verdict {
+                Verdict::Approved => ModerationStatus::Approved,
+                Verdict::Rejected => ModerationStatus::Rejected,
+            },
+            reason: data.reason,
+        })
+    }
+}
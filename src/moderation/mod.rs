@@ -0,0 +1,46 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{ config::Config, enums::ModerationStatus, moderation::http::HttpModeration };
+
+pub mod http;
+
+#[derive(Serialize)]
+pub struct ModerationRequest<'a> {
+    pub id: &'a Uuid,
+    pub project_id: &'a Uuid,
+    // Presigned, the object stays private until it is approved
+    pub url: &'a str,
+    pub mime_type: &'a str,
+}
+
+pub struct ModerationVerdict {
+    // Approved or Rejected, never Pending
+    pub status: ModerationStatus,
+    pub reason: Option<String>,
+}
+
+// Reviews uploads of public projects before they are published
+#[async_trait]
+pub trait ModerationBackend: Send + Sync {
+    async fn moderate(&self, request: &ModerationRequest<'_>) -> Result<ModerationVerdict, String>;
+}
+
+// None when no moderation service is configured, uploads are published right away then
+pub fn moderation_backend(config: &Config) -> Option<Arc<dyn ModerationBackend>> {
+    if config.moderation_service_url.is_empty() {
+        return None;
+    }
+
+    Some(
+        Arc::new(
+            HttpModeration::new(
+                config.moderation_service_url.clone(),
+                config.moderation_secret.clone()
+            )
+        )
+    )
+}
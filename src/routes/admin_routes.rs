@@ -0,0 +1,348 @@
+use axum::{
+    extract::{ Query, Request, State },
+    http::header::CONTENT_TYPE,
+    middleware::{ from_fn_with_state, Next },
+    response::{ IntoResponse, Response },
+    routing::{ get, post },
+    Json,
+    Router,
+};
+use serde::Deserialize;
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::{
+    enums::{ AppResponse, AssetJobOperation, AssetJobStatus },
+    jobs::{
+        acl_job::{ get_project_visibility, run_acl_remediation, start_acl_report },
+        import_job::{ run_v3_import, ImportV3Payload },
+        reconcile_job::start_reconciliation,
+    },
+    state::models::AppState,
+    utils::{
+        auth_utils::check_admin_key,
+        db_utils::get_client,
+        extractors::ExtractPath,
+        maintenance_utils::DEFAULT_MAINTENANCE_MESSAGE,
+    },
+};
+
+const BYTES_PER_GB: f64 = 1_000_000_000.0;
+
+async fn admin_middleware(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    if !check_admin_key(request.headers(), &state.config.admin_api_key) {
+        return AppResponse::Unauthorized.into_response();
+    }
+
+    return next.run(request).await;
+}
+
+async fn import_v3_assets(
+    State(state): State<AppState>,
+    Json(payload): Json<ImportV3Payload>
+) -> impl IntoResponse {
+    if payload.assets.is_empty() {
+        return AppResponse::Error("NO ASSETS TO IMPORT".to_owned());
+    }
+
+    let count = payload.assets.len();
+
+    state.tasks.clone().spawn(run_v3_import(state, payload));
+
+    return AppResponse::SuccessData(
+        "Import".to_owned(),
+        crate::enums::SuccessActions::Create,
+        json!({ "queued": count })
+    );
+}
+
+async fn get_storage_costs(State(state): State<AppState>) -> impl IntoResponse {
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    let rows = client.query(
+        "SELECT projects.id, projects.title,
+            COALESCE(storage.bytes, 0)::BIGINT AS storage_bytes,
+            COALESCE(egress.bytes, 0)::BIGINT AS egress_bytes
+         FROM projects
+         LEFT JOIN (
+            SELECT project_id, SUM(size_bytes) AS bytes FROM images GROUP BY project_id
+         ) storage ON storage.project_id = projects.id
+         LEFT JOIN (
+            SELECT project_id, SUM(bytes) AS bytes FROM asset_bandwidth
+            WHERE day >= date_trunc('month', CURRENT_DATE)
+            GROUP BY project_id
+         ) egress ON egress.project_id = projects.id
+         ORDER BY storage_bytes DESC;",
+        &[]
+    ).await;
+
+    if rows.is_err() {
+        return AppResponse::Error(rows.err().unwrap().to_string());
+    }
+
+    let mut total_storage_cost = 0.0;
+    let mut total_egress_cost = 0.0;
+
+    let projects: Vec<serde_json::Value> = rows
+        .unwrap()
+        .iter()
+        .map(|row| {
+            let id: Uuid = row.get("id");
+            let title: String = row.get("title");
+            let storage_bytes: i64 = row.get("storage_bytes");
+            let egress_bytes: i64 = row.get("egress_bytes");
+
+            let storage_cost = ((storage_bytes as f64) / BYTES_PER_GB) * state.config.storage_price_per_gb;
+            let egress_cost = ((egress_bytes as f64) / BYTES_PER_GB) * state.config.egress_price_per_gb;
+
+            total_storage_cost += storage_cost;
+            total_egress_cost += egress_cost;
+
+            json!({
+                "project_id": id,
+                "title": title,
+                "storage_bytes": storage_bytes,
+                "egress_bytes": egress_bytes,
+                "storage_cost": storage_cost,
+                "egress_cost": egress_cost,
+            })
+        })
+        .collect();
+
+    return AppResponse::SuccessData(
+        "Costs".to_owned(),
+        crate::enums::SuccessActions::Read,
+        json!({
+            "storage_price_per_gb": state.config.storage_price_per_gb,
+            "egress_price_per_gb": state.config.egress_price_per_gb,
+            "total_storage_cost": total_storage_cost,
+            "total_egress_cost": total_egress_cost,
+            "projects": projects,
+        })
+    );
+}
+
+#[derive(Deserialize)]
+struct AclRemediationQuery {
+    dry_run: Option<bool>,
+}
+
+async fn remediate_project_acls(
+    State(state): State<AppState>,
+    query: Query<AclRemediationQuery>,
+    ExtractPath(project_id): ExtractPath<Uuid>
+) -> impl IntoResponse {
+    let visibility = get_project_visibility(&state, &project_id).await;
+
+    if visibility.is_err() {
+        return visibility.err().unwrap();
+    }
+
+    let visibility = visibility.unwrap();
+    let dry_run = query.dry_run.unwrap_or(false);
+
+    if !start_acl_report(&state, project_id, visibility) {
+        return AppResponse::Error(format!("ACL REMEDIATION ALREADY RUNNING FOR {}", project_id));
+    }
+
+    state.tasks.clone().spawn(run_acl_remediation(state, project_id, visibility, dry_run));
+
+    return AppResponse::SuccessData(
+        "ACL remediation".to_owned(),
+        crate::enums::SuccessActions::Create,
+        json!({ "project_id": project_id, "visibility": visibility, "dry_run": dry_run })
+    );
+}
+
+async fn get_acl_report(
+    State(state): State<AppState>,
+    ExtractPath(project_id): ExtractPath<Uuid>
+) -> impl IntoResponse {
+    let report = state.acl_reports.lock().unwrap().get(&project_id).cloned();
+
+    if report.is_none() {
+        return AppResponse::Error(format!("NO ACL REPORT FOR {}", project_id));
+    }
+
+    return AppResponse::SuccessData(
+        "ACL report".to_owned(),
+        crate::enums::SuccessActions::Read,
+        json!(report.unwrap())
+    );
+}
+
+#[derive(Deserialize)]
+struct ReconcileQuery {
+    repair: Option<bool>,
+}
+
+// Compares the project's objects with its rows, see run_reconciliation. Without `repair` it
+// only reports what it finds.
+async fn reconcile_project(
+    State(state): State<AppState>,
+    query: Query<ReconcileQuery>,
+    ExtractPath(project_id): ExtractPath<Uuid>
+) -> impl IntoResponse {
+    let body = start_reconciliation(&state, project_id, query.repair.unwrap_or(false));
+
+    return Response::builder()
+        .header(CONTENT_TYPE, "application/x-ndjson")
+        .body(body)
+        .unwrap();
+}
+
+#[derive(Deserialize)]
+struct JobsQuery {
+    status: Option<AssetJobStatus>,
+    limit: Option<i64>,
+}
+
+async fn get_asset_jobs(State(state): State<AppState>, query: Query<JobsQuery>) -> impl IntoResponse {
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    let counts = client.query(
+        "SELECT status, COUNT(*) AS count FROM asset_jobs GROUP BY status;",
+        &[]
+    ).await;
+
+    if counts.is_err() {
+        return AppResponse::Error(counts.err().unwrap().to_string());
+    }
+
+    let mut totals = serde_json::Map::new();
+
+    for row in counts.unwrap() {
+        let status: AssetJobStatus = row.get("status");
+        let count: i64 = row.get("count");
+
+        totals.insert(json!(status).as_str().unwrap_or_default().to_owned(), json!(count));
+    }
+
+    let limit = query.limit.unwrap_or(100).clamp(1, 500);
+
+    let rows = client.query(
+        "SELECT id, operation, target, status, attempts, last_error,
+            (EXTRACT(EPOCH FROM run_after) * 1000)::BIGINT AS run_after,
+            (EXTRACT(EPOCH FROM created_at) * 1000)::BIGINT AS created_at
+         FROM asset_jobs
+         WHERE ($1::\"AssetJobStatus\" IS NULL OR status = $1)
+         ORDER BY created_at DESC
+         LIMIT $2;",
+        &[&query.status, &limit]
+    ).await;
+
+    if rows.is_err() {
+        return AppResponse::Error(rows.err().unwrap().to_string());
+    }
+
+    let jobs: Vec<serde_json::Value> = rows
+        .unwrap()
+        .iter()
+        .map(|row| {
+            let id: Uuid = row.get("id");
+            let operation: AssetJobOperation = row.get("operation");
+            let target: String = row.get("target");
+            let status: AssetJobStatus = row.get("status");
+            let attempts: i32 = row.get("attempts");
+            let last_error: Option<String> = row.get("last_error");
+            let run_after: i64 = row.get("run_after");
+            let created_at: i64 = row.get("created_at");
+
+            json!({
+                "id": id,
+                "operation": operation,
+                "target": target,
+                "status": status,
+                "attempts": attempts,
+                "last_error": last_error,
+                "run_after": run_after,
+                "created_at": created_at,
+            })
+        })
+        .collect();
+
+    return AppResponse::SuccessData(
+        "Jobs".to_owned(),
+        crate::enums::SuccessActions::Read,
+        json!({ "totals": totals, "jobs": jobs })
+    );
+}
+
+#[derive(Deserialize)]
+struct MaintenancePayload {
+    enabled: bool,
+    // Shown to clients whose uploads are rejected
+    message: Option<String>,
+}
+
+// `in_flight_uploads` drops to 0 once the uploads accepted before maintenance started are done,
+// from then on nothing writes to the bucket
+fn maintenance_status(state: &AppState) -> serde_json::Value {
+    let window = state.maintenance.window();
+
+    json!({
+        "enabled": window.is_some(),
+        "message": window.as_ref().map(|window| window.message.clone()),
+        "started_at": window.as_ref().map(|window| window.started_at),
+        "in_flight_uploads": state.maintenance.in_flight(),
+    })
+}
+
+async fn get_maintenance(State(state): State<AppState>) -> impl IntoResponse {
+    return AppResponse::SuccessData(
+        "Maintenance".to_owned(),
+        crate::enums::SuccessActions::Read,
+        maintenance_status(&state)
+    );
+}
+
+async fn set_maintenance(
+    State(state): State<AppState>,
+    Json(payload): Json<MaintenancePayload>
+) -> impl IntoResponse {
+    match payload.enabled {
+        true => {
+            let message = payload.message
+                .map(|message| message.trim().to_owned())
+                .filter(|message| !message.is_empty())
+                .unwrap_or(DEFAULT_MAINTENANCE_MESSAGE.to_owned());
+
+            tracing::warn!("MAINTENANCE MODE ON - {}", message);
+            state.maintenance.start(message);
+        }
+        false => {
+            tracing::warn!("MAINTENANCE MODE OFF");
+            state.maintenance.stop();
+        }
+    }
+
+    return AppResponse::SuccessData(
+        "Maintenance".to_owned(),
+        crate::enums::SuccessActions::Update,
+        maintenance_status(&state)
+    );
+}
+
+pub fn admin_routes(state: AppState) -> Router<AppState> {
+    Router::new().nest(
+        "/admin",
+        Router::new()
+            .route("/import/v3", post(import_v3_assets))
+            .route("/costs", get(get_storage_costs))
+            .route("/acl/:project_id", get(get_acl_report).post(remediate_project_acls))
+            .route("/jobs", get(get_asset_jobs))
+            .route("/reconcile/:project_id", post(reconcile_project))
+            .route("/maintenance", get(get_maintenance).post(set_maintenance))
+            .layer(from_fn_with_state(state, admin_middleware))
+    )
+}
@@ -0,0 +1,298 @@
+use axum::{
+    extract::State,
+    middleware::from_fn_with_state,
+    response::IntoResponse,
+    routing::{ delete, get, post },
+    Json,
+    Router,
+};
+use serde::Deserialize;
+use serde_json::json;
+use tokio_postgres::Row;
+use uuid::Uuid;
+
+use crate::{
+    enums::{ ApiKeyScope, AppResponse },
+    state::models::AppState,
+    utils::{
+        auth_utils::generate_api_key,
+        db_utils::get_client,
+        extractors::ExtractPath,
+        tenant_utils::{ owner_middleware, tenant_middleware },
+    },
+};
+
+const MAX_API_KEYS_PER_PROJECT: i64 = 20;
+const MAX_API_KEY_EXPIRY_DAYS: i32 = 3650;
+
+#[derive(Deserialize)]
+struct ApiKeyPayload {
+    name: String,
+    scopes: Vec<ApiKeyScope>,
+    // Never expires when omitted
+    expires_in_days: Option<i32>,
+}
+
+const API_KEY_COLUMNS: &str =
+    "id, name, prefix, scopes,
+    (EXTRACT(EPOCH FROM expires_at) * 1000)::BIGINT AS expires_at,
+    (EXTRACT(EPOCH FROM created_at) * 1000)::BIGINT AS created_at,
+    (EXTRACT(EPOCH FROM last_used_at) * 1000)::BIGINT AS last_used_at,
+    (EXTRACT(EPOCH FROM revoked_at) * 1000)::BIGINT AS revoked_at";
+
+// Everything but the key itself, which is only known when it's created or rotated
+fn api_key_json(row: &Row) -> serde_json::Value {
+    let id: Uuid = row.get("id");
+    let name: String = row.get("name");
+    let prefix: String = row.get("prefix");
+    let scopes: Vec<ApiKeyScope> = row.get("scopes");
+    let expires_at: Option<i64> = row.get("expires_at");
+    let created_at: i64 = row.get("created_at");
+    let last_used_at: Option<i64> = row.get("last_used_at");
+    let revoked_at: Option<i64> = row.get("revoked_at");
+
+    json!({
+        "id": id,
+        "name": name,
+        "prefix": prefix,
+        "scopes": scopes,
+        "expires_at": expires_at,
+        "created_at": created_at,
+        "last_used_at": last_used_at,
+        "revoked_at": revoked_at,
+    })
+}
+
+async fn list_api_keys(
+    State(state): State<AppState>,
+    ExtractPath(project_id): ExtractPath<Uuid>
+) -> impl IntoResponse {
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    // Revoked keys are listed as well, so it's clear where an old key stopped working
+    let rows = client.query(
+        &format!(
+            "SELECT {} FROM project_api_keys WHERE project_id = $1 ORDER BY created_at;",
+            API_KEY_COLUMNS
+        ),
+        &[&project_id]
+    ).await;
+
+    if rows.is_err() {
+        return AppResponse::Error(rows.err().unwrap().to_string());
+    }
+
+    let keys: Vec<serde_json::Value> = rows.unwrap().iter().map(api_key_json).collect();
+
+    return AppResponse::SuccessData(
+        "API keys".to_owned(),
+        crate::enums::SuccessActions::Read,
+        json!(keys)
+    );
+}
+
+async fn create_api_key(
+    State(state): State<AppState>,
+    ExtractPath(project_id): ExtractPath<Uuid>,
+    Json(payload): Json<ApiKeyPayload>
+) -> impl IntoResponse {
+    let name = payload.name.trim().to_owned();
+
+    if name.is_empty() {
+        return AppResponse::Error("API KEY HAS NO NAME".to_owned());
+    }
+
+    let scopes: Vec<ApiKeyScope> = [ApiKeyScope::Read, ApiKeyScope::Upload, ApiKeyScope::Manage]
+        .into_iter()
+        .filter(|scope| payload.scopes.contains(scope))
+        .collect();
+
+    if scopes.is_empty() {
+        return AppResponse::Error("API KEY HAS NO SCOPES".to_owned());
+    }
+
+    if
+        payload.expires_in_days.is_some_and(
+            |days| !(1..=MAX_API_KEY_EXPIRY_DAYS).contains(&days)
+        )
+    {
+        return AppResponse::Error(
+            format!("API KEYS EXPIRE AFTER 1 TO {} DAYS", MAX_API_KEY_EXPIRY_DAYS)
+        );
+    }
+
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let mut client = client.unwrap();
+
+    let transaction = client.transaction().await;
+
+    if transaction.is_err() {
+        return AppResponse::Error(transaction.err().unwrap().to_string());
+    }
+    let transaction = transaction.unwrap();
+
+    // Locks the project row, so concurrent requests can't go over the limit
+    let locked = transaction.execute(
+        "SELECT id FROM projects WHERE id = $1 FOR UPDATE;",
+        &[&project_id]
+    ).await;
+
+    if locked.is_err() {
+        return AppResponse::Error(locked.err().unwrap().to_string());
+    }
+
+    let count = transaction.query_one(
+        "SELECT COUNT(*) AS count FROM project_api_keys
+         WHERE project_id = $1 AND revoked_at IS NULL;",
+        &[&project_id]
+    ).await;
+
+    if count.is_err() {
+        return AppResponse::Error(count.err().unwrap().to_string());
+    }
+
+    let count: i64 = count.unwrap().get("count");
+
+    if count >= MAX_API_KEYS_PER_PROJECT {
+        return AppResponse::Error(
+            format!("PROJECT ALREADY HAS {} API KEYS", MAX_API_KEYS_PER_PROJECT)
+        );
+    }
+
+    let generated = generate_api_key();
+
+    let row = transaction.query_one(
+        &format!(
+            "INSERT INTO project_api_keys (project_id, name, prefix, key_hash, scopes, expires_at)
+             VALUES ($1, $2, $3, $4, $5, NOW() + make_interval(days => $6))
+             RETURNING {};",
+            API_KEY_COLUMNS
+        ),
+        &[
+            &project_id,
+            &name,
+            &generated.prefix,
+            &generated.hash,
+            &scopes,
+            &payload.expires_in_days,
+        ]
+    ).await;
+
+    if row.is_err() {
+        return AppResponse::Error(row.err().unwrap().to_string());
+    }
+
+    let row = row.unwrap();
+
+    let committed = transaction.commit().await;
+
+    if committed.is_err() {
+        return AppResponse::Error(committed.err().unwrap().to_string());
+    }
+
+    let mut key = api_key_json(&row);
+    key["key"] = json!(generated.key);
+
+    return AppResponse::SuccessData(
+        "API key".to_owned(),
+        crate::enums::SuccessActions::Create,
+        key
+    );
+}
+
+// Replaces the secret of the key but keeps its name, scopes and expiry. The old secret stops
+// working right away.
+async fn rotate_api_key(
+    State(state): State<AppState>,
+    ExtractPath((project_id, key_id)): ExtractPath<(Uuid, Uuid)>
+) -> impl IntoResponse {
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    let generated = generate_api_key();
+
+    let row = client.query_opt(
+        &format!(
+            "UPDATE project_api_keys SET prefix = $3, key_hash = $4, last_used_at = NULL
+             WHERE id = $1 AND project_id = $2 AND revoked_at IS NULL
+                AND (expires_at IS NULL OR expires_at > NOW())
+             RETURNING {};",
+            API_KEY_COLUMNS
+        ),
+        &[&key_id, &project_id, &generated.prefix, &generated.hash]
+    ).await;
+
+    if row.is_err() {
+        return AppResponse::Error(row.err().unwrap().to_string());
+    }
+
+    let row = row.unwrap();
+
+    if row.is_none() {
+        return AppResponse::Error(format!("NO ACTIVE API KEY - {}", key_id));
+    }
+
+    let mut key = api_key_json(&row.unwrap());
+    key["key"] = json!(generated.key);
+
+    return AppResponse::SuccessData(
+        "API key".to_owned(),
+        crate::enums::SuccessActions::Update,
+        key
+    );
+}
+
+async fn revoke_api_key(
+    State(state): State<AppState>,
+    ExtractPath((project_id, key_id)): ExtractPath<(Uuid, Uuid)>
+) -> impl IntoResponse {
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    // Kept rather than deleted, so the list still shows when the key was in use
+    let res = client.execute(
+        "UPDATE project_api_keys SET revoked_at = NOW()
+         WHERE id = $1 AND project_id = $2 AND revoked_at IS NULL;",
+        &[&key_id, &project_id]
+    ).await;
+
+    if res.is_err() {
+        return AppResponse::Error(res.err().unwrap().to_string());
+    }
+
+    if res.unwrap() == 0 {
+        return AppResponse::Error(format!("NO ACTIVE API KEY - {}", key_id));
+    }
+
+    return AppResponse::Success("API key".to_owned(), crate::enums::SuccessActions::Delete);
+}
+
+pub fn api_key_routes(state: AppState) -> Router<AppState> {
+    Router::new().nest(
+        "/api_keys",
+        Router::new()
+            .route("/:project_id", get(list_api_keys).post(create_api_key))
+            .route("/:project_id/:key_id", delete(revoke_api_key))
+            .route("/:project_id/:key_id/rotate", post(rotate_api_key))
+            // Keys act on the project without a user, so only owners can manage them
+            .layer(from_fn_with_state(state.clone(), owner_middleware))
+            .layer(from_fn_with_state(state, tenant_middleware))
+    )
+}
@@ -0,0 +1,472 @@
+use axum::{
+    extract::{ Query, State },
+    middleware::from_fn_with_state,
+    response::IntoResponse,
+    routing::{ get, post },
+    Json,
+    Router,
+};
+use serde::Deserialize;
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::{
+    enums::{ AppResponse, AuditAction, DbAccess, RequiredPermission },
+    jobs::asset_job::{ deleted_asset_columns, deletion_jobs, enqueue_jobs, notify_job_worker },
+    services::audit_service::{ record_audit, AuditActor },
+    state::models::AppState,
+    utils::{
+        auth_utils::check_asset_permissions,
+        db_utils::{ db_pool, get_client, get_locked_ids, locked_conflict },
+        extractors::{ AuthenticatedUser, ExtractPath },
+        tenant_utils::tenant_middleware,
+        trash_utils::trash_assets,
+        webhook_utils::{ enqueue_deleted_events, notify_webhook_worker },
+    },
+};
+
+const MAX_COLLECTION_TITLE_LENGTH: usize = 128;
+
+#[derive(Deserialize)]
+struct CollectionPayload {
+    title: String,
+    // Top level collection when omitted
+    parent_id: Option<Uuid>,
+}
+
+#[derive(Deserialize)]
+struct RenamePayload {
+    title: String,
+}
+
+#[derive(Deserialize)]
+struct AssignPayload {
+    ids: Vec<Uuid>,
+    // Takes the assets out of any collection when omitted
+    collection_id: Option<Uuid>,
+}
+
+#[derive(Deserialize)]
+struct CollectionDeleteQuery {
+    // Also deletes the nested collections and every asset in them
+    recursive: Option<bool>,
+    // Skips the trash for the deleted assets
+    permanent: Option<bool>,
+}
+
+fn collection_title(title: &str) -> Result<String, AppResponse> {
+    let title = title.trim();
+
+    if title.is_empty() || title.chars().count() > MAX_COLLECTION_TITLE_LENGTH {
+        return Err(AppResponse::Error(format!("INVALID COLLECTION TITLE - {}", title)));
+    }
+
+    Ok(title.to_owned())
+}
+
+async fn list_collections(
+    State(state): State<AppState>,
+    ExtractPath(project_id): ExtractPath<Uuid>
+) -> impl IntoResponse {
+    let client = get_client(db_pool(&state, DbAccess::Read)).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    let rows = client.query(
+        "SELECT id, title, parent_id,
+            (SELECT COUNT(*) FROM images
+             WHERE collection_id = collections.id AND deleted_at IS NULL) AS asset_count,
+            (EXTRACT(EPOCH FROM created_at) * 1000)::BIGINT AS created_at
+         FROM collections
+         WHERE project_id = $1
+         ORDER BY title, id;",
+        &[&project_id]
+    ).await;
+
+    if rows.is_err() {
+        return AppResponse::Error(rows.err().unwrap().to_string());
+    }
+
+    let collections: Vec<serde_json::Value> = rows
+        .unwrap()
+        .iter()
+        .map(|row| {
+            let id: Uuid = row.get("id");
+            let title: String = row.get("title");
+            let parent_id: Option<Uuid> = row.get("parent_id");
+            let asset_count: i64 = row.get("asset_count");
+            let created_at: i64 = row.get("created_at");
+
+            json!({
+                "id": id,
+                "title": title,
+                "parent_id": parent_id,
+                "asset_count": asset_count,
+                "created_at": created_at,
+            })
+        })
+        .collect();
+
+    return AppResponse::SuccessData(
+        "Collections".to_owned(),
+        crate::enums::SuccessActions::Read,
+        json!(collections)
+    );
+}
+
+async fn create_collection(
+    State(state): State<AppState>,
+    ExtractPath(project_id): ExtractPath<Uuid>,
+    Json(payload): Json<CollectionPayload>
+) -> impl IntoResponse {
+    let title = collection_title(&payload.title);
+
+    if title.is_err() {
+        return title.err().unwrap();
+    }
+
+    let title = title.unwrap();
+
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    // The parent has to be in the same project, otherwise nothing is inserted
+    let row = client.query_opt(
+        "INSERT INTO collections (project_id, parent_id, title)
+         SELECT $1, $2, $3
+         WHERE $2::UUID IS NULL OR EXISTS (SELECT 1 FROM collections WHERE id = $2 AND project_id = $1)
+         RETURNING id;",
+        &[&project_id, &payload.parent_id, &title]
+    ).await;
+
+    if row.is_err() {
+        return AppResponse::Error(row.err().unwrap().to_string());
+    }
+
+    let row = row.unwrap();
+
+    if row.is_none() {
+        return AppResponse::Error(format!("NO COLLECTION - {}", payload.parent_id.unwrap()));
+    }
+
+    let id: Uuid = row.unwrap().get("id");
+
+    return AppResponse::SuccessData(
+        "Collection".to_owned(),
+        crate::enums::SuccessActions::Create,
+        json!({ "id": id, "title": title, "parent_id": payload.parent_id })
+    );
+}
+
+async fn rename_collection(
+    State(state): State<AppState>,
+    ExtractPath((project_id, collection_id)): ExtractPath<(Uuid, Uuid)>,
+    Json(payload): Json<RenamePayload>
+) -> impl IntoResponse {
+    let title = collection_title(&payload.title);
+
+    if title.is_err() {
+        return title.err().unwrap();
+    }
+
+    let title = title.unwrap();
+
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    let res = client.execute(
+        "UPDATE collections SET title = $3 WHERE id = $1 AND project_id = $2;",
+        &[&collection_id, &project_id, &title]
+    ).await;
+
+    if res.is_err() {
+        return AppResponse::Error(res.err().unwrap().to_string());
+    }
+
+    if res.unwrap() == 0 {
+        return AppResponse::Error(format!("NO COLLECTION - {}", collection_id));
+    }
+
+    return AppResponse::SuccessData(
+        "Collection".to_owned(),
+        crate::enums::SuccessActions::Update,
+        json!({ "id": collection_id, "title": title })
+    );
+}
+
+// Without `recursive` only empty collections can be deleted. Otherwise the nested collections
+// go with it and their assets are trashed, or removed together with their objects when
+// `permanent` is set.
+async fn delete_collection(
+    State(state): State<AppState>,
+    AuthenticatedUser(claims): AuthenticatedUser,
+    query: Query<CollectionDeleteQuery>,
+    ExtractPath((project_id, collection_id)): ExtractPath<(Uuid, Uuid)>
+) -> impl IntoResponse {
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let mut client = client.unwrap();
+
+    let collections = client.query(
+        "WITH RECURSIVE tree AS (
+            SELECT id FROM collections WHERE id = $1 AND project_id = $2
+            UNION
+            SELECT collections.id FROM collections JOIN tree ON collections.parent_id = tree.id
+         )
+         SELECT id FROM tree;",
+        &[&collection_id, &project_id]
+    ).await;
+
+    if collections.is_err() {
+        return AppResponse::Error(collections.err().unwrap().to_string());
+    }
+
+    let collections: Vec<Uuid> = collections
+        .unwrap()
+        .iter()
+        .map(|row| row.get("id"))
+        .collect();
+
+    if collections.is_empty() {
+        return AppResponse::Error(format!("NO COLLECTION - {}", collection_id));
+    }
+
+    let assets = client.query(
+        "SELECT id FROM images WHERE collection_id = ANY($1) AND deleted_at IS NULL;",
+        &[&collections]
+    ).await;
+
+    if assets.is_err() {
+        return AppResponse::Error(assets.err().unwrap().to_string());
+    }
+
+    let ids: Vec<Uuid> = assets
+        .unwrap()
+        .iter()
+        .map(|row| row.get("id"))
+        .collect();
+
+    if !query.recursive.unwrap_or(false) && (collections.len() > 1 || !ids.is_empty()) {
+        return AppResponse::Conflict(
+            "The collection is not empty.".to_owned(),
+            json!({ "collections": collections.len() - 1, "assets": ids.len() })
+        );
+    }
+
+    if !ids.is_empty() {
+        let permitted = check_asset_permissions(
+            &state,
+            &client,
+            &claims,
+            RequiredPermission::Delete,
+            &ids
+        ).await;
+
+        if permitted.is_err() {
+            return permitted.err().unwrap();
+        }
+
+        let locked_ids = get_locked_ids(&client, &project_id, Some(&ids)).await;
+
+        if locked_ids.is_err() {
+            return locked_ids.err().unwrap();
+        }
+
+        let locked_ids = locked_ids.unwrap();
+
+        if !locked_ids.is_empty() {
+            return locked_conflict(locked_ids);
+        }
+    }
+
+    let permanent = query.permanent.unwrap_or(false);
+    let mut deleted: Vec<Uuid> = vec![];
+
+    if !ids.is_empty() && !permanent {
+        let trashed = trash_assets(&state, &client, &project_id, None, &ids).await;
+
+        if trashed.is_err() {
+            return trashed.err().unwrap();
+        }
+
+        deleted = trashed.unwrap();
+    }
+
+    let transaction = client.transaction().await;
+
+    if transaction.is_err() {
+        return AppResponse::Error(transaction.err().unwrap().to_string());
+    }
+    let transaction = transaction.unwrap();
+
+    // Assets that couldn't be moved to the trash are kept, they only leave the collection
+    let rows = transaction.query(
+        &format!(
+            "DELETE FROM images
+             WHERE collection_id = ANY($1) AND project_id = $2 AND deleted_at IS NULL AND $3
+             RETURNING id, {};",
+            deleted_asset_columns()
+        ),
+        &[&collections, &project_id, &permanent]
+    ).await;
+
+    if rows.is_err() {
+        return AppResponse::Error(rows.err().unwrap().to_string());
+    }
+
+    let rows = rows.unwrap();
+    let jobs = deletion_jobs(&transaction, &rows).await;
+
+    if jobs.is_err() {
+        return jobs.err().unwrap();
+    }
+
+    let enqueued = enqueue_jobs(&transaction, &jobs.unwrap()).await;
+
+    if enqueued.is_err() {
+        return enqueued.err().unwrap();
+    }
+
+    let events = enqueue_deleted_events(&transaction, &rows).await;
+
+    if events.is_err() {
+        return events.err().unwrap();
+    }
+
+    // Trashed assets are restored without a collection
+    let res = transaction.execute(
+        "UPDATE images SET collection_id = NULL WHERE collection_id = ANY($1);",
+        &[&collections]
+    ).await;
+
+    if res.is_err() {
+        return AppResponse::Error(res.err().unwrap().to_string());
+    }
+
+    let res = transaction.execute("DELETE FROM collections WHERE id = ANY($1);", &[&collections]).await;
+
+    if res.is_err() {
+        return AppResponse::Error(res.err().unwrap().to_string());
+    }
+
+    let committed = transaction.commit().await;
+
+    if committed.is_err() {
+        return AppResponse::Error(committed.err().unwrap().to_string());
+    }
+
+    notify_job_worker(&state);
+    notify_webhook_worker(&state);
+
+    deleted.extend(rows.iter().map(|row| row.get::<_, Uuid>("id")));
+
+    if !deleted.is_empty() {
+        record_audit(
+            &client,
+            &AuditActor::user(&claims),
+            &project_id,
+            AuditAction::Delete,
+            &deleted
+        ).await;
+    }
+
+    return AppResponse::SuccessData(
+        "Collection".to_owned(),
+        crate::enums::SuccessActions::Delete,
+        json!({ "collections": collections, "deleted": deleted })
+    );
+}
+
+async fn assign_assets(
+    State(state): State<AppState>,
+    AuthenticatedUser(claims): AuthenticatedUser,
+    ExtractPath(project_id): ExtractPath<Uuid>,
+    Json(payload): Json<AssignPayload>
+) -> impl IntoResponse {
+    if payload.ids.is_empty() {
+        return AppResponse::Error("NO ASSETS".to_owned());
+    }
+
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    let permitted = check_asset_permissions(
+        &state,
+        &client,
+        &claims,
+        RequiredPermission::Update,
+        &payload.ids
+    ).await;
+
+    if permitted.is_err() {
+        return permitted.err().unwrap();
+    }
+
+    let res = client.query(
+        "UPDATE images SET collection_id = $3
+         WHERE id = ANY($1) AND project_id = $2 AND deleted_at IS NULL
+            AND ($3::UUID IS NULL OR EXISTS (SELECT 1 FROM collections WHERE id = $3 AND project_id = $2))
+         RETURNING id;",
+        &[&payload.ids, &project_id, &payload.collection_id]
+    ).await;
+
+    if res.is_err() {
+        return AppResponse::Error(res.err().unwrap().to_string());
+    }
+
+    let assigned: Vec<Uuid> = res
+        .unwrap()
+        .iter()
+        .map(|row| row.get("id"))
+        .collect();
+
+    if assigned.is_empty() && payload.collection_id.is_some() {
+        return AppResponse::Error(format!("NO COLLECTION - {}", payload.collection_id.unwrap()));
+    }
+
+    record_audit(
+        &client,
+        &AuditActor::user(&claims),
+        &project_id,
+        AuditAction::Update,
+        &assigned
+    ).await;
+
+    return AppResponse::SuccessData(
+        "Collection".to_owned(),
+        crate::enums::SuccessActions::Update,
+        json!({ "collection_id": payload.collection_id, "ids": assigned })
+    );
+}
+
+pub fn collection_routes(state: AppState) -> Router<AppState> {
+    Router::new().nest(
+        "/collections",
+        Router::new()
+            .route("/:project_id", get(list_collections).post(create_collection))
+            .route("/:project_id/assign", post(assign_assets))
+            .route(
+                "/:project_id/:collection_id",
+                post(rename_collection).delete(delete_collection)
+            )
+            .layer(from_fn_with_state(state, tenant_middleware))
+    )
+}
@@ -1,47 +1,186 @@
-use std::{ env, str::FromStr };
+use std::{ collections::{ HashMap, HashSet }, io::{ BufWriter, Cursor } };
 
-use aws_sdk_s3::{ primitives::ByteStream, types::ObjectIdentifier };
 use axum::{
-    body::{ Body, Bytes },
-    extract::{ DefaultBodyLimit, Request, State },
+    body::Bytes,
+    extract::{ DefaultBodyLimit, FromRef, MatchedPath, Query, RawPathParams, Request, State },
     http::{ HeaderMap, HeaderValue },
     middleware::{ from_fn_with_state, Next },
     response::{ IntoResponse, Response },
-    routing::{ delete, post },
+    routing::{ delete, get, post, MethodRouter },
     Json,
     Router,
 };
-use axum_extra::extract::CookieJar;
 use axum_typed_multipart::{ FieldData, TryFromMultipart, TypedMultipart };
-use deadpool_postgres::GenericClient;
-use reqwest::{ header::CONTENT_TYPE, Method, StatusCode };
-use serde::Deserialize;
+use deadpool_postgres::{ GenericClient, Object };
+use futures::{ stream, StreamExt };
+use image::{ DynamicImage, ImageFormat };
+use reqwest::{
+    header::{ CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_TYPE, ETAG, IF_NONE_MATCH },
+    StatusCode,
+};
+use serde::{ Deserialize, Serialize };
 use base64::prelude::*;
 
 use serde_json::json;
+use tokio_postgres::{ error::SqlState, Row };
+use utoipa::{ IntoParams, OpenApi, ToSchema };
 use uuid::Uuid;
 
 use crate::{
-    enums::{ AppResponse, ImageType },
-    state::models::{ AppState, PermissionCheckResponse },
+    enums::{
+        AppResponse,
+        AssetJobOperation,
+        AssetKind,
+        AssetVisibility,
+        AuditAction,
+        AuditSource,
+        DbAccess,
+        Feature,
+        ImageType,
+        OutputFormat,
+        RequiredPermission,
+        ResponsePayload,
+        WebhookEvent,
+    },
+    jobs::{
+        acl_job::get_project_visibility,
+        asset_job::{
+            asset_deletion_jobs,
+            deleted_asset_columns,
+            deletion_jobs,
+            enqueue_jobs,
+            notify_job_worker,
+            AssetJob,
+        },
+        moderation_job::MODERATION_VISIBLE,
+        view_count_job::record_view,
+    },
+    services::{
+        asset_service::{ store_asset, AssetBody, NewAsset },
+        audit_service::{ record_audit, AuditActor },
+    },
+    state::models::{ AppState, Claims },
+    storage::{ backend::{ CopyOptions, PutOptions }, resolve_target, StorageTarget },
     utils::{
-        auth_utils::{ check_auth, insert_permissions },
-        db_utils::get_client,
-        extractors::ExtractPath,
-        image_utils::encode_lossy_webp,
-        s3_utils::recursive_delete,
+        asset_utils::{ asset_key, check_upload, extension_for_mime, image_key, supported_media_type },
+        auth_utils::{
+            check_asset_permissions,
+            check_project_owner,
+            check_project_permission,
+            denied_asset_ids,
+            get_project_permissions,
+            insert_permissions,
+            parse_permissions,
+        },
+        db_utils::{
+            db_pool,
+            get_client,
+            get_encode_options,
+            get_locked_ids,
+            get_project_usage,
+            locked_conflict,
+            record_bandwidth,
+            SetClause,
+        },
+        dedup_utils::{
+            content_hash,
+            group_similar,
+            hand_over_object,
+            resolve_objects,
+            StoredObject,
+            OBJECT_ID,
+        },
+        diff_utils::{ diff_heatmap, side_by_side, DiffMode },
+        domain_utils::{
+            generate_verification_token,
+            get_custom_domain,
+            has_verification_record,
+            is_valid_domain,
+            thumbnail_url,
+            verification_record_name,
+        },
+        extractors::{ AuthenticatedUser, ExtractPath },
+        image_utils::{
+            encode_upload,
+            load_oriented,
+            load_upload,
+            run_image_task,
+            transform_image,
+            DecodedImage,
+            EncodeOptions,
+            ImageMetadata,
+            ImageTransform,
+        },
+        maintenance_utils::maintenance_middleware,
+        s3_utils::{ delete_renditions, get_object_bytes, get_object_version_bytes, get_rendition },
+        sprite_utils::{
+            pack_sprite_sheet,
+            sprite_manifest_key,
+            SpriteSource,
+            MAX_SHEET_PIXELS,
+            MAX_SPRITES,
+            MAX_SPRITE_PADDING,
+            MAX_TILE_SIZE,
+        },
+        tag_utils::{ get_asset_tags, normalize_tags },
+        tenant_utils::{ owner_middleware, path_uuid, tenant_middleware },
+        trash_utils::{
+            is_in_trash,
+            live_visibility,
+            move_object,
+            move_sprite_manifest,
+            trash_assets,
+            trash_key,
+        },
+        usage_utils::{
+            get_asset_references,
+            group_references,
+            referenced_conflict,
+            repoint_references,
+        },
+        validation_utils::{ validate_image, validation_failed, ImageFacts },
+        variant_utils::{ clear_variants, queue_variants },
+        webhook_utils::{
+            deleted_asset_data,
+            emit_asset_event,
+            enqueue_deleted_events,
+            enqueue_event,
+            notify_webhook_worker,
+        },
+        zip_utils::{ archive_file_name, body_channel, ZipStream },
     },
     MAX_FILE_SIZE,
+    TRASH_RETENTION_DAYS,
 };
 
-#[derive(TryFromMultipart)]
+const MAX_TRANSFORM_OPERATIONS: usize = 20;
+const MAX_BULK_UPDATES: usize = 500;
+const MAX_MERGED_ASSETS: usize = 100;
+const DOWNLOAD_CONCURRENCY: usize = 8;
+// Perceptual hashes of re-encoded or resized copies rarely differ by more than a few bits
+const DEFAULT_DUPLICATE_DISTANCE: u32 = 4;
+const MAX_DUPLICATE_DISTANCE: u32 = 12;
+// Quick picks are shown as small tiles in the editor
+const QUICK_PICK_THUMBNAIL_SIZE: usize = 160;
+const MAX_RECENT_ASSETS: i64 = 50;
+
+#[derive(TryFromMultipart, ToSchema)]
 struct UpdatePayload {
     title: Option<String>,
     owner_id: Option<Uuid>,
     description: Option<String>,
+    // Replaces the stored file, it has to be of the same kind
     #[form_data(limit = "20MiB")]
+    #[schema(value_type = Option<String>, format = Binary)]
     file: Option<FieldData<Bytes>>,
+    // JSON encoded list of permissions
     permissions: Option<String>,
+    quality: Option<f32>,
+    lossless: Option<bool>,
+    // Scene grid of map images, used by the Foundry export
+    grid_size: Option<i32>,
+    grid_distance: Option<f64>,
+    grid_units: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -49,80 +188,304 @@ struct ImageDownload {
     id: Uuid,
 }
 
+#[derive(Deserialize)]
+struct DownloadQuery {
+    format: Option<OutputFormat>,
+}
+
 #[derive(Deserialize)]
 struct DownloadPayload {
     data: Vec<ImageDownload>,
 }
 
 #[derive(Deserialize)]
+struct ExportPayload {
+    // Exports every approved asset of the type when omitted
+    ids: Option<Vec<Uuid>>,
+}
+
+#[derive(Deserialize, ToSchema)]
 struct ImageDelete {
     ids: Vec<Uuid>,
     project_id: Uuid,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct DeleteQuery {
+    // Skips the trash, the asset cannot be restored afterwards
+    permanent: Option<bool>,
+    // With force=false the delete is refused while documents, maps or boards use the asset
+    force: Option<bool>,
+}
+
+#[derive(Deserialize, ToSchema)]
 struct BulkDeletePayload {
     data: ImageDelete,
 }
 
+#[derive(Deserialize, ToSchema)]
+struct BulkUpdateItem {
+    id: Uuid,
+    title: Option<String>,
+    owner_id: Option<Uuid>,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+enum BulkUpdateStatus {
+    Updated,
+    // Neither a title nor an owner was sent
+    Unchanged,
+    NotFound,
+    Denied,
+    Locked,
+}
+
+#[derive(Deserialize)]
+struct TransferPayload {
+    // Assets of the project the caller's token was issued for
+    ids: Vec<Uuid>,
+    project_id: Uuid,
+    image_type: ImageType,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum TransferMode {
+    Copy,
+    Move,
+}
+
+#[derive(Deserialize)]
+struct StatsQuery {
+    days: Option<i32>,
+    limit: Option<i64>,
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+enum AssetSort {
+    #[default]
+    Newest,
+    Oldest,
+    TitleAsc,
+    TitleDesc,
+}
+
+impl AssetSort {
+    // (sort key, direction, keyset comparison against the cursor row)
+    fn sql(&self) -> (&'static str, &'static str, &'static str) {
+        match self {
+            AssetSort::Newest => ("created_at", "DESC", "<"),
+            AssetSort::Oldest => ("created_at", "ASC", ">"),
+            AssetSort::TitleAsc => ("COALESCE(title, '')", "ASC", ">"),
+            AssetSort::TitleDesc => ("COALESCE(title, '')", "DESC", "<"),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ListQuery {
+    // Id of the last asset of the previous page
+    cursor: Option<Uuid>,
+    limit: Option<i64>,
+    search: Option<String>,
+    owner_id: Option<Uuid>,
+    sort: Option<AssetSort>,
+    // Comma separated, only assets with every one of the tags are listed
+    tags: Option<String>,
+    collection_id: Option<Uuid>,
+}
+
+#[derive(Deserialize)]
+struct DuplicatesQuery {
+    image_type: Option<ImageType>,
+    // How many bits of the perceptual hashes may differ, 0 only groups identical looking images
+    distance: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct MergePayload {
+    project_id: Uuid,
+    // The copy that stays, everything that shows one of the others is pointed at it
+    keep: Uuid,
+    ids: Vec<Uuid>,
+}
+
+#[derive(Deserialize)]
+struct AuditQuery {
+    // Id of the last entry of the previous page
+    cursor: Option<Uuid>,
+    limit: Option<i64>,
+    user_id: Option<Uuid>,
+    asset_id: Option<Uuid>,
+    action: Option<AuditAction>,
+    source: Option<AuditSource>,
+    // Unix timestamps in milliseconds
+    from: Option<i64>,
+    to: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct TagsPayload {
+    tags: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct RecentQuery {
+    limit: Option<i64>,
+    image_type: Option<ImageType>,
+}
+
+#[derive(Deserialize)]
+struct UploadedByQuery {
+    // Id of the last asset of the previous page
+    cursor: Option<Uuid>,
+    limit: Option<i64>,
+    image_type: Option<ImageType>,
+}
+
+#[derive(Deserialize)]
+struct DiffQuery {
+    from: String,
+    // Defaults to the current version
+    to: Option<String>,
+    mode: Option<DiffMode>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ModerationDecision {
+    Approve,
+    Reject,
+}
+
+#[derive(Deserialize)]
+struct LockPayload {
+    locked: bool,
+}
+
+#[derive(Deserialize)]
+struct EncodingPayload {
+    quality: Option<f32>,
+    lossless: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct CustomDomainPayload {
+    // None removes the custom domain
+    domain: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TransformPayload {
+    operations: Vec<ImageTransform>,
+}
+
+#[derive(Deserialize)]
+struct SpriteSheetPayload {
+    title: String,
+    ids: Vec<Uuid>,
+    tile_size: Option<u32>,
+    padding: Option<u32>,
+}
+
+#[utoipa::path(
+    post,
+    path = "/assets/update/{id}",
+    tag = "assets",
+    params(("id" = Uuid, Path, description = "Asset to update")),
+    request_body(content = UpdatePayload, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "Asset updated", body = ResponsePayload),
+        (
+            status = 422,
+            description = "The new file breaks a rule of the image type",
+            body = ResponsePayload,
+        )
+    )
+)]
 async fn update_asset(
     State(state): State<AppState>,
+    AuthenticatedUser(claims): AuthenticatedUser,
     ExtractPath(id): ExtractPath<Uuid>,
     TypedMultipart(
-        UpdatePayload { title, description, owner_id, permissions, file },
+        UpdatePayload {
+            title,
+            description,
+            owner_id,
+            permissions,
+            file,
+            quality,
+            lossless,
+            grid_size,
+            grid_distance,
+            grid_units,
+        },
     ): TypedMultipart<UpdatePayload>
 ) -> impl IntoResponse {
+    // Checked before anything is updated, grants can only be set on the asset being updated
+    let permissions = match permissions {
+        Some(permissions) => {
+            let parsed = parse_permissions(&permissions);
+
+            if parsed.is_err() {
+                return parsed.err().unwrap();
+            }
+
+            let parsed = parsed.unwrap();
+
+            if parsed.iter().any(|perm| perm.related_id != id) {
+                return AppResponse::Error(format!("PERMISSIONS FOR ANOTHER ASSET - {}", id));
+            }
+
+            Some(parsed)
+        }
+        None => None,
+    };
+
     let client = get_client(&state.pool).await;
 
     if client.is_err() {
         return client.err().unwrap();
     }
-    let client = client.unwrap();
-    if title.is_some() || owner_id.is_some() || description.is_some() {
-        if title.is_some() && owner_id.is_some() {
-            let res = client.query(
-                "UPDATE images SET title = $1, owner_id = $2 WHERE id = $3;",
-                &[&title.unwrap(), &owner_id.unwrap(), &id]
-            ).await;
-
-            if res.is_err() {
-                return AppResponse::Error(res.err().unwrap().to_string());
-            }
-        } else if title.is_some() && owner_id.is_none() {
-            let res = client.query(
-                "UPDATE images SET title = $1 WHERE id = $2;",
-                &[&title.unwrap(), &id]
-            ).await;
+    let mut client = client.unwrap();
+    let fields = SetClause::default()
+        .set("title", &title)
+        .set("owner_id", &owner_id)
+        .set("description", &description)
+        .touch("updated_at");
 
-            if res.is_err() {
-                return AppResponse::Error(res.err().unwrap().to_string());
-            }
-        } else if title.is_none() && owner_id.is_some() {
-            let res = client.query(
-                "UPDATE images SET owner_id = $1 WHERE id = $2;",
-                &[&owner_id.unwrap(), &id]
-            ).await;
+    if !fields.is_empty() {
+        let (query, params) = fields.update_by_id("images", &id);
+        let res = client.execute(&query, &params).await;
 
-            if res.is_err() {
-                return AppResponse::Error(res.err().unwrap().to_string());
-            }
+        if res.is_err() {
+            return AppResponse::Error(res.err().unwrap().to_string());
         }
+    }
 
-        if description.is_some() {
-            let res = client.query(
-                "UPDATE images SET description = $1 WHERE id = $2;",
-                &[&description.unwrap(), &id]
-            ).await;
+    if grid_size.is_some() || grid_distance.is_some() || grid_units.is_some() {
+        // Settings saved before map_settings existed are still on the image row
+        let res = client.query(
+            "INSERT INTO map_settings (image_id, grid_size, grid_distance, grid_units)
+             SELECT id, COALESCE($1, grid_size), COALESCE($2, grid_distance), COALESCE($3, grid_units)
+             FROM images WHERE id = $4
+             ON CONFLICT (image_id) DO UPDATE
+             SET grid_size = COALESCE($1, map_settings.grid_size),
+                grid_distance = COALESCE($2, map_settings.grid_distance),
+                grid_units = COALESCE($3, map_settings.grid_units);",
+            &[&grid_size, &grid_distance, &grid_units, &id]
+        ).await;
 
-            if res.is_err() {
-                return AppResponse::Error(res.err().unwrap().to_string());
-            }
+        if res.is_err() {
+            return AppResponse::Error(res.err().unwrap().to_string());
         }
     }
 
     if file.is_some() {
         let current_image = client.query_one(
-            "SELECT project_id, type FROM images WHERE id = $1;",
+            "SELECT project_id, type, kind, mime_type, object_id, pending FROM images WHERE id = $1 AND deleted_at IS NULL;",
             &[&id]
         ).await;
 
@@ -134,362 +497,4038 @@ async fn update_asset(
 
         let project_id: Uuid = current_image.get("project_id");
         let image_type: ImageType = current_image.get("type");
+        let kind: AssetKind = current_image.get("kind");
+        let mime_type: String = current_image.get("mime_type");
+        let shared_object: Option<Uuid> = current_image.get("object_id");
+        let pending: bool = current_image.get("pending");
 
         let file = file.unwrap();
 
-        let img_data = image::load_from_memory(&file.contents);
+        let sniffed = check_upload(&file.contents, file.metadata.content_type.as_deref());
 
-        if img_data.is_err() {
-            return AppResponse::Error(img_data.err().unwrap().to_string());
+        if sniffed.is_err() {
+            return AppResponse::Error(format!("{} - {}", sniffed.err().unwrap(), id));
         }
 
-        let lossy = encode_lossy_webp(img_data.unwrap());
+        let sniffed = sniffed.unwrap();
 
-        let upload = state.client
-            .put_object()
-            .bucket(&state.bucket)
-            .key(format!("assets/{}/{}/{}.webp", &project_id, &image_type, &id))
-            .body(ByteStream::from(lossy))
-            .acl(aws_sdk_s3::types::ObjectCannedAcl::PublicRead)
-            .content_type("image/webp")
-            .cache_control("max-age=600")
-            .send().await;
-
-        if upload.is_err() {
-            return AppResponse::Error(upload.err().unwrap().to_string());
+        if sniffed.kind != kind {
+            return AppResponse::Error(
+                format!("CANNOT REPLACE {} WITH {} - {}", kind, sniffed.kind, id)
+            );
         }
-    }
 
-    let _ = insert_permissions(permissions, &state).await;
+        // Replaced images keep their stored format, so their URLs don't change
+        let mut sniffed = match kind {
+            AssetKind::Image => supported_media_type(&mime_type).unwrap_or(sniffed),
+            _ => sniffed,
+        };
 
-    return AppResponse::Success("Image".to_owned(), crate::enums::SuccessActions::Update);
-}
+        let (body, metadata) = if kind == AssetKind::Image {
+            let encode_options = get_encode_options(&client, &project_id).await;
 
-async fn delete_asset(
-    State(state): State<AppState>,
-    ExtractPath((project_id, image_type, id)): ExtractPath<(Uuid, ImageType, Uuid)>
-) -> impl IntoResponse {
-    let client = get_client(&state.pool).await;
+            if encode_options.is_err() {
+                return encode_options.err().unwrap();
+            }
 
-    if client.is_err() {
-        return client.err().unwrap();
-    }
-    let client = client.unwrap();
+            let encode_options = encode_options.unwrap().with_overrides(quality, lossless);
 
-    let del_res = &state.client
-        .delete_object()
-        .bucket(&state.bucket)
-        .key(format!("assets/{}/{}/{}.webp", &project_id, &image_type, &id))
-        .send().await;
+            let contents = file.contents.clone();
+            let max_pixels = state.config.max_image_pixels;
+            let img_data = run_image_task(&state.encode_permits, "decode", move || {
+                load_upload(&contents, max_pixels)
+            }).await;
 
-    if del_res.is_err() {
-        tracing::error!("{}", del_res.as_ref().err().unwrap());
-    }
+            if img_data.is_err() {
+                return AppResponse::Error(img_data.err().unwrap());
+            }
 
-    let res = client.query("DELETE FROM images WHERE id = $1;", &[&id]).await;
+            let img_data = img_data.unwrap();
 
-    if res.is_err() {
-        return AppResponse::Error(res.err().unwrap().to_string());
-    }
+            if img_data.is_err() {
+                return AppResponse::Error(img_data.err().unwrap());
+            }
 
-    return AppResponse::Success("Image".to_owned(), crate::enums::SuccessActions::Delete);
-}
+            let img_data = img_data.unwrap();
+            let facts = ImageFacts::from_head(
+                &file.contents,
+                file.contents.len() as u64,
+                img_data.dimensions()
+            );
+            let valid = validate_image(&image_type, &facts);
 
-async fn bulk_delete_assets(
-    State(state): State<AppState>,
-    ExtractPath(image_type): ExtractPath<ImageType>,
-    Json(payload): Json<BulkDeletePayload>
-) -> impl IntoResponse {
-    let client = get_client(&state.pool).await;
+            if valid.is_err() {
+                return validation_failed(&image_type, valid.err().unwrap());
+            }
 
-    if client.is_err() {
-        return client.err().unwrap();
-    }
-    let client = client.unwrap();
+            let metadata = ImageMetadata::read(&img_data, &file.contents);
+            let format = OutputFormat::from_content_type(sniffed.mime_type).unwrap_or(
+                OutputFormat::Webp
+            );
 
-    let res = client.query(
-        "DELETE FROM images WHERE id = ANY($1) RETURNING id;",
-        &[&payload.data.ids]
-    ).await;
+            // An animation replacing an AVIF image switches it to WebP
+            sniffed = supported_media_type(img_data.storage_format(format).content_type()).unwrap();
 
-    if res.is_err() {
-        return AppResponse::Error(res.err().unwrap().to_string());
-    }
+            let encoded = run_image_task(&state.encode_permits, "encode", move || {
+                encode_upload(img_data, format, &encode_options)
+            }).await;
 
-    let deleted_ids: Vec<Uuid> = res
-        .unwrap()
-        .iter()
-        .map(|row| row.get("id"))
-        .collect();
+            if encoded.is_err() {
+                return AppResponse::Error(encoded.err().unwrap());
+            }
 
-    let mut delete_objects: Vec<ObjectIdentifier> = vec![];
-    for id in deleted_ids {
-        let obj_id = ObjectIdentifier::builder()
-            .set_key(
-                Some(format!("assets/{}/{}/{}.webp", &payload.data.project_id, &image_type, &id))
-            )
-            .build();
+            let encoded = encoded.unwrap();
 
-        if obj_id.is_err() {
-            continue;
+            if encoded.is_err() {
+                return AppResponse::Error(encoded.err().unwrap());
+            }
+
+            (encoded.unwrap(), Some(metadata))
+        } else {
+            (file.contents.to_vec(), None)
+        };
+
+        let size_bytes = body.len() as i64;
+        let hash = metadata.as_ref().map(|_| content_hash(&body));
+        let key = asset_key(&project_id, &image_type, &kind, &id, sniffed.mime_type);
+
+        let target = resolve_target(&state, &project_id).await;
+
+        if target.is_err() {
+            return target.err().unwrap();
         }
 
-        let obj_id = obj_id.unwrap();
+        let target = target.unwrap();
 
-        delete_objects.push(obj_id);
-    }
+        let visibility = live_visibility(&state, &project_id, pending).await;
 
-    let delete_cmd = aws_sdk_s3::types::Delete::builder().set_objects(Some(delete_objects)).build();
+        if visibility.is_err() {
+            return visibility.err().unwrap();
+        }
 
-    if delete_cmd.is_err() {
-        return AppResponse::Error(delete_cmd.err().unwrap().to_string());
-    }
-    let delete_cmd = delete_cmd.unwrap();
-    let delete_res = &state.client
-        .delete_objects()
-        .bucket(&state.bucket)
-        .delete(delete_cmd)
-        .send().await;
+        let visibility = visibility.unwrap();
 
-    if delete_res.is_err() {
-        AppResponse::Error(delete_res.as_ref().err().unwrap().to_string());
-    }
+        // A deduplicated asset gets its own object, the shared one is left to the other rows
+        if shared_object.is_none() {
+            let handed_over = hand_over_object(
+                &state,
+                &target,
+                &client,
+                &project_id,
+                &image_type,
+                &id,
+                &mime_type
+            ).await;
 
-    return AppResponse::Success("Images".to_owned(), crate::enums::SuccessActions::Delete);
-}
+            if handed_over.is_err() {
+                return handed_over.err().unwrap();
+            }
+        }
 
-async fn download_assets(
-    State(state): State<AppState>,
-    ExtractPath((project_id, image_type)): ExtractPath<(Uuid, ImageType)>,
-    Json(payload): Json<DownloadPayload>
-) -> impl IntoResponse {
-    let mut data_strings: Vec<String> = Vec::new();
-    for image in payload.data {
-        let data = state.client
-            .get_object()
-            .bucket(&state.bucket)
-            .key(format!("assets/{}/{}/{}.webp", &project_id, &image_type, &image.id))
-            .send().await;
+        let upload = target.put(&key, body, &PutOptions {
+            content_type: sniffed.mime_type,
+            cache_control: state.config.cache_control.for_image_type(&image_type),
+            visibility,
+        }).await;
 
-        if data.is_err() {
-            tracing::error!("ERROR GETTING IMAGE DATA - {}", data.err().unwrap());
-            continue;
+        if upload.is_err() {
+            return AppResponse::Error(upload.err().unwrap());
         }
 
-        let data = data.unwrap().body.collect().await;
+        if shared_object.is_none() {
+            let previous_key = asset_key(&project_id, &image_type, &kind, &id, &mime_type);
 
-        if data.is_err() {
-            tracing::error!("ERROR GETTING IMAGE DATA - {}", data.err().unwrap());
-            continue;
+            // e.g. an mp3 replaced by an ogg lands on a different key
+            if previous_key != key {
+                let del_res = target.delete(&previous_key).await;
+
+                if del_res.is_err() {
+                    tracing::error!("{}", del_res.err().unwrap());
+                }
+            }
+
+            let cleared = clear_variants(&client, &vec![id]).await;
+
+            if cleared.is_err() {
+                return cleared.err().unwrap();
+            }
+
+            delete_renditions(&target, &project_id, &image_type, &id).await;
         }
 
-        let data = data.unwrap().into_bytes();
+        let res = client.query(
+            "UPDATE images SET size_bytes = $1, mime_type = $2, width = $3, height = $4, original_format = $5,
+                is_animated = $6, content_hash = $7, object_id = NULL, perceptual_hash = $9,
+                dominant_color = $10, thumbhash = $11, updated_at = NOW()
+             WHERE id = $8;",
+            &[
+                &size_bytes,
+                &sniffed.mime_type,
+                &metadata.as_ref().map(|metadata| metadata.width),
+                &metadata.as_ref().map(|metadata| metadata.height),
+                &metadata.as_ref().and_then(|metadata| metadata.original_format.clone()),
+                &metadata.as_ref().is_some_and(|metadata| metadata.is_animated),
+                &hash,
+                &id,
+                &metadata.as_ref().map(|metadata| metadata.perceptual_hash),
+                &metadata.as_ref().and_then(|metadata| metadata.dominant_color.clone()),
+                &metadata.as_ref().map(|metadata| metadata.thumbhash.clone()),
+            ]
+        ).await;
 
-        let base_64 = BASE64_STANDARD.encode(data);
+        if res.is_err() {
+            return AppResponse::Error(res.err().unwrap().to_string());
+        }
 
-        data_strings.push(base_64);
+        if kind == AssetKind::Image {
+            queue_variants(&state, project_id, image_type, id, sniffed.mime_type.to_owned());
+        }
     }
-    return AppResponse::SuccessData(
-        "Assets".to_owned(),
-        crate::enums::SuccessActions::Download,
-        json!(data_strings)
-    );
-}
 
-async fn permission_middleware(
-    cookie_jar: CookieJar,
-    State(state): State<AppState>,
-    request: Request,
-    next: Next
-) -> Response {
-    let url = request.uri().to_string();
-    let id = Uuid::from_str(url.clone().split("/").last().unwrap());
+    if let Some(permissions) = permissions {
+        let res = insert_permissions(&mut client, &[id], &permissions).await;
 
-    if id.is_err() {
-        return AppResponse::Error(id.err().unwrap().to_string()).into_response();
+        if res.is_err() {
+            return res.err().unwrap();
+        }
     }
-    let id = id.unwrap();
 
-    let action = match url {
-        u if u.contains("/update/") => "update",
-        u if u.contains("/delete/") || request.method() == &Method::DELETE => "delete",
-        u if u.contains("upload") => "upload",
-        u if u.contains("download") => "read",
-        _ => "NONE",
-    };
+    emit_asset_event(&state, &client, WebhookEvent::Updated, &id).await;
+    record_audit(
+        &client,
+        &AuditActor::user(&claims),
+        &claims.project_id,
+        AuditAction::Update,
+        &[id]
+    ).await;
 
-    if action == "NONE" {
-        let res = Response::builder()
-            .status(StatusCode::INTERNAL_SERVER_ERROR)
-            .body(Body::from("There was an error with your request."))
-            .unwrap();
+    return AppResponse::Success("Image".to_owned(), crate::enums::SuccessActions::Update);
+}
 
-        return res;
+// Applies the editor's operations to the stored image and writes the result over its object,
+// so the previous content stays available as an object version
+async fn transform_asset(
+    State(state): State<AppState>,
+    AuthenticatedUser(claims): AuthenticatedUser,
+    ExtractPath(id): ExtractPath<Uuid>,
+    Json(payload): Json<TransformPayload>
+) -> impl IntoResponse {
+    if payload.operations.is_empty() {
+        return AppResponse::Error(format!("NO TRANSFORM OPERATIONS - {}", id));
     }
 
-    let claims = check_auth(
-        cookie_jar,
-        &state.reqwest_client,
-        state.auth_service_url,
-        request.headers().to_owned()
-    ).await;
+    if payload.operations.len() > MAX_TRANSFORM_OPERATIONS {
+        return AppResponse::Error(
+            format!("MORE THAN {} TRANSFORM OPERATIONS - {}", MAX_TRANSFORM_OPERATIONS, id)
+        );
+    }
 
-    if claims.is_err() {
-        let res = Response::builder()
-            .status(StatusCode::INTERNAL_SERVER_ERROR)
-            .body(Body::from("There was an error with your request."))
-            .unwrap();
+    let client = get_client(&state.pool).await;
 
-        return res;
+    if client.is_err() {
+        return client.err().unwrap();
     }
+    let client = client.unwrap();
 
-    let claims = claims.unwrap().claims;
-
-    if claims.is_none() {
-        let res = Response::builder()
-            .status(StatusCode::INTERNAL_SERVER_ERROR)
-            .body(Body::from("There was an error with your request."))
-            .unwrap();
+    let image = client.query_opt(
+        &format!(
+            "SELECT project_id, type, kind, mime_type, pending, object_id AS shared_object, {}
+             FROM images WHERE id = $1 AND deleted_at IS NULL;",
+            OBJECT_ID
+        ),
+        &[&id]
+    ).await;
 
-        return res;
+    if image.is_err() {
+        return AppResponse::Error(image.err().unwrap().to_string());
     }
 
-    let claims = claims.unwrap();
+    let image = image.unwrap();
 
-    let mut headers = HeaderMap::new();
+    if image.is_none() {
+        return AppResponse::Error(format!("NO IMAGE - {}", id));
+    }
 
-    headers.append(CONTENT_TYPE, HeaderValue::from_str("application/json").unwrap());
-    headers.append("user-id", HeaderValue::from_str(claims.user_id.to_string().as_str()).unwrap());
-    headers.append(
-        "project-id",
-        HeaderValue::from_str(claims.project_id.to_string().as_str()).unwrap()
-    );
+    let image = image.unwrap();
+    let project_id: Uuid = image.get("project_id");
+    let image_type: ImageType = image.get("type");
+    let kind: AssetKind = image.get("kind");
+    let mime_type: String = image.get("mime_type");
+    let pending: bool = image.get("pending");
+    let shared_object: Option<Uuid> = image.get("shared_object");
+    let object_id: Uuid = image.get("object_id");
 
-    let auth_service_url = env::var("AUTH_SERVICE_URL").expect("NO AUTH SERVICE");
-    let res = state.reqwest_client
-        .get(format!("{}/auth/permission/{}_images", auth_service_url, &action))
-        .headers(headers)
-        .send().await;
+    if kind != AssetKind::Image {
+        return AppResponse::Error(format!("CANNOT TRANSFORM {} - {}", kind, id));
+    }
 
-    if res.is_err() {
-        let res = Response::builder()
-            .status(StatusCode::INTERNAL_SERVER_ERROR)
-            .body(Body::from("There was an error with your request."))
-            .unwrap();
+    let encode_options = get_encode_options(&client, &project_id).await;
 
-        return res;
+    if encode_options.is_err() {
+        return encode_options.err().unwrap();
     }
 
-    let permissions = res.unwrap().json::<PermissionCheckResponse>().await;
+    let encode_options = encode_options.unwrap();
 
-    if permissions.is_err() {
-        let res = Response::builder()
-            .status(StatusCode::INTERNAL_SERVER_ERROR)
-            .body(Body::from("There was an error with your request."))
-            .unwrap();
+    let target = resolve_target(&state, &project_id).await;
 
-        return res;
+    if target.is_err() {
+        return target.err().unwrap();
     }
 
-    let permissions = permissions.unwrap();
-    let client = get_client(&state.pool).await;
+    let target = target.unwrap();
 
-    if client.is_err() {
-        let res = Response::builder()
-            .status(StatusCode::INTERNAL_SERVER_ERROR)
-            .body(Body::from("There was an error with your request."))
-            .unwrap();
+    let data = get_object_bytes(
+        &target,
+        &image_key(&project_id, &image_type, &object_id, &mime_type)
+    ).await;
 
-        return res;
+    if data.is_err() {
+        return data.err().unwrap();
     }
-    let client = client.unwrap();
-
-    let has_permission = match permissions.is_project_owner {
-        true => true,
-        false => {
-            let permission_check = client.query_opt(
-                "SELECT TRUE AS has_permission
-                 FROM images
-                 LEFT JOIN entity_permissions ON entity_permissions.related_id = images.id
-                 WHERE images.id = $1
-                    AND
-                        (images.owner_id = $2
-                    OR
-                        entity_permissions.role_id = $3
-                    OR
-                        (entity_permissions.user_id = $2 AND entity_permissions.permission_id = $4 AND entity_permissions.related_id = images.id)
-                    );",
-                &[&id, &claims.user_id, &permissions.role_id, &permissions.permission_id]
-            ).await;
 
-            if permission_check.is_err() {
-                false;
-            }
+    let data = data.unwrap();
+    let format = OutputFormat::from_content_type(&mime_type).unwrap_or(OutputFormat::Webp);
+    let operations = payload.operations;
+    let max_pixels = state.config.max_image_pixels;
 
-            let permission_check = permission_check.unwrap();
+    let transformed = run_image_task(&state.encode_permits, "transform", move || {
+        let img = transform_image(load_upload(&data, max_pixels)?, &operations)?;
+        let (width, height) = img.dimensions();
+        let phash = img.perceptual_hash();
+        let color = img.dominant_color();
+        let thumbhash = img.thumbhash();
+        let encoded = encode_upload(img, format, &encode_options)?;
 
-            permission_check.is_some()
-        }
-    };
+        Ok::<(Vec<u8>, u32, u32, i64, Option<String>, String), String>((
+            encoded,
+            width,
+            height,
+            phash,
+            color,
+            thumbhash,
+        ))
+    }).await;
 
-    if !has_permission {
-        return AppResponse::Auth.into_response();
+    if transformed.is_err() {
+        return AppResponse::Error(transformed.err().unwrap());
     }
 
-    return next.run(request).await;
-}
+    let transformed = transformed.unwrap();
 
-async fn delete_folder(
-    State(state): State<AppState>,
-    ExtractPath(project_id): ExtractPath<Uuid>
-) -> impl IntoResponse {
-    let location = format!("assets/{}", project_id);
+    if transformed.is_err() {
+        return AppResponse::Error(transformed.err().unwrap());
+    }
 
-    let res = recursive_delete(&state.client, &state.bucket, &location).await;
+    let (encoded, width, height, phash, dominant_color, thumbhash) = transformed.unwrap();
+    let size_bytes = encoded.len() as i64;
+    let hash = content_hash(&encoded);
 
-    if res.is_err() {
-        return res.err().unwrap();
+    // Same as a replaced file, rows sharing the object keep the untransformed content
+    if shared_object.is_none() {
+        let handed_over = hand_over_object(
+            &state,
+            &target,
+            &client,
+            &project_id,
+            &image_type,
+            &id,
+            &mime_type
+        ).await;
+
+        if handed_over.is_err() {
+            return handed_over.err().unwrap();
+        }
     }
 
-    let client = get_client(&state.pool).await;
+    let visibility = live_visibility(&state, &project_id, pending).await;
 
-    if client.is_err() {
-        return client.err().unwrap();
+    if visibility.is_err() {
+        return visibility.err().unwrap();
     }
-    let client = client.unwrap();
 
-    let img_delete_res = client.query(
-        "DELETE FROM images WHERE project_id = $1;",
-        &[&project_id]
+    let upload = target.put(
+        &image_key(&project_id, &image_type, &id, &mime_type),
+        encoded,
+        &PutOptions {
+            content_type: &mime_type,
+            cache_control: state.config.cache_control.for_image_type(&image_type),
+            visibility: visibility.unwrap(),
+        }
     ).await;
 
-    if img_delete_res.is_err() {
-        return AppResponse::Error(img_delete_res.err().unwrap().to_string());
+    if upload.is_err() {
+        return AppResponse::Error(upload.err().unwrap());
     }
 
-    AppResponse::Success("Images".to_owned(), crate::enums::SuccessActions::Delete)
-}
+    let version_id = upload.unwrap();
+
+    if shared_object.is_none() {
+        let cleared = clear_variants(&client, &vec![id]).await;
+
+        if cleared.is_err() {
+            return cleared.err().unwrap();
+        }
+
+        delete_renditions(&target, &project_id, &image_type, &id).await;
+    }
+
+    let res = client.execute(
+        "UPDATE images SET size_bytes = $1, width = $2, height = $3, content_hash = $4, object_id = NULL,
+            perceptual_hash = $6, dominant_color = $7, thumbhash = $8, updated_at = NOW()
+         WHERE id = $5;",
+        &[
+            &size_bytes,
+            &(width as i32),
+            &(height as i32),
+            &hash,
+            &id,
+            &phash,
+            &dominant_color,
+            &thumbhash,
+        ]
+    ).await;
+
+    if res.is_err() {
+        return AppResponse::Error(res.err().unwrap().to_string());
+    }
+
+    queue_variants(&state, project_id, image_type, id, mime_type);
+    emit_asset_event(&state, &client, WebhookEvent::Updated, &id).await;
+    record_audit(
+        &client,
+        &AuditActor::user(&claims),
+        &project_id,
+        AuditAction::Update,
+        &[id]
+    ).await;
+
+    return AppResponse::SuccessData(
+        "Image".to_owned(),
+        crate::enums::SuccessActions::Update,
+        json!({
+            "id": id,
+            "width": width,
+            "height": height,
+            "dominant_color": dominant_color,
+            "size_bytes": size_bytes,
+            "version_id": version_id,
+        })
+    );
+}
+
+#[utoipa::path(
+    delete,
+    path = "/assets/{project_id}/{image_type}/{id}",
+    tag = "assets",
+    params(
+        ("project_id" = Uuid, Path),
+        ("image_type" = ImageType, Path),
+        ("id" = Uuid, Path, description = "Asset to delete"),
+        DeleteQuery
+    ),
+    responses(
+        (status = 200, description = "Asset deleted or moved to the trash", body = ResponsePayload),
+        (status = 409, description = "The asset is locked or still in use", body = ResponsePayload)
+    )
+)]
+async fn delete_asset(
+    State(state): State<AppState>,
+    AuthenticatedUser(claims): AuthenticatedUser,
+    query: Query<DeleteQuery>,
+    ExtractPath((project_id, image_type, id)): ExtractPath<(Uuid, ImageType, Uuid)>
+) -> impl IntoResponse {
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let mut client = client.unwrap();
+
+    let locked_ids = get_locked_ids(&client, &project_id, Some(&vec![id])).await;
+
+    if locked_ids.is_err() {
+        return locked_ids.err().unwrap();
+    }
+
+    let locked_ids = locked_ids.unwrap();
+
+    if !locked_ids.is_empty() {
+        return locked_conflict(locked_ids);
+    }
+
+    if !query.force.unwrap_or(true) {
+        let references = get_asset_references(&client, &vec![id]).await;
+
+        if references.is_err() {
+            return references.err().unwrap();
+        }
+
+        let references = references.unwrap();
+
+        if !references.is_empty() {
+            return referenced_conflict(references);
+        }
+    }
+
+    if !query.permanent.unwrap_or(false) {
+        let trashed = trash_assets(&state, &client, &project_id, None, &vec![id]).await;
+
+        if trashed.is_err() {
+            return trashed.err().unwrap();
+        }
+
+        // Objects without a live row (e.g. gateway entity images) fall through to a permanent delete
+        if !trashed.unwrap().is_empty() {
+            record_audit(
+                &client,
+                &AuditActor::user(&claims),
+                &project_id,
+                AuditAction::Delete,
+                &[id]
+            ).await;
+
+            return AppResponse::Success("Image".to_owned(), crate::enums::SuccessActions::Delete);
+        }
+    }
+
+    let transaction = client.transaction().await;
+
+    if transaction.is_err() {
+        return AppResponse::Error(transaction.err().unwrap().to_string());
+    }
+    let transaction = transaction.unwrap();
+
+    let res = transaction.query(
+        &format!("DELETE FROM images WHERE id = $1 RETURNING id, {};", deleted_asset_columns()),
+        &[&id]
+    ).await;
+
+    if res.is_err() {
+        return AppResponse::Error(res.err().unwrap().to_string());
+    }
+
+    let rows = res.unwrap();
+
+    // Objects without a row (e.g. gateway entity images) are always WebP images
+    let jobs = match rows.is_empty() {
+        true =>
+            Ok(
+                asset_deletion_jobs(&project_id, &image_type, &AssetKind::Image, &id, "image/webp", false)
+            ),
+        false => deletion_jobs(&transaction, &rows).await,
+    };
+
+    if jobs.is_err() {
+        return jobs.err().unwrap();
+    }
+
+    let enqueued = enqueue_jobs(&transaction, &jobs.unwrap()).await;
+
+    if enqueued.is_err() {
+        return enqueued.err().unwrap();
+    }
+
+    let events = enqueue_deleted_events(&transaction, &rows).await;
+
+    if events.is_err() {
+        return events.err().unwrap();
+    }
+
+    let committed = transaction.commit().await;
+
+    if committed.is_err() {
+        return AppResponse::Error(committed.err().unwrap().to_string());
+    }
+
+    notify_job_worker(&state);
+    notify_webhook_worker(&state);
+    record_audit(
+        &client,
+        &AuditActor::user(&claims),
+        &project_id,
+        AuditAction::Delete,
+        &[id]
+    ).await;
+
+    return AppResponse::Success("Image".to_owned(), crate::enums::SuccessActions::Delete);
+}
+
+async fn restore_asset(
+    State(state): State<AppState>,
+    AuthenticatedUser(claims): AuthenticatedUser,
+    ExtractPath(id): ExtractPath<Uuid>
+) -> impl IntoResponse {
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    let image = client.query_opt(
+        &format!(
+            "SELECT type, kind, mime_type, pending, {} FROM images
+             WHERE id = $1 AND project_id = $2 AND deleted_at IS NOT NULL;",
+            OBJECT_ID
+        ),
+        &[&id, &claims.project_id]
+    ).await;
+
+    if image.is_err() {
+        return AppResponse::Error(image.err().unwrap().to_string());
+    }
+
+    let image = image.unwrap();
+
+    if image.is_none() {
+        return AppResponse::Error(format!("NO TRASHED IMAGE - {}", id));
+    }
+
+    let image = image.unwrap();
+    let image_type: ImageType = image.get("type");
+    let kind: AssetKind = image.get("kind");
+    let mime_type: String = image.get("mime_type");
+    let pending: bool = image.get("pending");
+    let object_id: Uuid = image.get("object_id");
+
+    let target = resolve_target(&state, &claims.project_id).await;
+
+    if target.is_err() {
+        return target.err().unwrap();
+    }
+
+    let target = target.unwrap();
+    let key = asset_key(&claims.project_id, &image_type, &kind, &object_id, &mime_type);
+    let manifest = sprite_manifest_key(
+        &claims.project_id,
+        &image_type,
+        &kind,
+        &object_id,
+        &mime_type
+    );
+    let in_trash = is_in_trash(&target, &key).await;
+
+    if in_trash {
+        let visibility = live_visibility(&state, &claims.project_id, pending).await;
+
+        if visibility.is_err() {
+            return visibility.err().unwrap();
+        }
+
+        let visibility = visibility.unwrap();
+        let moved = move_object(&target, &trash_key(&key), &key, visibility).await;
+
+        if moved.is_err() {
+            return moved.err().unwrap();
+        }
+
+        let moved = move_sprite_manifest(&target, manifest.clone(), false, visibility).await;
+
+        if moved.is_err() {
+            let _ = move_object(&target, &key, &trash_key(&key), AssetVisibility::Private).await;
+
+            return moved.err().unwrap();
+        }
+    }
+
+    let res = client.execute("UPDATE images SET deleted_at = NULL WHERE id = $1;", &[&id]).await;
+
+    if res.is_err() {
+        if in_trash {
+            let _ = move_object(&target, &key, &trash_key(&key), AssetVisibility::Private).await;
+            let _ = move_sprite_manifest(&target, manifest, true, AssetVisibility::Private).await;
+        }
+
+        return AppResponse::Error(res.err().unwrap().to_string());
+    }
+
+    return AppResponse::Success("Image".to_owned(), crate::enums::SuccessActions::Update);
+}
+
+async fn get_trashed_assets(
+    State(state): State<AppState>,
+    ExtractPath(project_id): ExtractPath<Uuid>
+) -> impl IntoResponse {
+    let client = get_client(db_pool(&state, DbAccess::Read)).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    let rows = client.query(
+        "SELECT id, title, type, kind, owner_id,
+            (EXTRACT(EPOCH FROM deleted_at) * 1000)::BIGINT AS deleted_at,
+            (EXTRACT(EPOCH FROM deleted_at + make_interval(days => $2)) * 1000)::BIGINT AS purge_at
+         FROM images
+         WHERE project_id = $1 AND deleted_at IS NOT NULL
+         ORDER BY deleted_at DESC;",
+        &[&project_id, &TRASH_RETENTION_DAYS]
+    ).await;
+
+    if rows.is_err() {
+        return AppResponse::Error(rows.err().unwrap().to_string());
+    }
+
+    let items: Vec<serde_json::Value> = rows
+        .unwrap()
+        .iter()
+        .map(|row| {
+            let id: Uuid = row.get("id");
+            let title: Option<String> = row.get("title");
+            let image_type: ImageType = row.get("type");
+            let kind: AssetKind = row.get("kind");
+            let owner_id: Uuid = row.get("owner_id");
+            let deleted_at: i64 = row.get("deleted_at");
+            let purge_at: i64 = row.get("purge_at");
+
+            json!({
+                "id": id,
+                "title": title,
+                "type": image_type.to_string(),
+                "kind": kind,
+                "owner_id": owner_id,
+                "deleted_at": deleted_at,
+                "purge_at": purge_at,
+            })
+        })
+        .collect();
+
+    return AppResponse::SuccessData(
+        "Trash".to_owned(),
+        crate::enums::SuccessActions::Read,
+        json!(items)
+    );
+}
+
+// Requested ids that weren't deleted, with the reason reported for them
+fn not_deleted(requested: &[Uuid], deleted: &[Uuid], reason: &str) -> Vec<serde_json::Value> {
+    requested
+        .iter()
+        .filter(|id| !deleted.contains(id))
+        .map(|id| json!({ "id": id, "reason": reason }))
+        .collect()
+}
+
+#[utoipa::path(
+    delete,
+    path = "/assets/bulk/delete/{image_type}",
+    tag = "assets",
+    params(("image_type" = ImageType, Path), DeleteQuery),
+    request_body = BulkDeletePayload,
+    responses(
+        (
+            status = 200,
+            description = "Ids deleted (`data.deleted`) or moved to the trash (`data.trashed`), and the ones that weren't under `data.failed`",
+            body = ResponsePayload,
+        ),
+        (
+            status = 409,
+            description = "Some assets are locked or still in use",
+            body = ResponsePayload,
+        )
+    )
+)]
+async fn bulk_delete_assets(
+    State(state): State<AppState>,
+    AuthenticatedUser(claims): AuthenticatedUser,
+    query: Query<DeleteQuery>,
+    ExtractPath(image_type): ExtractPath<ImageType>,
+    Json(payload): Json<BulkDeletePayload>
+) -> impl IntoResponse {
+    if payload.data.project_id != claims.project_id {
+        return AppResponse::Auth;
+    }
+
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let mut client = client.unwrap();
+
+    let permitted = check_asset_permissions(
+        &state,
+        &client,
+        &claims,
+        RequiredPermission::Delete,
+        &payload.data.ids
+    ).await;
+
+    if permitted.is_err() {
+        return permitted.err().unwrap();
+    }
+
+    let locked_ids = get_locked_ids(
+        &client,
+        &payload.data.project_id,
+        Some(&payload.data.ids)
+    ).await;
+
+    if locked_ids.is_err() {
+        return locked_ids.err().unwrap();
+    }
+
+    let locked_ids = locked_ids.unwrap();
+
+    if !locked_ids.is_empty() {
+        return locked_conflict(locked_ids);
+    }
+
+    if !query.force.unwrap_or(true) {
+        let references = get_asset_references(&client, &payload.data.ids).await;
+
+        if references.is_err() {
+            return references.err().unwrap();
+        }
+
+        let references = references.unwrap();
+
+        if !references.is_empty() {
+            return referenced_conflict(references);
+        }
+    }
+
+    if !query.permanent.unwrap_or(false) {
+        let trashed = trash_assets(
+            &state,
+            &client,
+            &payload.data.project_id,
+            Some(&image_type),
+            &payload.data.ids
+        ).await;
+
+        if trashed.is_err() {
+            return trashed.err().unwrap();
+        }
+
+        let trashed = trashed.unwrap();
+
+        record_audit(
+            &client,
+            &AuditActor::user(&claims),
+            &payload.data.project_id,
+            AuditAction::Delete,
+            &trashed
+        ).await;
+
+        return AppResponse::SuccessData(
+            "Images".to_owned(),
+            crate::enums::SuccessActions::Delete,
+            json!({
+                "trashed": trashed,
+                "failed": not_deleted(
+                    &payload.data.ids,
+                    &trashed,
+                    "NOT FOUND OR COULD NOT BE MOVED TO THE TRASH"
+                ),
+            })
+        );
+    }
+
+    let transaction = client.transaction().await;
+
+    if transaction.is_err() {
+        return AppResponse::Error(transaction.err().unwrap().to_string());
+    }
+    let transaction = transaction.unwrap();
+
+    let res = transaction.query(
+        &format!(
+            "DELETE FROM images WHERE id = ANY($1) AND project_id = $2 AND type = $3 RETURNING id, {};",
+            deleted_asset_columns()
+        ),
+        &[&payload.data.ids, &payload.data.project_id, &image_type]
+    ).await;
+
+    if res.is_err() {
+        return AppResponse::Error(res.err().unwrap().to_string());
+    }
+
+    let rows = res.unwrap();
+    let jobs = deletion_jobs(&transaction, &rows).await;
+
+    if jobs.is_err() {
+        return jobs.err().unwrap();
+    }
+
+    let jobs = jobs.unwrap();
+    let enqueued = enqueue_jobs(&transaction, &jobs).await;
+
+    if enqueued.is_err() {
+        return enqueued.err().unwrap();
+    }
+
+    let events = enqueue_deleted_events(&transaction, &rows).await;
+
+    if events.is_err() {
+        return events.err().unwrap();
+    }
+
+    let committed = transaction.commit().await;
+
+    if committed.is_err() {
+        return AppResponse::Error(committed.err().unwrap().to_string());
+    }
+
+    notify_job_worker(&state);
+    notify_webhook_worker(&state);
+
+    let deleted: Vec<Uuid> = rows
+        .iter()
+        .map(|row| row.get("id"))
+        .collect();
+
+    record_audit(
+        &client,
+        &AuditActor::user(&claims),
+        &payload.data.project_id,
+        AuditAction::Delete,
+        &deleted
+    ).await;
+
+    // The objects are removed by the asset job worker, which retries failed deletions
+    return AppResponse::SuccessData(
+        "Images".to_owned(),
+        crate::enums::SuccessActions::Delete,
+        json!({
+            "deleted": deleted,
+            "failed": not_deleted(&payload.data.ids, &deleted, "NOT FOUND"),
+            "queued_jobs": jobs.len(),
+        })
+    );
+}
+
+// Applies every item in one transaction, items that can't be updated are reported instead
+#[utoipa::path(
+    post,
+    path = "/assets/bulk/update",
+    tag = "assets",
+    request_body = Vec<BulkUpdateItem>,
+    responses(
+        (
+            status = 200,
+            description = "Status of every item under `data.results`",
+            body = ResponsePayload,
+        )
+    )
+)]
+async fn bulk_update_assets(
+    State(state): State<AppState>,
+    AuthenticatedUser(claims): AuthenticatedUser,
+    Json(items): Json<Vec<BulkUpdateItem>>
+) -> impl IntoResponse {
+    if items.is_empty() {
+        return AppResponse::Error("NO ASSETS TO UPDATE".to_owned());
+    }
+
+    if items.len() > MAX_BULK_UPDATES {
+        return AppResponse::Error(
+            format!("AT MOST {} ASSETS CAN BE UPDATED AT ONCE", MAX_BULK_UPDATES)
+        );
+    }
+
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let mut client = client.unwrap();
+
+    let ids: Vec<Uuid> = items
+        .iter()
+        .map(|item| item.id)
+        .collect();
+
+    let existing = client.query(
+        "SELECT id, locked FROM images
+         WHERE id = ANY($1) AND project_id = $2 AND deleted_at IS NULL;",
+        &[&ids, &claims.project_id]
+    ).await;
+
+    if existing.is_err() {
+        return AppResponse::Error(existing.err().unwrap().to_string());
+    }
+
+    let existing: Vec<(Uuid, bool)> = existing
+        .unwrap()
+        .iter()
+        .map(|row| (row.get("id"), row.get("locked")))
+        .collect();
+
+    let permissions = get_project_permissions(
+        &state,
+        &claims.user_id,
+        &claims.project_id,
+        RequiredPermission::Update.name()
+    ).await;
+
+    if permissions.is_err() {
+        return permissions.err().unwrap();
+    }
+
+    let denied = denied_asset_ids(
+        &client,
+        &ids,
+        &claims.project_id,
+        &claims.user_id,
+        &permissions.unwrap()
+    ).await;
+
+    if denied.is_err() {
+        return denied.err().unwrap();
+    }
+
+    let denied: HashSet<Uuid> = denied.unwrap().into_iter().collect();
+
+    let statuses: Vec<BulkUpdateStatus> = items
+        .iter()
+        .map(|item| {
+            let found = existing.iter().find(|(id, _)| *id == item.id);
+
+            match found {
+                None => BulkUpdateStatus::NotFound,
+                Some(_) if denied.contains(&item.id) => BulkUpdateStatus::Denied,
+                Some((_, true)) => BulkUpdateStatus::Locked,
+                Some(_) if item.title.is_none() && item.owner_id.is_none() =>
+                    BulkUpdateStatus::Unchanged,
+                Some(_) => BulkUpdateStatus::Updated,
+            }
+        })
+        .collect();
+
+    let transaction = client.transaction().await;
+
+    if transaction.is_err() {
+        return AppResponse::Error(transaction.err().unwrap().to_string());
+    }
+    let transaction = transaction.unwrap();
+
+    for (item, status) in items.iter().zip(statuses.iter()) {
+        if *status != BulkUpdateStatus::Updated {
+            continue;
+        }
+
+        let (query, params) = SetClause::default()
+            .set("title", &item.title)
+            .set("owner_id", &item.owner_id)
+            .touch("updated_at")
+            .update_by_id("images", &item.id);
+
+        let res = transaction.execute(&query, &params).await;
+
+        if res.is_err() {
+            return AppResponse::Error(res.err().unwrap().to_string());
+        }
+    }
+
+    let committed = transaction.commit().await;
+
+    if committed.is_err() {
+        return AppResponse::Error(committed.err().unwrap().to_string());
+    }
+
+    let mut results = vec![];
+    let mut updated: Vec<Uuid> = vec![];
+
+    for (item, status) in items.iter().zip(statuses) {
+        if status == BulkUpdateStatus::Updated {
+            emit_asset_event(&state, &client, WebhookEvent::Updated, &item.id).await;
+            updated.push(item.id);
+        }
+
+        results.push(json!({ "id": item.id, "status": status }));
+    }
+
+    record_audit(
+        &client,
+        &AuditActor::user(&claims),
+        &claims.project_id,
+        AuditAction::Update,
+        &updated
+    ).await;
+
+    return AppResponse::SuccessData(
+        "Images".to_owned(),
+        crate::enums::SuccessActions::Update,
+        json!(results)
+    );
+}
+
+// Copies or moves assets of the caller's project to another project (or image type). Objects
+// are copied server-side first, the rows are written in one transaction afterwards and the
+// copies are removed again if it fails. Moved assets keep their id, their old objects are
+// queued for deletion with the row update.
+async fn transfer_assets(
+    state: &AppState,
+    claims: &Claims,
+    payload: TransferPayload,
+    mode: TransferMode
+) -> AppResponse {
+    if payload.ids.is_empty() {
+        return AppResponse::Error("NO ASSETS TO TRANSFER".to_owned());
+    }
+
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let mut client = client.unwrap();
+
+    let source_permission = match mode {
+        TransferMode::Copy => RequiredPermission::Read,
+        TransferMode::Move => RequiredPermission::Delete,
+    };
+
+    let permitted = check_asset_permissions(
+        state,
+        &client,
+        claims,
+        source_permission,
+        &payload.ids
+    ).await;
+
+    if permitted.is_err() {
+        return permitted.err().unwrap();
+    }
+
+    let target_permissions = get_project_permissions(
+        state,
+        &claims.user_id,
+        &payload.project_id,
+        RequiredPermission::Upload.name()
+    ).await;
+
+    if target_permissions.is_err() {
+        return target_permissions.err().unwrap();
+    }
+
+    let target_permissions = target_permissions.unwrap();
+
+    // Same rule as check_project_permission, the owner flag is needed for approvals below
+    if !target_permissions.is_project_owner && target_permissions.permission_id.is_none() {
+        return AppResponse::Auth;
+    }
+
+    let rows = client.query(
+        &format!(
+            "SELECT id, type, kind, mime_type, size_bytes, object_id AS shared_object, {} FROM images
+             WHERE id = ANY($1) AND project_id = $2 AND deleted_at IS NULL AND awaiting_upload = FALSE;",
+            OBJECT_ID
+        ),
+        &[&payload.ids, &claims.project_id]
+    ).await;
+
+    if rows.is_err() {
+        return AppResponse::Error(rows.err().unwrap().to_string());
+    }
+
+    let rows = rows.unwrap();
+
+    if rows.len() != payload.ids.len() {
+        return AppResponse::Error("SOME ASSETS DO NOT EXIST".to_owned());
+    }
+
+    if mode == TransferMode::Move {
+        let locked_ids = get_locked_ids(&client, &claims.project_id, Some(&payload.ids)).await;
+
+        if locked_ids.is_err() {
+            return locked_ids.err().unwrap();
+        }
+
+        let locked_ids = locked_ids.unwrap();
+
+        if !locked_ids.is_empty() {
+            return locked_conflict(locked_ids);
+        }
+    }
+
+    let changes_project = payload.project_id != claims.project_id;
+
+    // Moves within a project don't take up more storage
+    if mode == TransferMode::Copy || changes_project {
+        let size_bytes: i64 = rows
+            .iter()
+            .map(|row| row.get::<_, Option<i64>>("size_bytes").unwrap_or(0))
+            .sum();
+
+        let usage = get_project_usage(state, &payload.project_id).await;
+
+        if usage.is_err() {
+            return usage.err().unwrap();
+        }
+
+        let usage = usage.unwrap();
+
+        if usage.bytes_stored + size_bytes > usage.quota_bytes {
+            return AppResponse::Error(
+                format!("STORAGE QUOTA EXCEEDED FOR PROJECT {}", &payload.project_id)
+            );
+        }
+    }
+
+    let require_approval = client.query_opt(
+        "SELECT require_upload_approval FROM projects WHERE id = $1;",
+        &[&payload.project_id]
+    ).await;
+
+    if require_approval.is_err() {
+        return AppResponse::Error(require_approval.err().unwrap().to_string());
+    }
+
+    let require_approval: Option<bool> = require_approval
+        .unwrap()
+        .and_then(|row| row.get("require_upload_approval"));
+
+    let pending = require_approval.unwrap_or(false) && !target_permissions.is_project_owner;
+    let visibility = live_visibility(state, &payload.project_id, pending).await;
+
+    if visibility.is_err() {
+        return visibility.err().unwrap();
+    }
+
+    let visibility = visibility.unwrap();
+
+    // Copies between projects in different S3 regions aren't supported by copy_object, those
+    // fail here and nothing is transferred
+    let source_storage = resolve_target(state, &claims.project_id).await;
+
+    if source_storage.is_err() {
+        return source_storage.err().unwrap();
+    }
+
+    let source_storage = source_storage.unwrap();
+    let target_storage = resolve_target(state, &payload.project_id).await;
+
+    if target_storage.is_err() {
+        return target_storage.err().unwrap();
+    }
+
+    let target_storage = target_storage.unwrap();
+
+    let mut transferred: Vec<(Uuid, Uuid)> = vec![];
+    let mut copied_keys: Vec<String> = vec![];
+    let mut jobs: Vec<AssetJob> = vec![];
+
+    for row in rows.iter() {
+        let id: Uuid = row.get("id");
+        let image_type: ImageType = row.get("type");
+        let kind: AssetKind = row.get("kind");
+        let mime_type: String = row.get("mime_type");
+        let shared_object: Option<Uuid> = row.get("shared_object");
+        let object_id: Uuid = row.get("object_id");
+
+        let new_id = match mode {
+            TransferMode::Copy => Uuid::new_v4(),
+            TransferMode::Move => id,
+        };
+
+        let source_key = asset_key(&claims.project_id, &image_type, &kind, &object_id, &mime_type);
+        let target_key = asset_key(&payload.project_id, &payload.image_type, &kind, &new_id, &mime_type);
+
+        // Already where it's being moved to
+        if source_key == target_key {
+            continue;
+        }
+
+        // Rows deduplicated against a moved asset keep its content under a new owner
+        if mode == TransferMode::Move && shared_object.is_none() {
+            let handed_over = hand_over_object(
+                state,
+                &source_storage,
+                &client,
+                &claims.project_id,
+                &image_type,
+                &id,
+                &mime_type
+            ).await;
+
+            if handed_over.is_err() {
+                return handed_over.err().unwrap();
+            }
+        }
+
+        let copy = target_storage.copy_from(
+            &source_storage,
+            &source_key,
+            &target_key,
+            &CopyOptions { visibility, content_type: None, cache_control: None }
+        ).await;
+
+        if copy.is_err() {
+            remove_keys(&target_storage, &copied_keys).await;
+            return AppResponse::Error(copy.err().unwrap());
+        }
+
+        copied_keys.push(target_key);
+        transferred.push((new_id, id));
+
+        // A shared object stays with the rows still pointing at it
+        if mode == TransferMode::Move && shared_object.is_none() {
+            jobs.extend(
+                asset_deletion_jobs(&claims.project_id, &image_type, &kind, &id, &mime_type, false)
+            );
+        }
+    }
+
+    let new_ids: Vec<Uuid> = transferred
+        .iter()
+        .map(|(new_id, _)| *new_id)
+        .collect();
+    let source_ids: Vec<Uuid> = transferred
+        .iter()
+        .map(|(_, source_id)| *source_id)
+        .collect();
+
+    let transaction = client.transaction().await;
+
+    if transaction.is_err() {
+        remove_keys(&target_storage, &copied_keys).await;
+        return AppResponse::Error(transaction.err().unwrap().to_string());
+    }
+    let transaction = transaction.unwrap();
+
+    let res = match mode {
+        TransferMode::Copy => {
+            let res = transaction.execute(
+                "INSERT INTO images (id, title, description, project_id, type, owner_id, size_bytes, pending, kind,
+                    mime_type, width, height, original_format, is_animated, content_hash, grid_size, grid_distance, grid_units,
+                    dominant_color, thumbhash)
+                 SELECT copies.id, title, description, $3, $4, $5, size_bytes, $6, kind,
+                    mime_type, width, height, original_format, is_animated, content_hash, grid_size, grid_distance, grid_units,
+                    dominant_color, thumbhash
+                 FROM UNNEST($1::UUID[], $2::UUID[]) AS copies (id, source_id)
+                 JOIN images ON images.id = copies.source_id;",
+                &[
+                    &new_ids,
+                    &source_ids,
+                    &payload.project_id,
+                    &payload.image_type,
+                    &claims.user_id,
+                    &pending,
+                ]
+            ).await;
+
+            let res = match res {
+                Ok(_) =>
+                    transaction.execute(
+                        "INSERT INTO asset_tags (image_id, tag)
+                         SELECT copies.id, tag
+                         FROM UNNEST($1::UUID[], $2::UUID[]) AS copies (id, source_id)
+                         JOIN asset_tags ON asset_tags.image_id = copies.source_id;",
+                        &[&new_ids, &source_ids]
+                    ).await,
+                Err(err) => Err(err),
+            };
+
+            match res {
+                Ok(_) =>
+                    transaction.execute(
+                        "INSERT INTO map_settings (image_id, grid_size, grid_distance, grid_units)
+                         SELECT copies.id, grid_size, grid_distance, grid_units
+                         FROM UNNEST($1::UUID[], $2::UUID[]) AS copies (id, source_id)
+                         JOIN map_settings ON map_settings.image_id = copies.source_id;",
+                        &[&new_ids, &source_ids]
+                    ).await,
+                Err(err) => Err(err),
+            }
+        }
+        TransferMode::Move =>
+            transaction.execute(
+                "UPDATE images
+                 SET project_id = $2, type = $3, object_id = NULL,
+                    pending = CASE WHEN project_id = $2 THEN pending ELSE $4 END
+                 WHERE id = ANY($1);",
+                &[&new_ids, &payload.project_id, &payload.image_type, &pending]
+            ).await,
+    };
+
+    if res.is_err() {
+        remove_keys(&target_storage, &copied_keys).await;
+        return AppResponse::Error(res.err().unwrap().to_string());
+    }
+
+    let enqueued = enqueue_jobs(&transaction, &jobs).await;
+
+    if enqueued.is_err() {
+        remove_keys(&target_storage, &copied_keys).await;
+        return enqueued.err().unwrap();
+    }
+
+    // To receivers of the source project a moved asset is gone
+    if mode == TransferMode::Move && changes_project {
+        for row in rows.iter().filter(|row| source_ids.contains(&row.get("id"))) {
+            let id: Uuid = row.get("id");
+            let image_type: ImageType = row.get("type");
+
+            let event = enqueue_event(
+                &transaction,
+                &claims.project_id,
+                WebhookEvent::Deleted,
+                deleted_asset_data(&id, &image_type, true)
+            ).await;
+
+            if event.is_err() {
+                remove_keys(&target_storage, &copied_keys).await;
+                return event.err().unwrap();
+            }
+        }
+    }
+
+    let committed = transaction.commit().await;
+
+    if committed.is_err() {
+        remove_keys(&target_storage, &copied_keys).await;
+        return AppResponse::Error(committed.err().unwrap().to_string());
+    }
+
+    notify_job_worker(state);
+    notify_webhook_worker(state);
+
+    let event = match mode {
+        TransferMode::Move if !changes_project => WebhookEvent::Updated,
+        _ => WebhookEvent::Uploaded,
+    };
+
+    for id in new_ids.iter() {
+        emit_asset_event(state, &client, event, id).await;
+    }
+
+    let items: Vec<serde_json::Value> = transferred
+        .iter()
+        .map(|(id, source_id)| json!({ "id": id, "source_id": source_id }))
+        .collect();
+
+    return AppResponse::SuccessData(
+        "Images".to_owned(),
+        match mode {
+            TransferMode::Copy => crate::enums::SuccessActions::Create,
+            TransferMode::Move => crate::enums::SuccessActions::Update,
+        },
+        json!(items)
+    );
+}
+
+// Cleans up objects copied for a transfer that did not go through
+async fn remove_keys(target: &StorageTarget, keys: &Vec<String>) {
+    for key in keys {
+        let del_res = target.delete(key).await;
+
+        if del_res.is_err() {
+            tracing::error!("ERROR REMOVING {} - {}", key, del_res.err().unwrap());
+        }
+    }
+}
+
+async fn copy_assets(
+    State(state): State<AppState>,
+    AuthenticatedUser(claims): AuthenticatedUser,
+    Json(payload): Json<TransferPayload>
+) -> impl IntoResponse {
+    return transfer_assets(&state, &claims, payload, TransferMode::Copy).await;
+}
+
+async fn move_assets(
+    State(state): State<AppState>,
+    AuthenticatedUser(claims): AuthenticatedUser,
+    Json(payload): Json<TransferPayload>
+) -> impl IntoResponse {
+    return transfer_assets(&state, &claims, payload, TransferMode::Move).await;
+}
+
+async fn download_assets(
+    State(state): State<AppState>,
+    AuthenticatedUser(claims): AuthenticatedUser,
+    query: Query<DownloadQuery>,
+    ExtractPath((project_id, image_type)): ExtractPath<(Uuid, ImageType)>,
+    Json(payload): Json<DownloadPayload>
+) -> impl IntoResponse {
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    let ids: Vec<Uuid> = payload.data
+        .iter()
+        .map(|image| image.id)
+        .collect();
+
+    let permitted = check_asset_permissions(&state, &client, &claims, RequiredPermission::Read, &ids).await;
+
+    if permitted.is_err() {
+        return permitted.err().unwrap();
+    }
+
+    let objects = resolve_objects(&client, &ids).await;
+
+    if objects.is_err() {
+        return objects.err().unwrap();
+    }
+
+    let objects = objects.unwrap();
+
+    let titles = client.query("SELECT id, title FROM images WHERE id = ANY($1);", &[&ids]).await;
+
+    if titles.is_err() {
+        return AppResponse::Error(titles.err().unwrap().to_string());
+    }
+
+    let titles: HashMap<Uuid, Option<String>> = titles
+        .unwrap()
+        .iter()
+        .map(|row| (row.get("id"), row.get("title")))
+        .collect();
+
+    let target = resolve_target(&state, &project_id).await;
+
+    if target.is_err() {
+        return target.err().unwrap();
+    }
+
+    let target = target.unwrap();
+
+    let format = query.format.unwrap_or(OutputFormat::Webp);
+
+    let mut fetched: Vec<(usize, Uuid, Result<Bytes, AppResponse>)> = stream
+        ::iter(ids.clone().into_iter().enumerate())
+        .map(|(index, id)| {
+            let state = &state;
+            let target = &target;
+            let project_id = &project_id;
+            let image_type = &image_type;
+            let object = objects.get(&id).cloned().unwrap_or(StoredObject::unresolved(&id));
+
+            async move {
+                let data = get_rendition(
+                    state,
+                    target,
+                    project_id,
+                    image_type,
+                    &object.id,
+                    &object.mime_type,
+                    format
+                ).await;
+
+                (index, id, data)
+            }
+        })
+        .buffer_unordered(DOWNLOAD_CONCURRENCY)
+        .collect().await;
+
+    // Entries come back in the order they were requested in
+    fetched.sort_by_key(|(index, _, _)| *index);
+
+    let mut assets: Vec<serde_json::Value> = vec![];
+    let mut failed: Vec<serde_json::Value> = vec![];
+    let mut downloaded: Vec<Uuid> = vec![];
+    let mut total_bytes: i64 = 0;
+
+    for (_, id, data) in fetched {
+        if data.is_err() {
+            let err = data.err().unwrap();
+            tracing::error!("ERROR GETTING IMAGE DATA - {:?}", err);
+            failed.push(json!({ "id": id, "reason": "COULD NOT FETCH ASSET" }));
+            continue;
+        }
+
+        let data = data.unwrap();
+        total_bytes += data.len() as i64;
+        record_view(&state, id);
+        downloaded.push(id);
+
+        assets.push(
+            json!({
+                "id": id,
+                "title": titles.get(&id).cloned().flatten(),
+                "content_type": format.content_type(),
+                "data": BASE64_STANDARD.encode(data),
+            })
+        );
+    }
+
+    record_bandwidth(&state.pool, &project_id, total_bytes).await;
+    record_audit(
+        &client,
+        &AuditActor::user(&claims),
+        &project_id,
+        AuditAction::Download,
+        &downloaded
+    ).await;
+
+    return AppResponse::SuccessData(
+        "Assets".to_owned(),
+        crate::enums::SuccessActions::Download,
+        json!({ "assets": assets, "failed": failed })
+    );
+}
+
+// Streams a ZIP of the selected assets. Entries are written as each object is fetched,
+// so large exports never sit in memory as a whole.
+async fn export_assets(
+    State(state): State<AppState>,
+    AuthenticatedUser(claims): AuthenticatedUser,
+    ExtractPath((project_id, image_type)): ExtractPath<(Uuid, ImageType)>,
+    Json(payload): Json<ExportPayload>
+) -> Response {
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap().into_response();
+    }
+    let client = client.unwrap();
+
+    let rows = client.query(
+        &format!(
+            "SELECT id, title, kind, mime_type, {} FROM images
+             WHERE project_id = $1 AND type = $2 AND pending = FALSE AND deleted_at IS NULL
+                AND ($3::UUID[] IS NULL OR id = ANY($3))
+             ORDER BY title, id;",
+            OBJECT_ID
+        ),
+        &[&project_id, &image_type, &payload.ids]
+    ).await;
+
+    if rows.is_err() {
+        return AppResponse::Error(rows.err().unwrap().to_string()).into_response();
+    }
+
+    let rows = rows.unwrap();
+
+    if rows.is_empty() {
+        return (StatusCode::NOT_FOUND, "Not Found").into_response();
+    }
+
+    // Exporting everything needs access to everything
+    let ids: Vec<Uuid> = rows
+        .iter()
+        .map(|row| row.get("id"))
+        .collect();
+
+    let permitted = check_asset_permissions(&state, &client, &claims, RequiredPermission::Read, &ids).await;
+
+    if permitted.is_err() {
+        return permitted.err().unwrap().into_response();
+    }
+
+    let target = resolve_target(&state, &project_id).await;
+
+    if target.is_err() {
+        return target.err().unwrap().into_response();
+    }
+
+    let target = target.unwrap();
+
+    // Recorded once the export starts, the archive is only written while it's streamed
+    record_audit(
+        &client,
+        &AuditActor::user(&claims),
+        &project_id,
+        AuditAction::Download,
+        &ids
+    ).await;
+
+    let file_name = format!("arkive-{}-{}.zip", project_id, image_type);
+    let (writer, body) = body_channel();
+    let runtime = tokio::runtime::Handle::current();
+
+    tokio::task::spawn_blocking(move || {
+        let mut archive = ZipStream::new(BufWriter::new(writer));
+        let mut used_names: HashSet<String> = HashSet::new();
+        let mut total_bytes: i64 = 0;
+
+        for row in rows {
+            let id: Uuid = row.get("id");
+            let title: Option<String> = row.get("title");
+            let kind: AssetKind = row.get("kind");
+            let mime_type: String = row.get("mime_type");
+            let object_id: Uuid = row.get("object_id");
+
+            let key = asset_key(&project_id, &image_type, &kind, &object_id, &mime_type);
+            let data = runtime.block_on(get_object_bytes(&target, &key));
+
+            if data.is_err() {
+                tracing::error!("ERROR GETTING ASSET DATA FOR EXPORT - {:?}", data.err().unwrap());
+                continue;
+            }
+
+            let data = data.unwrap();
+            let name = archive_file_name(
+                &title.unwrap_or_else(|| id.to_string()),
+                extension_for_mime(&mime_type),
+                &mut used_names
+            );
+
+            let written = archive.add_file(&name, &data);
+
+            if written.is_err() {
+                // The client went away, nothing left to stream to
+                tracing::error!("EXPORT ABORTED - {}", written.err().unwrap());
+                return;
+            }
+
+            total_bytes += data.len() as i64;
+        }
+
+        let finished = archive.finish();
+
+        if finished.is_err() {
+            tracing::error!("EXPORT ABORTED - {}", finished.err().unwrap());
+            return;
+        }
+
+        runtime.block_on(record_bandwidth(&state.pool, &project_id, total_bytes));
+    });
+
+    return (
+        StatusCode::OK,
+        [
+            (CONTENT_TYPE, HeaderValue::from_str("application/zip").unwrap()),
+            (
+                CONTENT_DISPOSITION,
+                HeaderValue::from_str(&format!("attachment; filename=\"{}\"", file_name)).unwrap(),
+            ),
+        ],
+        body,
+    ).into_response();
+}
+
+async fn create_sprite_sheet(
+    AuthenticatedUser(claims): AuthenticatedUser,
+    State(state): State<AppState>,
+    ExtractPath((project_id, image_type)): ExtractPath<(Uuid, ImageType)>,
+    Json(payload): Json<SpriteSheetPayload>
+) -> impl IntoResponse {
+    if payload.ids.is_empty() {
+        return AppResponse::Error("NO IMAGES SELECTED FOR SPRITE SHEET".to_owned());
+    }
+
+    // Every original is held in memory until the sheet is packed
+    if payload.ids.len() > MAX_SPRITES {
+        return AppResponse::Error(format!("SPRITE SHEETS HAVE AT MOST {} IMAGES", MAX_SPRITES));
+    }
+
+    // The sheet is a new asset of the caller's, reading the sources isn't enough
+    let can_upload = check_project_permission(
+        &state,
+        &claims.user_id,
+        &project_id,
+        RequiredPermission::Upload
+    ).await;
+
+    if can_upload.is_err() {
+        return can_upload.err().unwrap();
+    }
+
+    if !can_upload.unwrap() {
+        return AppResponse::Auth;
+    }
+
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    let permitted = check_asset_permissions(&state, &client, &claims, RequiredPermission::Read, &payload.ids).await;
+
+    if permitted.is_err() {
+        return permitted.err().unwrap();
+    }
+
+    let rows = client.query(
+        &format!(
+            "SELECT id, title, mime_type, {} FROM images
+             WHERE id = ANY($1) AND project_id = $2 AND type = $3 AND deleted_at IS NULL;",
+            OBJECT_ID
+        ),
+        &[&payload.ids, &project_id, &image_type]
+    ).await;
+
+    if rows.is_err() {
+        return AppResponse::Error(rows.err().unwrap().to_string());
+    }
+
+    let target = resolve_target(&state, &project_id).await;
+
+    if target.is_err() {
+        return target.err().unwrap();
+    }
+
+    let target = target.unwrap();
+
+    let mut originals: Vec<(Uuid, String, Bytes)> = vec![];
+
+    for row in rows.unwrap() {
+        let id: Uuid = row.get("id");
+        let title: Option<String> = row.get("title");
+        let mime_type: String = row.get("mime_type");
+        let object_id: Uuid = row.get("object_id");
+
+        let data = target.get(&image_key(&project_id, &image_type, &object_id, &mime_type)).await;
+
+        if data.is_err() {
+            return AppResponse::Error(data.err().unwrap());
+        }
+
+        originals.push((id, title.unwrap_or_default(), data.unwrap()));
+    }
+
+    let tile_size = payload.tile_size.map(|tile_size| tile_size.clamp(1, MAX_TILE_SIZE));
+    let padding = payload.padding.unwrap_or(0).min(MAX_SPRITE_PADDING);
+    let max_pixels = state.config.max_image_pixels;
+
+    let packed = run_image_task(&state.encode_permits, "sprite", move || {
+        let mut sources: Vec<SpriteSource> = vec![];
+        let mut pixels: u64 = 0;
+
+        for (id, title, data) in originals {
+            let mut img = load_oriented(&data, max_pixels)?;
+
+            if let Some(tile_size) = tile_size {
+                img = img.thumbnail(tile_size, tile_size);
+            }
+
+            // Stops before decoding the rest once the sprites alone can't fit on a sheet
+            pixels += (img.width() as u64) * (img.height() as u64);
+
+            if pixels > MAX_SHEET_PIXELS {
+                return Err(format!("SPRITE SHEET EXCEEDS {} PIXELS", MAX_SHEET_PIXELS));
+            }
+
+            sources.push(SpriteSource { id, title, image: img });
+        }
+
+        pack_sprite_sheet(sources, padding)
+    }).await;
+
+    if packed.is_err() {
+        return AppResponse::Error(packed.err().unwrap());
+    }
+
+    let packed = packed.unwrap();
+
+    if packed.is_err() {
+        return AppResponse::Error(packed.err().unwrap());
+    }
+
+    let (sheet, frames) = packed.unwrap();
+    let (sheet_width, sheet_height) = sheet.dimensions();
+
+    let id = Uuid::new_v4();
+    let key = image_key(&project_id, &image_type, &id, "image/webp");
+    let manifest_key = sprite_manifest_key(
+        &project_id,
+        &image_type,
+        &AssetKind::Image,
+        &id,
+        "image/webp"
+    ).unwrap();
+
+    let manifest =
+        json!({
+        "frames": frames,
+        "meta": {
+            "image": key,
+            "size": { "w": sheet_width, "h": sheet_height },
+        },
+    });
+
+    let visibility = get_project_visibility(&state, &project_id).await;
+
+    if visibility.is_err() {
+        return visibility.err().unwrap();
+    }
+
+    let visibility = visibility.unwrap();
+
+    let usage = get_project_usage(&state, &project_id).await;
+
+    if usage.is_err() {
+        return usage.err().unwrap();
+    }
+
+    let usage = usage.unwrap();
+
+    // The manifest goes first, so only it has to be cleaned up when storing the sheet fails
+    let manifest_upload = target.put(&manifest_key, manifest.to_string().into_bytes(), &PutOptions {
+        content_type: "application/json",
+        cache_control: state.config.cache_control.for_image_type(&image_type),
+        visibility,
+    }).await;
+
+    if manifest_upload.is_err() {
+        return AppResponse::Error(manifest_upload.err().unwrap());
+    }
+
+    let new = NewAsset {
+        id,
+        key_id: None,
+        title: &payload.title,
+        project_id: &project_id,
+        image_type: &image_type,
+        owner_id: &claims.user_id,
+        pending: false,
+        visibility,
+        // The manifest is keyed by the sheet's own id
+        dedupe: false,
+        max_size_bytes: Some(usage.quota_bytes - usage.bytes_stored),
+    };
+
+    let body = AssetBody::Image {
+        img: DecodedImage::Still(DynamicImage::ImageRgba8(sheet)),
+        head: &[],
+        format: OutputFormat::Webp,
+        options: &EncodeOptions::default(),
+    };
+
+    let stored = store_asset(&state, &client, &target, &new, body, None).await;
+
+    if stored.is_err() {
+        let _ = target.delete(&manifest_key).await;
+
+        return stored.err().unwrap().into();
+    }
+
+    record_audit(
+        &client,
+        &AuditActor::user(&claims),
+        &project_id,
+        AuditAction::Upload,
+        &[id]
+    ).await;
+
+    return AppResponse::SuccessData(
+        "Sprite sheet".to_owned(),
+        crate::enums::SuccessActions::Create,
+        json!({ "id": id, "manifest": manifest })
+    );
+}
+
+// Groups of images that look alike, largest savings first. Each group suggests the copy with
+// the most pixels to keep. Direct uploads are stored without being decoded, so they only get a
+// perceptual hash (and show up here) once their file is replaced or transformed.
+async fn get_duplicates(
+    State(state): State<AppState>,
+    query: Query<DuplicatesQuery>,
+    ExtractPath(project_id): ExtractPath<Uuid>
+) -> impl IntoResponse {
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    let distance = query.distance
+        .unwrap_or(DEFAULT_DUPLICATE_DISTANCE)
+        .min(MAX_DUPLICATE_DISTANCE);
+
+    let rows = client.query(
+        &format!(
+            "SELECT id, title, type, size_bytes, width, height, perceptual_hash, {},
+                (EXTRACT(EPOCH FROM created_at) * 1000)::BIGINT AS created_at
+             FROM images
+             WHERE project_id = $1 AND kind = $2 AND perceptual_hash IS NOT NULL
+                AND pending = FALSE AND deleted_at IS NULL
+                AND ($3::\"ImageType\" IS NULL OR type = $3)
+             ORDER BY created_at, id;",
+            OBJECT_ID
+        ),
+        &[&project_id, &AssetKind::Image, &query.image_type]
+    ).await;
+
+    if rows.is_err() {
+        return AppResponse::Error(rows.err().unwrap().to_string());
+    }
+
+    let rows = rows.unwrap();
+
+    // Only images of the same type are grouped, a map can't stand in for a token
+    let hashes: Vec<(ImageType, i64)> = rows
+        .iter()
+        .map(|row| (row.get("type"), row.get("perceptual_hash")))
+        .collect();
+
+    let groups = tokio::task::spawn_blocking(move || {
+        [ImageType::Images, ImageType::MapImages]
+            .into_iter()
+            .flat_map(|image_type| {
+                let indices: Vec<usize> = (0..hashes.len())
+                    .filter(|index| hashes[*index].0 == image_type)
+                    .collect();
+                let type_hashes: Vec<i64> = indices
+                    .iter()
+                    .map(|index| hashes[*index].1)
+                    .collect();
+
+                group_similar(&type_hashes, distance)
+                    .into_iter()
+                    .map(|group| {
+                        group
+                            .into_iter()
+                            .map(|position| indices[position])
+                            .collect::<Vec<usize>>()
+                    })
+                    .collect::<Vec<Vec<usize>>>()
+            })
+            .collect::<Vec<Vec<usize>>>()
+    }).await;
+
+    if groups.is_err() {
+        return AppResponse::Error(groups.err().unwrap().to_string());
+    }
+
+    let mut groups: Vec<serde_json::Value> = groups
+        .unwrap()
+        .into_iter()
+        .map(|mut group| {
+            group.sort();
+
+            let pixels = |index: &usize| {
+                let width: Option<i32> = rows[*index].get("width");
+                let height: Option<i32> = rows[*index].get("height");
+
+                (width.unwrap_or(0) as i64) * (height.unwrap_or(0) as i64)
+            };
+
+            // Ties go to the oldest copy
+            let keep = *group
+                .iter()
+                .rev()
+                .max_by_key(|index| pixels(index))
+                .unwrap();
+            let keep_object: Uuid = rows[keep].get("object_id");
+
+            // Copies sharing an object with another copy take up no space of their own
+            let mut counted: HashSet<Uuid> = HashSet::from([keep_object]);
+            let mut wasted_bytes: i64 = 0;
+
+            for index in &group {
+                let object_id: Uuid = rows[*index].get("object_id");
+                let size_bytes: Option<i64> = rows[*index].get("size_bytes");
+
+                if counted.insert(object_id) {
+                    wasted_bytes += size_bytes.unwrap_or(0);
+                }
+            }
+
+            let items: Vec<serde_json::Value> = group
+                .iter()
+                .map(|index| {
+                    let row = &rows[*index];
+                    let id: Uuid = row.get("id");
+                    let title: Option<String> = row.get("title");
+                    let image_type: ImageType = row.get("type");
+                    let size_bytes: Option<i64> = row.get("size_bytes");
+                    let width: Option<i32> = row.get("width");
+                    let height: Option<i32> = row.get("height");
+                    let object_id: Uuid = row.get("object_id");
+                    let created_at: Option<i64> = row.get("created_at");
+
+                    json!({
+                        "id": id,
+                        "object_id": object_id,
+                        "title": title,
+                        "type": image_type.to_string(),
+                        "size_bytes": size_bytes,
+                        "width": width,
+                        "height": height,
+                        "created_at": created_at,
+                    })
+                })
+                .collect();
+
+            let keep_id: Uuid = rows[keep].get("id");
+
+            json!({ "keep": keep_id, "wasted_bytes": wasted_bytes, "items": items })
+        })
+        .collect();
+
+    groups.sort_by_key(|group| std::cmp::Reverse(group["wasted_bytes"].as_i64().unwrap_or(0)));
+
+    return AppResponse::SuccessData(
+        "Duplicates".to_owned(),
+        crate::enums::SuccessActions::Read,
+        json!({ "distance": distance, "groups": groups })
+    );
+}
+
+// Points every reference to the copies at the one that's kept, gives it their tags and deletes
+// the copies for good. All of it happens in one transaction.
+async fn merge_assets(
+    State(state): State<AppState>,
+    AuthenticatedUser(claims): AuthenticatedUser,
+    Json(payload): Json<MergePayload>
+) -> impl IntoResponse {
+    if payload.project_id != claims.project_id {
+        return AppResponse::Auth;
+    }
+
+    let mut ids: Vec<Uuid> = payload.ids
+        .into_iter()
+        .filter(|id| *id != payload.keep)
+        .collect();
+    ids.sort();
+    ids.dedup();
+
+    if ids.is_empty() {
+        return AppResponse::Error("NO ASSETS TO MERGE".to_owned());
+    }
+
+    if ids.len() > MAX_MERGED_ASSETS {
+        return AppResponse::Error(
+            format!("AT MOST {} ASSETS CAN BE MERGED AT ONCE", MAX_MERGED_ASSETS)
+        );
+    }
+
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let mut client = client.unwrap();
+
+    let permitted = check_asset_permissions(
+        &state,
+        &client,
+        &claims,
+        RequiredPermission::Delete,
+        &ids
+    ).await;
+
+    if permitted.is_err() {
+        return permitted.err().unwrap();
+    }
+
+    let locked_ids = get_locked_ids(&client, &payload.project_id, Some(&ids)).await;
+
+    if locked_ids.is_err() {
+        return locked_ids.err().unwrap();
+    }
+
+    let locked_ids = locked_ids.unwrap();
+
+    if !locked_ids.is_empty() {
+        return locked_conflict(locked_ids);
+    }
+
+    // The kept image and the copies have to be live images of the same type and project
+    let mergeable = client.query_one(
+        "SELECT COUNT(*) FILTER (WHERE id = ANY($3)) AS copies, COUNT(*) FILTER (WHERE id = $2) AS kept
+         FROM images
+         WHERE project_id = $1 AND kind = $4 AND pending = FALSE AND deleted_at IS NULL
+            AND type = (SELECT type FROM images WHERE id = $2)
+            AND (id = $2 OR id = ANY($3));",
+        &[&payload.project_id, &payload.keep, &ids, &AssetKind::Image]
+    ).await;
+
+    if mergeable.is_err() {
+        return AppResponse::Error(mergeable.err().unwrap().to_string());
+    }
+
+    let mergeable = mergeable.unwrap();
+    let copies: i64 = mergeable.get("copies");
+    let kept: i64 = mergeable.get("kept");
+
+    if kept != 1 || copies != (ids.len() as i64) {
+        return AppResponse::Error(format!("ASSETS CAN'T BE MERGED INTO {}", payload.keep));
+    }
+
+    let transaction = client.transaction().await;
+
+    if transaction.is_err() {
+        return AppResponse::Error(transaction.err().unwrap().to_string());
+    }
+    let transaction = transaction.unwrap();
+
+    let repointed = repoint_references(&transaction, &ids, &payload.keep).await;
+
+    if repointed.is_err() {
+        return repointed.err().unwrap();
+    }
+
+    let tagged = transaction.execute(
+        "INSERT INTO asset_tags (image_id, tag)
+         SELECT $1, tag FROM asset_tags WHERE image_id = ANY($2)
+         ON CONFLICT DO NOTHING;",
+        &[&payload.keep, &ids]
+    ).await;
+
+    if tagged.is_err() {
+        return AppResponse::Error(tagged.err().unwrap().to_string());
+    }
+
+    let res = transaction.query(
+        &format!(
+            "DELETE FROM images WHERE id = ANY($1) AND project_id = $2 RETURNING id, {};",
+            deleted_asset_columns()
+        ),
+        &[&ids, &payload.project_id]
+    ).await;
+
+    if res.is_err() {
+        return AppResponse::Error(res.err().unwrap().to_string());
+    }
+
+    let rows = res.unwrap();
+    let jobs = deletion_jobs(&transaction, &rows).await;
+
+    if jobs.is_err() {
+        return jobs.err().unwrap();
+    }
+
+    let enqueued = enqueue_jobs(&transaction, &jobs.unwrap()).await;
+
+    if enqueued.is_err() {
+        return enqueued.err().unwrap();
+    }
+
+    let events = enqueue_deleted_events(&transaction, &rows).await;
+
+    if events.is_err() {
+        return events.err().unwrap();
+    }
+
+    let committed = transaction.commit().await;
+
+    if committed.is_err() {
+        return AppResponse::Error(committed.err().unwrap().to_string());
+    }
+
+    notify_job_worker(&state);
+    notify_webhook_worker(&state);
+
+    record_audit(
+        &client,
+        &AuditActor::user(&claims),
+        &payload.project_id,
+        AuditAction::Delete,
+        &ids
+    ).await;
+
+    return AppResponse::SuccessData(
+        "Images".to_owned(),
+        crate::enums::SuccessActions::Delete,
+        json!({ "keep": payload.keep, "deleted": ids, "references": repointed.unwrap() })
+    );
+}
+
+// Newest entries first. Entries stay after their asset is deleted, that's what they're for.
+async fn get_audit_log(
+    State(state): State<AppState>,
+    AuthenticatedUser(claims): AuthenticatedUser,
+    query: Query<AuditQuery>,
+    ExtractPath(project_id): ExtractPath<Uuid>
+) -> impl IntoResponse {
+    let is_owner = check_project_owner(&state, &claims).await;
+
+    if is_owner.is_err() {
+        return is_owner.err().unwrap();
+    }
+
+    if !is_owner.unwrap() {
+        return AppResponse::Auth;
+    }
+
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    let limit = query.limit.unwrap_or(50).clamp(1, 200);
+
+    let rows = client.query(
+        "SELECT id, asset_id, action, source, user_id, api_key_id,
+            (EXTRACT(EPOCH FROM created_at) * 1000)::BIGINT AS created_at
+         FROM asset_audit_log
+         WHERE project_id = $1
+            AND ($2::UUID IS NULL OR (created_at, id) < (SELECT created_at, id FROM asset_audit_log WHERE id = $2))
+            AND ($3::UUID IS NULL OR user_id = $3)
+            AND ($4::UUID IS NULL OR asset_id = $4)
+            AND ($5::\"AuditAction\" IS NULL OR action = $5)
+            AND ($6::\"AuditSource\" IS NULL OR source = $6)
+            AND ($7::BIGINT IS NULL OR created_at >= TO_TIMESTAMP($7 / 1000.0))
+            AND ($8::BIGINT IS NULL OR created_at <= TO_TIMESTAMP($8 / 1000.0))
+         ORDER BY created_at DESC, id DESC
+         LIMIT $9;",
+        &[
+            &project_id,
+            &query.cursor,
+            &query.user_id,
+            &query.asset_id,
+            &query.action,
+            &query.source,
+            &query.from,
+            &query.to,
+            &limit,
+        ]
+    ).await;
+
+    if rows.is_err() {
+        return AppResponse::Error(rows.err().unwrap().to_string());
+    }
+
+    let rows = rows.unwrap();
+
+    let next_cursor: Option<Uuid> = match rows.len() as i64 == limit {
+        true => rows.last().map(|row| row.get("id")),
+        false => None,
+    };
+
+    let items: Vec<serde_json::Value> = rows
+        .iter()
+        .map(|row| {
+            let id: Uuid = row.get("id");
+            let asset_id: Uuid = row.get("asset_id");
+            let action: AuditAction = row.get("action");
+            let source: AuditSource = row.get("source");
+            let user_id: Option<Uuid> = row.get("user_id");
+            let api_key_id: Option<Uuid> = row.get("api_key_id");
+            let created_at: i64 = row.get("created_at");
+
+            json!({
+                "id": id,
+                "asset_id": asset_id,
+                "action": action,
+                "source": source,
+                "user_id": user_id,
+                "api_key_id": api_key_id,
+                "created_at": created_at,
+            })
+        })
+        .collect();
+
+    return AppResponse::SuccessData(
+        "Audit log".to_owned(),
+        crate::enums::SuccessActions::Read,
+        json!({ "items": items, "next_cursor": next_cursor })
+    );
+}
+
+async fn get_asset_stats(
+    _: AuthenticatedUser,
+    State(state): State<AppState>,
+    query: Query<StatsQuery>,
+    ExtractPath(project_id): ExtractPath<Uuid>
+) -> impl IntoResponse {
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    let days = query.days.unwrap_or(30).clamp(1, 365);
+    let limit = query.limit.unwrap_or(10).clamp(1, 100);
+
+    let totals = client.query_one(
+        "SELECT COUNT(*) AS count, COALESCE(SUM(size_bytes), 0)::BIGINT AS size_bytes
+         FROM images WHERE project_id = $1 AND deleted_at IS NULL;",
+        &[&project_id]
+    ).await;
+
+    if totals.is_err() {
+        return AppResponse::Error(totals.err().unwrap().to_string());
+    }
+
+    let totals = totals.unwrap();
+    let count: i64 = totals.get("count");
+    let size_bytes: i64 = totals.get("size_bytes");
+
+    let most_viewed = client.query(
+        "SELECT images.id, images.title, images.type, SUM(asset_views.views)::BIGINT AS views
+         FROM asset_views
+         JOIN images ON images.id = asset_views.image_id
+         WHERE images.project_id = $1 AND images.deleted_at IS NULL
+            AND asset_views.day > CURRENT_DATE - $2::INT
+         GROUP BY images.id
+         ORDER BY views DESC
+         LIMIT $3;",
+        &[&project_id, &days, &limit]
+    ).await;
+
+    if most_viewed.is_err() {
+        return AppResponse::Error(most_viewed.err().unwrap().to_string());
+    }
+
+    let most_viewed: Vec<serde_json::Value> = most_viewed
+        .unwrap()
+        .iter()
+        .map(|row| {
+            let id: Uuid = row.get("id");
+            let title: Option<String> = row.get("title");
+            let image_type: ImageType = row.get("type");
+            let views: i64 = row.get("views");
+
+            json!({ "id": id, "title": title, "type": image_type.to_string(), "views": views })
+        })
+        .collect();
+
+    return AppResponse::SuccessData(
+        "Stats".to_owned(),
+        crate::enums::SuccessActions::Read,
+        json!({
+            "count": count,
+            "size_bytes": size_bytes,
+            "days": days,
+            "most_viewed": most_viewed,
+        })
+    );
+}
+
+async fn lock_asset(
+    State(state): State<AppState>,
+    AuthenticatedUser(claims): AuthenticatedUser,
+    ExtractPath(id): ExtractPath<Uuid>,
+    Json(payload): Json<LockPayload>
+) -> impl IntoResponse {
+    let is_owner = check_project_owner(&state, &claims).await;
+
+    if is_owner.is_err() {
+        return is_owner.err().unwrap();
+    }
+
+    if !is_owner.unwrap() {
+        return AppResponse::Auth;
+    }
+
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    let res = client.execute(
+        "UPDATE images SET locked = $1 WHERE id = $2 AND project_id = $3 AND deleted_at IS NULL;",
+        &[&payload.locked, &id, &claims.project_id]
+    ).await;
+
+    if res.is_err() {
+        return AppResponse::Error(res.err().unwrap().to_string());
+    }
+
+    return AppResponse::Success("Image".to_owned(), crate::enums::SuccessActions::Update);
+}
+
+// Role grants and user grants on the asset, in the shape update_asset takes them. Only the
+// asset's owner and project owners can see who else has access.
+async fn get_asset_permissions(
+    State(state): State<AppState>,
+    AuthenticatedUser(claims): AuthenticatedUser,
+    ExtractPath(id): ExtractPath<Uuid>
+) -> impl IntoResponse {
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    let asset = client.query_opt(
+        "SELECT owner_id FROM images WHERE id = $1 AND project_id = $2;",
+        &[&id, &claims.project_id]
+    ).await;
+
+    if asset.is_err() {
+        return AppResponse::Error(asset.err().unwrap().to_string());
+    }
+
+    let asset = asset.unwrap();
+
+    if asset.is_none() {
+        return AppResponse::Error(format!("NO ASSET - {}", id));
+    }
+
+    let owner_id: Uuid = asset.unwrap().get("owner_id");
+
+    if owner_id != claims.user_id {
+        let is_owner = check_project_owner(&state, &claims).await;
+
+        if is_owner.is_err() {
+            return is_owner.err().unwrap();
+        }
+
+        if !is_owner.unwrap() {
+            return AppResponse::Auth;
+        }
+    }
+
+    let rows = client.query(
+        "SELECT id, role_id, user_id, permission_id FROM entity_permissions
+         WHERE related_id = $1
+         ORDER BY role_id NULLS LAST, user_id;",
+        &[&id]
+    ).await;
+
+    if rows.is_err() {
+        return AppResponse::Error(rows.err().unwrap().to_string());
+    }
+
+    let mut roles: Vec<serde_json::Value> = vec![];
+    let mut users: Vec<serde_json::Value> = vec![];
+
+    for row in rows.unwrap() {
+        let grant_id: Uuid = row.get("id");
+        let role_id: Option<Uuid> = row.get("role_id");
+        let user_id: Option<Uuid> = row.get("user_id");
+        let permission_id: Option<Uuid> = row.get("permission_id");
+
+        match role_id {
+            Some(role_id) =>
+                roles.push(json!({ "id": grant_id, "related_id": id, "role_id": role_id })),
+            None =>
+                users.push(
+                    json!({
+                        "id": grant_id,
+                        "related_id": id,
+                        "user_id": user_id,
+                        "permission_id": permission_id,
+                    })
+                ),
+        }
+    }
+
+    return AppResponse::SuccessData(
+        "Permissions".to_owned(),
+        crate::enums::SuccessActions::Read,
+        json!({ "roles": roles, "users": users })
+    );
+}
+
+async fn add_asset_tags(
+    State(state): State<AppState>,
+    AuthenticatedUser(claims): AuthenticatedUser,
+    ExtractPath(id): ExtractPath<Uuid>,
+    Json(payload): Json<TagsPayload>
+) -> impl IntoResponse {
+    let tags = normalize_tags(payload.tags.iter().map(|tag| tag.as_str()));
+
+    if tags.is_empty() {
+        return AppResponse::Error("NO VALID TAGS".to_owned());
+    }
+
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    let res = client.execute(
+        "INSERT INTO asset_tags (image_id, tag)
+         SELECT id, UNNEST($3::TEXT[]) FROM images WHERE id = $1 AND project_id = $2 AND deleted_at IS NULL
+         ON CONFLICT DO NOTHING;",
+        &[&id, &claims.project_id, &tags]
+    ).await;
+
+    if res.is_err() {
+        return AppResponse::Error(res.err().unwrap().to_string());
+    }
+
+    let tags = get_asset_tags(&client, &id).await;
+
+    if tags.is_err() {
+        return tags.err().unwrap();
+    }
+
+    return AppResponse::SuccessData(
+        "Tags".to_owned(),
+        crate::enums::SuccessActions::Update,
+        json!({ "id": id, "tags": tags.unwrap() })
+    );
+}
+
+async fn remove_asset_tag(
+    State(state): State<AppState>,
+    AuthenticatedUser(claims): AuthenticatedUser,
+    ExtractPath((id, tag)): ExtractPath<(Uuid, String)>
+) -> impl IntoResponse {
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    let res = client.execute(
+        "DELETE FROM asset_tags
+         WHERE image_id = $1 AND tag = $3 AND image_id IN (SELECT id FROM images WHERE project_id = $2);",
+        &[&id, &claims.project_id, &tag.trim().to_lowercase()]
+    ).await;
+
+    if res.is_err() {
+        return AppResponse::Error(res.err().unwrap().to_string());
+    }
+
+    let tags = get_asset_tags(&client, &id).await;
+
+    if tags.is_err() {
+        return tags.err().unwrap();
+    }
+
+    return AppResponse::SuccessData(
+        "Tags".to_owned(),
+        crate::enums::SuccessActions::Update,
+        json!({ "id": id, "tags": tags.unwrap() })
+    );
+}
+
+async fn favorite_asset(
+    State(state): State<AppState>,
+    AuthenticatedUser(claims): AuthenticatedUser,
+    ExtractPath(id): ExtractPath<Uuid>
+) -> impl IntoResponse {
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    let res = client.execute(
+        "INSERT INTO asset_favorites (user_id, image_id)
+         SELECT $1, id FROM images WHERE id = $2 AND project_id = $3 AND deleted_at IS NULL
+         ON CONFLICT DO NOTHING;",
+        &[&claims.user_id, &id, &claims.project_id]
+    ).await;
+
+    if res.is_err() {
+        return AppResponse::Error(res.err().unwrap().to_string());
+    }
+
+    return AppResponse::Success("Favorite".to_owned(), crate::enums::SuccessActions::Create);
+}
+
+async fn unfavorite_asset(
+    State(state): State<AppState>,
+    AuthenticatedUser(claims): AuthenticatedUser,
+    ExtractPath(id): ExtractPath<Uuid>
+) -> impl IntoResponse {
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    let res = client.execute(
+        "DELETE FROM asset_favorites WHERE user_id = $1 AND image_id = $2;",
+        &[&claims.user_id, &id]
+    ).await;
+
+    if res.is_err() {
+        return AppResponse::Error(res.err().unwrap().to_string());
+    }
+
+    return AppResponse::Success("Favorite".to_owned(), crate::enums::SuccessActions::Delete);
+}
+
+// Tiles for the editor's quick picks, only images get a thumbnail
+async fn quick_pick_items(
+    state: &AppState,
+    client: &Object,
+    project_id: &Uuid,
+    rows: &[Row]
+) -> Result<Vec<serde_json::Value>, AppResponse> {
+    let domain = get_custom_domain(client, project_id).await;
+    let target = resolve_target(state, project_id).await?;
+
+    Ok(
+        rows
+            .iter()
+            .map(|row| {
+                let id: Uuid = row.get("id");
+                let title: Option<String> = row.get("title");
+                let image_type: ImageType = row.get("type");
+                let kind: AssetKind = row.get("kind");
+                let mime_type: String = row.get("mime_type");
+                let dominant_color: Option<String> = row.get("dominant_color");
+                let object_id: Uuid = row.get("object_id");
+                let created_at: Option<i64> = row.get("created_at");
+
+                let thumbnail = match kind {
+                    AssetKind::Image =>
+                        Some(
+                            thumbnail_url(
+                                state,
+                                &target,
+                                domain.as_deref(),
+                                project_id,
+                                &image_type,
+                                &object_id,
+                                &mime_type,
+                                QUICK_PICK_THUMBNAIL_SIZE,
+                                QUICK_PICK_THUMBNAIL_SIZE
+                            )
+                        ),
+                    _ => None,
+                };
+
+                json!({
+                    "id": id,
+                    "title": title,
+                    "type": image_type.to_string(),
+                    "kind": kind,
+                    "mime_type": mime_type,
+                    "thumbnail_url": thumbnail,
+                    "dominant_color": dominant_color,
+                    "created_at": created_at,
+                })
+            })
+            .collect()
+    )
+}
+
+// The caller's favorites in the project of their token, most recently favorited first
+async fn get_favorite_assets(
+    State(state): State<AppState>,
+    AuthenticatedUser(claims): AuthenticatedUser
+) -> impl IntoResponse {
+    // Read right after (un)favoriting, a lagging replica would show the old list
+    let client = get_client(db_pool(&state, DbAccess::Write)).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    let rows = client.query(
+        &format!(
+            "SELECT id, title, type, kind, mime_type, dominant_color, {},
+                (EXTRACT(EPOCH FROM images.created_at) * 1000)::BIGINT AS created_at
+             FROM asset_favorites
+             JOIN images ON images.id = asset_favorites.image_id
+             WHERE asset_favorites.user_id = $1 AND project_id = $2 AND pending = FALSE
+                AND deleted_at IS NULL AND {}
+             ORDER BY asset_favorites.created_at DESC;",
+            OBJECT_ID,
+            MODERATION_VISIBLE
+        ),
+        &[&claims.user_id, &claims.project_id]
+    ).await;
+
+    if rows.is_err() {
+        return AppResponse::Error(rows.err().unwrap().to_string());
+    }
+
+    let items = quick_pick_items(&state, &client, &claims.project_id, &rows.unwrap()).await;
+
+    if items.is_err() {
+        return items.err().unwrap();
+    }
+
+    return AppResponse::SuccessData(
+        "Favorites".to_owned(),
+        crate::enums::SuccessActions::Read,
+        json!(items.unwrap())
+    );
+}
+
+async fn get_recent_assets(
+    State(state): State<AppState>,
+    query: Query<RecentQuery>,
+    ExtractPath(project_id): ExtractPath<Uuid>
+) -> impl IntoResponse {
+    // Opened right after an upload, which a lagging replica wouldn't list yet
+    let client = get_client(db_pool(&state, DbAccess::Write)).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    let limit = query.limit.unwrap_or(20).clamp(1, MAX_RECENT_ASSETS);
+
+    let rows = client.query(
+        &format!(
+            "SELECT id, title, type, kind, mime_type, dominant_color, {},
+                (EXTRACT(EPOCH FROM created_at) * 1000)::BIGINT AS created_at
+             FROM images
+             WHERE project_id = $1 AND ($2::\"ImageType\" IS NULL OR type = $2) AND pending = FALSE
+                AND deleted_at IS NULL AND {}
+             ORDER BY created_at DESC, id DESC
+             LIMIT $3;",
+            OBJECT_ID,
+            MODERATION_VISIBLE
+        ),
+        &[&project_id, &query.image_type, &limit]
+    ).await;
+
+    if rows.is_err() {
+        return AppResponse::Error(rows.err().unwrap().to_string());
+    }
+
+    let items = quick_pick_items(&state, &client, &project_id, &rows.unwrap()).await;
+
+    if items.is_err() {
+        return items.err().unwrap();
+    }
+
+    return AppResponse::SuccessData(
+        "Recent assets".to_owned(),
+        crate::enums::SuccessActions::Read,
+        json!(items.unwrap())
+    );
+}
+
+// Assets the user uploaded to the project of the caller's token, newest first. Owners can look
+// anyone up, everyone else only themselves. Uploads are attributed through the audit log, assets
+// from before it existed fall back to their owner.
+async fn get_uploaded_by(
+    State(state): State<AppState>,
+    AuthenticatedUser(claims): AuthenticatedUser,
+    query: Query<UploadedByQuery>,
+    ExtractPath(user_id): ExtractPath<Uuid>
+) -> impl IntoResponse {
+    if user_id != claims.user_id {
+        let is_owner = check_project_owner(&state, &claims).await;
+
+        if is_owner.is_err() {
+            return is_owner.err().unwrap();
+        }
+
+        if !is_owner.unwrap() {
+            return AppResponse::Auth;
+        }
+    }
+
+    let client = get_client(db_pool(&state, DbAccess::Read)).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    let limit = query.limit.unwrap_or(50).clamp(1, 200);
+
+    let rows = client.query(
+        "SELECT id, title, type, kind, mime_type, size_bytes, pending, dominant_color,
+            (EXTRACT(EPOCH FROM created_at) * 1000)::BIGINT AS created_at,
+            (EXTRACT(EPOCH FROM updated_at) * 1000)::BIGINT AS updated_at
+         FROM images
+         WHERE project_id = $1 AND deleted_at IS NULL AND awaiting_upload = FALSE
+            AND ($3::\"ImageType\" IS NULL OR type = $3)
+            AND ($4::UUID IS NULL OR (created_at, id) < (SELECT created_at, id FROM images WHERE id = $4))
+            AND (
+                EXISTS (
+                    SELECT 1 FROM asset_audit_log
+                    WHERE asset_id = images.id AND action = $5 AND user_id = $2
+                )
+                OR (
+                    owner_id = $2
+                    AND NOT EXISTS (
+                        SELECT 1 FROM asset_audit_log WHERE asset_id = images.id AND action = $5
+                    )
+                )
+            )
+         ORDER BY created_at DESC, id DESC
+         LIMIT $6;",
+        &[
+            &claims.project_id,
+            &user_id,
+            &query.image_type,
+            &query.cursor,
+            &AuditAction::Upload,
+            &limit,
+        ]
+    ).await;
+
+    if rows.is_err() {
+        return AppResponse::Error(rows.err().unwrap().to_string());
+    }
+
+    let rows = rows.unwrap();
+
+    let next_cursor: Option<Uuid> = match rows.len() as i64 == limit {
+        true => rows.last().map(|row| row.get("id")),
+        false => None,
+    };
+
+    let items: Vec<serde_json::Value> = rows
+        .iter()
+        .map(|row| {
+            let id: Uuid = row.get("id");
+            let title: Option<String> = row.get("title");
+            let image_type: ImageType = row.get("type");
+            let kind: AssetKind = row.get("kind");
+            let mime_type: String = row.get("mime_type");
+            let size_bytes: Option<i64> = row.get("size_bytes");
+            let pending: bool = row.get("pending");
+            let dominant_color: Option<String> = row.get("dominant_color");
+            let created_at: Option<i64> = row.get("created_at");
+            let updated_at: Option<i64> = row.get("updated_at");
+
+            json!({
+                "id": id,
+                "title": title,
+                "image_type": image_type.to_string(),
+                "kind": kind,
+                "mime_type": mime_type,
+                "size_bytes": size_bytes,
+                "pending": pending,
+                "dominant_color": dominant_color,
+                "created_at": created_at,
+                "updated_at": updated_at,
+            })
+        })
+        .collect();
+
+    return AppResponse::SuccessData(
+        "Uploaded assets".to_owned(),
+        crate::enums::SuccessActions::Read,
+        json!({ "items": items, "next_cursor": next_cursor })
+    );
+}
+
+async fn get_pending_assets(
+    State(state): State<AppState>,
+    AuthenticatedUser(claims): AuthenticatedUser,
+    ExtractPath(project_id): ExtractPath<Uuid>
+) -> impl IntoResponse {
+    let is_owner = check_project_owner(&state, &claims).await;
+
+    if is_owner.is_err() {
+        return is_owner.err().unwrap();
+    }
+
+    if !is_owner.unwrap() {
+        return AppResponse::Auth;
+    }
+
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    let rows = client.query(
+        "SELECT id, title, type, owner_id FROM images
+         WHERE project_id = $1 AND pending = TRUE AND awaiting_upload = FALSE AND deleted_at IS NULL
+         ORDER BY created_at;",
+        &[&project_id]
+    ).await;
+
+    if rows.is_err() {
+        return AppResponse::Error(rows.err().unwrap().to_string());
+    }
+
+    let items: Vec<serde_json::Value> = rows
+        .unwrap()
+        .iter()
+        .map(|row| {
+            let id: Uuid = row.get("id");
+            let title: Option<String> = row.get("title");
+            let image_type: ImageType = row.get("type");
+            let owner_id: Uuid = row.get("owner_id");
+
+            json!({ "id": id, "title": title, "type": image_type.to_string(), "owner_id": owner_id })
+        })
+        .collect();
+
+    return AppResponse::SuccessData(
+        "Pending images".to_owned(),
+        crate::enums::SuccessActions::Read,
+        json!(items)
+    );
+}
+
+async fn moderate_asset(
+    State(state): State<AppState>,
+    AuthenticatedUser(claims): AuthenticatedUser,
+    ExtractPath((decision, id)): ExtractPath<(ModerationDecision, Uuid)>
+) -> impl IntoResponse {
+    let is_owner = check_project_owner(&state, &claims).await;
+
+    if is_owner.is_err() {
+        return is_owner.err().unwrap();
+    }
+
+    if !is_owner.unwrap() {
+        return AppResponse::Auth;
+    }
+
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let mut client = client.unwrap();
+
+    let image = client.query_opt(
+        &format!(
+            "SELECT type, kind, mime_type, {}, NOT {} AS held_for_moderation FROM images
+             WHERE id = $1 AND project_id = $2 AND pending = TRUE AND awaiting_upload = FALSE
+                AND deleted_at IS NULL;",
+            OBJECT_ID,
+            MODERATION_VISIBLE
+        ),
+        &[&id, &claims.project_id]
+    ).await;
+
+    if image.is_err() {
+        return AppResponse::Error(image.err().unwrap().to_string());
+    }
+
+    let image = image.unwrap();
+
+    if image.is_none() {
+        return AppResponse::Error(format!("NO PENDING IMAGE - {}", id));
+    }
+
+    let image = image.unwrap();
+    let image_type: ImageType = image.get("type");
+    let kind: AssetKind = image.get("kind");
+    let mime_type: String = image.get("mime_type");
+    let object_id: Uuid = image.get("object_id");
+    let held_for_moderation: bool = image.get("held_for_moderation");
+    let key = asset_key(&claims.project_id, &image_type, &kind, &object_id, &mime_type);
+
+    match decision {
+        // Images still held by the moderation service are published once it approves them
+        ModerationDecision::Approve if held_for_moderation => {
+            let res = client.execute("UPDATE images SET pending = FALSE WHERE id = $1;", &[&id]).await;
+
+            if res.is_err() {
+                return AppResponse::Error(res.err().unwrap().to_string());
+            }
+
+            return AppResponse::Success("Image".to_owned(), crate::enums::SuccessActions::Update);
+        }
+        ModerationDecision::Approve => {
+            let visibility = get_project_visibility(&state, &claims.project_id).await;
+
+            if visibility.is_err() {
+                return visibility.err().unwrap();
+            }
+
+            let target = resolve_target(&state, &claims.project_id).await;
+
+            if target.is_err() {
+                return target.err().unwrap();
+            }
+
+            let target = target.unwrap();
+
+            let res = target.set_visibility(&key, visibility.unwrap()).await;
+
+            if res.is_err() {
+                return AppResponse::Error(res.err().unwrap());
+            }
+
+            let res = client.execute("UPDATE images SET pending = FALSE WHERE id = $1;", &[&id]).await;
+
+            if res.is_err() {
+                return AppResponse::Error(res.err().unwrap().to_string());
+            }
+
+            emit_asset_event(&state, &client, WebhookEvent::Updated, &id).await;
+
+            return AppResponse::Success("Image".to_owned(), crate::enums::SuccessActions::Update);
+        }
+        ModerationDecision::Reject => {
+            let transaction = client.transaction().await;
+
+            if transaction.is_err() {
+                return AppResponse::Error(transaction.err().unwrap().to_string());
+            }
+            let transaction = transaction.unwrap();
+
+            let res = transaction.query(
+                &format!("DELETE FROM images WHERE id = $1 RETURNING id, {};", deleted_asset_columns()),
+                &[&id]
+            ).await;
+
+            if res.is_err() {
+                return AppResponse::Error(res.err().unwrap().to_string());
+            }
+
+            let rows = res.unwrap();
+            let jobs = deletion_jobs(&transaction, &rows).await;
+
+            if jobs.is_err() {
+                return jobs.err().unwrap();
+            }
+
+            let enqueued = enqueue_jobs(&transaction, &jobs.unwrap()).await;
+
+            if enqueued.is_err() {
+                return enqueued.err().unwrap();
+            }
+
+            let events = enqueue_deleted_events(&transaction, &rows).await;
+
+            if events.is_err() {
+                return events.err().unwrap();
+            }
+
+            let committed = transaction.commit().await;
+
+            if committed.is_err() {
+                return AppResponse::Error(committed.err().unwrap().to_string());
+            }
+
+            notify_job_worker(&state);
+            notify_webhook_worker(&state);
+
+            return AppResponse::Success("Image".to_owned(), crate::enums::SuccessActions::Delete);
+        }
+    }
+}
+
+async fn list_assets(
+    State(state): State<AppState>,
+    query: Query<ListQuery>,
+    ExtractPath((project_id, image_type)): ExtractPath<(Uuid, ImageType)>
+) -> impl IntoResponse {
+    let client = get_client(db_pool(&state, DbAccess::Read)).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    let limit = query.limit.unwrap_or(50).clamp(1, 100);
+    let search = query.search.as_ref().map(|search| search.trim().to_lowercase());
+    let (sort_key, direction, comparison) = query.sort.unwrap_or_default().sql();
+    let tags = query.tags
+        .as_ref()
+        .map(|tags| normalize_tags(tags.split(',')))
+        .filter(|tags| !tags.is_empty());
+
+    let rows = client.query(
+        &format!(
+            "SELECT id, title, description, owner_id, kind, mime_type, size_bytes, locked,
+                width, height, dominant_color, original_format, is_animated, collection_id, {object_id},
+                ARRAY(SELECT tag FROM asset_tags WHERE image_id = images.id ORDER BY tag) AS tags,
+                (EXTRACT(EPOCH FROM created_at) * 1000)::BIGINT AS created_at
+             FROM images
+             WHERE project_id = $1 AND type = $2 AND pending = FALSE AND deleted_at IS NULL
+                AND {moderation}
+                AND ($3::TEXT IS NULL OR POSITION($3 IN LOWER(title)) > 0)
+                AND ($4::UUID IS NULL OR owner_id = $4)
+                AND ($5::UUID IS NULL OR ({key}, id) {cmp} (SELECT {key}, id FROM images WHERE id = $5))
+                AND ($7::TEXT[] IS NULL OR
+                    (SELECT COUNT(*) FROM asset_tags WHERE image_id = images.id AND tag = ANY($7)) = CARDINALITY($7))
+                AND ($8::UUID IS NULL OR collection_id = $8)
+             ORDER BY {key} {dir}, id {dir}
+             LIMIT $6;",
+            object_id = OBJECT_ID,
+            moderation = MODERATION_VISIBLE,
+            key = sort_key,
+            cmp = comparison,
+            dir = direction
+        ),
+        &[
+            &project_id,
+            &image_type,
+            &search,
+            &query.owner_id,
+            &query.cursor,
+            &limit,
+            &tags,
+            &query.collection_id,
+        ]
+    ).await;
+
+    if rows.is_err() {
+        return AppResponse::Error(rows.err().unwrap().to_string());
+    }
+
+    let rows = rows.unwrap();
+
+    let next_cursor: Option<Uuid> = match rows.len() as i64 == limit {
+        true => rows.last().map(|row| row.get("id")),
+        false => None,
+    };
+
+    let items: Vec<serde_json::Value> = rows
+        .iter()
+        .map(|row| {
+            let id: Uuid = row.get("id");
+            let title: Option<String> = row.get("title");
+            let description: Option<String> = row.get("description");
+            let owner_id: Uuid = row.get("owner_id");
+            let kind: AssetKind = row.get("kind");
+            let mime_type: String = row.get("mime_type");
+            let size_bytes: Option<i64> = row.get("size_bytes");
+            let locked: bool = row.get("locked");
+            let width: Option<i32> = row.get("width");
+            let height: Option<i32> = row.get("height");
+            let dominant_color: Option<String> = row.get("dominant_color");
+            let original_format: Option<String> = row.get("original_format");
+            let is_animated: bool = row.get("is_animated");
+            let collection_id: Option<Uuid> = row.get("collection_id");
+            let object_id: Uuid = row.get("object_id");
+            let tags: Vec<String> = row.get("tags");
+            let created_at: Option<i64> = row.get("created_at");
+
+            json!({
+                "id": id,
+                "object_id": object_id,
+                "title": title,
+                "description": description,
+                "owner_id": owner_id,
+                "kind": kind,
+                "mime_type": mime_type,
+                "size_bytes": size_bytes,
+                "width": width,
+                "height": height,
+                "dominant_color": dominant_color,
+                "original_format": original_format,
+                "is_animated": is_animated,
+                "locked": locked,
+                "collection_id": collection_id,
+                "tags": tags,
+                "created_at": created_at,
+            })
+        })
+        .collect();
+
+    return AppResponse::SuccessData(
+        "Images".to_owned(),
+        crate::enums::SuccessActions::Read,
+        json!({ "items": items, "next_cursor": next_cursor })
+    );
+}
+
+async fn get_asset_key(state: &AppState, id: &Uuid) -> Result<(StorageTarget, String), AppResponse> {
+    let client = get_client(&state.pool).await?;
+
+    let image = client.query_opt(
+        &format!("SELECT project_id, type, mime_type, {} FROM images WHERE id = $1;", OBJECT_ID),
+        &[&id]
+    ).await;
+
+    if image.is_err() {
+        return Err(AppResponse::Error(image.err().unwrap().to_string()));
+    }
+
+    let image = image.unwrap();
+
+    if image.is_none() {
+        return Err(AppResponse::Error(format!("IMAGE NOT FOUND - {}", id)));
+    }
+
+    let image = image.unwrap();
+    let project_id: Uuid = image.get("project_id");
+    let image_type: ImageType = image.get("type");
+    let mime_type: String = image.get("mime_type");
+    let object_id: Uuid = image.get("object_id");
+    let target = resolve_target(state, &project_id).await?;
+
+    Ok((target, image_key(&project_id, &image_type, &object_id, &mime_type)))
+}
+
+// Documents, maps and boards that show the asset, to check before deleting it
+async fn get_asset_usage(
+    State(state): State<AppState>,
+    ExtractPath(id): ExtractPath<Uuid>
+) -> impl IntoResponse {
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    let references = get_asset_references(&client, &vec![id]).await;
+
+    if references.is_err() {
+        return references.err().unwrap();
+    }
+
+    let references = references.unwrap();
+
+    return AppResponse::SuccessData(
+        "Usage".to_owned(),
+        crate::enums::SuccessActions::Read,
+        json!({
+            "id": id,
+            "in_use": !references.is_empty(),
+            "references": group_references(references),
+        })
+    );
+}
+
+async fn get_asset_versions(
+    State(state): State<AppState>,
+    ExtractPath(id): ExtractPath<Uuid>
+) -> impl IntoResponse {
+    let key = get_asset_key(&state, &id).await;
+
+    if key.is_err() {
+        return key.err().unwrap();
+    }
+
+    let (target, key) = key.unwrap();
+
+    let versions = target.list_versions(&key).await;
+
+    if versions.is_err() {
+        return AppResponse::Error(versions.err().unwrap());
+    }
+
+    let versions: Vec<serde_json::Value> = versions
+        .unwrap()
+        .iter()
+        .map(|version| {
+            json!({
+                "version_id": version.version_id,
+                "is_latest": version.is_latest,
+                "size_bytes": version.size,
+                "last_modified": version.last_modified,
+            })
+        })
+        .collect();
+
+    return AppResponse::SuccessData(
+        "Versions".to_owned(),
+        crate::enums::SuccessActions::Read,
+        json!(versions)
+    );
+}
+
+async fn diff_asset_versions(
+    State(state): State<AppState>,
+    query: Query<DiffQuery>,
+    ExtractPath(id): ExtractPath<Uuid>
+) -> Response {
+    let key = get_asset_key(&state, &id).await;
+
+    if key.is_err() {
+        return key.err().unwrap().into_response();
+    }
+
+    let (target, key) = key.unwrap();
+
+    let from = get_object_version_bytes(&target, &key, &query.from).await;
+
+    if from.is_err() {
+        return from.err().unwrap().into_response();
+    }
+
+    let to = match &query.to {
+        Some(version_id) => get_object_version_bytes(&target, &key, version_id).await,
+        None => get_object_bytes(&target, &key).await,
+    };
+
+    if to.is_err() {
+        return to.err().unwrap().into_response();
+    }
+
+    let from = from.unwrap();
+    let to = to.unwrap();
+    let mode = query.mode.unwrap_or_default();
+    let max_pixels = state.config.max_image_pixels;
+
+    let diff = run_image_task(&state.encode_permits, "diff", move || {
+        let from = load_oriented(&from, max_pixels)?;
+        let to = load_oriented(&to, max_pixels)?;
+
+        let composite = match mode {
+            DiffMode::SideBySide => side_by_side(&from, &to),
+            DiffMode::Heatmap => diff_heatmap(&from, &to),
+        };
+
+        let mut output = Cursor::new(Vec::new());
+
+        composite.write_to(&mut output, ImageFormat::Png).map_err(|err| err.to_string())?;
+
+        Ok::<Vec<u8>, String>(output.into_inner())
+    }).await;
+
+    if diff.is_err() {
+        return AppResponse::Error(diff.err().unwrap()).into_response();
+    }
+
+    let diff = diff.unwrap();
+
+    if diff.is_err() {
+        return AppResponse::Error(diff.err().unwrap()).into_response();
+    }
+
+    return (
+        StatusCode::OK,
+        [
+            (CONTENT_TYPE, HeaderValue::from_str("image/png").unwrap()),
+            (CACHE_CONTROL, HeaderValue::from_str("max-age=3600").unwrap()),
+        ],
+        diff.unwrap(),
+    ).into_response();
+}
+
+async fn update_encoding_settings(
+    State(state): State<AppState>,
+    AuthenticatedUser(claims): AuthenticatedUser,
+    ExtractPath(project_id): ExtractPath<Uuid>,
+    Json(payload): Json<EncodingPayload>
+) -> impl IntoResponse {
+    let is_owner = check_project_owner(&state, &claims).await;
+
+    if is_owner.is_err() {
+        return is_owner.err().unwrap();
+    }
+
+    if !is_owner.unwrap() {
+        return AppResponse::Auth;
+    }
+
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    let quality = payload.quality.map(|quality| quality.clamp(0.0, 100.0));
+
+    let res = client.execute(
+        "UPDATE projects SET webp_quality = $1, webp_lossless = $2 WHERE id = $3;",
+        &[&quality, &payload.lossless, &project_id]
+    ).await;
+
+    if res.is_err() {
+        return AppResponse::Error(res.err().unwrap().to_string());
+    }
+
+    return AppResponse::Success("Encoding settings".to_owned(), crate::enums::SuccessActions::Update);
+}
+
+// A new domain isn't served until verify_custom_domain finds the returned TXT record
+async fn update_custom_domain(
+    State(state): State<AppState>,
+    ExtractPath(project_id): ExtractPath<Uuid>,
+    Json(payload): Json<CustomDomainPayload>
+) -> impl IntoResponse {
+    let domain = payload.domain.map(|domain| domain.trim().to_lowercase());
+
+    if domain.as_ref().is_some_and(|domain| !is_valid_domain(domain)) {
+        return AppResponse::Error(format!("INVALID DOMAIN - {}", domain.unwrap()));
+    }
+
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    if domain.is_some() {
+        let taken = client.query_opt(
+            "SELECT 1 FROM projects WHERE custom_domain = $1 AND id <> $2;",
+            &[&domain, &project_id]
+        ).await;
+
+        if taken.is_err() {
+            return AppResponse::Error(taken.err().unwrap().to_string());
+        }
+
+        if taken.unwrap().is_some() {
+            return AppResponse::Conflict(
+                "The domain is used by another project.".to_owned(),
+                json!({ "domain": domain })
+            );
+        }
+    }
+
+    let token = domain.as_ref().map(|_| generate_verification_token());
+
+    let res = client.execute(
+        "UPDATE projects SET custom_domain = $1, custom_domain_token = $2,
+            custom_domain_verified_at = NULL
+         WHERE id = $3;",
+        &[&domain, &token, &project_id]
+    ).await;
+
+    // Backed by a unique index, for two projects claiming the same domain at once
+    if res.as_ref().is_err_and(|err| err.code() == Some(&SqlState::UNIQUE_VIOLATION)) {
+        return AppResponse::Conflict(
+            "The domain is used by another project.".to_owned(),
+            json!({ "domain": domain })
+        );
+    }
+
+    if res.is_err() {
+        return AppResponse::Error(res.err().unwrap().to_string());
+    }
+
+    return AppResponse::SuccessData(
+        "Custom domain".to_owned(),
+        crate::enums::SuccessActions::Update,
+        json!({
+            "domain": domain,
+            "verified": false,
+            "record": domain.as_ref().map(|domain| {
+                json!({
+                    "type": "TXT",
+                    "name": verification_record_name(domain),
+                    "value": token,
+                })
+            }),
+        })
+    );
+}
+
+async fn verify_custom_domain(
+    State(state): State<AppState>,
+    ExtractPath(project_id): ExtractPath<Uuid>
+) -> impl IntoResponse {
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    let row = client.query_opt(
+        "SELECT custom_domain, custom_domain_token FROM projects WHERE id = $1;",
+        &[&project_id]
+    ).await;
+
+    if row.is_err() {
+        return AppResponse::Error(row.err().unwrap().to_string());
+    }
+
+    let row = row.unwrap();
+    let domain: Option<String> = row.as_ref().and_then(|row| row.get("custom_domain"));
+    let token: Option<String> = row.as_ref().and_then(|row| row.get("custom_domain_token"));
+
+    if domain.is_none() || token.is_none() {
+        return AppResponse::Error("NO CUSTOM DOMAIN TO VERIFY".to_owned());
+    }
+
+    let (domain, token) = (domain.unwrap(), token.unwrap());
+    let found = has_verification_record(&state, &domain, &token).await;
+
+    if found.is_err() {
+        return AppResponse::Unavailable(found.err().unwrap());
+    }
+
+    if !found.unwrap() {
+        return AppResponse::Error(
+            format!("VERIFICATION RECORD NOT FOUND - {}", verification_record_name(&domain))
+        );
+    }
+
+    // Checked again, another project may have verified the domain since it was set here
+    let res = client.execute(
+        "UPDATE projects SET custom_domain_verified_at = NOW()
+         WHERE id = $1 AND custom_domain = $2 AND NOT EXISTS (
+            SELECT 1 FROM projects AS others
+            WHERE others.custom_domain = $2 AND others.id <> $1
+                AND others.custom_domain_verified_at IS NOT NULL
+         );",
+        &[&project_id, &domain]
+    ).await;
+
+    if res.is_err() {
+        return AppResponse::Error(res.err().unwrap().to_string());
+    }
+
+    if res.unwrap() == 0 {
+        return AppResponse::Conflict(
+            "The domain is used by another project.".to_owned(),
+            json!({ "domain": domain })
+        );
+    }
+
+    return AppResponse::SuccessData(
+        "Custom domain".to_owned(),
+        crate::enums::SuccessActions::Update,
+        json!({ "domain": domain, "verified": true })
+    );
+}
+
+async fn get_enabled_features(
+    State(state): State<AppState>,
+    AuthenticatedUser(claims): AuthenticatedUser,
+    ExtractPath(project_id): ExtractPath<Uuid>
+) -> impl IntoResponse {
+    let features: Vec<Feature> = Feature::ALL.into_iter()
+        .filter(|feature| state.config.feature_flags.is_enabled(*feature, &project_id, Some(&claims.user_id)))
+        .collect();
+
+    return AppResponse::SuccessData(
+        "Features".to_owned(),
+        crate::enums::SuccessActions::Read,
+        json!(features)
+    );
+}
+
+async fn get_asset_manifest(
+    State(state): State<AppState>,
+    ExtractPath(project_id): ExtractPath<Uuid>,
+    headers: HeaderMap
+) -> Response {
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap().into_response();
+    }
+    let client = client.unwrap();
+
+    let version = client.query_one(
+        &format!(
+            "SELECT COUNT(*) AS count, COALESCE((EXTRACT(EPOCH FROM MAX(updated_at)) * 1000)::BIGINT, 0) AS last_updated
+             FROM images WHERE project_id = $1 AND pending = FALSE AND deleted_at IS NULL AND {};",
+            MODERATION_VISIBLE
+        ),
+        &[&project_id]
+    ).await;
+
+    if version.is_err() {
+        return AppResponse::Error(version.err().unwrap().to_string()).into_response();
+    }
+
+    let version = version.unwrap();
+    let count: i64 = version.get("count");
+    let last_updated: i64 = version.get("last_updated");
+
+    // The count is part of the tag so deletions invalidate it as well
+    let etag = format!("\"{}-{}-{}\"", project_id, last_updated, count);
+
+    let if_none_match = headers.get(IF_NONE_MATCH).and_then(|value| value.to_str().ok());
+
+    if if_none_match == Some(etag.as_str()) {
+        return (StatusCode::NOT_MODIFIED, [(ETAG, etag)]).into_response();
+    }
+
+    let rows = client.query(
+        &format!(
+            "SELECT id, title, thumbhash, (EXTRACT(EPOCH FROM updated_at) * 1000)::BIGINT AS version
+             FROM images WHERE project_id = $1 AND pending = FALSE AND deleted_at IS NULL AND {};",
+            MODERATION_VISIBLE
+        ),
+        &[&project_id]
+    ).await;
+
+    if rows.is_err() {
+        return AppResponse::Error(rows.err().unwrap().to_string()).into_response();
+    }
+
+    let mut manifest = serde_json::Map::new();
+
+    for row in rows.unwrap() {
+        let id: Uuid = row.get("id");
+        let title: Option<String> = row.get("title");
+        let thumbhash: Option<String> = row.get("thumbhash");
+        let version: Option<i64> = row.get("version");
+
+        manifest.insert(
+            id.to_string(),
+            json!({ "title": title, "version": version, "thumbhash": thumbhash })
+        );
+    }
+
+    return (
+        [(ETAG, etag), (CACHE_CONTROL, "private, no-cache".to_owned())],
+        AppResponse::SuccessData(
+            "Manifest".to_owned(),
+            crate::enums::SuccessActions::Read,
+            serde_json::Value::Object(manifest)
+        ),
+    ).into_response();
+}
+
+// Checks a route's permission (see permission_routes) on the asset in its `id` path parameter,
+// so neither depends on how the URL is spelled. Runs inside the tenant middleware, which
+// provides the claims.
+async fn permission_middleware(
+    State((state, permission)): State<(AppState, RequiredPermission)>,
+    AuthenticatedUser(claims): AuthenticatedUser,
+    matched_path: MatchedPath,
+    params: RawPathParams,
+    request: Request,
+    next: Next
+) -> Response {
+    let id = path_uuid(&Some(params), "id");
+
+    // A route without an id can't be checked here, refuse it instead of letting it through
+    if id.is_none() {
+        tracing::error!("PERMISSION CHECKED ROUTE HAS NO ID - {}", matched_path.as_str());
+
+        return AppResponse::Error(
+            format!("NO ASSET ID IN ROUTE - {}", matched_path.as_str())
+        ).into_response();
+    }
+
+    let id = id.unwrap();
+
+    if id.is_err() {
+        return id.err().unwrap().into_response();
+    }
+
+    let id = id.unwrap();
+
+    let permissions = get_project_permissions(
+        &state,
+        &claims.user_id,
+        &claims.project_id,
+        permission.name()
+    ).await;
+
+    if permissions.is_err() {
+        return permissions.err().unwrap().into_response();
+    }
+
+    let permissions = permissions.unwrap();
+
+    if permissions.is_project_owner {
+        return next.run(request).await;
+    }
+
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap().into_response();
+    }
+    let client = client.unwrap();
+
+    let permission_check = client.query_opt(
+        "SELECT TRUE AS has_permission
+         FROM images
+         LEFT JOIN entity_permissions ON entity_permissions.related_id = images.id
+         WHERE images.id = $1
+            AND
+                (images.owner_id = $2
+            OR
+                entity_permissions.role_id = $3
+            OR
+                (entity_permissions.user_id = $2 AND entity_permissions.permission_id = $4 AND entity_permissions.related_id = images.id)
+            )
+         LIMIT 1;",
+        &[&id, &claims.user_id, &permissions.role_id, &permissions.permission_id]
+    ).await;
+
+    if permission_check.is_err() {
+        return AppResponse::Error(permission_check.err().unwrap().to_string()).into_response();
+    }
+
+    if permission_check.unwrap().is_none() {
+        return AppResponse::Auth.into_response();
+    }
+
+    return next.run(request).await;
+}
+
+async fn delete_folder(
+    State(state): State<AppState>,
+    AuthenticatedUser(claims): AuthenticatedUser,
+    ExtractPath(project_id): ExtractPath<Uuid>
+) -> impl IntoResponse {
+    // Removes every asset of the project, whoever they belong to
+    let is_owner = check_project_owner(&state, &claims).await;
+
+    if is_owner.is_err() {
+        return is_owner.err().unwrap();
+    }
+
+    if !is_owner.unwrap() {
+        return AppResponse::Auth;
+    }
+
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    let locked_ids = get_locked_ids(&client, &project_id, None).await;
+
+    if locked_ids.is_err() {
+        return locked_ids.err().unwrap();
+    }
+
+    let locked_ids = locked_ids.unwrap();
+
+    if !locked_ids.is_empty() {
+        return locked_conflict(locked_ids);
+    }
+
+    let location = format!("assets/{}", project_id);
+
+    let target = resolve_target(&state, &project_id).await;
+
+    if target.is_err() {
+        return target.err().unwrap();
+    }
+
+    let res = target.unwrap().delete_prefix(&location).await;
+
+    if res.is_err() {
+        return AppResponse::Error(res.err().unwrap());
+    }
+
+    let img_delete_res = client.query(
+        "DELETE FROM images WHERE project_id = $1 RETURNING id;",
+        &[&project_id]
+    ).await;
+
+    if img_delete_res.is_err() {
+        return AppResponse::Error(img_delete_res.err().unwrap().to_string());
+    }
+
+    let deleted: Vec<Uuid> = img_delete_res
+        .unwrap()
+        .iter()
+        .map(|row| row.get("id"))
+        .collect();
+
+    record_audit(
+        &client,
+        &AuditActor::user(&claims),
+        &project_id,
+        AuditAction::Delete,
+        &deleted
+    ).await;
+
+    let enqueued = enqueue_jobs(
+        &client,
+        &[
+            AssetJob {
+                operation: AssetJobOperation::DeletePrefix,
+                target: trash_key(&location),
+            },
+        ]
+    ).await;
+
+    if enqueued.is_err() {
+        return enqueued.err().unwrap();
+    }
+
+    notify_job_worker(&state);
+
+    AppResponse::Success("Images".to_owned(), crate::enums::SuccessActions::Delete)
+}
+
+// Removes every asset of one image type, e.g. the maps of a campaign being reset. The type's
+// folder isn't dropped as a whole, objects deduplicated with assets of the other type stay.
+async fn delete_type_folder(
+    State(state): State<AppState>,
+    AuthenticatedUser(claims): AuthenticatedUser,
+    ExtractPath((project_id, image_type)): ExtractPath<(Uuid, ImageType)>
+) -> impl IntoResponse {
+    let is_owner = check_project_owner(&state, &claims).await;
+
+    if is_owner.is_err() {
+        return is_owner.err().unwrap();
+    }
+
+    if !is_owner.unwrap() {
+        return AppResponse::Auth;
+    }
+
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let mut client = client.unwrap();
+
+    let locked = client.query(
+        "SELECT id FROM images WHERE project_id = $1 AND type = $2 AND locked = TRUE;",
+        &[&project_id, &image_type]
+    ).await;
+
+    if locked.is_err() {
+        return AppResponse::Error(locked.err().unwrap().to_string());
+    }
+
+    let locked_ids: Vec<Uuid> = locked
+        .unwrap()
+        .iter()
+        .map(|row| row.get("id"))
+        .collect();
+
+    if !locked_ids.is_empty() {
+        return locked_conflict(locked_ids);
+    }
+
+    let transaction = client.transaction().await;
+
+    if transaction.is_err() {
+        return AppResponse::Error(transaction.err().unwrap().to_string());
+    }
+    let transaction = transaction.unwrap();
+
+    let rows = transaction.query(
+        &format!(
+            "DELETE FROM images WHERE project_id = $1 AND type = $2 RETURNING id, {};",
+            deleted_asset_columns()
+        ),
+        &[&project_id, &image_type]
+    ).await;
+
+    if rows.is_err() {
+        return AppResponse::Error(rows.err().unwrap().to_string());
+    }
+
+    let rows = rows.unwrap();
+    let jobs = deletion_jobs(&transaction, &rows).await;
+
+    if jobs.is_err() {
+        return jobs.err().unwrap();
+    }
+
+    let enqueued = enqueue_jobs(&transaction, &jobs.unwrap()).await;
+
+    if enqueued.is_err() {
+        return enqueued.err().unwrap();
+    }
+
+    let events = enqueue_deleted_events(&transaction, &rows).await;
+
+    if events.is_err() {
+        return events.err().unwrap();
+    }
+
+    let committed = transaction.commit().await;
+
+    if committed.is_err() {
+        return AppResponse::Error(committed.err().unwrap().to_string());
+    }
+
+    notify_job_worker(&state);
+    notify_webhook_worker(&state);
+
+    let deleted: Vec<Uuid> = rows
+        .iter()
+        .map(|row| row.get("id"))
+        .collect();
+
+    record_audit(
+        &client,
+        &AuditActor::user(&claims),
+        &project_id,
+        AuditAction::Delete,
+        &deleted
+    ).await;
+
+    return AppResponse::SuccessData(
+        "Images".to_owned(),
+        crate::enums::SuccessActions::Delete,
+        json!({ "deleted": deleted })
+    );
+}
+
+// Lets permission_middleware use the extractors that need the app state
+impl FromRef<(AppState, RequiredPermission)> for AppState {
+    fn from_ref((state, _): &(AppState, RequiredPermission)) -> Self {
+        state.clone()
+    }
+}
+
+// Routes checked against the asset in their :id. The permission is part of the route's
+// definition, so none can be added to the group without one.
+fn permission_routes(
+    state: &AppState,
+    routes: Vec<(&'static str, MethodRouter<AppState>, RequiredPermission)>
+) -> Router<AppState> {
+    routes.into_iter().fold(Router::new(), |router, (path, method_router, permission)| {
+        router.route(
+            path,
+            method_router.route_layer(
+                from_fn_with_state((state.clone(), permission), permission_middleware)
+            )
+        )
+    })
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    paths(update_asset, delete_asset, bulk_delete_assets, bulk_update_assets),
+    components(schemas(BulkUpdateStatus))
+)]
+pub struct CrudApi;
 
 pub fn crud_routes(state: AppState) -> Router<AppState> {
     Router::new().nest(
         "/assets",
         Router::new()
             .merge(
-                Router::new()
-                    // routes must end with :id for middleware use
-                    .route("/update/:id", post(update_asset))
-                    .route("/:project_id/:image_type/:id", delete(delete_asset))
-                    .layer(from_fn_with_state(state, permission_middleware))
+                permission_routes(
+                    &state,
+                    vec![
+                        ("/update/:id", post(update_asset), RequiredPermission::Update),
+                        ("/:id/transform", post(transform_asset), RequiredPermission::Update),
+                        ("/:id/usage", get(get_asset_usage), RequiredPermission::Read),
+                        ("/versions/:id", get(get_asset_versions), RequiredPermission::Read),
+                        ("/diff/:id", get(diff_asset_versions), RequiredPermission::Read),
+                        // Undoes a delete, so it takes the same permission
+                        ("/restore/:id", post(restore_asset), RequiredPermission::Delete),
+                        ("/:id/tags", post(add_asset_tags), RequiredPermission::Update),
+                        ("/:id/tags/:tag", delete(remove_asset_tag), RequiredPermission::Update),
+                        (
+                            "/:id/favorite",
+                            post(favorite_asset).delete(unfavorite_asset),
+                            RequiredPermission::Read,
+                        ),
+                        (
+                            "/:project_id/:image_type/:id",
+                            delete(delete_asset),
+                            RequiredPermission::Delete,
+                        )
+                    ]
+                )
+                    .layer(from_fn_with_state(state.clone(), tenant_middleware))
                     .layer(DefaultBodyLimit::max(MAX_FILE_SIZE))
             )
+            .merge(
+                Router::new()
+                    .route("/domain/:project_id", post(update_custom_domain))
+                    .route("/domain/:project_id/verify", post(verify_custom_domain))
+                    // Every asset of the project is served under its domain, so only owners
+                    // can point one at it
+                    .layer(from_fn_with_state(state.clone(), owner_middleware))
+                    .layer(from_fn_with_state(state.clone(), tenant_middleware))
+            )
+            .merge(
+                Router::new()
+                    // Writes a new object like an upload does
+                    .route("/spritesheet/:project_id/:image_type", post(create_sprite_sheet))
+                    .layer(from_fn_with_state(state.clone(), maintenance_middleware))
+                    .layer(from_fn_with_state(state.clone(), tenant_middleware))
+            )
             .merge(
                 Router::new()
                     .route("/folder/:project_id", delete(delete_folder))
+                    .route("/folder/:project_id/:image_type", delete(delete_type_folder))
                     .route("/download/:project_id/:image_type", post(download_assets))
+                    .route("/export/:project_id/:image_type", post(export_assets))
                     // Need the "delete" despite the method because other entities
                     // can be arkived. This is to keep a consistent URL with other
                     // entities on the UI side.
                     .route("/bulk/delete/:image_type", delete(bulk_delete_assets))
+                    .route("/bulk/update", post(bulk_update_assets))
+                    .route("/copy", post(copy_assets))
+                    .route("/move", post(move_assets))
+                    .route("/stats/:project_id", get(get_asset_stats))
+                    .route("/audit/:project_id", get(get_audit_log))
+                    .route("/duplicates/:project_id", get(get_duplicates))
+                    .route("/merge", post(merge_assets))
+                    .route("/manifest/:project_id", get(get_asset_manifest))
+                    .route("/features/:project_id", get(get_enabled_features))
+                    .route("/encoding/:project_id", post(update_encoding_settings))
+                    .route("/lock/:id", post(lock_asset))
+                    .route("/trash/:project_id", get(get_trashed_assets))
+                    .route("/pending/:project_id", get(get_pending_assets))
+                    .route("/favorites", get(get_favorite_assets))
+                    .route("/recent/:project_id", get(get_recent_assets))
+                    .route("/uploaded-by/:user_id", get(get_uploaded_by))
+                    .route("/moderate/:decision/:id", post(moderate_asset))
+                    .route("/:id/permissions", get(get_asset_permissions))
+                    .route("/:project_id/:image_type", get(list_assets))
+                    .layer(from_fn_with_state(state, tenant_middleware))
             )
     )
 }
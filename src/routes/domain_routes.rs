@@ -0,0 +1,220 @@
+use std::str::FromStr;
+
+use axum::{
+    extract::{ Query, State },
+    http::{ HeaderMap, HeaderValue },
+    response::{ IntoResponse, Response },
+    routing::get,
+    Router,
+};
+use reqwest::{ header::{ CACHE_CONTROL, CONTENT_TYPE, HOST }, StatusCode };
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::{
+    enums::{ AppResponse, AssetVisibility, ImageType },
+    jobs::{
+        acl_job::get_asset_visibility,
+        moderation_job::MODERATION_VISIBLE,
+        view_count_job::record_view,
+    },
+    state::models::AppState,
+    storage::resolve_target,
+    utils::{
+        db_utils::{ get_client, record_bandwidth },
+        dedup_utils::{ StoredObject, OBJECT_ID },
+        domain_utils::get_project_for_domain,
+        extractors::ExtractPath,
+        s3_utils::get_object_bytes,
+        thumbnail_utils::{ get_or_create_thumbnail, sign_thumbnail_url },
+    },
+};
+
+#[derive(Deserialize)]
+struct ServeDimensions {
+    width: Option<usize>,
+    height: Option<usize>,
+}
+
+fn request_domain(headers: &HeaderMap) -> Option<String> {
+    let host = headers.get(HOST)?.to_str().ok()?;
+    let domain = host.split(':').next()?;
+
+    Some(domain.to_lowercase())
+}
+
+// Serves assets for projects that CNAME their own domain to this service. The project
+// is resolved from the Host header, so only that project's assets are reachable.
+async fn serve_asset(
+    State(state): State<AppState>,
+    query: Query<ServeDimensions>,
+    ExtractPath((image_type, file)): ExtractPath<(ImageType, String)>,
+    headers: HeaderMap
+) -> Response {
+    let domain = request_domain(&headers);
+    // :id.webp or :id.avif, the stored format decides what's served
+    let id = Uuid::from_str(file.split('.').next().unwrap_or_default());
+
+    if domain.is_none() || id.is_err() {
+        return (StatusCode::NOT_FOUND, "Not Found").into_response();
+    }
+
+    let id = id.unwrap();
+
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap().into_response();
+    }
+    let client = client.unwrap();
+
+    let project_id = get_project_for_domain(&client, &domain.unwrap()).await;
+
+    if project_id.is_err() {
+        return project_id.err().unwrap().into_response();
+    }
+
+    let project_id = project_id.unwrap();
+
+    if project_id.is_none() {
+        return (StatusCode::NOT_FOUND, "Not Found").into_response();
+    }
+
+    let project_id = project_id.unwrap();
+
+    let target = resolve_target(&state, &project_id).await;
+
+    if target.is_err() {
+        return target.err().unwrap().into_response();
+    }
+
+    let target = target.unwrap();
+
+    // Public URLs of deduplicated assets carry the id of the stored object, which may no
+    // longer have a row of its own
+    let image = client.query_opt(
+        &format!(
+            "SELECT id, mime_type, {} FROM images
+             WHERE (id = $1 OR object_id = $1) AND project_id = $2 AND type = $3
+                AND pending = FALSE AND deleted_at IS NULL AND {}
+             LIMIT 1;",
+            OBJECT_ID,
+            MODERATION_VISIBLE
+        ),
+        &[&id, &project_id, &image_type]
+    ).await;
+
+    if image.is_err() {
+        return AppResponse::Error(image.err().unwrap().to_string()).into_response();
+    }
+
+    let image = image.unwrap();
+
+    if image.is_none() {
+        return (StatusCode::NOT_FOUND, "Not Found").into_response();
+    }
+
+    let image = image.unwrap();
+    let object = StoredObject { id: image.get("object_id"), mime_type: image.get("mime_type") };
+    let visibility = get_asset_visibility(&client, &project_id, &image.get("id")).await;
+
+    if visibility.is_err() {
+        return visibility.err().unwrap().into_response();
+    }
+
+    let visibility = visibility.unwrap();
+
+    let (content_type, data) = if query.width.is_some() && query.height.is_some() {
+        let (width, height) = (query.width.unwrap(), query.height.unwrap());
+
+        // The thumbnail service can't read dedicated buckets
+        let proxied = if target.is_default() {
+            let url = sign_thumbnail_url(
+                &state,
+                &target,
+                &project_id,
+                &image_type,
+                &object.id,
+                &object.mime_type,
+                width,
+                height
+            );
+
+            let res = state.reqwest_client.get(url).send().await;
+
+            match res {
+                Ok(res) if res.status().is_success() => {
+                    let content_type = res
+                        .headers()
+                        .get(CONTENT_TYPE)
+                        .and_then(|value| value.to_str().ok())
+                        .unwrap_or("image/webp")
+                        .to_owned();
+
+                    res.bytes().await.ok().map(|data| (content_type, data))
+                }
+                Ok(res) => {
+                    tracing::error!("THUMBNAIL SERVICE RETURNED {} - {}", res.status(), id);
+                    None
+                }
+                Err(err) => {
+                    tracing::error!("THUMBNAIL SERVICE UNAVAILABLE - {}", err);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        if proxied.is_some() {
+            proxied.unwrap()
+        } else {
+            let data = get_or_create_thumbnail(
+                &state,
+                &target,
+                &project_id,
+                &image_type,
+                &object.id,
+                &object.mime_type,
+                width,
+                height
+            ).await;
+
+            if data.is_err() {
+                return data.err().unwrap().into_response();
+            }
+
+            ("image/webp".to_owned(), data.unwrap())
+        }
+    } else {
+        let data = get_object_bytes(&target, &object.key(&project_id, &image_type)).await;
+
+        if data.is_err() {
+            return data.err().unwrap().into_response();
+        }
+
+        (object.mime_type.clone(), data.unwrap())
+    };
+
+    record_view(&state, id);
+    record_bandwidth(&state.pool, &project_id, data.len() as i64).await;
+
+    // Same as the raw asset route, CDNs in front of the domain must not keep private objects
+    let cache_control = match visibility {
+        AssetVisibility::Public => state.config.cache_control.for_image_type(&image_type),
+        AssetVisibility::Private => "private, max-age=3600",
+    };
+
+    return (
+        StatusCode::OK,
+        [
+            (CONTENT_TYPE, HeaderValue::from_str(&content_type).unwrap()),
+            (CACHE_CONTROL, HeaderValue::from_str(cache_control).unwrap()),
+        ],
+        data,
+    ).into_response();
+}
+
+pub fn domain_routes() -> Router<AppState> {
+    Router::new().route("/serve/:image_type/:file", get(serve_asset))
+}
@@ -1,33 +1,97 @@
 use std::str::FromStr;
 
-use aws_sdk_s3::primitives::ByteStream;
 use axum::{
-    extract::{ Multipart, State },
+    extract::{ Multipart, Query, State },
     http::{ HeaderMap, HeaderName },
+    middleware::from_fn_with_state,
     response::IntoResponse,
-    routing::post,
+    routing::{ delete, get, post },
+    Json,
     Router,
 };
-use reqwest::Method;
-use tower_http::cors::{ AllowOrigin, CorsLayer };
+use reqwest::{ header::CONTENT_TYPE, Method };
+use serde::Deserialize;
+use serde_json::json;
+use tower_http::cors::CorsLayer;
 use uuid::Uuid;
 
 use crate::{
-    enums::{ AppResponse, ImageType },
+    enums::{
+        ApiKeyScope,
+        AppResponse,
+        AssetKind,
+        AuditAction,
+        AuditSource,
+        DbAccess,
+        ImageType,
+        OutputFormat,
+        WebhookEvent,
+    },
+    jobs::acl_job::get_project_visibility,
+    services::{
+        asset_service::{ store_asset, AssetBody, NewAsset, StoreError },
+        audit_service::{ record_audit, AuditActor },
+    },
     state::models::AppState,
-    utils::{ db_utils::get_client, image_utils::encode_lossy_webp },
+    storage::resolve_target,
+    utils::{
+        asset_utils::image_key,
+        auth_utils::check_api_key,
+        db_utils::{
+            db_pool,
+            get_client,
+            get_encode_options,
+            get_locked_ids,
+            get_project_usage,
+            locked_conflict,
+        },
+        dedup_utils::OBJECT_ID,
+        extractors::ExtractPath,
+        maintenance_utils::maintenance_middleware,
+        metrics_utils::record_upload_size,
+        progress_utils::UploadProgress,
+        stream_utils::spool_field,
+        trash_utils::trash_assets,
+        validation_utils::{ validate_image, validation_failed, ImageFacts },
+        webhook_utils::emit_asset_event,
+    },
+    PRESIGN_DURATION,
 };
 
+#[derive(Deserialize)]
+struct ExtensionRenamePayload {
+    title: String,
+}
+
+#[derive(Deserialize)]
+struct ExtensionUploadQuery {
+    quality: Option<f32>,
+    lossless: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct ExtensionListQuery {
+    page: Option<i64>,
+    limit: Option<i64>,
+}
+
 async fn upload(
     State(state): State<AppState>,
+    query: Query<ExtensionUploadQuery>,
     headers: HeaderMap,
     mut multipart: Multipart
 ) -> impl IntoResponse {
-    let api_key = headers.get("x-api-key");
-    if api_key.is_none() {
-        return AppResponse::Unauthorized;
+    let api_project = check_api_key(&headers, &state, ApiKeyScope::Upload).await;
+
+    if api_project.is_err() {
+        return api_project.err().unwrap();
     }
-    let api_key = api_key.unwrap().to_str().unwrap().to_string();
+
+    let api_project = api_project.unwrap();
+
+    let project_id = api_project.project_id;
+    let user_id = api_project.owner_id;
+
     let client = get_client(&state.pool).await;
 
     if client.is_err() {
@@ -36,88 +100,434 @@ async fn upload(
 
     let client = client.unwrap();
 
-    let is_api_key_valid = client.query_one(
-        "SELECT id, owner_id FROM projects WHERE api_key = $1;",
-        &[&api_key]
-    ).await;
+    let encode_options = get_encode_options(&client, &project_id).await;
+
+    if encode_options.is_err() {
+        return encode_options.err().unwrap();
+    }
+
+    let encode_options = encode_options.unwrap().with_overrides(query.quality, query.lossless);
+
+    let target = resolve_target(&state, &project_id).await;
 
-    if is_api_key_valid.is_err() {
-        return AppResponse::Unauthorized;
+    if target.is_err() {
+        return target.err().unwrap();
     }
 
-    let data = is_api_key_valid.unwrap();
+    let target = target.unwrap();
 
-    let project_id: Uuid = data.get("id");
-    let user_id: Uuid = data.get("owner_id");
+    let visibility = get_project_visibility(&state, &project_id).await;
+
+    if visibility.is_err() {
+        return visibility.err().unwrap();
+    }
+
+    let visibility = visibility.unwrap();
+
+    // Extension uploads have no status polling, so progress updates go nowhere
+    let progress = UploadProgress::start(
+        &state.upload_tracker,
+        &state.upload_events,
+        None,
+        user_id
+    );
+    let mut uploaded: Vec<serde_json::Value> = vec![];
 
     while let Some(field) = multipart.next_field().await.unwrap() {
         let name = field.name().unwrap_or("unnamed").to_string();
-        let data = field.bytes().await;
 
         if name == "unnamed" {
             continue;
         }
 
-        if data.is_err() {
+        let spooled = spool_field(field, &progress).await;
+
+        if spooled.is_err() {
             return AppResponse::Error(
-                format!("ERROR GETTING FILE DATA EXTENSION ROUTE - {}", data.err().unwrap())
+                format!("ERROR GETTING FILE DATA EXTENSION ROUTE - {}", spooled.err().unwrap())
             );
         }
 
         let id = Uuid::new_v4();
-        let data = data.unwrap().to_vec();
 
-        let img_data = image::load_from_memory(&data);
+        let spooled = spooled.unwrap();
+
+        record_upload_size(&AssetKind::Image, spooled.size);
+
+        let img_data = spooled.decode_image(
+            &state.encode_permits,
+            state.config.max_image_pixels
+        ).await;
 
         if img_data.is_err() {
-            return AppResponse::Error(format!("{}", img_data.err().unwrap()));
+            return AppResponse::Error(img_data.err().unwrap());
+        }
+
+        let img_data = img_data.unwrap();
+        let facts = ImageFacts::from_head(&spooled.head, spooled.size, img_data.dimensions());
+        let valid = validate_image(&ImageType::Images, &facts);
+
+        if valid.is_err() {
+            return validation_failed(&ImageType::Images, valid.err().unwrap());
         }
 
-        let lossy = encode_lossy_webp(img_data.unwrap());
-
-        let upload = state.client
-            .put_object()
-            .bucket(&state.bucket)
-            .key(format!("assets/{}/{}/{}.webp", &project_id, &ImageType::Images, &id))
-            .body(ByteStream::from(lossy))
-            .acl(aws_sdk_s3::types::ObjectCannedAcl::Private)
-            .content_type("image/webp")
-            .cache_control("max-age=600")
-            .send().await;
-
-        if upload.is_ok() {
-            let res = client.query(
-                "INSERT INTO images (id, title, project_id, type, owner_id) VALUES ($1, $2, $3, $4, $5);",
-                &[&id, &name, &project_id, &ImageType::Images, &user_id]
-            ).await;
-
-            if res.is_err() {
-                let del_res = &state.client
-                    .delete_object()
-                    .bucket(&state.bucket)
-                    .key(format!("assets/{}/{}/{}.webp", &project_id, &ImageType::Images, &id))
-                    .send().await;
-
-                if del_res.is_err() {
-                    tracing::error!("{}", del_res.as_ref().err().unwrap());
-                }
-                return AppResponse::Error(format!("{}", res.err().unwrap()));
-            }
-        } else {
-            return AppResponse::Error(format!("{}", upload.err().unwrap()));
+        let usage = get_project_usage(&state, &project_id).await;
+
+        if usage.is_err() {
+            return usage.err().unwrap();
+        }
+
+        let usage = usage.unwrap();
+
+        let new = NewAsset {
+            id,
+            key_id: None,
+            title: &name,
+            project_id: &project_id,
+            image_type: &ImageType::Images,
+            owner_id: &user_id,
+            pending: false,
+            visibility,
+            dedupe: true,
+            max_size_bytes: Some(usage.quota_bytes - usage.bytes_stored),
+        };
+
+        let body = AssetBody::Image {
+            img: img_data,
+            head: &spooled.head,
+            format: OutputFormat::Webp,
+            options: &encode_options,
+        };
+
+        let stored = store_asset(&state, &client, &target, &new, body, Some(&progress)).await;
+
+        if stored.is_err() {
+            return match stored.err().unwrap() {
+                StoreError::QuotaExceeded =>
+                    AppResponse::Error(
+                        format!("STORAGE QUOTA EXCEEDED FOR PROJECT {} - {}", &project_id, &name)
+                    ),
+                err => err.into(),
+            };
         }
+
+        let stored = stored.unwrap();
+        let metadata = stored.metadata.unwrap();
+
+        emit_asset_event(&state, &client, WebhookEvent::Uploaded, &id).await;
+        record_audit(
+            &client,
+            &AuditActor::api_key(&api_project, AuditSource::Extension),
+            &project_id,
+            AuditAction::Upload,
+            &[id]
+        ).await;
+
+        uploaded.push(
+            json!({
+                "id": id,
+                "title": name,
+                "size_bytes": stored.size_bytes,
+                "width": metadata.width,
+                "height": metadata.height,
+                "original_format": metadata.original_format,
+                "is_animated": metadata.is_animated,
+                "dominant_color": metadata.dominant_color,
+                "deduplicated": stored.object_id.is_some(),
+            })
+        );
     }
 
-    return AppResponse::Success("".to_string(), crate::enums::SuccessActions::Upload);
+    return AppResponse::SuccessData(
+        "Image(s)".to_owned(),
+        crate::enums::SuccessActions::Upload,
+        json!({ "uploaded": uploaded })
+    );
+}
+
+async fn list_assets(
+    State(state): State<AppState>,
+    query: Query<ExtensionListQuery>,
+    headers: HeaderMap
+) -> impl IntoResponse {
+    let api_project = check_api_key(&headers, &state, ApiKeyScope::Read).await;
+
+    if api_project.is_err() {
+        return api_project.err().unwrap();
+    }
+
+    let api_project = api_project.unwrap();
+
+    let client = get_client(db_pool(&state, DbAccess::Read)).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+
+    let client = client.unwrap();
+
+    let limit = query.limit.unwrap_or(50).clamp(1, 100);
+    let page = query.page.unwrap_or(0).max(0);
+
+    let rows = client.query(
+        "SELECT id, title, type FROM images
+         WHERE project_id = $1 AND pending = FALSE AND deleted_at IS NULL
+         ORDER BY created_at DESC, id
+         LIMIT $2 OFFSET $3;",
+        &[&api_project.project_id, &limit, &(page * limit)]
+    ).await;
+
+    if rows.is_err() {
+        return AppResponse::Error(rows.err().unwrap().to_string());
+    }
+
+    let items: Vec<serde_json::Value> = rows
+        .unwrap()
+        .iter()
+        .map(|row| {
+            let id: Uuid = row.get("id");
+            let title: Option<String> = row.get("title");
+            let image_type: ImageType = row.get("type");
+
+            json!({ "id": id, "title": title, "type": image_type.to_string() })
+        })
+        .collect();
+
+    return AppResponse::SuccessData(
+        "Assets".to_owned(),
+        crate::enums::SuccessActions::Read,
+        json!({ "page": page, "limit": limit, "items": items })
+    );
+}
+
+async fn get_asset_url(
+    State(state): State<AppState>,
+    ExtractPath(id): ExtractPath<Uuid>,
+    headers: HeaderMap
+) -> impl IntoResponse {
+    let api_project = check_api_key(&headers, &state, ApiKeyScope::Read).await;
+
+    if api_project.is_err() {
+        return api_project.err().unwrap();
+    }
+
+    let api_project = api_project.unwrap();
+
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+
+    let client = client.unwrap();
+
+    let image = client.query_opt(
+        &format!(
+            "SELECT type, mime_type, {} FROM images WHERE id = $1 AND project_id = $2 AND deleted_at IS NULL;",
+            OBJECT_ID
+        ),
+        &[&id, &api_project.project_id]
+    ).await;
+
+    if image.is_err() {
+        return AppResponse::Error(image.err().unwrap().to_string());
+    }
+
+    let image = image.unwrap();
+
+    if image.is_none() {
+        return AppResponse::Auth;
+    }
+
+    let image = image.unwrap();
+    let image_type: ImageType = image.get("type");
+    let mime_type: String = image.get("mime_type");
+    let object_id: Uuid = image.get("object_id");
+
+    let target = resolve_target(&state, &api_project.project_id).await;
+
+    if target.is_err() {
+        return target.err().unwrap();
+    }
+
+    let target = target.unwrap();
+
+    let url = target.presign_get(
+        &image_key(&api_project.project_id, &image_type, &object_id, &mime_type),
+        PRESIGN_DURATION
+    ).await;
+
+    if url.is_err() {
+        return AppResponse::Error(url.err().unwrap());
+    }
+
+    record_audit(
+        &client,
+        &AuditActor::api_key(&api_project, AuditSource::Extension),
+        &api_project.project_id,
+        AuditAction::Download,
+        &[id]
+    ).await;
+
+    return AppResponse::SuccessData(
+        "Asset URL".to_owned(),
+        crate::enums::SuccessActions::Read,
+        json!({ "id": id, "url": url.unwrap(), "expires_in": PRESIGN_DURATION.as_secs() })
+    );
+}
+
+async fn delete_asset(
+    State(state): State<AppState>,
+    ExtractPath(id): ExtractPath<Uuid>,
+    headers: HeaderMap
+) -> impl IntoResponse {
+    let api_project = check_api_key(&headers, &state, ApiKeyScope::Manage).await;
+
+    if api_project.is_err() {
+        return api_project.err().unwrap();
+    }
+
+    let api_project = api_project.unwrap();
+
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+
+    let client = client.unwrap();
+
+    let locked_ids = get_locked_ids(&client, &api_project.project_id, Some(&vec![id])).await;
+
+    if locked_ids.is_err() {
+        return locked_ids.err().unwrap();
+    }
+
+    let locked_ids = locked_ids.unwrap();
+
+    if !locked_ids.is_empty() {
+        return locked_conflict(locked_ids);
+    }
+
+    // Extension deletes always go to the trash, they can be restored from the app
+    let trashed = trash_assets(&state, &client, &api_project.project_id, None, &vec![id]).await;
+
+    if trashed.is_err() {
+        return trashed.err().unwrap();
+    }
+
+    if trashed.unwrap().is_empty() {
+        return AppResponse::Auth;
+    }
+
+    record_audit(
+        &client,
+        &AuditActor::api_key(&api_project, AuditSource::Extension),
+        &api_project.project_id,
+        AuditAction::Delete,
+        &[id]
+    ).await;
+
+    return AppResponse::Success("Image".to_owned(), crate::enums::SuccessActions::Delete);
+}
+
+async fn rename_asset(
+    State(state): State<AppState>,
+    ExtractPath(id): ExtractPath<Uuid>,
+    headers: HeaderMap,
+    Json(payload): Json<ExtensionRenamePayload>
+) -> impl IntoResponse {
+    let api_project = check_api_key(&headers, &state, ApiKeyScope::Manage).await;
+
+    if api_project.is_err() {
+        return api_project.err().unwrap();
+    }
+
+    let api_project = api_project.unwrap();
+
+    if payload.title.trim().is_empty() {
+        return AppResponse::Error("TITLE CANNOT BE EMPTY".to_owned());
+    }
+
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+
+    let client = client.unwrap();
+
+    let res = client.execute(
+        "UPDATE images SET title = $1, updated_at = NOW() WHERE id = $2 AND project_id = $3;",
+        &[&payload.title, &id, &api_project.project_id]
+    ).await;
+
+    if res.is_err() {
+        return AppResponse::Error(res.err().unwrap().to_string());
+    }
+
+    if res.unwrap() == 0 {
+        return AppResponse::Auth;
+    }
+
+    emit_asset_event(&state, &client, WebhookEvent::Updated, &id).await;
+    record_audit(
+        &client,
+        &AuditActor::api_key(&api_project, AuditSource::Extension),
+        &api_project.project_id,
+        AuditAction::Update,
+        &[id]
+    ).await;
+
+    return AppResponse::Success("Image".to_owned(), crate::enums::SuccessActions::Update);
+}
+
+async fn get_usage(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    let api_project = check_api_key(&headers, &state, ApiKeyScope::Read).await;
+
+    if api_project.is_err() {
+        return api_project.err().unwrap();
+    }
+
+    let api_project = api_project.unwrap();
+
+    let usage = get_project_usage(&state, &api_project.project_id).await;
+
+    if usage.is_err() {
+        return usage.err().unwrap();
+    }
+
+    let usage = usage.unwrap();
+
+    return AppResponse::SuccessData(
+        "Usage".to_owned(),
+        crate::enums::SuccessActions::Read,
+        json!({
+            "upload_count": usage.upload_count,
+            "monthly_upload_count": usage.monthly_upload_count,
+            "bytes_stored": usage.bytes_stored,
+            "quota_bytes": usage.quota_bytes,
+            "remaining_bytes": (usage.quota_bytes - usage.bytes_stored).max(0),
+        })
+    );
 }
 
-pub fn extension_routes() -> Router<AppState> {
+pub fn extension_routes(state: AppState) -> Router<AppState> {
     let extension_cors = CorsLayer::new()
-        .allow_methods([Method::POST, Method::OPTIONS])
-        .allow_headers([HeaderName::from_str("x-api-key").unwrap()])
-        .allow_origin(AllowOrigin::any());
+        .allow_methods([Method::GET, Method::POST, Method::PATCH, Method::DELETE, Method::OPTIONS])
+        .allow_headers([HeaderName::from_str("x-api-key").unwrap(), CONTENT_TYPE])
+        .allow_origin(state.config.extension_cors_origins.allow_origin());
     Router::new().nest(
         "/extension",
-        Router::new().route("/upload", post(upload)).layer(extension_cors)
+        Router::new()
+            .route(
+                "/upload",
+                post(upload).layer(from_fn_with_state(state.clone(), maintenance_middleware))
+            )
+            .route("/assets", get(list_assets))
+            .route("/assets/:id", delete(delete_asset).patch(rename_asset))
+            .route("/assets/:id/url", get(get_asset_url))
+            .route("/usage", get(get_usage))
+            .layer(extension_cors)
     )
 }
@@ -1,29 +1,75 @@
 use std::str::FromStr;
 
-use aws_sdk_s3::presigning::PresigningConfig;
 use axum::{
     extract::{ Query, State },
-    http::{ HeaderName, HeaderValue },
+    http::{ HeaderMap, HeaderName, HeaderValue },
+    middleware::from_fn_with_state,
     response::IntoResponse,
-    routing::get,
+    routing::{ get, post },
+    Json,
     Router,
 };
-use base64::prelude::*;
-use hmac::{ Hmac, Mac };
 use reqwest::{ header::{ CACHE_CONTROL, CONTENT_TYPE }, Method, StatusCode };
 use serde::Deserialize;
-use sha2::Sha512;
-use tower_http::cors::{ AllowOrigin, CorsLayer };
+use serde_json::json;
+use tokio_postgres::Row;
+use tower_http::cors::CorsLayer;
+use url::Url;
 use uuid::Uuid;
 
 use crate::{
-    enums::ImageType,
+    enums::{
+        ApiKeyScope,
+        AppResponse,
+        AssetKind,
+        AssetVisibility,
+        AuditAction,
+        AuditSource,
+        DbAccess,
+        FoundrySource,
+        FoundrySyncStatus,
+        ImageType,
+    },
+    jobs::{
+        acl_job::get_project_visibility,
+        foundry_sync_job::{ get_foundry_connection, run_foundry_sync },
+        moderation_job::{ is_hidden_by_moderation, MODERATION_VISIBLE },
+        view_count_job::record_view,
+    },
+    services::audit_service::{ record_audit, AuditActor },
     state::models::AppState,
-    utils::extractors::ExtractPath,
+    storage::{ resolve_target, StorageTarget },
+    utils::{
+        auth_utils::check_api_key,
+        db_utils::{ db_pool, get_client },
+        dedup_utils::{ resolve_object, StoredObject, OBJECT_ID },
+        domain_utils::{ asset_url, get_custom_domain },
+        extractors::ExtractPath,
+        hotlink_utils::hotlink_middleware,
+        tenant_utils::{ owner_middleware, tenant_middleware },
+        thumbnail_utils::{
+            get_or_create_thumbnail,
+            sign_thumbnail_url,
+            snap_thumbnail_size,
+            thumbnail_key,
+        },
+    },
     PRESIGN_DURATION,
 };
 
-type HmacSha512 = Hmac<Sha512>;
+// Foundry's own scene defaults, used for maps without grid info
+const FOUNDRY_GRID_SIZE: i32 = 100;
+const FOUNDRY_GRID_DISTANCE: f64 = 5.0;
+const FOUNDRY_GRID_UNITS: &str = "ft";
+// Square grid
+const FOUNDRY_GRID_TYPE: i32 = 1;
+const MAX_FOUNDRY_SYNC: usize = 50;
+const DEFAULT_FOUNDRY_TARGET_PATH: &str = "arkive/maps";
+// Grid columns of a scene, maps without map_settings fall back to what was stored on the image
+const SCENE_GRID: &str =
+    "COALESCE(map_settings.grid_size, images.grid_size) AS grid_size,
+    COALESCE(map_settings.grid_distance, images.grid_distance) AS grid_distance,
+    COALESCE(map_settings.grid_units, images.grid_units) AS grid_units";
 
 #[derive(Deserialize)]
 struct ThumbnailDimensions {
@@ -31,28 +77,148 @@ struct ThumbnailDimensions {
     height: Option<usize>,
 }
 
+#[derive(Deserialize)]
+struct FoundryPullQuery {
+    after: Option<Uuid>,
+    limit: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct FoundryConnectionPayload {
+    url: String,
+    session: String,
+    source: Option<FoundrySource>,
+    bucket: Option<String>,
+    target_path: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct FoundrySyncPayload {
+    ids: Vec<Uuid>,
+}
+
+// Scenes and modules keep the URL around, so projects serving public assets get the permanent
+// URL and only private ones fall back to a presigned one.
+async fn foundry_asset_url(
+    target: &StorageTarget,
+    domain: Option<&str>,
+    visibility: &AssetVisibility,
+    project_id: &Uuid,
+    image_type: &ImageType,
+    object: &StoredObject
+) -> Result<String, AppResponse> {
+    if domain.is_some() || visibility == &AssetVisibility::Public {
+        return Ok(asset_url(target, domain, project_id, image_type, &object.id, &object.mime_type));
+    }
+
+    target.presign_get(&object.key(project_id, image_type), PRESIGN_DURATION).await.map_err(
+        AppResponse::Error
+    )
+}
+
+fn foundry_scene(row: &Row, url: String) -> serde_json::Value {
+    let id: Uuid = row.get("id");
+    let title: Option<String> = row.get("title");
+    let width: Option<i32> = row.get("width");
+    let height: Option<i32> = row.get("height");
+    let grid_size: Option<i32> = row.get("grid_size");
+    let grid_distance: Option<f64> = row.get("grid_distance");
+    let grid_units: Option<String> = row.get("grid_units");
+
+    json!({
+        "name": title.unwrap_or_else(|| id.to_string()),
+        "width": width,
+        "height": height,
+        "padding": 0,
+        "background": { "src": url },
+        "grid": {
+            "type": FOUNDRY_GRID_TYPE,
+            "size": grid_size.unwrap_or(FOUNDRY_GRID_SIZE),
+            "distance": grid_distance.unwrap_or(FOUNDRY_GRID_DISTANCE),
+            "units": grid_units.unwrap_or(FOUNDRY_GRID_UNITS.to_owned()),
+        },
+        "flags": { "arkive": { "id": id } },
+    })
+}
+
 async fn get_thumbnail(
     State(state): State<AppState>,
     query: Query<ThumbnailDimensions>,
     ExtractPath((project_id, image_type, image_id)): ExtractPath<(Uuid, ImageType, Uuid)>
 ) -> impl IntoResponse {
-    if query.width.is_some() && query.height.is_some() {
-        let mut hmac = HmacSha512::new_from_slice(&state.thumbnail_secret.as_bytes()).unwrap();
-        let sized_url = format!(
-            "{}x{}/assets/{}/{}/{}.webp",
-            query.width.unwrap(),
-            query.height.unwrap(),
-            &project_id,
-            &image_type,
-            &image_id
+    // Deduplicated assets are stored under the id of the asset they share content with
+    let (object, hidden) = match get_client(db_pool(&state, DbAccess::Read)).await {
+        Ok(client) =>
+            (
+                resolve_object(&client, &image_id).await.unwrap_or(
+                    StoredObject::unresolved(&image_id)
+                ),
+                is_hidden_by_moderation(&client, &image_id).await.unwrap_or(true),
+            ),
+        Err(_) => (StoredObject::unresolved(&image_id), state.moderation.is_some()),
+    };
+
+    if hidden {
+        return (
+            StatusCode::NOT_FOUND,
+            [
+                (CONTENT_TYPE, HeaderValue::from_str("text/plain").unwrap()),
+                (CACHE_CONTROL, HeaderValue::from_str("no-store").unwrap()),
+            ],
+            "NOT FOUND".to_owned(),
+        );
+    }
+
+    record_view(&state, image_id);
+
+    let target = resolve_target(&state, &project_id).await;
+
+    if target.is_err() {
+        tracing::error!("{:?}", target.err().unwrap());
+
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            [
+                (CONTENT_TYPE, HeaderValue::from_str("text/plain").unwrap()),
+                (CACHE_CONTROL, HeaderValue::from_str("no-store").unwrap()),
+            ],
+            "ERROR RESOLVING STORAGE TARGET".to_owned(),
         );
-        hmac.update(&sized_url.as_bytes());
+    }
 
-        let res = hmac.finalize().into_bytes();
+    let target = target.unwrap();
+    let mut key = object.key(&project_id, &image_type);
 
-        let base_64 = BASE64_STANDARD.encode(res).replace('+', "-").replace('/', "_");
+    // The thumbnail service can't read dedicated buckets, those get a locally resized copy
+    if query.width.is_some() && query.height.is_some() && !target.is_default() {
+        let (width, height) = snap_thumbnail_size(query.width.unwrap(), query.height.unwrap());
+        let created = get_or_create_thumbnail(
+            &state,
+            &target,
+            &project_id,
+            &image_type,
+            &object.id,
+            &object.mime_type,
+            width,
+            height
+        ).await;
 
-        let url = format!("{}/{}/{}", &state.thumbnail_service_url, &base_64, &sized_url);
+        if created.is_ok() {
+            key = thumbnail_key(&project_id, &image_type, &object.id, width, height);
+        } else {
+            tracing::error!("{:?}", created.err().unwrap());
+        }
+    } else if query.width.is_some() && query.height.is_some() {
+        let url = sign_thumbnail_url(
+            &state,
+            &target,
+            &project_id,
+            &image_type,
+            &object.id,
+            &object.mime_type,
+            query.width.unwrap(),
+            query.height.unwrap()
+        );
 
         return (
             StatusCode::OK,
@@ -64,14 +230,7 @@ async fn get_thumbnail(
         );
     }
 
-    let command = state.client
-        .get_object()
-        .bucket(&state.bucket)
-        .key(format!("assets/{}/{}/{}.webp", &project_id, &image_type, &image_id))
-        .presigned(PresigningConfig::expires_in(PRESIGN_DURATION).unwrap()).await
-        .unwrap();
-
-    let url = command.uri();
+    let url = target.presign_get(&key, PRESIGN_DURATION).await.unwrap();
 
     return (
         StatusCode::OK,
@@ -83,15 +242,578 @@ async fn get_thumbnail(
     );
 }
 
-pub fn foundry_routes() -> Router<AppState> {
+async fn export_scenes(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    let api_project = check_api_key(&headers, &state, ApiKeyScope::Read).await;
+
+    if api_project.is_err() {
+        return api_project.err().unwrap();
+    }
+
+    let api_project = api_project.unwrap();
+
+    let visibility = get_project_visibility(&state, &api_project.project_id).await;
+
+    if visibility.is_err() {
+        return visibility.err().unwrap();
+    }
+
+    let visibility = visibility.unwrap();
+
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+
+    let client = client.unwrap();
+
+    let rows = client.query(
+        &format!(
+            "SELECT id, title, mime_type, width, height, {}, {} FROM images
+             LEFT JOIN map_settings ON map_settings.image_id = images.id
+             WHERE project_id = $1 AND type = $2 AND kind = $3 AND pending = FALSE AND deleted_at IS NULL
+                AND {}
+             ORDER BY title, id;",
+            SCENE_GRID,
+            OBJECT_ID,
+            MODERATION_VISIBLE
+        ),
+        &[&api_project.project_id, &ImageType::MapImages, &AssetKind::Image]
+    ).await;
+
+    if rows.is_err() {
+        return AppResponse::Error(rows.err().unwrap().to_string());
+    }
+
+    let domain = get_custom_domain(&client, &api_project.project_id).await;
+    let target = resolve_target(&state, &api_project.project_id).await;
+
+    if target.is_err() {
+        return target.err().unwrap();
+    }
+
+    let target = target.unwrap();
+
+    let mut scenes: Vec<serde_json::Value> = vec![];
+
+    for row in rows.unwrap().iter() {
+        let object = StoredObject { id: row.get("object_id"), mime_type: row.get("mime_type") };
+
+        let url = foundry_asset_url(
+            &target,
+            domain.as_deref(),
+            &visibility,
+            &api_project.project_id,
+            &ImageType::MapImages,
+            &object
+        ).await;
+
+        if url.is_err() {
+            return url.err().unwrap();
+        }
+
+        scenes.push(foundry_scene(row, url.unwrap()));
+    }
+
+    return AppResponse::SuccessData(
+        "Scenes".to_owned(),
+        crate::enums::SuccessActions::Read,
+        json!({ "project_id": api_project.project_id, "scenes": scenes })
+    );
+}
+
+// A single map as a ready to import scene, so the module can create it in one call
+async fn get_scene(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    ExtractPath(id): ExtractPath<Uuid>
+) -> impl IntoResponse {
+    let api_project = check_api_key(&headers, &state, ApiKeyScope::Read).await;
+
+    if api_project.is_err() {
+        return api_project.err().unwrap();
+    }
+
+    let api_project = api_project.unwrap();
+
+    let visibility = get_project_visibility(&state, &api_project.project_id).await;
+
+    if visibility.is_err() {
+        return visibility.err().unwrap();
+    }
+
+    let visibility = visibility.unwrap();
+
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+
+    let client = client.unwrap();
+
+    let row = client.query_opt(
+        &format!(
+            "SELECT id, title, mime_type, width, height, {}, {} FROM images
+             LEFT JOIN map_settings ON map_settings.image_id = images.id
+             WHERE id = $1 AND project_id = $2 AND type = $3 AND kind = $4 AND pending = FALSE
+                AND deleted_at IS NULL AND {};",
+            SCENE_GRID,
+            OBJECT_ID,
+            MODERATION_VISIBLE
+        ),
+        &[&id, &api_project.project_id, &ImageType::MapImages, &AssetKind::Image]
+    ).await;
+
+    if row.is_err() {
+        return AppResponse::Error(row.err().unwrap().to_string());
+    }
+
+    let row = row.unwrap();
+
+    if row.is_none() {
+        return AppResponse::Error(format!("MAP NOT FOUND - {}", id));
+    }
+
+    let row = row.unwrap();
+    let domain = get_custom_domain(&client, &api_project.project_id).await;
+    let target = resolve_target(&state, &api_project.project_id).await;
+
+    if target.is_err() {
+        return target.err().unwrap();
+    }
+
+    let object = StoredObject { id: row.get("object_id"), mime_type: row.get("mime_type") };
+
+    let url = foundry_asset_url(
+        &target.unwrap(),
+        domain.as_deref(),
+        &visibility,
+        &api_project.project_id,
+        &ImageType::MapImages,
+        &object
+    ).await;
+
+    if url.is_err() {
+        return url.err().unwrap();
+    }
+
+    return AppResponse::SuccessData(
+        "Scene".to_owned(),
+        crate::enums::SuccessActions::Read,
+        foundry_scene(&row, url.unwrap())
+    );
+}
+
+// Assets in upload order. Modules poll with the last id they've seen as `after` to only
+// get what was added since.
+async fn pull_assets(
+    State(state): State<AppState>,
+    query: Query<FoundryPullQuery>,
+    headers: HeaderMap
+) -> impl IntoResponse {
+    let api_project = check_api_key(&headers, &state, ApiKeyScope::Read).await;
+
+    if api_project.is_err() {
+        return api_project.err().unwrap();
+    }
+
+    let api_project = api_project.unwrap();
+
+    let visibility = get_project_visibility(&state, &api_project.project_id).await;
+
+    if visibility.is_err() {
+        return visibility.err().unwrap();
+    }
+
+    let visibility = visibility.unwrap();
+
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+
+    let client = client.unwrap();
+
+    let limit = query.limit.unwrap_or(50).clamp(1, 100);
+
+    let rows = client.query(
+        &format!(
+            "SELECT id, title, type, mime_type, width, height, {},
+                (EXTRACT(EPOCH FROM created_at) * 1000)::BIGINT AS created_at
+             FROM images
+             WHERE project_id = $1 AND kind = $2 AND pending = FALSE AND deleted_at IS NULL AND {}
+                AND ($3::UUID IS NULL OR (created_at, id) > (SELECT created_at, id FROM images WHERE id = $3))
+             ORDER BY created_at, id
+             LIMIT $4;",
+            OBJECT_ID,
+            MODERATION_VISIBLE
+        ),
+        &[&api_project.project_id, &AssetKind::Image, &query.after, &limit]
+    ).await;
+
+    if rows.is_err() {
+        return AppResponse::Error(rows.err().unwrap().to_string());
+    }
+
+    let rows = rows.unwrap();
+    let domain = get_custom_domain(&client, &api_project.project_id).await;
+    let target = resolve_target(&state, &api_project.project_id).await;
+
+    if target.is_err() {
+        return target.err().unwrap();
+    }
+
+    let target = target.unwrap();
+
+    let mut items: Vec<serde_json::Value> = vec![];
+
+    for row in rows.iter() {
+        let id: Uuid = row.get("id");
+        let title: Option<String> = row.get("title");
+        let image_type: ImageType = row.get("type");
+        let width: Option<i32> = row.get("width");
+        let height: Option<i32> = row.get("height");
+        let object = StoredObject { id: row.get("object_id"), mime_type: row.get("mime_type") };
+        let created_at: Option<i64> = row.get("created_at");
+
+        let url = foundry_asset_url(
+            &target,
+            domain.as_deref(),
+            &visibility,
+            &api_project.project_id,
+            &image_type,
+            &object
+        ).await;
+
+        if url.is_err() {
+            return url.err().unwrap();
+        }
+
+        items.push(
+            json!({
+                "id": id,
+                "title": title,
+                "type": image_type.to_string(),
+                "width": width,
+                "height": height,
+                "url": url.unwrap(),
+                "created_at": created_at,
+            })
+        );
+    }
+
+    // Without new assets the module keeps polling from where it was
+    let cursor: Option<Uuid> = rows
+        .last()
+        .map(|row| row.get("id"))
+        .or(query.after);
+
+    let pulled: Vec<Uuid> = rows
+        .iter()
+        .map(|row| row.get("id"))
+        .collect();
+
+    // The module stores what it pulls, so every pulled asset counts as downloaded
+    record_audit(
+        &client,
+        &AuditActor::api_key(&api_project, AuditSource::Foundry),
+        &api_project.project_id,
+        AuditAction::Download,
+        &pulled
+    ).await;
+
+    return AppResponse::SuccessData(
+        "Assets".to_owned(),
+        crate::enums::SuccessActions::Read,
+        json!({ "items": items, "cursor": cursor, "has_more": rows.len() as i64 == limit })
+    );
+}
+
+async fn get_connection(
+    State(state): State<AppState>,
+    ExtractPath(project_id): ExtractPath<Uuid>
+) -> impl IntoResponse {
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    let connection = get_foundry_connection(&client, &project_id).await;
+
+    if connection.is_err() {
+        return connection.err().unwrap();
+    }
+
+    // The session is never sent back, it can only be replaced
+    let connection = connection
+        .unwrap()
+        .map(|connection| {
+            json!({
+                "url": connection.url,
+                "source": connection.source,
+                "bucket": connection.bucket,
+                "target_path": connection.target_path,
+            })
+        });
+
+    return AppResponse::SuccessData(
+        "Foundry connection".to_owned(),
+        crate::enums::SuccessActions::Read,
+        json!(connection)
+    );
+}
+
+// Folder inside the Foundry source, without leading or trailing slashes
+fn clean_target_path(target_path: &str) -> Option<String> {
+    let segments: Vec<&str> = target_path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect();
+
+    if segments.is_empty() || segments.iter().any(|segment| *segment == "." || *segment == "..") {
+        return None;
+    }
+
+    Some(segments.join("/"))
+}
+
+async fn save_connection(
+    State(state): State<AppState>,
+    ExtractPath(project_id): ExtractPath<Uuid>,
+    Json(payload): Json<FoundryConnectionPayload>
+) -> impl IntoResponse {
+    let url = payload.url.trim();
+
+    if Url::parse(url).map_or(true, |url| url.scheme() != "https" && url.scheme() != "http") {
+        return AppResponse::Error(format!("INVALID FOUNDRY URL - {}", url));
+    }
+
+    if payload.session.trim().is_empty() {
+        return AppResponse::Error("FOUNDRY SESSION IS REQUIRED".to_owned());
+    }
+
+    let source = payload.source.unwrap_or(FoundrySource::Data);
+    let bucket = payload.bucket.filter(|bucket| !bucket.trim().is_empty());
+
+    if source == FoundrySource::S3 && bucket.is_none() {
+        return AppResponse::Error("A BUCKET IS REQUIRED FOR THE S3 SOURCE".to_owned());
+    }
+
+    let target_path = clean_target_path(
+        payload.target_path.as_deref().unwrap_or(DEFAULT_FOUNDRY_TARGET_PATH)
+    );
+
+    if target_path.is_none() {
+        return AppResponse::Error("INVALID FOUNDRY TARGET PATH".to_owned());
+    }
+
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    let res = client.execute(
+        "INSERT INTO foundry_connections (project_id, url, session, source, bucket, target_path)
+         VALUES ($1, $2, $3, $4, $5, $6)
+         ON CONFLICT (project_id) DO UPDATE
+         SET url = $2, session = $3, source = $4, bucket = $5, target_path = $6;",
+        &[
+            &project_id,
+            &url,
+            &payload.session.trim(),
+            &source,
+            &bucket.filter(|_| source == FoundrySource::S3),
+            &target_path.unwrap(),
+        ]
+    ).await;
+
+    if res.is_err() {
+        return AppResponse::Error(res.err().unwrap().to_string());
+    }
+
+    return AppResponse::Success(
+        "Foundry connection".to_owned(),
+        crate::enums::SuccessActions::Update
+    );
+}
+
+async fn delete_connection(
+    State(state): State<AppState>,
+    ExtractPath(project_id): ExtractPath<Uuid>
+) -> impl IntoResponse {
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    let res = client.execute(
+        "DELETE FROM foundry_connections WHERE project_id = $1;",
+        &[&project_id]
+    ).await;
+
+    if res.is_err() {
+        return AppResponse::Error(res.err().unwrap().to_string());
+    }
+
+    return AppResponse::Success(
+        "Foundry connection".to_owned(),
+        crate::enums::SuccessActions::Delete
+    );
+}
+
+// Marks the map images as pending and pushes them to the project's Foundry instance in the
+// background. Progress is read from the sync status route.
+async fn sync_maps(
+    State(state): State<AppState>,
+    ExtractPath(project_id): ExtractPath<Uuid>,
+    Json(payload): Json<FoundrySyncPayload>
+) -> impl IntoResponse {
+    if payload.ids.is_empty() || payload.ids.len() > MAX_FOUNDRY_SYNC {
+        return AppResponse::Error(format!("SYNC BETWEEN 1 AND {} MAPS AT ONCE", MAX_FOUNDRY_SYNC));
+    }
+
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    let connection = get_foundry_connection(&client, &project_id).await;
+
+    if connection.is_err() {
+        return connection.err().unwrap();
+    }
+
+    if connection.unwrap().is_none() {
+        return AppResponse::Error(format!("PROJECT HAS NO FOUNDRY CONNECTION - {}", project_id));
+    }
+
+    // Only what the Foundry module could pull as well is pushed
+    let rows = client.query(
+        &format!(
+            "INSERT INTO foundry_syncs (image_id, project_id, status, requested_at)
+             SELECT id, project_id, $4, NOW() FROM images
+             WHERE id = ANY($1) AND project_id = $2 AND type = $3 AND kind = 'image' AND pending = FALSE
+                AND deleted_at IS NULL AND {}
+             ON CONFLICT (image_id) DO UPDATE SET status = $4, error = NULL, requested_at = NOW()
+             RETURNING image_id;",
+            MODERATION_VISIBLE
+        ),
+        &[&payload.ids, &project_id, &ImageType::MapImages, &FoundrySyncStatus::Pending]
+    ).await;
+
+    if rows.is_err() {
+        return AppResponse::Error(rows.err().unwrap().to_string());
+    }
+
+    let queued: Vec<Uuid> = rows
+        .unwrap()
+        .iter()
+        .map(|row| row.get("image_id"))
+        .collect();
+    let skipped: Vec<&Uuid> = payload.ids
+        .iter()
+        .filter(|id| !queued.contains(id))
+        .collect();
+
+    if !queued.is_empty() {
+        state.tasks.spawn(run_foundry_sync(state.clone(), project_id, queued.clone()));
+    }
+
+    return AppResponse::SuccessData(
+        "Foundry sync".to_owned(),
+        crate::enums::SuccessActions::Create,
+        json!({ "queued": queued, "skipped": skipped })
+    );
+}
+
+async fn get_sync_status(
+    State(state): State<AppState>,
+    ExtractPath(project_id): ExtractPath<Uuid>
+) -> impl IntoResponse {
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    let rows = client.query(
+        "SELECT foundry_syncs.image_id, images.title, foundry_syncs.status, foundry_syncs.path, foundry_syncs.error,
+            (EXTRACT(EPOCH FROM foundry_syncs.synced_at) * 1000)::BIGINT AS synced_at
+         FROM foundry_syncs
+         JOIN images ON images.id = foundry_syncs.image_id
+         WHERE foundry_syncs.project_id = $1 AND images.deleted_at IS NULL
+         ORDER BY foundry_syncs.requested_at DESC;",
+        &[&project_id]
+    ).await;
+
+    if rows.is_err() {
+        return AppResponse::Error(rows.err().unwrap().to_string());
+    }
+
+    let items: Vec<serde_json::Value> = rows
+        .unwrap()
+        .iter()
+        .map(|row| {
+            let status: FoundrySyncStatus = row.get("status");
+            let title: Option<String> = row.get("title");
+            let path: Option<String> = row.get("path");
+            let error: Option<String> = row.get("error");
+            let synced_at: Option<i64> = row.get("synced_at");
+
+            json!({
+                "id": row.get::<_, Uuid>("image_id"),
+                "title": title,
+                "status": status,
+                "path": path,
+                "error": error,
+                "synced_at": synced_at,
+            })
+        })
+        .collect();
+
+    return AppResponse::SuccessData(
+        "Foundry sync".to_owned(),
+        crate::enums::SuccessActions::Read,
+        json!(items)
+    );
+}
+
+pub fn foundry_routes(state: AppState) -> Router<AppState> {
     let extension_cors = CorsLayer::new()
-        .allow_methods([Method::POST, Method::OPTIONS])
+        .allow_methods([Method::GET, Method::POST, Method::OPTIONS])
         .allow_headers([HeaderName::from_str("x-api-key").unwrap()])
-        .allow_origin(AllowOrigin::any());
+        .allow_origin(state.config.foundry_cors_origins.allow_origin());
     Router::new().nest(
         "/foundry",
         Router::new()
             .route("/:project_id/:image_type/:image_id", get(get_thumbnail))
+            .layer(from_fn_with_state(state.clone(), hotlink_middleware))
+            .route("/scenes", get(export_scenes))
+            .route("/scenes/:id", get(get_scene))
+            .route("/assets", get(pull_assets))
             .layer(extension_cors)
+            // Called from Arkive itself, the connection holds a Foundry session so only owners
+            // can see or use it
+            .merge(
+                Router::new()
+                    .route(
+                        "/connection/:project_id",
+                        get(get_connection).post(save_connection).delete(delete_connection)
+                    )
+                    .route("/sync/:project_id", post(sync_maps).get(get_sync_status))
+                    .layer(from_fn_with_state(state.clone(), owner_middleware))
+                    .layer(from_fn_with_state(state, tenant_middleware))
+            )
     )
 }
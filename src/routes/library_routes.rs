@@ -0,0 +1,583 @@
+use axum::{
+    extract::{ Query, State },
+    middleware::from_fn_with_state,
+    response::IntoResponse,
+    routing::{ delete, get, post },
+    Json,
+    Router,
+};
+use serde::Deserialize;
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::{
+    enums::{
+        AppResponse,
+        AssetJobOperation,
+        AssetKind,
+        AssetVisibility,
+        AuditAction,
+        DbAccess,
+        ImageType,
+        RequiredPermission,
+        WebhookEvent,
+    },
+    jobs::{
+        asset_job::{ enqueue_jobs, notify_job_worker, AssetJob },
+        moderation_job::MODERATION_VISIBLE,
+    },
+    services::audit_service::{ record_audit, AuditActor },
+    state::models::AppState,
+    storage::{ backend::CopyOptions, resolve_target, StorageTarget },
+    utils::{
+        asset_utils::{ asset_key, library_key },
+        auth_utils::{ check_project_owner, get_project_permissions },
+        db_utils::{ db_pool, get_client, get_project_usage },
+        dedup_utils::OBJECT_ID,
+        extractors::{ AuthenticatedUser, ExtractPath },
+        tenant_utils::tenant_middleware,
+        trash_utils::live_visibility,
+        webhook_utils::{ emit_asset_event, notify_webhook_worker },
+    },
+};
+
+const MAX_LICENSE_LENGTH: usize = 64;
+const MAX_ATTRIBUTION_LENGTH: usize = 512;
+
+#[derive(Deserialize)]
+struct PublishPayload {
+    ids: Vec<Uuid>,
+    // SPDX identifier or the name of the license, e.g. CC-BY-4.0
+    license: String,
+    // Who to credit, shown next to the asset wherever it's imported
+    attribution: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ImportPayload {
+    ids: Vec<Uuid>,
+}
+
+#[derive(Deserialize)]
+struct LibraryQuery {
+    // Id of the last asset of the previous page
+    cursor: Option<Uuid>,
+    limit: Option<i64>,
+    search: Option<String>,
+    kind: Option<AssetKind>,
+}
+
+// Cleans up objects copied for a request that did not go through
+async fn remove_keys(target: &StorageTarget, keys: &Vec<String>) {
+    for key in keys {
+        let del_res = target.delete(key).await;
+
+        if del_res.is_err() {
+            tracing::error!("ERROR REMOVING {} - {}", key, del_res.err().unwrap());
+        }
+    }
+}
+
+async fn list_library(
+    State(state): State<AppState>,
+    AuthenticatedUser(_claims): AuthenticatedUser,
+    query: Query<LibraryQuery>
+) -> impl IntoResponse {
+    let client = get_client(db_pool(&state, DbAccess::Read)).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    let limit = query.limit.unwrap_or(50).clamp(1, 100);
+    let search = query.search.as_ref().map(|search| search.trim().to_lowercase());
+
+    let rows = client.query(
+        "SELECT id, title, description, kind, mime_type, size_bytes, width, height, is_animated,
+            license, attribution, source_project_id,
+            (EXTRACT(EPOCH FROM created_at) * 1000)::BIGINT AS created_at
+         FROM library_images
+         WHERE ($1::TEXT IS NULL OR POSITION($1 IN LOWER(title)) > 0)
+            AND ($2::\"AssetKind\" IS NULL OR kind = $2)
+            AND ($3::UUID IS NULL OR (created_at, id) < (SELECT created_at, id FROM library_images WHERE id = $3))
+         ORDER BY created_at DESC, id DESC
+         LIMIT $4;",
+        &[&search, &query.kind, &query.cursor, &limit]
+    ).await;
+
+    if rows.is_err() {
+        return AppResponse::Error(rows.err().unwrap().to_string());
+    }
+
+    let rows = rows.unwrap();
+    let target = state.storage.default_target();
+
+    let next_cursor: Option<Uuid> = match rows.len() as i64 == limit {
+        true => rows.last().map(|row| row.get("id")),
+        false => None,
+    };
+
+    let items: Vec<serde_json::Value> = rows
+        .iter()
+        .map(|row| {
+            let id: Uuid = row.get("id");
+            let title: Option<String> = row.get("title");
+            let description: Option<String> = row.get("description");
+            let kind: AssetKind = row.get("kind");
+            let mime_type: String = row.get("mime_type");
+            let size_bytes: Option<i64> = row.get("size_bytes");
+            let width: Option<i32> = row.get("width");
+            let height: Option<i32> = row.get("height");
+            let is_animated: bool = row.get("is_animated");
+            let license: String = row.get("license");
+            let attribution: Option<String> = row.get("attribution");
+            let source_project_id: Uuid = row.get("source_project_id");
+            let created_at: i64 = row.get("created_at");
+
+            json!({
+                "id": id,
+                "title": title,
+                "description": description,
+                "kind": kind,
+                "mime_type": mime_type,
+                "size_bytes": size_bytes,
+                "width": width,
+                "height": height,
+                "is_animated": is_animated,
+                "license": license,
+                "attribution": attribution,
+                "source_project_id": source_project_id,
+                "url": target.public_url(&library_key(&id, &mime_type)),
+                "created_at": created_at,
+            })
+        })
+        .collect();
+
+    return AppResponse::SuccessData(
+        "Library".to_owned(),
+        crate::enums::SuccessActions::Read,
+        json!({ "items": items, "next_cursor": next_cursor })
+    );
+}
+
+// Copies assets of the caller's project into the library. The library copy is independent, later
+// changes to the project's assets don't affect it. Only project owners can publish.
+async fn publish_to_library(
+    State(state): State<AppState>,
+    AuthenticatedUser(claims): AuthenticatedUser,
+    Json(payload): Json<PublishPayload>
+) -> impl IntoResponse {
+    if payload.ids.is_empty() {
+        return AppResponse::Error("NO ASSETS TO PUBLISH".to_owned());
+    }
+
+    let license = payload.license.trim().to_owned();
+    let attribution = payload.attribution
+        .as_ref()
+        .map(|attribution| attribution.trim().to_owned())
+        .filter(|attribution| !attribution.is_empty());
+
+    if license.is_empty() || license.chars().count() > MAX_LICENSE_LENGTH {
+        return AppResponse::Error(format!("INVALID LICENSE - {}", license));
+    }
+
+    if
+        attribution
+            .as_ref()
+            .is_some_and(|attribution| attribution.chars().count() > MAX_ATTRIBUTION_LENGTH)
+    {
+        return AppResponse::Error("ATTRIBUTION IS TOO LONG".to_owned());
+    }
+
+    let is_owner = check_project_owner(&state, &claims).await;
+
+    if is_owner.is_err() {
+        return is_owner.err().unwrap();
+    }
+
+    if !is_owner.unwrap() {
+        return AppResponse::Auth;
+    }
+
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let mut client = client.unwrap();
+
+    // Held, pending and hidden assets aren't published
+    let rows = client.query(
+        &format!(
+            "SELECT id, type, kind, mime_type, {} FROM images
+             WHERE id = ANY($1) AND project_id = $2 AND deleted_at IS NULL AND awaiting_upload = FALSE
+                AND pending = FALSE AND {};",
+            OBJECT_ID,
+            MODERATION_VISIBLE
+        ),
+        &[&payload.ids, &claims.project_id]
+    ).await;
+
+    if rows.is_err() {
+        return AppResponse::Error(rows.err().unwrap().to_string());
+    }
+
+    let rows = rows.unwrap();
+
+    if rows.len() != payload.ids.len() {
+        return AppResponse::Error("SOME ASSETS DO NOT EXIST".to_owned());
+    }
+
+    let source_storage = resolve_target(&state, &claims.project_id).await;
+
+    if source_storage.is_err() {
+        return source_storage.err().unwrap();
+    }
+
+    let source_storage = source_storage.unwrap();
+    let library_storage = state.storage.default_target();
+
+    let mut published: Vec<(Uuid, Uuid)> = vec![];
+    let mut copied_keys: Vec<String> = vec![];
+
+    for row in rows.iter() {
+        let id: Uuid = row.get("id");
+        let image_type: ImageType = row.get("type");
+        let kind: AssetKind = row.get("kind");
+        let mime_type: String = row.get("mime_type");
+        let object_id: Uuid = row.get("object_id");
+        let library_id = Uuid::new_v4();
+
+        let key = library_key(&library_id, &mime_type);
+
+        let copy = library_storage.copy_from(
+            &source_storage,
+            &asset_key(&claims.project_id, &image_type, &kind, &object_id, &mime_type),
+            &key,
+            &CopyOptions { visibility: AssetVisibility::Public, content_type: None, cache_control: None }
+        ).await;
+
+        if copy.is_err() {
+            remove_keys(&library_storage, &copied_keys).await;
+            return AppResponse::Error(copy.err().unwrap());
+        }
+
+        copied_keys.push(key);
+        published.push((library_id, id));
+    }
+
+    let library_ids: Vec<Uuid> = published
+        .iter()
+        .map(|(library_id, _)| *library_id)
+        .collect();
+    let source_ids: Vec<Uuid> = published
+        .iter()
+        .map(|(_, source_id)| *source_id)
+        .collect();
+
+    let transaction = client.transaction().await;
+
+    if transaction.is_err() {
+        remove_keys(&library_storage, &copied_keys).await;
+        return AppResponse::Error(transaction.err().unwrap().to_string());
+    }
+    let transaction = transaction.unwrap();
+
+    let res = transaction.execute(
+        "INSERT INTO library_images (id, source_image_id, source_project_id, published_by, title, description,
+            kind, mime_type, size_bytes, width, height, original_format, is_animated, content_hash, license, attribution)
+         SELECT copies.id, images.id, images.project_id, $3, title, description,
+            kind, mime_type, size_bytes, width, height, original_format, is_animated, content_hash, $4, $5
+         FROM UNNEST($1::UUID[], $2::UUID[]) AS copies (id, source_id)
+         JOIN images ON images.id = copies.source_id;",
+        &[&library_ids, &source_ids, &claims.user_id, &license, &attribution]
+    ).await;
+
+    if res.is_err() {
+        remove_keys(&library_storage, &copied_keys).await;
+        return AppResponse::Error(res.err().unwrap().to_string());
+    }
+
+    let committed = transaction.commit().await;
+
+    if committed.is_err() {
+        remove_keys(&library_storage, &copied_keys).await;
+        return AppResponse::Error(committed.err().unwrap().to_string());
+    }
+
+    let items: Vec<serde_json::Value> = published
+        .iter()
+        .map(|(id, source_id)| json!({ "id": id, "source_id": source_id }))
+        .collect();
+
+    return AppResponse::SuccessData(
+        "Library".to_owned(),
+        crate::enums::SuccessActions::Create,
+        json!(items)
+    );
+}
+
+// Copies library assets into the project as regular assets. The new rows keep a reference to
+// the library asset, so its license and attribution can be looked up.
+async fn import_from_library(
+    State(state): State<AppState>,
+    AuthenticatedUser(claims): AuthenticatedUser,
+    ExtractPath((project_id, image_type)): ExtractPath<(Uuid, ImageType)>,
+    Json(payload): Json<ImportPayload>
+) -> impl IntoResponse {
+    if payload.ids.is_empty() {
+        return AppResponse::Error("NO ASSETS TO IMPORT".to_owned());
+    }
+
+    let permissions = get_project_permissions(
+        &state,
+        &claims.user_id,
+        &project_id,
+        RequiredPermission::Upload.name()
+    ).await;
+
+    if permissions.is_err() {
+        return permissions.err().unwrap();
+    }
+
+    let permissions = permissions.unwrap();
+
+    if !permissions.is_project_owner && permissions.permission_id.is_none() {
+        return AppResponse::Auth;
+    }
+
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let mut client = client.unwrap();
+
+    let rows = client.query(
+        "SELECT id, kind, mime_type, size_bytes FROM library_images WHERE id = ANY($1);",
+        &[&payload.ids]
+    ).await;
+
+    if rows.is_err() {
+        return AppResponse::Error(rows.err().unwrap().to_string());
+    }
+
+    let rows = rows.unwrap();
+
+    if rows.len() != payload.ids.len() {
+        return AppResponse::Error("SOME LIBRARY ASSETS DO NOT EXIST".to_owned());
+    }
+
+    let size_bytes: i64 = rows
+        .iter()
+        .map(|row| row.get::<_, Option<i64>>("size_bytes").unwrap_or(0))
+        .sum();
+
+    let usage = get_project_usage(&state, &project_id).await;
+
+    if usage.is_err() {
+        return usage.err().unwrap();
+    }
+
+    let usage = usage.unwrap();
+
+    if usage.bytes_stored + size_bytes > usage.quota_bytes {
+        return AppResponse::Error(format!("STORAGE QUOTA EXCEEDED FOR PROJECT {}", &project_id));
+    }
+
+    let require_approval = client.query_opt(
+        "SELECT require_upload_approval FROM projects WHERE id = $1;",
+        &[&project_id]
+    ).await;
+
+    if require_approval.is_err() {
+        return AppResponse::Error(require_approval.err().unwrap().to_string());
+    }
+
+    let require_approval: Option<bool> = require_approval
+        .unwrap()
+        .and_then(|row| row.get("require_upload_approval"));
+
+    let pending = require_approval.unwrap_or(false) && !permissions.is_project_owner;
+    let visibility = live_visibility(&state, &project_id, pending).await;
+
+    if visibility.is_err() {
+        return visibility.err().unwrap();
+    }
+
+    let visibility = visibility.unwrap();
+    let library_storage = state.storage.default_target();
+    let target_storage = resolve_target(&state, &project_id).await;
+
+    if target_storage.is_err() {
+        return target_storage.err().unwrap();
+    }
+
+    let target_storage = target_storage.unwrap();
+
+    let mut imported: Vec<(Uuid, Uuid)> = vec![];
+    let mut copied_keys: Vec<String> = vec![];
+
+    for row in rows.iter() {
+        let library_id: Uuid = row.get("id");
+        let kind: AssetKind = row.get("kind");
+        let mime_type: String = row.get("mime_type");
+        let id = Uuid::new_v4();
+
+        let key = asset_key(&project_id, &image_type, &kind, &id, &mime_type);
+
+        let copy = target_storage.copy_from(
+            &library_storage,
+            &library_key(&library_id, &mime_type),
+            &key,
+            &CopyOptions { visibility, content_type: None, cache_control: None }
+        ).await;
+
+        if copy.is_err() {
+            remove_keys(&target_storage, &copied_keys).await;
+            return AppResponse::Error(copy.err().unwrap());
+        }
+
+        copied_keys.push(key);
+        imported.push((id, library_id));
+    }
+
+    let new_ids: Vec<Uuid> = imported
+        .iter()
+        .map(|(id, _)| *id)
+        .collect();
+    let library_ids: Vec<Uuid> = imported
+        .iter()
+        .map(|(_, library_id)| *library_id)
+        .collect();
+
+    let transaction = client.transaction().await;
+
+    if transaction.is_err() {
+        remove_keys(&target_storage, &copied_keys).await;
+        return AppResponse::Error(transaction.err().unwrap().to_string());
+    }
+    let transaction = transaction.unwrap();
+
+    let res = transaction.execute(
+        "INSERT INTO images (id, title, description, project_id, type, owner_id, size_bytes, pending, kind,
+            mime_type, width, height, original_format, is_animated, content_hash, library_id)
+         SELECT copies.id, title, description, $3, $4, $5, size_bytes, $6, kind,
+            mime_type, width, height, original_format, is_animated, content_hash, library_images.id
+         FROM UNNEST($1::UUID[], $2::UUID[]) AS copies (id, library_id)
+         JOIN library_images ON library_images.id = copies.library_id;",
+        &[&new_ids, &library_ids, &project_id, &image_type, &claims.user_id, &pending]
+    ).await;
+
+    if res.is_err() {
+        remove_keys(&target_storage, &copied_keys).await;
+        return AppResponse::Error(res.err().unwrap().to_string());
+    }
+
+    let committed = transaction.commit().await;
+
+    if committed.is_err() {
+        remove_keys(&target_storage, &copied_keys).await;
+        return AppResponse::Error(committed.err().unwrap().to_string());
+    }
+
+    record_audit(
+        &client,
+        &AuditActor::user(&claims),
+        &project_id,
+        AuditAction::Upload,
+        &new_ids
+    ).await;
+
+    for id in new_ids.iter() {
+        emit_asset_event(&state, &client, WebhookEvent::Uploaded, id).await;
+    }
+
+    notify_webhook_worker(&state);
+
+    let items: Vec<serde_json::Value> = imported
+        .iter()
+        .map(|(id, library_id)| json!({ "id": id, "library_id": library_id }))
+        .collect();
+
+    return AppResponse::SuccessData(
+        "Images".to_owned(),
+        crate::enums::SuccessActions::Create,
+        json!(items)
+    );
+}
+
+// Only the owners of the project an asset was published from can take it down. Copies
+// already imported into projects stay.
+async fn unpublish_from_library(
+    State(state): State<AppState>,
+    AuthenticatedUser(claims): AuthenticatedUser,
+    ExtractPath(id): ExtractPath<Uuid>
+) -> impl IntoResponse {
+    let is_owner = check_project_owner(&state, &claims).await;
+
+    if is_owner.is_err() {
+        return is_owner.err().unwrap();
+    }
+
+    if !is_owner.unwrap() {
+        return AppResponse::Auth;
+    }
+
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    let row = client.query_opt(
+        "DELETE FROM library_images WHERE id = $1 AND source_project_id = $2 RETURNING mime_type;",
+        &[&id, &claims.project_id]
+    ).await;
+
+    if row.is_err() {
+        return AppResponse::Error(row.err().unwrap().to_string());
+    }
+
+    let row = row.unwrap();
+
+    if row.is_none() {
+        return AppResponse::Error(format!("NO LIBRARY ASSET - {}", id));
+    }
+
+    let mime_type: String = row.unwrap().get("mime_type");
+
+    let enqueued = enqueue_jobs(
+        &client,
+        &[
+            AssetJob {
+                operation: AssetJobOperation::DeleteObject,
+                target: library_key(&id, &mime_type),
+            },
+        ]
+    ).await;
+
+    if enqueued.is_err() {
+        return enqueued.err().unwrap();
+    }
+
+    notify_job_worker(&state);
+
+    return AppResponse::Success("Library asset".to_owned(), crate::enums::SuccessActions::Delete);
+}
+
+pub fn library_routes(state: AppState) -> Router<AppState> {
+    Router::new().nest(
+        "/library",
+        Router::new()
+            .route("/", get(list_library))
+            .route("/publish", post(publish_to_library))
+            .route("/:id", delete(unpublish_from_library))
+            .route(
+                "/import/:project_id/:image_type",
+                post(import_from_library).layer(from_fn_with_state(state, tenant_middleware))
+            )
+    )
+}
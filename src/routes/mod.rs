@@ -3,3 +3,14 @@ pub mod thumbnail_routes;
 pub mod upload_routes;
 pub mod extension_routes;
 pub mod foundry_routes;
+pub mod placeholder_routes;
+pub mod public_routes;
+pub mod admin_routes;
+pub mod user_routes;
+pub mod domain_routes;
+pub mod webhook_routes;
+pub mod storage_routes;
+pub mod openapi_routes;
+pub mod api_key_routes;
+pub mod collection_routes;
+pub mod library_routes;
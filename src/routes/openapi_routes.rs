@@ -0,0 +1,37 @@
+use axum::{ routing::get, Json, Router };
+use utoipa::OpenApi;
+
+use crate::{
+    enums::ResponsePayload,
+    routes::{ crud_routes::CrudApi, upload_routes::UploadApi },
+    state::models::AppState,
+};
+
+// Routes are documented next to their handlers, this only adds what they share. Every route
+// answers with a ResponsePayload, `data` holds the entity described by the route.
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "Arkive asset service"),
+    components(schemas(ResponsePayload)),
+    tags(
+        (name = "assets", description = "Reading, updating and deleting assets"),
+        (name = "uploads", description = "Multipart, presigned and resumable uploads")
+    )
+)]
+struct ApiDoc;
+
+fn openapi_document() -> utoipa::openapi::OpenApi {
+    let mut document = ApiDoc::openapi();
+
+    document.merge(CrudApi::openapi());
+    document.merge(UploadApi::openapi());
+
+    document
+}
+
+pub fn openapi_routes() -> Router<AppState> {
+    // Built from the derives at startup, it can't change while the service runs
+    let document = openapi_document();
+
+    Router::new().route("/openapi.json", get(move || async move { Json(document) }))
+}
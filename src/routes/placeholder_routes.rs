@@ -0,0 +1,41 @@
+use axum::{
+    extract::Query,
+    http::HeaderValue,
+    response::IntoResponse,
+    routing::get,
+    Router,
+};
+use reqwest::{ header::{ CACHE_CONTROL, CONTENT_TYPE }, StatusCode };
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::{
+    enums::PlaceholderKind,
+    state::models::AppState,
+    utils::{ extractors::ExtractPath, placeholder_utils::generate_placeholder_svg },
+};
+
+#[derive(Deserialize)]
+struct PlaceholderQuery {
+    name: Option<String>,
+}
+
+async fn get_placeholder(
+    query: Query<PlaceholderQuery>,
+    ExtractPath((kind, id)): ExtractPath<(PlaceholderKind, Uuid)>
+) -> impl IntoResponse {
+    let svg = generate_placeholder_svg(&kind, &id, query.name.as_deref());
+
+    return (
+        StatusCode::OK,
+        [
+            (CONTENT_TYPE, HeaderValue::from_str("image/svg+xml").unwrap()),
+            (CACHE_CONTROL, HeaderValue::from_str("max-age=86400").unwrap()),
+        ],
+        svg,
+    );
+}
+
+pub fn placeholder_routes() -> Router<AppState> {
+    Router::new().nest("/placeholder", Router::new().route("/:kind/:id", get(get_placeholder)))
+}
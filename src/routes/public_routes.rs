@@ -0,0 +1,341 @@
+use std::{ io::Cursor, str::FromStr };
+
+use axum::{
+    extract::{ Query, State },
+    http::HeaderValue,
+    response::{ IntoResponse, Response },
+    routing::get,
+    Json,
+    Router,
+};
+use image::ImageReader;
+use reqwest::{ header::{ CACHE_CONTROL, CONTENT_TYPE }, StatusCode };
+use serde::Deserialize;
+use serde_json::json;
+use url::Url;
+use uuid::Uuid;
+
+use crate::{
+    enums::{ AppResponse, DbAccess, ImageType },
+    jobs::{ moderation_job::MODERATION_VISIBLE, sitemap_job::sitemap_key },
+    state::models::AppState,
+    storage::{ resolve_target, StorageTarget },
+    utils::{
+        asset_utils::image_key,
+        db_utils::{ db_pool, get_client },
+        dedup_utils::OBJECT_ID,
+        domain_utils::{ asset_url, get_custom_domain, thumbnail_url },
+        extractors::ExtractPath,
+    },
+};
+
+const GALLERY_THUMBNAIL_SIZE: usize = 320;
+const GALLERY_MAX_LIMIT: i64 = 100;
+
+#[derive(Deserialize)]
+struct OEmbedQuery {
+    url: String,
+    maxwidth: Option<usize>,
+    maxheight: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct GalleryQuery {
+    page: Option<i64>,
+    limit: Option<i64>,
+}
+
+async fn get_public_gallery(
+    State(state): State<AppState>,
+    query: Query<GalleryQuery>,
+    ExtractPath(project_id): ExtractPath<Uuid>
+) -> impl IntoResponse {
+    let client = get_client(db_pool(&state, DbAccess::Read)).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    let project = client.query_opt(
+        "SELECT id FROM projects WHERE id = $1 AND is_public = TRUE;",
+        &[&project_id]
+    ).await;
+
+    if project.is_err() {
+        return AppResponse::Error(project.err().unwrap().to_string());
+    }
+
+    if project.unwrap().is_none() {
+        return AppResponse::Auth;
+    }
+
+    let domain = get_custom_domain(&client, &project_id).await;
+    let target = resolve_target(&state, &project_id).await;
+
+    if target.is_err() {
+        return target.err().unwrap();
+    }
+
+    let target = target.unwrap();
+
+    let limit = query.limit.unwrap_or(50).clamp(1, GALLERY_MAX_LIMIT);
+    let page = query.page.unwrap_or(0).max(0);
+
+    let rows = client.query(
+        &format!(
+            "SELECT id, title, description, type, mime_type, {} FROM images
+             WHERE project_id = $1 AND is_public = TRUE AND pending = FALSE AND deleted_at IS NULL
+                AND {}
+             ORDER BY created_at DESC, id
+             LIMIT $2 OFFSET $3;",
+            OBJECT_ID,
+            MODERATION_VISIBLE
+        ),
+        &[&project_id, &limit, &(page * limit)]
+    ).await;
+
+    if rows.is_err() {
+        return AppResponse::Error(rows.err().unwrap().to_string());
+    }
+
+    let items: Vec<serde_json::Value> = rows
+        .unwrap()
+        .iter()
+        .map(|row| {
+            let id: Uuid = row.get("id");
+            let title: Option<String> = row.get("title");
+            let description: Option<String> = row.get("description");
+            let image_type: ImageType = row.get("type");
+            let mime_type: String = row.get("mime_type");
+            let object_id: Uuid = row.get("object_id");
+
+            json!({
+                "id": id,
+                "title": title,
+                "alt": description.or(title.clone()).unwrap_or_default(),
+                "type": image_type.to_string(),
+                "thumbnail_url": thumbnail_url(
+                    &state,
+                    &target,
+                    domain.as_deref(),
+                    &project_id,
+                    &image_type,
+                    &object_id,
+                    &mime_type,
+                    GALLERY_THUMBNAIL_SIZE,
+                    GALLERY_THUMBNAIL_SIZE
+                ),
+            })
+        })
+        .collect();
+
+    return AppResponse::SuccessData(
+        "Gallery".to_owned(),
+        crate::enums::SuccessActions::Read,
+        json!({ "page": page, "limit": limit, "items": items })
+    );
+}
+
+// Accepts both direct storage URLs (.../assets/:project_id/:image_type/:id.<webp|avif>)
+// and thumbnail route URLs (.../:project_id/:image_type/:id).
+fn parse_asset_url(url: &str) -> Option<(Uuid, ImageType, Uuid)> {
+    let url = Url::parse(url).ok()?;
+    let segments: Vec<&str> = url.path_segments()?.filter(|s| !s.is_empty()).collect();
+
+    if segments.len() < 3 {
+        return None;
+    }
+
+    let tail = &segments[segments.len() - 3..];
+    let project_id = Uuid::from_str(tail[0]).ok()?;
+    let image_type: ImageType = serde_json::from_value(json!(tail[1])).ok()?;
+    let id = Uuid::from_str(tail[2].split('.').next()?).ok()?;
+
+    Some((project_id, image_type, id))
+}
+
+// The image header is enough to read the dimensions without fetching the whole object
+async fn read_header_dimensions(
+    target: &StorageTarget,
+    key: &str
+) -> Result<Option<(u32, u32)>, AppResponse> {
+    let header = target.get_range(key, 1024).await;
+
+    if header.is_err() {
+        return Err(AppResponse::Error(header.err().unwrap()));
+    }
+
+    Ok(
+        ImageReader::new(Cursor::new(header.unwrap()))
+            .with_guessed_format()
+            .ok()
+            .and_then(|reader| reader.into_dimensions().ok())
+    )
+}
+
+async fn get_oembed(State(state): State<AppState>, query: Query<OEmbedQuery>) -> Response {
+    let parsed = parse_asset_url(&query.url);
+
+    if parsed.is_none() {
+        return (StatusCode::NOT_FOUND, "Not Found").into_response();
+    }
+
+    let (project_id, image_type, id) = parsed.unwrap();
+
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap().into_response();
+    }
+    let client = client.unwrap();
+
+    let image = client.query_opt(
+        &format!(
+            "SELECT images.title, images.mime_type, images.width, images.height,
+                COALESCE(images.object_id, images.id) AS object_id
+             FROM images
+             JOIN projects ON projects.id = images.project_id
+             WHERE (images.id = $1 OR images.object_id = $1) AND images.project_id = $2 AND images.type = $3
+                AND images.is_public = TRUE AND images.pending = FALSE AND images.deleted_at IS NULL
+                AND projects.is_public = TRUE AND {}
+             LIMIT 1;",
+            MODERATION_VISIBLE
+        ),
+        &[&id, &project_id, &image_type]
+    ).await;
+
+    if image.is_err() {
+        return AppResponse::Error(image.err().unwrap().to_string()).into_response();
+    }
+
+    let image = image.unwrap();
+
+    if image.is_none() {
+        return (StatusCode::NOT_FOUND, "Not Found").into_response();
+    }
+
+    let image = image.unwrap();
+    let title: Option<String> = image.get("title");
+    let mime_type: String = image.get("mime_type");
+    let width: Option<i32> = image.get("width");
+    let height: Option<i32> = image.get("height");
+    let object_id: Uuid = image.get("object_id");
+    let domain = get_custom_domain(&client, &project_id).await;
+    let key = image_key(&project_id, &image_type, &object_id, &mime_type);
+    let target = resolve_target(&state, &project_id).await;
+
+    if target.is_err() {
+        return target.err().unwrap().into_response();
+    }
+
+    let target = target.unwrap();
+
+    // Dimensions are recorded on upload, older assets fall back to reading the header
+    let dimensions = match (width, height) {
+        (Some(width), Some(height)) => Some((width as u32, height as u32)),
+        _ => {
+            let dimensions = read_header_dimensions(&target, &key).await;
+
+            if dimensions.is_err() {
+                return dimensions.err().unwrap().into_response();
+            }
+
+            dimensions.unwrap()
+        }
+    };
+
+    if dimensions.is_none() {
+        return AppResponse::Error(format!("COULD NOT READ DIMENSIONS - {}", &key)).into_response();
+    }
+
+    let (width, height) = dimensions.unwrap();
+
+    let thumbnail_width = query.maxwidth.unwrap_or(GALLERY_THUMBNAIL_SIZE);
+    let thumbnail_height = query.maxheight.unwrap_or(GALLERY_THUMBNAIL_SIZE);
+
+    return Json(
+        json!({
+            "version": "1.0",
+            "type": "photo",
+            "provider_name": "Arkive",
+            "title": title.unwrap_or_default(),
+            "url": asset_url(
+                &target,
+                domain.as_deref(),
+                &project_id,
+                &image_type,
+                &object_id,
+                &mime_type
+            ),
+            "width": width,
+            "height": height,
+            "thumbnail_url": thumbnail_url(
+                &state,
+                &target,
+                domain.as_deref(),
+                &project_id,
+                &image_type,
+                &object_id,
+                &mime_type,
+                thumbnail_width,
+                thumbnail_height
+            ),
+            "thumbnail_width": thumbnail_width,
+            "thumbnail_height": thumbnail_height,
+        })
+    ).into_response();
+}
+
+// The job removes sitemaps of projects that went private, until it runs the stored one could
+// still be around
+async fn get_public_sitemap(
+    State(state): State<AppState>,
+    ExtractPath(project_id): ExtractPath<Uuid>
+) -> Response {
+    let client = get_client(db_pool(&state, DbAccess::Read)).await;
+
+    if client.is_err() {
+        return client.err().unwrap().into_response();
+    }
+    let client = client.unwrap();
+
+    let project = client.query_opt(
+        "SELECT id FROM projects WHERE id = $1 AND is_public = TRUE;",
+        &[&project_id]
+    ).await;
+
+    if project.is_err() {
+        return AppResponse::Error(project.err().unwrap().to_string()).into_response();
+    }
+
+    if project.unwrap().is_none() {
+        return (StatusCode::NOT_FOUND, "Not Found").into_response();
+    }
+
+    let target = state.storage.default_target();
+
+    let data = target.get(&sitemap_key(&project_id)).await;
+
+    if data.is_err() {
+        return (StatusCode::NOT_FOUND, "Not Found").into_response();
+    }
+
+    return (
+        StatusCode::OK,
+        [
+            (CONTENT_TYPE, HeaderValue::from_str("application/xml").unwrap()),
+            (CACHE_CONTROL, HeaderValue::from_str("max-age=3600").unwrap()),
+        ],
+        data.unwrap(),
+    ).into_response();
+}
+
+pub fn public_routes() -> Router<AppState> {
+    Router::new().route("/oembed", get(get_oembed)).nest(
+        "/public",
+        Router::new()
+            .route("/gallery/:project_id", get(get_public_gallery))
+            .route("/sitemap/:project_id", get(get_public_sitemap))
+    )
+}
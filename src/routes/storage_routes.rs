@@ -0,0 +1,113 @@
+use axum::{
+    body::Bytes,
+    extract::{ DefaultBodyLimit, Query, State },
+    http::HeaderValue,
+    middleware::from_fn_with_state,
+    response::{ IntoResponse, Response },
+    routing::get,
+    Router,
+};
+use reqwest::{ header::{ CACHE_CONTROL, CONTENT_TYPE }, StatusCode };
+
+use crate::{
+    enums::{ AppResponse, AssetVisibility },
+    state::models::AppState,
+    storage::{ backend::{ PutOptions, StorageBackend }, local::SignedQuery },
+    utils::{ extractors::ExtractPath, maintenance_utils::maintenance_middleware },
+    MAX_FILE_SIZE,
+};
+
+// Stands in for the bucket endpoints when objects are on local disk. Public objects are served
+// as is, private ones and uploads need a URL signed by LocalBackend.
+async fn serve_object(
+    State(state): State<AppState>,
+    query: Option<Query<SignedQuery>>,
+    ExtractPath((bucket, key)): ExtractPath<(String, String)>
+) -> Response {
+    let backend = state.storage.local_backend();
+
+    if backend.is_none() {
+        return (StatusCode::NOT_FOUND, "Not Found").into_response();
+    }
+
+    let backend = backend.unwrap();
+    let metadata = backend.read_metadata(&bucket, &key).await;
+
+    if metadata.is_err() {
+        return (StatusCode::NOT_FOUND, "Not Found").into_response();
+    }
+
+    let metadata = metadata.unwrap();
+    let signed = query.is_some_and(|query| backend.verify("GET", &bucket, &key, &query));
+
+    // Same answer as for a missing object, so private keys can't be probed
+    if !metadata.public && !signed {
+        return (StatusCode::NOT_FOUND, "Not Found").into_response();
+    }
+
+    let data = backend.get(&bucket, &key).await;
+
+    if data.is_err() {
+        return AppResponse::Error(data.err().unwrap()).into_response();
+    }
+
+    let content_type = HeaderValue::from_str(&metadata.content_type);
+    let cache_control = HeaderValue::from_str(&metadata.cache_control);
+
+    if content_type.is_err() || cache_control.is_err() {
+        return AppResponse::Error(format!("INVALID STORED HEADERS - {}", key)).into_response();
+    }
+
+    return (
+        StatusCode::OK,
+        [
+            (CONTENT_TYPE, content_type.unwrap()),
+            (CACHE_CONTROL, cache_control.unwrap()),
+        ],
+        data.unwrap(),
+    ).into_response();
+}
+
+async fn receive_object(
+    State(state): State<AppState>,
+    Query(query): Query<SignedQuery>,
+    ExtractPath((bucket, key)): ExtractPath<(String, String)>,
+    body: Bytes
+) -> Response {
+    let backend = state.storage.local_backend();
+
+    if backend.is_none() {
+        return (StatusCode::NOT_FOUND, "Not Found").into_response();
+    }
+
+    let backend = backend.unwrap();
+
+    if !backend.verify("PUT", &bucket, &key, &query) {
+        return AppResponse::Unauthorized.into_response();
+    }
+
+    // Presigned uploads are always private until confirmed, like their S3 counterparts
+    let res = backend.put(&bucket, &key, body.to_vec(), &PutOptions {
+        content_type: query.content_type.as_deref().unwrap_or("application/octet-stream"),
+        cache_control: query.cache_control.as_deref().unwrap_or_default(),
+        visibility: AssetVisibility::Private,
+    }).await;
+
+    if res.is_err() {
+        return AppResponse::Error(res.err().unwrap()).into_response();
+    }
+
+    return StatusCode::OK.into_response();
+}
+
+pub fn storage_routes(state: AppState) -> Router<AppState> {
+    Router::new()
+        .route(
+            "/storage/:bucket/*key",
+            get(serve_object)
+                .put(receive_object)
+                // Presigned uploads to local storage end up here
+                .layer(from_fn_with_state(state, maintenance_middleware))
+        )
+        .layer(DefaultBodyLimit::max(MAX_FILE_SIZE))
+}
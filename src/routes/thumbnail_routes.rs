@@ -1,87 +1,934 @@
-use aws_sdk_s3::presigning::PresigningConfig;
+use std::time::{ SystemTime, UNIX_EPOCH };
+
 use axum::{
+    body::{ Body, Bytes },
     extract::{ Query, State },
-    http::HeaderValue,
-    response::IntoResponse,
-    routing::get,
+    http::{ HeaderMap, HeaderValue },
+    middleware::from_fn_with_state,
+    response::{ IntoResponse, Response },
+    routing::{ get, post },
     Router,
 };
 use axum_macros::debug_handler;
-use base64::prelude::*;
-use hmac::{ Hmac, Mac };
-use reqwest::{ header::{ CACHE_CONTROL, CONTENT_TYPE }, StatusCode };
+use reqwest::{
+    header::{
+        ACCEPT_RANGES,
+        CACHE_CONTROL,
+        CONTENT_LENGTH,
+        CONTENT_RANGE,
+        CONTENT_TYPE,
+        ETAG,
+        IF_NONE_MATCH,
+        IF_RANGE,
+        RANGE,
+    },
+    StatusCode,
+};
 use serde::Deserialize;
-use sha2::Sha512;
+use serde_json::{ json, Value };
+use sha2::{ Digest, Sha256 };
 use uuid::Uuid;
 
 use crate::{
-    enums::ImageType,
+    enums::{ AppResponse, AssetVisibility, DbAccess, ImageType },
+    jobs::{
+        acl_job::get_asset_visibility,
+        moderation_job::is_hidden_by_moderation,
+        prewarm_job::run_thumbnail_prewarm,
+        view_count_job::record_view,
+    },
     state::models::AppState,
-    utils::extractors::ExtractPath,
+    storage::{ resolve_target, StorageTarget },
+    utils::{
+        db_utils::{ db_pool, get_client },
+        dedup_utils::{ resolve_object, StoredObject, OBJECT_ID },
+        domain_utils::{ asset_url, get_custom_domain, thumbnail_url },
+        extractors::ExtractPath,
+        hotlink_utils::hotlink_middleware,
+        tenant_utils::{ entity_project_middleware, tenant_middleware },
+        thumbnail_utils::{
+            check_thumbnail_callback,
+            get_or_create_thumbnail,
+            snap_thumbnail_size,
+            srcset,
+            srcset_sizes,
+            thumbnail_key,
+            thumbnail_prefix,
+            thumbnail_service_available,
+            SRCSET_MAX_WIDTHS,
+            SRCSET_WIDTHS,
+            THUMBNAIL_PRESETS,
+        },
+        variant_utils::{ find_variant, variant_prefix },
+    },
     PRESIGN_DURATION,
 };
 
-type HmacSha512 = Hmac<Sha512>;
-
 #[derive(Deserialize)]
 struct ThumbnailDimensions {
     width: Option<usize>,
     height: Option<usize>,
 }
 
+// Thumbnail URLs only change with the asset, the requested size or the signing secret, so
+// clients revalidating one they already have get a 304. Presigned URLs expire, their tags roll
+// over every max-age window so a cached one is never reused past its expiry.
+fn url_response(
+    state: &AppState,
+    headers: &HeaderMap,
+    source: &str,
+    url: String,
+    presigned: bool
+) -> Response {
+    let max_age = match presigned {
+        true => state.config.thumbnail_max_age.min(PRESIGN_DURATION.as_secs()),
+        false => state.config.thumbnail_max_age,
+    };
+    let window = match presigned {
+        true =>
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |now| now.as_secs() / max_age.max(1)),
+        false => 0,
+    };
+
+    let etag = format!(
+        "\"{:x}\"",
+        Sha256::digest(
+            format!("{}/{}/{}", source, state.config.thumbnail_secret_version, window).as_bytes()
+        )
+    );
+    let cache_control = format!("max-age={}", max_age);
+
+    let if_none_match = headers.get(IF_NONE_MATCH).and_then(|value| value.to_str().ok());
+
+    if if_none_match.is_some_and(|value| value.split(',').any(|tag| tag.trim() == etag)) {
+        return (
+            StatusCode::NOT_MODIFIED,
+            [(ETAG, etag), (CACHE_CONTROL, cache_control)],
+        ).into_response();
+    }
+
+    return (
+        StatusCode::OK,
+        [
+            (CONTENT_TYPE, "text/plain".to_owned()),
+            (CACHE_CONTROL, cache_control),
+            (ETAG, etag),
+        ],
+        url,
+    ).into_response();
+}
+
+// Presigned URL of a thumbnail resized here, generated on first request
+async fn local_thumbnail_url(
+    state: &AppState,
+    target: &StorageTarget,
+    project_id: &Uuid,
+    image_type: &ImageType,
+    object: &StoredObject,
+    width: usize,
+    height: usize
+) -> Result<String, AppResponse> {
+    let (width, height) = snap_thumbnail_size(width, height);
+    let key = thumbnail_key(project_id, image_type, &object.id, width, height);
+
+    if target.head(&key).await.is_err() {
+        get_or_create_thumbnail(
+            state,
+            target,
+            project_id,
+            image_type,
+            &object.id,
+            &object.mime_type,
+            width,
+            height
+        ).await?;
+    }
+
+    let url = target.presign_get(&key, PRESIGN_DURATION).await;
+
+    if url.is_err() {
+        return Err(AppResponse::Error(url.err().unwrap()));
+    }
+
+    Ok(url.unwrap())
+}
+
 #[debug_handler]
 async fn get_thumbnail(
     State(state): State<AppState>,
     query: Query<ThumbnailDimensions>,
+    headers: HeaderMap,
     ExtractPath((project_id, image_type, image_id)): ExtractPath<(Uuid, ImageType, Uuid)>
 ) -> impl IntoResponse {
-    if query.width.is_some() && query.height.is_some() {
-        let mut hmac = HmacSha512::new_from_slice(&state.thumbnail_secret.as_bytes()).unwrap();
-        let sized_url = format!(
-            "{}x{}/assets/{}/{}/{}.webp",
-            query.width.unwrap(),
-            query.height.unwrap(),
-            &project_id,
-            &image_type,
-            &image_id
-        );
-        hmac.update(&sized_url.as_bytes());
+    // Deduplicated assets are stored under the id of the asset they share content with.
+    // Without the database, moderation can't be checked, so moderated projects serve nothing.
+    let (domain, object, hidden, variant, visibility) = match get_client(db_pool(&state, DbAccess::Read)).await {
+        Ok(client) => {
+            let object = resolve_object(&client, &image_id).await.unwrap_or(
+                StoredObject::unresolved(&image_id)
+            );
+            let variant = match (query.width, query.height) {
+                (Some(width), Some(height)) =>
+                    find_variant(&client, &object.id, width, height).await.unwrap_or(None),
+                _ => None,
+            };
+
+            (
+                get_custom_domain(&client, &project_id).await,
+                object,
+                is_hidden_by_moderation(&client, &image_id).await.unwrap_or(true),
+                variant,
+                get_asset_visibility(&client, &project_id, &image_id).await.unwrap_or(
+                    AssetVisibility::Private
+                ),
+            )
+        }
+        Err(_) =>
+            (
+                None,
+                StoredObject::unresolved(&image_id),
+                state.moderation.is_some(),
+                None,
+                AssetVisibility::Private,
+            ),
+    };
 
-        let res = hmac.finalize().into_bytes();
+    if hidden {
+        return (
+            StatusCode::NOT_FOUND,
+            [
+                (CONTENT_TYPE, HeaderValue::from_str("text/plain").unwrap()),
+                (CACHE_CONTROL, HeaderValue::from_str("no-store").unwrap()),
+            ],
+            "NOT FOUND".to_owned(),
+        ).into_response();
+    }
+
+    record_view(&state, image_id);
 
-        let base_64 = BASE64_STANDARD.encode(res).replace('+', "-").replace('/', "_");
+    let target = resolve_target(&state, &project_id).await;
 
-        let url = format!("{}/{}/{}", &state.thumbnail_service_url, &base_64, &sized_url);
+    if target.is_err() {
+        tracing::error!("{:?}", target.err().unwrap());
 
         return (
-            StatusCode::OK,
+            StatusCode::INTERNAL_SERVER_ERROR,
             [
                 (CONTENT_TYPE, HeaderValue::from_str("text/plain").unwrap()),
-                (CACHE_CONTROL, HeaderValue::from_str("max-age=3600").unwrap()),
+                (CACHE_CONTROL, HeaderValue::from_str("no-store").unwrap()),
             ],
-            url.to_string(),
+            "ERROR RESOLVING STORAGE TARGET".to_owned(),
+        ).into_response();
+    }
+
+    let target = target.unwrap();
+
+    // Whatever decides the returned URL, the branch it came from included
+    let etag_source = |source: &str| {
+        format!(
+            "{}/{}/{}/{:?}x{:?}/{}/{}",
+            project_id,
+            image_type,
+            object.id,
+            query.width,
+            query.height,
+            domain.as_deref().unwrap_or_default(),
+            source
+        )
+    };
+
+    // A variant generated at upload time beats resizing, whichever service would do it
+    if let Some(key) = variant {
+        let url = target.presign_get(&key, PRESIGN_DURATION).await;
+
+        if url.is_ok() {
+            return url_response(&state, &headers, &etag_source("variant"), url.unwrap(), true);
+        }
+
+        tracing::error!("ERROR PRESIGNING VARIANT - {}", url.err().unwrap());
+    }
+
+    // Custom domains resize through their own serve route, which has the same fallback.
+    // The thumbnail service only reads the default S3 bucket, dedicated buckets and local storage
+    // always resize here.
+    if
+        query.width.is_some() &&
+        query.height.is_some() &&
+        domain.is_none() &&
+        (!target.is_default() || !thumbnail_service_available(&state).await)
+    {
+        let url = local_thumbnail_url(
+            &state,
+            &target,
+            &project_id,
+            &image_type,
+            &object,
+            query.width.unwrap(),
+            query.height.unwrap()
+        ).await;
+
+        if url.is_err() {
+            tracing::error!("{:?}", url.err().unwrap());
+
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                [
+                    (CONTENT_TYPE, HeaderValue::from_str("text/plain").unwrap()),
+                    (CACHE_CONTROL, HeaderValue::from_str("no-store").unwrap()),
+                ],
+                "ERROR GENERATING THUMBNAIL".to_owned(),
+            ).into_response();
+        }
+
+        return url_response(&state, &headers, &etag_source("local"), url.unwrap(), true);
+    }
+
+    if query.width.is_some() && query.height.is_some() {
+        let url = thumbnail_url(
+            &state,
+            &target,
+            domain.as_deref(),
+            &project_id,
+            &image_type,
+            &object.id,
+            &object.mime_type,
+            query.width.unwrap(),
+            query.height.unwrap()
+        );
+
+        return url_response(&state, &headers, &etag_source("service"), url, false);
+    }
+
+    // Public objects are linked directly so they're served by the CDN, private ones are presigned
+    if domain.is_some() || visibility == AssetVisibility::Public {
+        let url = asset_url(
+            &target,
+            domain.as_deref(),
+            &project_id,
+            &image_type,
+            &object.id,
+            &object.mime_type
         );
+
+        return url_response(&state, &headers, &etag_source("direct"), url, false);
     }
 
-    let command = state.client
-        .get_object()
-        .bucket(&state.bucket)
-        .key(format!("assets/{}/{}/{}.webp", &project_id, &image_type, &image_id))
-        .presigned(PresigningConfig::expires_in(PRESIGN_DURATION).unwrap()).await
+    let url = target
+        .presign_get(&object.key(&project_id, &image_type), PRESIGN_DURATION).await
         .unwrap();
 
-    let url = command.uri();
+    return url_response(&state, &headers, &etag_source("presigned"), url, true);
+}
 
-    return (
-        StatusCode::OK,
-        [
-            (CONTENT_TYPE, HeaderValue::from_str("text/plain").unwrap()),
-            (CACHE_CONTROL, HeaderValue::from_str("max-age=3600").unwrap()),
-        ],
-        url.to_string(),
+#[derive(Deserialize)]
+struct SrcsetQuery {
+    // Comma separated, e.g. 320,640,1280
+    widths: Option<String>,
+}
+
+// Thumbnail URLs for several widths at once, ready to be used as an img srcset. Like
+// get_thumbnail, sizes are resized here when the thumbnail service can't serve the project.
+async fn get_thumbnail_srcset(
+    State(state): State<AppState>,
+    query: Query<SrcsetQuery>,
+    ExtractPath((project_id, image_type, image_id)): ExtractPath<(Uuid, ImageType, Uuid)>
+) -> impl IntoResponse {
+    let widths: Vec<usize> = match &query.widths {
+        Some(widths) => {
+            let parsed: Result<Vec<usize>, _> = widths
+                .split(',')
+                .map(|width| width.trim().parse::<usize>())
+                .collect();
+
+            if parsed.is_err() {
+                return AppResponse::Error(format!("INVALID WIDTHS - {}", widths));
+            }
+
+            parsed.unwrap()
+        }
+        None => SRCSET_WIDTHS.to_vec(),
+    };
+
+    if widths.is_empty() || widths.len() > SRCSET_MAX_WIDTHS {
+        return AppResponse::Error(
+            format!("EXPECTED 1 TO {} WIDTHS, GOT {}", SRCSET_MAX_WIDTHS, widths.len())
+        );
+    }
+
+    let client = get_client(db_pool(&state, DbAccess::Read)).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    let row = client.query_opt(
+        "SELECT width, height FROM images WHERE id = $1 AND project_id = $2 AND type = $3
+            AND deleted_at IS NULL;",
+        &[&image_id, &project_id, &image_type]
+    ).await;
+
+    if row.is_err() {
+        return AppResponse::Error(row.err().unwrap().to_string());
+    }
+
+    let row = row.unwrap();
+
+    if row.is_none() || is_hidden_by_moderation(&client, &image_id).await.unwrap_or(true) {
+        return AppResponse::Error(format!("NO IMAGE - {}", image_id));
+    }
+
+    let row = row.unwrap();
+    let width: Option<i32> = row.get("width");
+    let height: Option<i32> = row.get("height");
+    let dimensions = width.zip(height).map(|(width, height)| (width as u32, height as u32));
+
+    let object = resolve_object(&client, &image_id).await;
+
+    if object.is_err() {
+        return object.err().unwrap();
+    }
+
+    let object = object.unwrap();
+    let domain = get_custom_domain(&client, &project_id).await;
+
+    let target = resolve_target(&state, &project_id).await;
+
+    if target.is_err() {
+        return target.err().unwrap();
+    }
+
+    let target = target.unwrap();
+
+    let local =
+        domain.is_none() && (!target.is_default() || !thumbnail_service_available(&state).await);
+
+    let mut urls: Vec<(usize, String)> = vec![];
+
+    for (width, height) in srcset_sizes(&widths, dimensions) {
+        let url = match local {
+            true =>
+                local_thumbnail_url(
+                    &state,
+                    &target,
+                    &project_id,
+                    &image_type,
+                    &object,
+                    width,
+                    height
+                ).await,
+            false =>
+                Ok(
+                    thumbnail_url(
+                        &state,
+                        &target,
+                        domain.as_deref(),
+                        &project_id,
+                        &image_type,
+                        &object.id,
+                        &object.mime_type,
+                        width,
+                        height
+                    )
+                ),
+        };
+
+        if url.is_err() {
+            return url.err().unwrap();
+        }
+
+        urls.push((width, url.unwrap()));
+    }
+
+    return AppResponse::SuccessData(
+        "Srcset".to_owned(),
+        crate::enums::SuccessActions::Read,
+        json!({
+            "srcset": srcset(&urls),
+            "urls": urls
+                .iter()
+                .map(|(width, url)| json!({ "width": width, "url": url }))
+                .collect::<Vec<Value>>(),
+        })
+    );
+}
+
+#[derive(Deserialize)]
+struct ThumbnailCallbackVariant {
+    width: i32,
+    height: i32,
+    key: String,
+}
+
+#[derive(Deserialize)]
+struct ThumbnailCallbackPayload {
+    project_id: Uuid,
+    image_type: ImageType,
+    object_id: Uuid,
+    variants: Vec<ThumbnailCallbackVariant>,
+}
+
+// The thumbnail service reports the sizes it generated and stored in the default bucket. They
+// are recorded as variants, so later requests for those sizes are presigned here instead of
+// going through the service again.
+async fn thumbnail_callback(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Bytes
+) -> impl IntoResponse {
+    if !check_thumbnail_callback(&headers, &state.config.thumbnail_secret, &body) {
+        return AppResponse::Unauthorized;
+    }
+
+    let payload = serde_json::from_slice::<ThumbnailCallbackPayload>(&body);
+
+    if payload.is_err() {
+        return AppResponse::Error(format!("INVALID CALLBACK - {}", payload.err().unwrap()));
+    }
+
+    let payload = payload.unwrap();
+
+    // Only keys that are deleted along with the object are accepted, anything else would
+    // outlive it
+    let thumbs = thumbnail_prefix(&payload.project_id, &payload.image_type, &payload.object_id);
+    let variants = variant_prefix(&payload.project_id, &payload.image_type, &payload.object_id);
+
+    let invalid = payload.variants
+        .iter()
+        .find(|variant| {
+            variant.width <= 0 ||
+                variant.height <= 0 ||
+                !(variant.key.starts_with(&thumbs) || variant.key.starts_with(&variants))
+        });
+
+    if let Some(variant) = invalid {
+        return AppResponse::Error(format!("INVALID VARIANT - {}", variant.key));
+    }
+
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    let exists = client.query_opt(
+        "SELECT 1 FROM images WHERE id = $1 AND project_id = $2 AND type = $3 AND object_id IS NULL;",
+        &[&payload.object_id, &payload.project_id, &payload.image_type]
+    ).await;
+
+    if exists.is_err() {
+        return AppResponse::Error(exists.err().unwrap().to_string());
+    }
+
+    if exists.unwrap().is_none() {
+        return AppResponse::Error(format!("NO OBJECT - {}", payload.object_id));
+    }
+
+    let widths: Vec<i32> = payload.variants
+        .iter()
+        .map(|variant| variant.width)
+        .collect();
+    let heights: Vec<i32> = payload.variants
+        .iter()
+        .map(|variant| variant.height)
+        .collect();
+    let keys: Vec<&str> = payload.variants
+        .iter()
+        .map(|variant| variant.key.as_str())
+        .collect();
+
+    let res = client.execute(
+        "INSERT INTO image_variants (object_id, width, height, key)
+         SELECT $1, width, height, key FROM UNNEST($2::INT[], $3::INT[], $4::TEXT[]) AS v(width, height, key)
+         ON CONFLICT (object_id, width) DO UPDATE SET height = EXCLUDED.height, key = EXCLUDED.key;",
+        &[&payload.object_id, &widths, &heights, &keys]
+    ).await;
+
+    if res.is_err() {
+        return AppResponse::Error(res.err().unwrap().to_string());
+    }
+
+    return AppResponse::SuccessData(
+        "Thumbnail variants".to_owned(),
+        crate::enums::SuccessActions::Update,
+        json!({ "recorded": res.unwrap() })
+    );
+}
+
+async fn prewarm_thumbnails(
+    State(state): State<AppState>,
+    ExtractPath(project_id): ExtractPath<Uuid>
+) -> impl IntoResponse {
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    let rows = client.query(
+        &format!(
+            "SELECT DISTINCT {}, type, mime_type FROM images WHERE project_id = $1 AND deleted_at IS NULL;",
+            OBJECT_ID
+        ),
+        &[&project_id]
+    ).await;
+
+    if rows.is_err() {
+        return AppResponse::Error(rows.err().unwrap().to_string());
+    }
+
+    let assets: Vec<(StoredObject, ImageType)> = rows
+        .unwrap()
+        .iter()
+        .map(|row| (
+            StoredObject { id: row.get("object_id"), mime_type: row.get("mime_type") },
+            row.get("type"),
+        ))
+        .collect();
+
+    let count = assets.len();
+
+    state.tasks.clone().spawn(run_thumbnail_prewarm(state, project_id, assets));
+
+    return AppResponse::SuccessData(
+        "Thumbnail prewarm".to_owned(),
+        crate::enums::SuccessActions::Create,
+        json!({ "queued": count, "sizes": THUMBNAIL_PRESETS })
     );
 }
 
-pub fn thumbnail_routes() -> Router<AppState> {
-    Router::new().route("/:project_id/:image_type/:image_id", get(get_thumbnail))
+// Only single "bytes=" ranges are supported, anything else is answered with the whole object.
+// Err when the range lies outside of the object.
+fn parse_range(value: &str, size: u64) -> Result<Option<(u64, u64)>, ()> {
+    let spec = value.trim().strip_prefix("bytes=");
+
+    if spec.is_none() || spec.unwrap().contains(',') {
+        return Ok(None);
+    }
+
+    let bounds = spec.unwrap().split_once('-');
+
+    if bounds.is_none() {
+        return Ok(None);
+    }
+
+    let (start, end) = bounds.unwrap();
+
+    // bytes=-500 is the last 500 bytes
+    if start.trim().is_empty() {
+        let suffix = end.trim().parse::<u64>();
+
+        if suffix.is_err() {
+            return Ok(None);
+        }
+
+        let suffix = suffix.unwrap();
+
+        if suffix == 0 || size == 0 {
+            return Err(());
+        }
+
+        return Ok(Some((size.saturating_sub(suffix), size - 1)));
+    }
+
+    let start = start.trim().parse::<u64>();
+    let end = match end.trim().is_empty() {
+        true => Ok(u64::MAX),
+        false => end.trim().parse::<u64>(),
+    };
+
+    if start.is_err() || end.is_err() {
+        return Ok(None);
+    }
+
+    let (start, end) = (start.unwrap(), end.unwrap());
+
+    if start >= size || start > end {
+        return Err(());
+    }
+
+    Ok(Some((start, end.min(size - 1))))
+}
+
+// Streams the object through this service, for clients that can't follow presigned URLs
+// because of CORS.
+async fn get_raw_asset(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    ExtractPath((project_id, image_type, image_id)): ExtractPath<(Uuid, ImageType, Uuid)>
+) -> Response {
+    let client = get_client(db_pool(&state, DbAccess::Read)).await;
+
+    if client.is_err() {
+        return client.err().unwrap().into_response();
+    }
+    let client = client.unwrap();
+
+    let object = resolve_object(&client, &image_id).await;
+
+    if object.is_err() {
+        return object.err().unwrap().into_response();
+    }
+
+    let object = object.unwrap();
+    let hidden = is_hidden_by_moderation(&client, &image_id).await;
+
+    if hidden.is_err() {
+        return hidden.err().unwrap().into_response();
+    }
+
+    if hidden.unwrap() {
+        return (StatusCode::NOT_FOUND, "Not Found").into_response();
+    }
+
+    let visibility = get_asset_visibility(&client, &project_id, &image_id).await;
+
+    if visibility.is_err() {
+        return visibility.err().unwrap().into_response();
+    }
+
+    let visibility = visibility.unwrap();
+
+    let target = resolve_target(&state, &project_id).await;
+
+    if target.is_err() {
+        return target.err().unwrap().into_response();
+    }
+
+    let target = target.unwrap();
+    let key = object.key(&project_id, &image_type);
+    let info = target.head(&key).await;
+
+    if info.is_err() {
+        return (StatusCode::NOT_FOUND, "Not Found").into_response();
+    }
+
+    let info = info.unwrap();
+    let size = info.size as u64;
+    let etag = match info.etag.starts_with('"') {
+        true => info.etag,
+        false => format!("\"{}\"", info.etag),
+    };
+
+    let header = |name| headers.get(name).and_then(|value: &HeaderValue| value.to_str().ok());
+
+    if header(IF_NONE_MATCH).is_some_and(|value| value.split(',').any(|tag| tag.trim() == etag)) {
+        return Response::builder()
+            .status(StatusCode::NOT_MODIFIED)
+            .header(ETAG, &etag)
+            .body(Body::empty())
+            .unwrap();
+    }
+
+    // A stale If-Range means the client's partial copy is outdated, so it gets everything
+    let range = match header(RANGE) {
+        Some(value) if header(IF_RANGE).is_none_or(|if_range| if_range == etag) =>
+            parse_range(value, size),
+        _ => Ok(None),
+    };
+
+    if range.is_err() {
+        return Response::builder()
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .header(CONTENT_RANGE, format!("bytes */{}", size))
+            .body(Body::empty())
+            .unwrap();
+    }
+
+    let range = range.unwrap();
+
+    // Players fetch media in many ranges, only the first one counts as a view
+    if range.is_none_or(|(start, _)| start == 0) {
+        record_view(&state, image_id);
+    }
+
+    let stream = target.get_stream(&key, range).await;
+
+    if stream.is_err() {
+        return AppResponse::Error(stream.err().unwrap()).into_response();
+    }
+
+    let stream = stream.unwrap();
+
+    // Shared caches in front of this service must not keep a copy of private objects
+    let cache_control = match visibility {
+        AssetVisibility::Public =>
+            stream.cache_control.unwrap_or(
+                state.config.cache_control.for_image_type(&image_type).to_owned()
+            ),
+        AssetVisibility::Private => "private, max-age=3600".to_owned(),
+    };
+
+    let response = Response::builder()
+        .header(CONTENT_TYPE, stream.content_type.unwrap_or(object.mime_type))
+        .header(CACHE_CONTROL, cache_control)
+        .header(ETAG, &etag)
+        .header(ACCEPT_RANGES, "bytes");
+
+    let response = match range {
+        Some((start, end)) =>
+            response
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, size))
+                .header(CONTENT_LENGTH, end - start + 1),
+        None => response.status(StatusCode::OK).header(CONTENT_LENGTH, size),
+    };
+
+    return response.body(stream.body).unwrap();
+}
+
+pub fn thumbnail_routes(state: AppState) -> Router<AppState> {
+    Router::new()
+        .merge(
+            Router::new()
+                .route("/:project_id/:image_type/:image_id", get(get_thumbnail))
+                .route("/:project_id/:image_type/:image_id/srcset", get(get_thumbnail_srcset))
+                .route("/assets/raw/:project_id/:image_type/:image_id", get(get_raw_asset))
+                .layer(from_fn_with_state(state.clone(), entity_project_middleware))
+                .layer(from_fn_with_state(state.clone(), hotlink_middleware))
+        )
+        .route("/thumbnails/callback", post(thumbnail_callback))
+        .merge(
+            Router::new()
+                .route("/thumbnails/prewarm/:project_id", post(prewarm_thumbnails))
+                .layer(from_fn_with_state(state, tenant_middleware))
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{ body::Body, http::Request };
+    use reqwest::StatusCode;
+    use serde_json::json;
+    use tower::ServiceExt;
+    use uuid::Uuid;
+
+    use crate::{
+        enums::ImageType,
+        state::models::AppState,
+        test_utils::{ drop_test_schema, test_state, TEST_THUMBNAIL_SECRET },
+        utils::{
+            thumbnail_utils::{ sign_thumbnail_callback, thumbnail_key, THUMBNAIL_SIGNATURE_HEADER },
+            variant_utils::variant_key,
+        },
+    };
+
+    use super::thumbnail_routes;
+
+    async fn create_tables(state: &AppState) {
+        let client = state.pool.get().await.unwrap();
+
+        client
+            .batch_execute(
+                "CREATE TYPE \"ImageType\" AS ENUM ('images', 'map_images');
+                 CREATE TABLE images (
+                    id UUID PRIMARY KEY,
+                    project_id UUID NOT NULL,
+                    type \"ImageType\" NOT NULL,
+                    object_id UUID
+                 );
+                 CREATE TABLE image_variants (
+                    object_id UUID NOT NULL,
+                    width INT NOT NULL,
+                    height INT NOT NULL,
+                    key TEXT NOT NULL,
+                    PRIMARY KEY (object_id, width)
+                 );"
+            ).await
+            .unwrap();
+    }
+
+    async fn send_callback(state: &AppState, body: String, signature: &str) -> StatusCode {
+        let request = Request::post("/thumbnails/callback")
+            .header(THUMBNAIL_SIGNATURE_HEADER, signature)
+            .body(Body::from(body))
+            .unwrap();
+
+        let res = thumbnail_routes(state.clone())
+            .with_state(state.clone())
+            .oneshot(request).await
+            .unwrap();
+
+        return res.status();
+    }
+
+    #[tokio::test]
+    async fn thumbnail_callback_records_variants() {
+        let state = test_state().await;
+
+        if state.is_none() {
+            return;
+        }
+
+        let state = state.unwrap();
+        create_tables(&state).await;
+
+        let client = state.pool.get().await.unwrap();
+        let project_id = Uuid::new_v4();
+        let image_id = Uuid::new_v4();
+
+        client
+            .execute(
+                "INSERT INTO images (id, project_id, type) VALUES ($1, $2, $3);",
+                &[&image_id, &project_id, &ImageType::Images]
+            ).await
+            .unwrap();
+
+        let thumbnail = thumbnail_key(&project_id, &ImageType::Images, &image_id, 160, 90);
+        let variant = variant_key(&project_id, &ImageType::Images, &image_id, 640);
+        let body = json!({
+            "project_id": project_id,
+            "image_type": "images",
+            "object_id": image_id,
+            "variants": [
+                { "width": 160, "height": 90, "key": thumbnail },
+                { "width": 640, "height": 360, "key": variant },
+            ],
+        }).to_string();
+
+        // Unsigned callbacks are refused before anything is recorded
+        let status = send_callback(&state, body.clone(), "invalid").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let signature = sign_thumbnail_callback(TEST_THUMBNAIL_SECRET, body.as_bytes());
+        let status = send_callback(&state, body, &signature).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let rows = client
+            .query(
+                "SELECT width, height, key FROM image_variants WHERE object_id = $1 ORDER BY width;",
+                &[&image_id]
+            ).await
+            .unwrap();
+        let recorded: Vec<(i32, i32, String)> = rows
+            .iter()
+            .map(|row| (row.get("width"), row.get("height"), row.get("key")))
+            .collect();
+
+        assert_eq!(recorded, vec![(160, 90, thumbnail), (640, 360, variant)]);
+
+        // Objects that don't exist get nothing recorded
+        let unknown_id = Uuid::new_v4();
+        let body = json!({
+            "project_id": project_id,
+            "image_type": "images",
+            "object_id": unknown_id,
+            "variants": [
+                {
+                    "width": 160,
+                    "height": 90,
+                    "key": thumbnail_key(&project_id, &ImageType::Images, &unknown_id, 160, 90),
+                },
+            ],
+        }).to_string();
+        let signature = sign_thumbnail_callback(TEST_THUMBNAIL_SECRET, body.as_bytes());
+        let status = send_callback(&state, body, &signature).await;
+        assert_ne!(status, StatusCode::OK);
+
+        drop_test_schema(&state).await;
+    }
 }
@@ -1,153 +1,604 @@
-use std::env;
+use std::{ collections::HashMap, io::Cursor };
 
-use aws_sdk_s3::primitives::ByteStream;
 use axum::{
-    extract::{ DefaultBodyLimit, Multipart, State },
+    body::{ to_bytes, Body, Bytes },
+    extract::{ DefaultBodyLimit, FromRequest, Multipart, Query, Request, State },
     http::HeaderMap,
-    response::IntoResponse,
-    routing::post,
+    middleware::from_fn_with_state,
+    response::{ sse::{ Event, KeepAlive, Sse }, IntoResponse, Response },
+    routing::{ get, post },
+    Json,
     Router,
 };
-use axum_extra::extract::CookieJar;
+use deadpool_postgres::Object;
+use futures::{ stream, StreamExt };
+use image::{ DynamicImage, ImageFormat, ImageReader };
+use reqwest::StatusCode;
+use serde::{ Deserialize, Serialize };
+use serde_json::{ json, Value };
+use sha2::{ Digest, Sha256 };
+use tokio::sync::broadcast::error::RecvError;
+use tokio_postgres::Row;
+use utoipa::{ IntoParams, OpenApi, ToSchema };
 use uuid::Uuid;
 
 use crate::{
-    enums::{ AppResponse, ImageType },
-    state::models::AppState,
+    enums::{
+        AppResponse,
+        AssetKind,
+        AssetVisibility,
+        AuditAction,
+        Feature,
+        ImageType,
+        ModerationStatus,
+        OutputFormat,
+        RequiredPermission,
+        ResponsePayload,
+        UploadEventKind,
+        UploadResultStatus,
+        UploadStage,
+        WebhookEvent,
+    },
+    jobs::{
+        asset_job::{ deleted_asset_columns, deletion_jobs, enqueue_jobs, notify_job_worker },
+        moderation_job::{ notify_moderation_worker, requires_moderation },
+    },
+    services::{
+        asset_service::{ store_asset, AssetBody, NewAsset },
+        audit_service::{ record_audit, AuditActor },
+    },
+    state::models::{ AppState, Claims },
+    storage::{ backend::{ PutOptions, UploadedPart }, resolve_target, StorageTarget },
     utils::{
-        auth_utils::check_auth,
-        db_utils::get_client,
-        extractors::ExtractPath,
-        image_utils::encode_lossy_webp,
+        asset_utils::{ asset_key, check_upload, sniff_asset, supported_media_type, SniffedAsset },
+        auth_utils::{ check_gateway_signature, check_project_owner, check_project_permission },
+        avatar_utils::{ avatar_key, delete_avatar, resize_avatar, AVATAR_CANONICAL_SIZE },
+        db_utils::{ get_client, get_encode_options },
+        extractors::{ AuthenticatedUser, ExtractPath },
+        image_utils::{
+            is_animated_webp,
+            load_oriented,
+            run_image_task,
+            DecodedImage,
+            EncodeOptions,
+            IMAGE_TOO_LARGE,
+        },
+        maintenance_utils::maintenance_middleware,
+        metrics_utils::record_upload_size,
+        progress_utils::{ get_upload_status, UploadEvent, UploadProgress },
+        remote_utils::fetch_remote,
+        stream_utils::{ spool_field, SpooledFile },
+        tag_utils::normalize_tags,
+        tenant_utils::tenant_middleware,
+        trash_utils::live_visibility,
+        validation_utils::{ validate_image, validation_failed, ImageFacts, ValidationError },
+        variant_utils::queue_variants,
+        webhook_utils::emit_asset_event,
+        zip_utils::extract_archive,
     },
+    MAX_ARCHIVE_ENTRIES,
+    MAX_ARCHIVE_SIZE,
     MAX_FILE_SIZE,
+    MAX_RESUMABLE_FILE_SIZE,
+    PRESIGN_DURATION,
+    UPLOAD_CHUNK_SIZE,
+    UPLOAD_SESSION_TTL_HOURS,
 };
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct UploadQuery {
+    upload_id: Option<Uuid>,
+    quality: Option<f32>,
+    lossless: Option<bool>,
+    // Storage format of uploaded images, WebP unless AVIF is asked for
+    format: Option<OutputFormat>,
+    // Roll back every file of the request if any of them fails
+    atomic: Option<bool>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct UploadResult {
+    id: Option<Uuid>,
+    title: String,
+    status: UploadResultStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    asset: Option<serde_json::Value>,
+    // Every rule of the image type the file broke
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Vec<Object>>)]
+    validation_errors: Option<Vec<ValidationError>>,
+    // Where the file was inside an uploaded archive
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<String>,
+}
+
+impl UploadResult {
+    fn unprocessed(title: String, status: UploadResultStatus) -> Self {
+        UploadResult {
+            id: None,
+            title,
+            status,
+            error: None,
+            asset: None,
+            validation_errors: None,
+            path: None,
+        }
+    }
+
+    fn failed(title: String, error: &str) -> Self {
+        UploadResult {
+            error: Some(error.to_owned()),
+            ..UploadResult::unprocessed(title, UploadResultStatus::Failed)
+        }
+    }
+
+    fn invalid(title: String, errors: Vec<ValidationError>) -> Self {
+        UploadResult {
+            validation_errors: Some(errors),
+            ..UploadResult::failed(title, "FAILED VALIDATION")
+        }
+    }
+}
+
+async fn requires_approval(
+    state: &AppState,
+    client: &Object,
+    project_id: &Uuid,
+    claims: &Claims
+) -> Result<bool, AppResponse> {
+    let project = client.query_opt(
+        "SELECT require_upload_approval FROM projects WHERE id = $1;",
+        &[&project_id]
+    ).await;
+
+    if project.is_err() {
+        return Err(AppResponse::Error(project.err().unwrap().to_string()));
+    }
+
+    let require_approval: Option<bool> = project
+        .unwrap()
+        .and_then(|row| row.get("require_upload_approval"));
+
+    if !require_approval.unwrap_or(false) {
+        return Ok(false);
+    }
+
+    let is_owner = check_project_owner(state, claims).await?;
+
+    Ok(!is_owner)
+}
+
+// Everything the files of one upload request have in common
+struct UploadContext {
+    project_id: Uuid,
+    image_type: ImageType,
+    owner_id: Uuid,
+    pending: bool,
+    visibility: AssetVisibility,
+    format: OutputFormat,
+    encode_options: EncodeOptions,
+    target: StorageTarget,
+}
+
+// Checks the requested format against the project and reads its upload settings, shared by
+// multipart and URL uploads
+async fn prepare_upload(
+    state: &AppState,
+    client: &Object,
+    claims: &Claims,
+    project_id: Uuid,
+    image_type: ImageType,
+    query: &UploadQuery
+) -> Result<UploadContext, AppResponse> {
+    let encode_options = get_encode_options(client, &project_id).await?;
+    let encode_options = encode_options.with_overrides(query.quality, query.lossless);
+
+    let format = query.format.unwrap_or(OutputFormat::Webp);
+
+    if !format.is_storage_format() {
+        return Err(
+            AppResponse::Error(format!("UNSUPPORTED OUTPUT FORMAT - {}", format.extension()))
+        );
+    }
+
+    if
+        format == OutputFormat::Avif &&
+        !state.config.feature_flags.is_enabled(Feature::Avif, &project_id, Some(&claims.user_id))
+    {
+        return Err(AppResponse::Error("AVIF IS NOT ENABLED FOR THIS PROJECT".to_owned()));
+    }
+
+    // Pending uploads stay private until an owner approves them
+    let pending = requires_approval(state, client, &project_id, claims).await?;
+    let visibility = live_visibility(state, &project_id, pending).await?;
+
+    let target = resolve_target(state, &project_id).await?;
+
+    Ok(UploadContext {
+        project_id,
+        image_type,
+        owner_id: claims.user_id,
+        pending,
+        visibility,
+        format,
+        encode_options,
+        target,
+    })
+}
+
+// Sniffs, validates and stores one file. Failures end up in its result instead of failing the
+// request, so the rest of a batch goes on.
+async fn store_spooled(
+    state: &AppState,
+    client: &Object,
+    upload: &UploadContext,
+    name: String,
+    spooled: &SpooledFile,
+    progress: &UploadProgress
+) -> UploadResult {
+    let sniffed = check_upload(&spooled.head, spooled.declared_type.as_deref());
+
+    if sniffed.is_err() {
+        let reason = sniffed.err().unwrap();
+        tracing::error!("{} - {}", reason, name);
+        progress.failed(&name);
+        return UploadResult::failed(name, &reason);
+    }
+
+    let sniffed = sniffed.unwrap();
+
+    record_upload_size(&sniffed.kind, spooled.size);
+
+    // Only images are re-encoded, other kinds are streamed to storage as uploaded
+    let decoded = if sniffed.kind == AssetKind::Image {
+        progress.stage(UploadStage::Decoding, &name);
+
+        let img_data = spooled.decode_image(
+            &state.encode_permits,
+            state.config.max_image_pixels
+        ).await;
+
+        if img_data.is_err() {
+            let err = img_data.err().unwrap();
+            tracing::error!("{}", err);
+            progress.failed(&name);
+
+            if err.starts_with(IMAGE_TOO_LARGE) {
+                return UploadResult::failed(name, IMAGE_TOO_LARGE);
+            }
+
+            return UploadResult::failed(name, "COULD NOT DECODE IMAGE");
+        }
+
+        let img_data = img_data.unwrap();
+        let facts = ImageFacts::from_head(&spooled.head, spooled.size, img_data.dimensions());
+        let valid = validate_image(&upload.image_type, &facts);
+
+        if valid.is_err() {
+            progress.failed(&name);
+            return UploadResult::invalid(name, valid.err().unwrap());
+        }
+
+        Some(img_data)
+    } else {
+        None
+    };
+
+    let id = Uuid::new_v4();
+    let new = NewAsset {
+        id,
+        key_id: None,
+        title: &name,
+        project_id: &upload.project_id,
+        image_type: &upload.image_type,
+        owner_id: &upload.owner_id,
+        pending: upload.pending,
+        visibility: upload.visibility,
+        dedupe: true,
+        max_size_bytes: None,
+    };
+
+    let body = match decoded {
+        Some(img) => AssetBody::Image {
+            img,
+            head: &spooled.head,
+            format: upload.format,
+            options: &upload.encode_options,
+        },
+        None => AssetBody::File { path: spooled.path(), size: spooled.size, asset: sniffed },
+    };
+
+    let stored = store_asset(state, client, &upload.target, &new, body, Some(progress)).await;
+
+    if stored.is_err() {
+        let err = stored.err().unwrap();
+        tracing::error!("{:?}", err);
+        progress.failed(&name);
+        return UploadResult::failed(name, err.reason());
+    }
+
+    let stored = stored.unwrap();
+    let metadata = stored.metadata;
+
+    progress.stored(&name, id);
+
+    UploadResult {
+        id: Some(id),
+        asset: Some(
+            json!({
+                "kind": stored.asset.kind,
+                "mime_type": stored.asset.mime_type,
+                "size_bytes": stored.size_bytes,
+                "width": metadata.as_ref().map(|metadata| metadata.width),
+                "height": metadata.as_ref().map(|metadata| metadata.height),
+                "is_animated": metadata.as_ref().is_some_and(|metadata| metadata.is_animated),
+                "dominant_color": metadata.as_ref().and_then(|metadata| metadata.dominant_color.clone()),
+                "original_format": metadata.and_then(|metadata| metadata.original_format),
+                "object_id": stored.object_id.unwrap_or(id),
+                "deduplicated": stored.object_id.is_some(),
+            })
+        ),
+        ..UploadResult::unprocessed(name, UploadResultStatus::Uploaded)
+    }
+}
+
+// Removes the rows (and the objects no other row uses) inserted by a failed atomic upload
+async fn rollback_uploads(
+    state: &AppState,
+    client: &mut Object,
+    ids: &Vec<Uuid>
+) -> Result<(), AppResponse> {
+    if ids.is_empty() {
+        return Ok(());
+    }
+
+    let transaction = client.transaction().await;
+
+    if transaction.is_err() {
+        return Err(AppResponse::Error(transaction.err().unwrap().to_string()));
+    }
+
+    let transaction = transaction.unwrap();
+
+    let rows = transaction.query(
+        &format!("DELETE FROM images WHERE id = ANY($1) RETURNING {};", deleted_asset_columns()),
+        &[&ids]
+    ).await;
+
+    if rows.is_err() {
+        return Err(AppResponse::Error(rows.err().unwrap().to_string()));
+    }
+
+    let jobs = deletion_jobs(&transaction, &rows.unwrap()).await?;
+
+    enqueue_jobs(&transaction, &jobs).await?;
+
+    let res = transaction.commit().await;
+
+    if res.is_err() {
+        return Err(AppResponse::Error(res.err().unwrap().to_string()));
+    }
+
+    notify_job_worker(state);
+
+    Ok(())
+}
+
+#[utoipa::path(
+    post,
+    path = "/upload/{project_id}/{image_type}",
+    tag = "uploads",
+    params(("project_id" = Uuid, Path), ("image_type" = ImageType, Path), UploadQuery),
+    request_body(
+        content = Object,
+        content_type = "multipart/form-data",
+        description = "Every named field is one file, the field name becomes its title"
+    ),
+    responses(
+        (
+            status = 200,
+            description = "One UploadResult per field under `data.results`",
+            body = ResponsePayload,
+        )
+    )
+)]
 async fn upload_image(
-    cookie_jar: CookieJar,
+    AuthenticatedUser(claims): AuthenticatedUser,
     State(state): State<AppState>,
+    query: Query<UploadQuery>,
     ExtractPath((project_id, image_type)): ExtractPath<(Uuid, ImageType)>,
-    headers: HeaderMap,
     mut multipart: Multipart
 ) -> impl IntoResponse {
-    let claims = check_auth(
-        cookie_jar,
-        &state.reqwest_client,
-        state.auth_service_url,
-        headers
+    let can_upload = check_project_permission(
+        &state,
+        &claims.user_id,
+        &project_id,
+        RequiredPermission::Upload
     ).await;
 
-    if claims.is_err() {
-        return AppResponse::Unauthorized;
+    if can_upload.is_err() {
+        return can_upload.err().unwrap();
     }
 
-    let claims = claims.unwrap().claims;
-
-    if claims.is_none() {
-        return AppResponse::Unauthorized;
+    if !can_upload.unwrap() {
+        return AppResponse::Auth;
     }
 
-    let claims = claims.unwrap();
+    let atomic = query.atomic.unwrap_or(false);
+    let mut results: Vec<UploadResult> = vec![];
 
-    let mut errors: Vec<String> = vec![];
+    let progress = UploadProgress::start(
+        &state.upload_tracker,
+        &state.upload_events,
+        query.upload_id,
+        claims.user_id
+    );
 
     let client = get_client(&state.pool).await;
 
     if client.is_err() {
         return client.err().unwrap();
     }
-    let client = client.unwrap();
+    let mut client = client.unwrap();
+
+    let upload = prepare_upload(&state, &client, &claims, project_id, image_type, &query).await;
+
+    if upload.is_err() {
+        progress.finish();
+        return upload.err().unwrap();
+    }
+
+    let upload = upload.unwrap();
 
     while let Some(field) = multipart.next_field().await.unwrap() {
         let name = field.name().unwrap_or("unnamed").to_string();
-        let data = field.bytes().await;
 
         if name == "unnamed" {
             continue;
         }
 
-        if data.is_err() {
-            errors.push(name);
-            tracing::error!("ERROR GETTING FILE DATA - {}", data.err().unwrap());
+        // The rest of an atomic batch is not worth processing once it's going to be rolled back
+        if atomic && results.iter().any(|result| result.status == UploadResultStatus::Failed) {
+            results.push(UploadResult::unprocessed(name, UploadResultStatus::Skipped));
             continue;
         }
 
-        let id = Uuid::new_v4();
-        let data = data.unwrap().to_vec();
+        progress.stage(UploadStage::Receiving, &name);
 
-        let img_data = image::load_from_memory(&data);
+        let spooled = spool_field(field, &progress).await;
 
-        if img_data.is_err() {
-            tracing::error!("{}", img_data.err().unwrap());
+        if spooled.is_err() {
+            tracing::error!("ERROR GETTING FILE DATA - {}", spooled.err().unwrap());
+            progress.failed(&name);
+            results.push(UploadResult::failed(name, "COULD NOT READ FILE"));
             continue;
         }
 
-        let lossy = encode_lossy_webp(img_data.unwrap());
+        progress.file_received(&name);
 
-        let upload = state.client
-            .put_object()
-            .bucket(&state.bucket)
-            .key(format!("assets/{}/{}/{}.webp", &project_id, &image_type, &id))
-            .body(ByteStream::from(lossy))
-            .acl(aws_sdk_s3::types::ObjectCannedAcl::PublicRead)
-            .content_type("image/webp")
-            .cache_control("max-age=600")
-            .send().await;
+        results.push(
+            store_spooled(&state, &client, &upload, name, &spooled.unwrap(), &progress).await
+        );
+    }
 
-        if upload.is_ok() {
-            let res = client.query(
-                "INSERT INTO images (id, title, project_id, type, owner_id) VALUES ($1, $2, $3, $4, $5);",
-                &[&id, &name, &project_id, &image_type, &claims.user_id]
-            ).await;
+    let rolled_back =
+        atomic && results.iter().any(|result| result.status == UploadResultStatus::Failed);
 
-            if res.is_err() {
-                tracing::error!("{}", res.err().unwrap());
+    if rolled_back {
+        let ids: Vec<Uuid> = results
+            .iter()
+            .filter_map(|result| result.id)
+            .collect();
 
-                let del_res = &state.client
-                    .delete_object()
-                    .bucket(&state.bucket)
-                    .key(format!("assets/{}/{}/{}.webp", &project_id, &image_type, &id))
-                    .send().await;
+        let res = rollback_uploads(&state, &mut client, &ids).await;
 
-                if del_res.is_err() {
-                    tracing::error!("{}", del_res.as_ref().err().unwrap());
-                }
-                errors.push(name);
-                continue;
-            }
-        } else {
-            tracing::error!("{}", upload.err().unwrap());
-            errors.push(name);
-            continue;
+        if res.is_err() {
+            progress.finish();
+            return res.err().unwrap();
+        }
+
+        for result in results.iter_mut().filter(|result| result.id.is_some()) {
+            result.status = UploadResultStatus::RolledBack;
         }
     }
-    tracing::error!("{:?}", errors);
-    return AppResponse::Success("Image(s)".to_owned(), crate::enums::SuccessActions::Upload);
+
+    progress.finish();
+
+    // Only sent once the batch is final, rolled back uploads never existed as far as
+    // receivers are concerned
+    let uploaded: Vec<Uuid> = results
+        .iter()
+        .filter(|result| result.status == UploadResultStatus::Uploaded)
+        .filter_map(|result| result.id)
+        .collect();
+
+    for id in &uploaded {
+        emit_asset_event(&state, &client, WebhookEvent::Uploaded, id).await;
+    }
+
+    record_audit(
+        &client,
+        &AuditActor::user(&claims),
+        &project_id,
+        AuditAction::Upload,
+        &uploaded
+    ).await;
+
+    return AppResponse::SuccessData(
+        "Image(s)".to_owned(),
+        crate::enums::SuccessActions::Upload,
+        json!({ "results": results, "rolled_back": rolled_back })
+    );
 }
 
-async fn upload_user_avatar(
-    cookie_jar: CookieJar,
+#[derive(Deserialize, ToSchema)]
+struct UrlUploadPayload {
+    url: String,
+    // The last segment of the URL's path when left out
+    title: Option<String>,
+}
+
+fn url_title(url: &str) -> String {
+    url.split(['?', '#'])
+        .next()
+        .and_then(|path| path.trim_end_matches('/').rsplit('/').next())
+        .filter(|segment| !segment.is_empty() && !segment.contains(':'))
+        .unwrap_or("untitled")
+        .to_owned()
+}
+
+// "Import from URL". The file is downloaded by this service, with the limits of a regular upload
+// and only from public hosts, then stored like an uploaded file.
+#[utoipa::path(
+    post,
+    path = "/upload/from-url/{project_id}/{image_type}",
+    tag = "uploads",
+    params(("project_id" = Uuid, Path), ("image_type" = ImageType, Path), UploadQuery),
+    request_body = UrlUploadPayload,
+    responses(
+        (
+            status = 200,
+            description = "The UploadResult of the downloaded file under `data.results`",
+            body = ResponsePayload,
+        )
+    )
+)]
+async fn upload_from_url(
+    AuthenticatedUser(claims): AuthenticatedUser,
     State(state): State<AppState>,
-    headers: HeaderMap,
-    mut multipart: Multipart
+    query: Query<UploadQuery>,
+    ExtractPath((project_id, image_type)): ExtractPath<(Uuid, ImageType)>,
+    Json(payload): Json<UrlUploadPayload>
 ) -> impl IntoResponse {
-    let claims = check_auth(
-        cookie_jar,
-        &state.reqwest_client,
-        state.auth_service_url,
-        headers
+    let can_upload = check_project_permission(
+        &state,
+        &claims.user_id,
+        &project_id,
+        RequiredPermission::Upload
     ).await;
 
-    if claims.is_err() {
-        return AppResponse::Unauthorized;
+    if can_upload.is_err() {
+        return can_upload.err().unwrap();
     }
 
-    let claims = claims.unwrap().claims;
-
-    if claims.is_none() {
-        return AppResponse::Unauthorized;
+    if !can_upload.unwrap() {
+        return AppResponse::Auth;
     }
 
-    let claims = claims.unwrap();
+    let title = payload.title
+        .map(|title| title.trim().to_owned())
+        .filter(|title| !title.is_empty())
+        .unwrap_or(url_title(&payload.url));
+
+    let progress = UploadProgress::start(
+        &state.upload_tracker,
+        &state.upload_events,
+        query.upload_id,
+        claims.user_id
+    );
 
     let client = get_client(&state.pool).await;
 
@@ -156,200 +607,1788 @@ async fn upload_user_avatar(
     }
     let client = client.unwrap();
 
-    let user = client.query_one(
-        "SELECT users.id, users.image FROM users WHERE users.id = $1;",
-        &[&claims.user_id]
-    ).await;
+    let upload = prepare_upload(&state, &client, &claims, project_id, image_type, &query).await;
 
-    if user.is_err() {
-        return AppResponse::Unauthorized;
+    if upload.is_err() {
+        progress.finish();
+        return upload.err().unwrap();
     }
-    let user = user.unwrap();
-    let user_id: Uuid = user.get("id");
-    let user_image: Option<String> = user.get("image");
 
-    match user_image {
-        Some(img) => {
-            let key = img.split("/").last().unwrap();
-            let _ = &state.client.delete_object().bucket(&state.bucket).key(key).send().await;
-        }
-        None => {}
-    }
+    let upload = upload.unwrap();
 
-    while let Some(field) = multipart.next_field().await.unwrap() {
-        let name = field.name().unwrap_or("unnamed").to_string();
-        let data = field.bytes().await;
+    progress.stage(UploadStage::Receiving, &title);
 
-        if name == "unnamed" {
-            continue;
+    let result = match fetch_remote(&state, &payload.url, MAX_FILE_SIZE as u64).await {
+        Ok(spooled) => {
+            progress.received(spooled.size as usize);
+            progress.file_received(&title);
+            store_spooled(&state, &client, &upload, title, &spooled, &progress).await
         }
-
-        if data.is_err() {
-            tracing::error!("ERROR GETTING FILE DATA - {}", data.err().unwrap());
-            continue;
+        Err(err) => {
+            tracing::error!("ERROR FETCHING REMOTE FILE - {}", err);
+            progress.failed(&title);
+            UploadResult::failed(title, err.reason())
         }
+    };
 
-        let id = Uuid::new_v4();
-        let data = data.unwrap().to_vec();
+    progress.finish();
 
-        let img_data = image::load_from_memory(&data);
+    if let Some(id) = result.id {
+        emit_asset_event(&state, &client, WebhookEvent::Uploaded, &id).await;
 
-        if img_data.is_err() {
-            tracing::error!("{}", img_data.err().unwrap());
-            continue;
-        }
+        record_audit(
+            &client,
+            &AuditActor::user(&claims),
+            &project_id,
+            AuditAction::Upload,
+            &[id]
+        ).await;
+    }
 
-        let lossy = encode_lossy_webp(img_data.unwrap());
-
-        let do_spaces_name = env::var("DO_SPACES_NAME").expect("NO DO NAME");
-        let do_spaces_endpoint = env
-            ::var("DO_SPACES_ENDPOINT")
-            .expect("NO DO ENDPOINT")
-            .replace("https://", "");
-        let key = format!("assets/avatars/{}-{}.webp", &user_id, &id);
-
-        let upload = state.client
-            .put_object()
-            .bucket(&state.bucket)
-            .key(&key)
-            .body(ByteStream::from(lossy))
-            .acl(aws_sdk_s3::types::ObjectCannedAcl::PublicRead)
-            .content_type("image/webp")
-            .cache_control("max-age=600")
-            .send().await;
-
-        if upload.is_ok() {
-            let new_url = format!("https://{}.{}/{}", do_spaces_name, do_spaces_endpoint, &key);
-            let res = client.query(
-                "UPDATE users SET image = $1 WHERE users.id = $2",
-                &[&new_url, &claims.user_id]
-            ).await;
-
-            if res.is_err() {
-                tracing::error!("{}", res.err().unwrap());
-
-                let del_res = &state.client
-                    .delete_object()
-                    .bucket(&state.bucket)
-                    .key(&key)
-                    .send().await;
-
-                if del_res.is_err() {
-                    tracing::error!("{}", del_res.as_ref().err().unwrap());
-                }
+    return AppResponse::SuccessData(
+        "Image".to_owned(),
+        crate::enums::SuccessActions::Upload,
+        json!({ "results": [result] })
+    );
+}
 
-                continue;
-            }
-        } else {
-            tracing::error!("{}", upload.err().unwrap());
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ArchiveQuery {
+    // Also files the assets into collections named after their folders, nested the same way
+    collections: Option<bool>,
+}
+
+// Finds or creates the collection for a folder path of an archive, one level at a time
+async fn folder_collection(
+    client: &Object,
+    project_id: &Uuid,
+    folders: &[String],
+    known: &mut HashMap<Vec<String>, Uuid>
+) -> Result<Option<Uuid>, AppResponse> {
+    let mut parent_id: Option<Uuid> = None;
+
+    for depth in 1..=folders.len() {
+        let path = folders[..depth].to_vec();
+
+        if let Some(id) = known.get(&path) {
+            parent_id = Some(*id);
             continue;
         }
+
+        let title = &folders[depth - 1];
+
+        let row = client.query_one(
+            "WITH existing AS (
+                SELECT id FROM collections
+                WHERE project_id = $1 AND parent_id IS NOT DISTINCT FROM $2 AND title = $3
+                LIMIT 1
+             ), inserted AS (
+                INSERT INTO collections (project_id, parent_id, title)
+                SELECT $1, $2, $3 WHERE NOT EXISTS (SELECT 1 FROM existing)
+                RETURNING id
+             )
+             SELECT id FROM existing UNION ALL SELECT id FROM inserted;",
+            &[&project_id, &parent_id, &title]
+        ).await;
+
+        if row.is_err() {
+            return Err(AppResponse::Error(row.err().unwrap().to_string()));
+        }
+
+        let id: Uuid = row.unwrap().get("id");
+
+        known.insert(path, id);
+        parent_id = Some(id);
     }
-    return AppResponse::Success("Avatar".to_owned(), crate::enums::SuccessActions::Upload);
+
+    Ok(parent_id)
 }
 
-async fn upload_gateway_entity(
+// "Import from ZIP". Every file of the archive goes through the same pipeline as a multipart
+// upload, its folder names become tags and, optionally, collections.
+#[utoipa::path(
+    post,
+    path = "/upload/archive/{project_id}/{image_type}",
+    tag = "uploads",
+    params(
+        ("project_id" = Uuid, Path),
+        ("image_type" = ImageType, Path),
+        UploadQuery,
+        ArchiveQuery
+    ),
+    request_body(
+        content = Object,
+        content_type = "multipart/form-data",
+        description = "A single field with the ZIP archive"
+    ),
+    responses(
+        (
+            status = 200,
+            description = "One UploadResult per file of the archive under `data.results`",
+            body = ResponsePayload,
+        )
+    )
+)]
+async fn upload_archive(
+    AuthenticatedUser(claims): AuthenticatedUser,
     State(state): State<AppState>,
-    ExtractPath((project_id, entity_id)): ExtractPath<(Uuid, Uuid)>,
+    query: Query<UploadQuery>,
+    archive_query: Query<ArchiveQuery>,
+    ExtractPath((project_id, image_type)): ExtractPath<(Uuid, ImageType)>,
     mut multipart: Multipart
 ) -> impl IntoResponse {
+    let can_upload = check_project_permission(
+        &state,
+        &claims.user_id,
+        &project_id,
+        RequiredPermission::Upload
+    ).await;
+
+    if can_upload.is_err() {
+        return can_upload.err().unwrap();
+    }
+
+    if !can_upload.unwrap() {
+        return AppResponse::Auth;
+    }
+
+    let atomic = query.atomic.unwrap_or(false);
+
+    let progress = UploadProgress::start(
+        &state.upload_tracker,
+        &state.upload_events,
+        query.upload_id,
+        claims.user_id
+    );
+
     let client = get_client(&state.pool).await;
 
     if client.is_err() {
         return client.err().unwrap();
     }
-    let client = client.unwrap();
+    let mut client = client.unwrap();
 
-    while let Some(field) = multipart.next_field().await.unwrap() {
-        let name = field.name().unwrap_or("unnamed").to_string();
-        let data = field.bytes().await;
+    let upload = prepare_upload(&state, &client, &claims, project_id, image_type, &query).await;
 
-        if name == "unnamed" {
+    if upload.is_err() {
+        progress.finish();
+        return upload.err().unwrap();
+    }
+
+    let upload = upload.unwrap();
+
+    let field = multipart.next_field().await;
+
+    if field.is_err() {
+        progress.finish();
+        return AppResponse::Error(field.err().unwrap().to_string());
+    }
+
+    let field = field.unwrap();
+
+    if field.is_none() {
+        progress.finish();
+        return AppResponse::Error("NO ARCHIVE".to_owned());
+    }
+
+    let field = field.unwrap();
+    let archive_name = field.name().unwrap_or("archive").to_owned();
+
+    progress.stage(UploadStage::Receiving, &archive_name);
+
+    let archive = spool_field(field, &progress).await;
+
+    if archive.is_err() {
+        tracing::error!("ERROR GETTING ARCHIVE DATA - {}", archive.err().unwrap());
+        progress.finish();
+        return AppResponse::Error("COULD NOT READ ARCHIVE".to_owned());
+    }
+
+    let archive = archive.unwrap();
+    let archive_path = archive.path().to_owned();
+
+    let entries = tokio::task::spawn_blocking(move || {
+        extract_archive(
+            &archive_path,
+            MAX_ARCHIVE_ENTRIES,
+            MAX_ARCHIVE_SIZE as u64,
+            MAX_FILE_SIZE as u64
+        )
+    }).await;
+
+    // The extracted files are all that's needed from here on
+    drop(archive);
+
+    if entries.is_err() {
+        progress.finish();
+        return AppResponse::Error(entries.err().unwrap().to_string());
+    }
+
+    let entries = entries.unwrap();
+
+    if entries.is_err() {
+        progress.finish();
+        return AppResponse::Error(entries.err().unwrap());
+    }
+
+    let entries = entries.unwrap();
+
+    progress.file_received(&archive_name);
+
+    let mut results: Vec<UploadResult> = vec![];
+    let mut folders: Vec<Vec<String>> = vec![];
+
+    for entry in entries {
+        let failed = results.iter().any(|result| result.status == UploadResultStatus::Failed);
+
+        let mut result = match entry.file {
+            _ if atomic && failed => {
+                UploadResult::unprocessed(entry.title, UploadResultStatus::Skipped)
+            }
+            Ok(spooled) => {
+                store_spooled(&state, &client, &upload, entry.title, &spooled, &progress).await
+            }
+            Err(err) => {
+                progress.failed(&entry.title);
+                UploadResult::failed(entry.title, &err)
+            }
+        };
+
+        result.path = Some(entry.path);
+        results.push(result);
+        folders.push(entry.folders);
+    }
+
+    let rolled_back =
+        atomic && results.iter().any(|result| result.status == UploadResultStatus::Failed);
+
+    if rolled_back {
+        let ids: Vec<Uuid> = results
+            .iter()
+            .filter_map(|result| result.id)
+            .collect();
+
+        let res = rollback_uploads(&state, &mut client, &ids).await;
+
+        if res.is_err() {
+            progress.finish();
+            return res.err().unwrap();
+        }
+
+        for result in results.iter_mut().filter(|result| result.id.is_some()) {
+            result.status = UploadResultStatus::RolledBack;
+        }
+    }
+
+    progress.finish();
+
+    let use_collections = archive_query.collections.unwrap_or(false);
+    let mut collections: HashMap<Vec<String>, Uuid> = HashMap::new();
+    let mut uploaded: Vec<Uuid> = vec![];
+
+    for (result, folders) in results.iter().zip(folders.iter()) {
+        if result.status != UploadResultStatus::Uploaded || result.id.is_none() {
             continue;
         }
 
-        if data.is_err() {
-            tracing::error!("ERROR GETTING FILE DATA - {}", data.err().unwrap());
+        let id = result.id.unwrap();
+        uploaded.push(id);
+
+        if folders.is_empty() {
             continue;
         }
 
-        let id = Uuid::new_v4();
-        let data = data.unwrap().to_vec();
+        let tags = normalize_tags(folders.iter().map(|folder| folder.as_str()));
 
-        let img_data = image::load_from_memory(&data);
+        let res = client.execute(
+            "INSERT INTO asset_tags (image_id, tag)
+             SELECT $1, UNNEST($2::TEXT[])
+             ON CONFLICT DO NOTHING;",
+            &[&id, &tags]
+        ).await;
 
-        if img_data.is_err() {
-            tracing::error!("{}", img_data.err().unwrap());
+        if res.is_err() {
+            tracing::error!("ERROR TAGGING ARCHIVE ENTRY - {}", res.err().unwrap());
+        }
+
+        if !use_collections {
             continue;
         }
 
-        let lossy = encode_lossy_webp(img_data.unwrap());
-
-        let upload = state.client
-            .put_object()
-            .bucket(&state.bucket)
-            .key(format!("assets/{}/{}/{}.webp", &project_id, ImageType::Images, &entity_id))
-            .body(ByteStream::from(lossy))
-            .acl(aws_sdk_s3::types::ObjectCannedAcl::PublicRead)
-            .content_type("image/webp")
-            .cache_control("max-age=600")
-            .send().await;
-
-        if upload.is_ok() {
-            let project_res = client.query_one(
-                "SELECT owner_id FROM projects WHERE projects.id = $1;",
-                &[&project_id]
-            ).await;
-
-            if project_res.is_err() {
-                let _ = &state.client
-                    .delete_object()
-                    .bucket(&state.bucket)
-                    .key(format!("assets/{}/{}/{}.webp", &project_id, &ImageType::Images, &id))
-                    .send().await;
-
-                continue;
-            }
+        let collection_id = folder_collection(&client, &project_id, folders, &mut collections).await;
+
+        if collection_id.is_err() {
+            return collection_id.err().unwrap();
+        }
 
-            let project_res = project_res.unwrap();
+        let res = client.execute(
+            "UPDATE images SET collection_id = $2 WHERE id = $1;",
+            &[&id, &collection_id.unwrap()]
+        ).await;
 
-            let owner_id: Uuid = project_res.get("owner_id");
+        if res.is_err() {
+            return AppResponse::Error(res.err().unwrap().to_string());
+        }
+    }
 
-            let res = client.query(
-                "INSERT INTO images (id, title, project_id, type, owner_id) VALUES ($1, $2, $3, $4, $5);",
-                &[&id, &name, &project_id, &ImageType::Images, &owner_id]
-            ).await;
+    for id in &uploaded {
+        emit_asset_event(&state, &client, WebhookEvent::Uploaded, id).await;
+    }
 
-            if res.is_err() {
-                tracing::error!("{}", res.err().unwrap());
+    record_audit(
+        &client,
+        &AuditActor::user(&claims),
+        &project_id,
+        AuditAction::Upload,
+        &uploaded
+    ).await;
 
-                let del_res = &state.client
-                    .delete_object()
-                    .bucket(&state.bucket)
-                    .key(format!("assets/{}/{}/{}.webp", &project_id, &ImageType::Images, &id))
-                    .send().await;
+    return AppResponse::SuccessData(
+        "Image(s)".to_owned(),
+        crate::enums::SuccessActions::Upload,
+        json!({ "results": results, "rolled_back": rolled_back })
+    );
+}
 
-                if del_res.is_err() {
-                    tracing::error!("{}", del_res.as_ref().err().unwrap());
-                }
-                continue;
-            }
-        } else {
-            tracing::error!("{}", upload.err().unwrap());
-            continue;
+// For uploads that are flattened to a single frame, avatars and gateway entity images
+async fn decode_still(
+    state: &AppState,
+    data: Vec<u8>,
+    declared: Option<&str>
+) -> Result<DynamicImage, String> {
+    let sniffed = check_upload(&data, declared)?;
+
+    if sniffed.kind != AssetKind::Image {
+        return Err(format!("UNSUPPORTED FILE TYPE - {}", sniffed.mime_type));
+    }
+
+    let max_pixels = state.config.max_image_pixels;
+
+    run_image_task(&state.encode_permits, "decode", move || load_oriented(&data, max_pixels)).await?
+}
+
+// Stores every avatar size and points users.image at the canonical one. Returns the URL of
+// each size.
+async fn store_user_avatar(
+    state: &AppState,
+    client: &Object,
+    user_id: &Uuid,
+    img: DynamicImage
+) -> Result<Value, AppResponse> {
+    let id = Uuid::new_v4();
+    let variants = resize_avatar(&state.encode_permits, img).await?;
+
+    // Avatars belong to users, not projects
+    let target = state.storage.default_target();
+    let new_url = target.public_url(&avatar_key(user_id, &id, AVATAR_CANONICAL_SIZE));
+    let mut urls = serde_json::Map::new();
+
+    for (size, data) in variants {
+        let key = avatar_key(user_id, &id, size);
+
+        let upload = target.put(&key, data, &PutOptions {
+            content_type: "image/webp",
+            cache_control: &state.config.cache_control.avatars,
+            visibility: AssetVisibility::Public,
+        }).await;
+
+        if upload.is_err() {
+            // Removes whichever sizes made it
+            delete_avatar(state, &new_url).await;
+            return Err(AppResponse::Error(upload.err().unwrap()));
         }
+
+        urls.insert(size.to_string(), json!(target.public_url(&key)));
+    }
+
+    let res = client.query(
+        "UPDATE users SET image = $1 WHERE users.id = $2",
+        &[&new_url, &user_id]
+    ).await;
+
+    if res.is_err() {
+        delete_avatar(state, &new_url).await;
+        return Err(AppResponse::Error(res.err().unwrap().to_string()));
+    }
+
+    Ok(json!({ "image": new_url, "sizes": urls }))
+}
+
+async fn upload_user_avatar(
+    AuthenticatedUser(claims): AuthenticatedUser,
+    State(state): State<AppState>,
+    mut multipart: Multipart
+) -> impl IntoResponse {
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    let user = client.query_one(
+        "SELECT users.id, users.image FROM users WHERE users.id = $1;",
+        &[&claims.user_id]
+    ).await;
+
+    if user.is_err() {
+        return AppResponse::Unauthorized;
+    }
+    let user = user.unwrap();
+    let user_id: Uuid = user.get("id");
+    let user_image: Option<String> = user.get("image");
+
+    match user_image {
+        Some(img) => {
+            delete_avatar(&state, &img).await;
+        }
+        None => {}
+    }
+
+    let mut avatar: Option<Value> = None;
+    // Why the last file was turned down, for when none of them could be used
+    let mut rejected: Option<String> = None;
+
+    while let Some(field) = multipart.next_field().await.unwrap() {
+        let name = field.name().unwrap_or("unnamed").to_string();
+        let declared = field.content_type().map(|content_type| content_type.to_owned());
+        let data = field.bytes().await;
+
+        if name == "unnamed" {
+            continue;
+        }
+
+        if data.is_err() {
+            tracing::error!("ERROR GETTING FILE DATA - {}", data.err().unwrap());
+            continue;
+        }
+
+        let data = data.unwrap().to_vec();
+
+        let img_data = decode_still(&state, data, declared.as_deref()).await;
+
+        if img_data.is_err() {
+            let reason = img_data.err().unwrap();
+            tracing::error!("{}", reason);
+            rejected = Some(reason);
+            continue;
+        }
+
+        let res = store_user_avatar(&state, &client, &user_id, img_data.unwrap()).await;
+
+        if res.is_err() {
+            tracing::error!("{:?}", res.err().unwrap());
+            continue;
+        }
+
+        avatar = Some(res.unwrap());
+    }
+
+    if avatar.is_none() {
+        return AppResponse::Error(match rejected {
+            Some(reason) => format!("NO AVATAR STORED FOR USER - {} - {}", &user_id, reason),
+            None => format!("NO AVATAR STORED FOR USER - {}", &user_id),
+        });
+    }
+
+    return AppResponse::SuccessData(
+        "Avatar".to_owned(),
+        crate::enums::SuccessActions::Upload,
+        avatar.unwrap()
+    );
+}
+
+async fn fetch_gravatar_avatar(
+    AuthenticatedUser(claims): AuthenticatedUser,
+    State(state): State<AppState>
+) -> impl IntoResponse {
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    let user = client.query_one(
+        "SELECT users.id, users.email, users.image, users.use_gravatar FROM users WHERE users.id = $1;",
+        &[&claims.user_id]
+    ).await;
+
+    if user.is_err() {
+        return AppResponse::Unauthorized;
+    }
+    let user = user.unwrap();
+    let user_id: Uuid = user.get("id");
+    let email: String = user.get("email");
+    let user_image: Option<String> = user.get("image");
+    let use_gravatar: bool = user.get("use_gravatar");
+
+    if !use_gravatar {
+        return AppResponse::Auth;
+    }
+
+    if user_image.is_some() {
+        return AppResponse::Success("Avatar".to_owned(), crate::enums::SuccessActions::Upload);
+    }
+
+    let mut hasher = Sha256::new();
+    hasher.update(email.trim().to_lowercase().as_bytes());
+    let email_hash = format!("{:x}", hasher.finalize());
+
+    let res = state.reqwest_client
+        .get(format!("{}/{}?s=256&d=404", &state.config.avatar_fallback_url, &email_hash))
+        .send().await;
+
+    if res.is_err() {
+        return AppResponse::Error(res.err().unwrap().to_string());
+    }
+
+    let res = res.unwrap();
+
+    if res.status() != StatusCode::OK {
+        return AppResponse::Error(format!("NO FALLBACK AVATAR FOR USER - {}", &user_id));
+    }
+
+    let data = res.bytes().await;
+
+    if data.is_err() {
+        return AppResponse::Error(data.err().unwrap().to_string());
+    }
+
+    let img_data = decode_still(&state, data.unwrap().to_vec(), None).await;
+
+    if img_data.is_err() {
+        return AppResponse::Error(img_data.err().unwrap().to_string());
+    }
+
+    let res = store_user_avatar(&state, &client, &user_id, img_data.unwrap()).await;
+
+    if res.is_err() {
+        return res.err().unwrap();
+    }
+
+    return AppResponse::SuccessData(
+        "Avatar".to_owned(),
+        crate::enums::SuccessActions::Upload,
+        res.unwrap()
+    );
+}
+
+// Called by the gateway service, not by users, so it's authenticated with a signature over the
+// target and the body made with the shared gateway secret
+async fn upload_gateway_entity(
+    State(state): State<AppState>,
+    ExtractPath((project_id, entity_id)): ExtractPath<(Uuid, Uuid)>,
+    request: Request
+) -> impl IntoResponse {
+    // Read in full before anything is parsed, the signature covers the raw body
+    let (parts, body) = request.into_parts();
+    let body = to_bytes(body, MAX_FILE_SIZE).await;
+
+    if body.is_err() {
+        return AppResponse::Error(format!("INVALID REQUEST BODY - {}", body.err().unwrap()));
+    }
+
+    let body = body.unwrap();
+
+    if
+        !check_gateway_signature(
+            &parts.headers,
+            &state.config.gateway_secret,
+            &project_id,
+            &entity_id,
+            &body
+        )
+    {
+        return AppResponse::Unauthorized;
+    }
+
+    let multipart = Multipart::from_request(
+        Request::from_parts(parts, Body::from(body)),
+        &state
+    ).await;
+
+    if multipart.is_err() {
+        return AppResponse::Error(multipart.err().unwrap().body_text());
+    }
+
+    let mut multipart = multipart.unwrap();
+
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    let target = resolve_target(&state, &project_id).await;
+
+    if target.is_err() {
+        return target.err().unwrap();
+    }
+
+    let target = target.unwrap();
+
+    let project_res = client.query_one(
+        "SELECT owner_id, asset_visibility FROM projects WHERE projects.id = $1;",
+        &[&project_id]
+    ).await;
+
+    if project_res.is_err() {
+        return AppResponse::Error(project_res.err().unwrap().to_string());
+    }
+
+    let project_res = project_res.unwrap();
+    let owner_id: Uuid = project_res.get("owner_id");
+    let visibility: Option<AssetVisibility> = project_res.get("asset_visibility");
+    let encode_options = EncodeOptions::default();
+    let mut uploaded: Vec<Uuid> = vec![];
+    let mut rejected: Vec<Value> = vec![];
+
+    while let Some(field) = multipart.next_field().await.unwrap() {
+        let name = field.name().unwrap_or("unnamed").to_string();
+        let declared = field.content_type().map(|content_type| content_type.to_owned());
+        let data = field.bytes().await;
+
+        if name == "unnamed" {
+            continue;
+        }
+
+        if data.is_err() {
+            tracing::error!("ERROR GETTING FILE DATA - {}", data.err().unwrap());
+            continue;
+        }
+
+        let data = data.unwrap().to_vec();
+
+        let img_data = decode_still(&state, data.clone(), declared.as_deref()).await;
+
+        if img_data.is_err() {
+            let reason = img_data.err().unwrap();
+            tracing::error!("{}", reason);
+            rejected.push(json!({ "file": name, "reason": reason }));
+            continue;
+        }
+
+        let img_data = img_data.unwrap();
+        let facts = ImageFacts::from_head(
+            &data,
+            data.len() as u64,
+            (img_data.width(), img_data.height())
+        );
+        let valid = validate_image(&ImageType::Images, &facts);
+
+        if valid.is_err() {
+            return validation_failed(&ImageType::Images, valid.err().unwrap());
+        }
+
+        let new = NewAsset {
+            id: Uuid::new_v4(),
+            key_id: Some(entity_id),
+            title: &name,
+            project_id: &project_id,
+            image_type: &ImageType::Images,
+            owner_id: &owner_id,
+            pending: false,
+            visibility: visibility.unwrap_or(AssetVisibility::Public),
+            dedupe: false,
+            max_size_bytes: None,
+        };
+
+        let body = AssetBody::Image {
+            img: DecodedImage::Still(img_data),
+            head: &data,
+            format: OutputFormat::Webp,
+            options: &encode_options,
+        };
+
+        let stored = store_asset(&state, &client, &target, &new, body, None).await;
+
+        if stored.is_err() {
+            tracing::error!("{:?}", stored.err().unwrap());
+            continue;
+        }
+
+        uploaded.push(new.id);
+    }
+
+    record_audit(
+        &client,
+        &AuditActor::gateway(),
+        &project_id,
+        AuditAction::Upload,
+        &uploaded
+    ).await;
+
+    return AppResponse::SuccessData(
+        "Image(s)".to_owned(),
+        crate::enums::SuccessActions::Upload,
+        json!({ "uploaded": uploaded, "rejected": rejected })
+    );
+}
+
+// Server-sent events for an upload started with the same upload_id. Clients can subscribe before
+// sending the upload, the stream ends after its `completed` event.
+async fn upload_progress_stream(
+    AuthenticatedUser(claims): AuthenticatedUser,
+    State(state): State<AppState>,
+    ExtractPath(upload_id): ExtractPath<Uuid>
+) -> Response {
+    // Subscribed before the status is read, so an upload finishing in between isn't missed
+    let receiver = state.upload_events.subscribe();
+    let status = get_upload_status(&state.upload_tracker, &upload_id);
+
+    if status.as_ref().is_some_and(|status| status.user_id != claims.user_id) {
+        return AppResponse::Auth.into_response();
+    }
+
+    let user_id = claims.user_id;
+
+    // Already over, there is nothing left to wait for
+    let finished: Vec<UploadEvent> = status
+        .filter(|status| status.stage == UploadStage::Completed)
+        .map(|status| UploadEvent {
+            upload_id,
+            user_id,
+            kind: UploadEventKind::Completed,
+            file: None,
+            asset_id: None,
+            bytes_received: status.bytes_received,
+        })
+        .into_iter()
+        .collect();
+
+    let live = stream::unfold(
+        (receiver, !finished.is_empty()),
+        move |(mut receiver, done)| async move {
+            if done {
+                return None;
+            }
+
+            loop {
+                match receiver.recv().await {
+                    Ok(event) if event.upload_id == upload_id && event.user_id == user_id => {
+                        let done = event.kind == UploadEventKind::Completed;
+
+                        return Some((event, (receiver, done)));
+                    }
+                    Ok(_) => {}
+                    // Progress is best effort, a subscriber that fell behind picks up from here
+                    Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => {
+                        return None;
+                    }
+                }
+            }
+        }
+    );
+
+    let events = stream
+        ::iter(finished)
+        .chain(live)
+        .map(|event| Event::default().event(event.kind.name()).json_data(&event))
+        // Open streams would otherwise hold up the graceful shutdown
+        .take_until(state.shutdown.clone().cancelled_owned());
+
+    Sse::new(events).keep_alive(KeepAlive::default()).into_response()
+}
+
+async fn get_upload_status_route(
+    AuthenticatedUser(claims): AuthenticatedUser,
+    State(state): State<AppState>,
+    ExtractPath(upload_id): ExtractPath<Uuid>
+) -> impl IntoResponse {
+    let status = get_upload_status(&state.upload_tracker, &upload_id);
+
+    if status.is_none() {
+        return AppResponse::Error(format!("UPLOAD NOT FOUND - {}", upload_id));
+    }
+
+    let status = status.unwrap();
+
+    if status.user_id != claims.user_id {
+        return AppResponse::Auth;
+    }
+
+    return AppResponse::SuccessData(
+        "Upload status".to_owned(),
+        crate::enums::SuccessActions::Read,
+        json!(status)
+    );
+}
+
+#[derive(Deserialize, ToSchema)]
+struct PresignPayload {
+    title: String,
+    content_type: String,
+}
+
+// Direct uploads skip the encoding pipeline, so images have to arrive as WebP or AVIF. The
+// rules of the image type are checked against what is announced, and against the object itself
+// once it's uploaded.
+fn direct_media_type(
+    state: &AppState,
+    project_id: &Uuid,
+    claims: &Claims,
+    image_type: &ImageType,
+    content_type: &str,
+    size_bytes: Option<u64>
+) -> Result<SniffedAsset, AppResponse> {
+    let media_type = supported_media_type(content_type);
+
+    if media_type.is_none() {
+        return Err(AppResponse::Error(format!("UNSUPPORTED CONTENT TYPE - {}", content_type)));
+    }
+
+    let media_type = media_type.unwrap();
+
+    if
+        media_type.mime_type == OutputFormat::Avif.content_type() &&
+        !state.config.feature_flags.is_enabled(Feature::Avif, project_id, Some(&claims.user_id))
+    {
+        return Err(AppResponse::Error("AVIF IS NOT ENABLED FOR THIS PROJECT".to_owned()));
+    }
+
+    if media_type.kind == AssetKind::Image {
+        let facts = ImageFacts {
+            format: ImageFormat::from_mime_type(media_type.mime_type),
+            size_bytes,
+            dimensions: None,
+        };
+        let valid = validate_image(image_type, &facts);
+
+        if valid.is_err() {
+            return Err(validation_failed(image_type, valid.err().unwrap()));
+        }
+    }
+
+    Ok(media_type)
+}
+
+// Reserves a hidden images row and hands out a presigned PUT, the row only becomes
+// visible once the client calls the confirm route after uploading.
+#[utoipa::path(
+    post,
+    path = "/upload/presign/{project_id}/{image_type}",
+    tag = "uploads",
+    params(("project_id" = Uuid, Path), ("image_type" = ImageType, Path)),
+    request_body = PresignPayload,
+    responses(
+        (
+            status = 200,
+            description = "URL and headers to PUT the file with",
+            body = ResponsePayload,
+        ),
+        (
+            status = 422,
+            description = "The format breaks a rule of the image type",
+            body = ResponsePayload,
+        )
+    )
+)]
+async fn presign_upload(
+    State(state): State<AppState>,
+    AuthenticatedUser(claims): AuthenticatedUser,
+    ExtractPath((project_id, image_type)): ExtractPath<(Uuid, ImageType)>,
+    Json(payload): Json<PresignPayload>
+) -> impl IntoResponse {
+    let media_type = direct_media_type(
+        &state,
+        &project_id,
+        &claims,
+        &image_type,
+        &payload.content_type,
+        None
+    );
+
+    if media_type.is_err() {
+        return media_type.err().unwrap();
+    }
+
+    let media_type = media_type.unwrap();
+
+    let can_upload = check_project_permission(
+        &state,
+        &claims.user_id,
+        &project_id,
+        RequiredPermission::Upload
+    ).await;
+
+    if can_upload.is_err() {
+        return can_upload.err().unwrap();
+    }
+
+    if !can_upload.unwrap() {
+        return AppResponse::Auth;
+    }
+
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    let target = resolve_target(&state, &project_id).await;
+
+    if target.is_err() {
+        return target.err().unwrap();
+    }
+
+    let target = target.unwrap();
+
+    let id = Uuid::new_v4();
+    let key = asset_key(&project_id, &image_type, &media_type.kind, &id, media_type.mime_type);
+
+    let res = client.execute(
+        "INSERT INTO images (id, title, project_id, type, owner_id, pending, awaiting_upload, kind, mime_type)
+         VALUES ($1, $2, $3, $4, $5, TRUE, TRUE, $6, $7);",
+        &[
+            &id,
+            &payload.title,
+            &project_id,
+            &image_type,
+            &claims.user_id,
+            &media_type.kind,
+            &media_type.mime_type,
+        ]
+    ).await;
+
+    if res.is_err() {
+        return AppResponse::Error(res.err().unwrap().to_string());
+    }
+
+    let command = target.presign_put(
+        &key,
+        &PutOptions {
+            content_type: media_type.mime_type,
+            cache_control: state.config.cache_control.for_image_type(&image_type),
+            visibility: AssetVisibility::Private,
+        },
+        PRESIGN_DURATION
+    ).await;
+
+    if command.is_err() {
+        let _ = client.execute("DELETE FROM images WHERE id = $1;", &[&id]).await;
+        return AppResponse::Error(command.err().unwrap());
+    }
+
+    let command = command.unwrap();
+
+    return AppResponse::SuccessData(
+        "Upload URL".to_owned(),
+        crate::enums::SuccessActions::Create,
+        json!({
+            "id": id,
+            "url": command.url,
+            "headers": command.headers,
+            "expires_in": PRESIGN_DURATION.as_secs(),
+        })
+    );
+}
+
+#[utoipa::path(
+    post,
+    path = "/upload/confirm/{project_id}/{id}",
+    tag = "uploads",
+    params(("project_id" = Uuid, Path), ("id" = Uuid, Path, description = "Reserved upload")),
+    responses(
+        (status = 200, description = "The uploaded asset", body = ResponsePayload),
+        (
+            status = 422,
+            description = "The file breaks a rule of the image type",
+            body = ResponsePayload,
+        )
+    )
+)]
+async fn confirm_upload(
+    State(state): State<AppState>,
+    AuthenticatedUser(claims): AuthenticatedUser,
+    ExtractPath((project_id, id)): ExtractPath<(Uuid, Uuid)>
+) -> impl IntoResponse {
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    let image = client.query_opt(
+        "SELECT type, kind, mime_type FROM images
+         WHERE id = $1 AND project_id = $2 AND owner_id = $3 AND awaiting_upload = TRUE;",
+        &[&id, &project_id, &claims.user_id]
+    ).await;
+
+    if image.is_err() {
+        return AppResponse::Error(image.err().unwrap().to_string());
+    }
+
+    let image = image.unwrap();
+
+    if image.is_none() {
+        return AppResponse::Error(format!("NO RESERVED UPLOAD - {}", id));
+    }
+
+    let target = resolve_target(&state, &project_id).await;
+
+    if target.is_err() {
+        return target.err().unwrap();
+    }
+
+    return finalize_reserved_upload(
+        &state,
+        &client,
+        &claims,
+        &target.unwrap(),
+        &project_id,
+        &id,
+        &image.unwrap()
+    ).await;
+}
+
+// Enough for the dimensions of JPEGs that carry large EXIF or ICC segments before them
+const DIMENSIONS_PROBE_BYTES: u64 = 128 * 1024;
+
+// Checks an object uploaded straight to storage against its reserved row (type, kind and
+// mime_type), then makes the row visible. Shared by presigned and resumable uploads.
+async fn finalize_reserved_upload(
+    state: &AppState,
+    client: &Object,
+    claims: &Claims,
+    target: &StorageTarget,
+    project_id: &Uuid,
+    id: &Uuid,
+    image: &Row
+) -> AppResponse {
+    let image_type: ImageType = image.get("type");
+    let kind: AssetKind = image.get("kind");
+    let mime_type: String = image.get("mime_type");
+    let key = asset_key(project_id, &image_type, &kind, id, &mime_type);
+
+    let head = target.head(&key).await;
+
+    if head.is_err() {
+        return AppResponse::Error(format!("UPLOAD NOT FOUND - {}", id));
+    }
+
+    let size_bytes = head.unwrap().size;
+
+    // The presigned URL pins the content type header, not the body, so check the magic bytes
+    let header = target.get_range(&key, DIMENSIONS_PROBE_BYTES).await.ok();
+
+    // The image header carries the dimensions, so there is no need to fetch the whole object
+    let dimensions = header
+        .as_ref()
+        .filter(|_| kind == AssetKind::Image)
+        .and_then(|header| {
+            ImageReader::new(Cursor::new(header))
+                .with_guessed_format()
+                .ok()
+                .and_then(|reader| reader.into_dimensions().ok())
+        });
+
+    let is_animated = header.as_ref().is_some_and(|header| is_animated_webp(header));
+
+    let is_valid = header.is_some_and(|header| {
+        match kind {
+            AssetKind::Image =>
+                image
+                    ::guess_format(&header)
+                    .is_ok_and(|format| Some(format) == ImageFormat::from_mime_type(&mime_type)),
+            _ => sniff_asset(&header).is_some_and(|sniffed| sniffed.mime_type == mime_type),
+        }
+    });
+
+    if !is_valid {
+        let _ = target.delete(&key).await;
+        let _ = client.execute("DELETE FROM images WHERE id = $1;", &[&id]).await;
+
+        return AppResponse::Error(format!("UPLOADED FILE DOES NOT MATCH {} - {}", mime_type, id));
+    }
+
+    if kind == AssetKind::Image {
+        // The object is decoded later on for thumbnails and variants, so it's held to the same
+        // pixel limit as uploads decoded here. Without readable dimensions that can't be checked.
+        let max_pixels = state.config.max_image_pixels;
+        let too_large = match dimensions {
+            Some((width, height)) => (width as u64) * (height as u64) > max_pixels,
+            None => true,
+        };
+
+        if too_large {
+            let _ = target.delete(&key).await;
+            let _ = client.execute("DELETE FROM images WHERE id = $1;", &[&id]).await;
+
+            return AppResponse::Error(format!("{} OF {} - {}", IMAGE_TOO_LARGE, max_pixels, id));
+        }
+
+        let facts = ImageFacts {
+            format: ImageFormat::from_mime_type(&mime_type),
+            size_bytes: Some(size_bytes as u64),
+            dimensions,
+        };
+        let valid = validate_image(&image_type, &facts);
+
+        if valid.is_err() {
+            let _ = target.delete(&key).await;
+            let _ = client.execute("DELETE FROM images WHERE id = $1;", &[&id]).await;
+
+            return validation_failed(&image_type, valid.err().unwrap());
+        }
+    }
+
+    let pending = requires_approval(state, client, project_id, claims).await;
+
+    if pending.is_err() {
+        return pending.err().unwrap();
+    }
+
+    let pending = pending.unwrap();
+    let moderation_status = match kind {
+        AssetKind::Image if requires_moderation(state, client, project_id).await =>
+            Some(ModerationStatus::Pending),
+        _ => None,
+    };
+
+    if !pending && moderation_status.is_none() {
+        let visibility = live_visibility(state, project_id, pending).await;
+
+        if visibility.is_err() {
+            return visibility.err().unwrap();
+        }
+
+        let res = target.set_visibility(&key, visibility.unwrap()).await;
+
+        if res.is_err() {
+            return AppResponse::Error(res.err().unwrap());
+        }
+    }
+
+    let width = dimensions.map(|(width, _)| width as i32);
+    let height = dimensions.map(|(_, height)| height as i32);
+    // Direct uploads are stored exactly as sent
+    let original_format = match kind {
+        AssetKind::Image => Some(mime_type.clone()),
+        _ => None,
+    };
+
+    // The row was inserted when the URL was handed out, the asset only exists from now on
+    let res = client.execute(
+        "UPDATE images SET size_bytes = $1, pending = $2, awaiting_upload = FALSE, width = $3, height = $4, original_format = $5,
+            is_animated = $6, moderation_status = $8, created_at = NOW(), updated_at = NOW()
+         WHERE id = $7;",
+        &[
+            &size_bytes,
+            &pending,
+            &width,
+            &height,
+            &original_format,
+            &is_animated,
+            &id,
+            &moderation_status,
+        ]
+    ).await;
+
+    if res.is_err() {
+        return AppResponse::Error(res.err().unwrap().to_string());
+    }
+
+    if moderation_status.is_some() {
+        notify_moderation_worker(state);
+    }
+
+    if kind == AssetKind::Image {
+        queue_variants(state, *project_id, image_type, *id, mime_type.clone());
+    }
+
+    emit_asset_event(state, client, WebhookEvent::Uploaded, id).await;
+    record_audit(client, &AuditActor::user(claims), project_id, AuditAction::Upload, &[*id]).await;
+
+    return AppResponse::SuccessData(
+        "Image".to_owned(),
+        crate::enums::SuccessActions::Upload,
+        json!({
+            "id": id,
+            "kind": kind,
+            "mime_type": mime_type,
+            "size_bytes": size_bytes,
+            "width": width,
+            "height": height,
+            "original_format": original_format,
+            "is_animated": is_animated,
+        })
+    );
+}
+
+#[derive(Deserialize, ToSchema)]
+struct SessionPayload {
+    title: String,
+    content_type: String,
+    image_type: ImageType,
+    size_bytes: i64,
+}
+
+// Columns of an upload session together with its reserved images row
+const SESSION_COLUMNS: &str =
+    "upload_sessions.upload_id, upload_sessions.size_bytes, upload_sessions.received_bytes,
+     upload_sessions.part_etags, images.type, images.kind, images.mime_type";
+
+async fn get_session(
+    client: &Object,
+    claims: &Claims,
+    project_id: &Uuid,
+    id: &Uuid
+) -> Result<Row, AppResponse> {
+    let session = client.query_opt(
+        &format!(
+            "SELECT {} FROM upload_sessions
+             JOIN images ON images.id = upload_sessions.id
+             WHERE upload_sessions.id = $1 AND upload_sessions.project_id = $2
+                AND upload_sessions.owner_id = $3 AND upload_sessions.expires_at > NOW();",
+            SESSION_COLUMNS
+        ),
+        &[&id, &project_id, &claims.user_id]
+    ).await;
+
+    if session.is_err() {
+        return Err(AppResponse::Error(session.err().unwrap().to_string()));
+    }
+
+    let session = session.unwrap();
+
+    if session.is_none() {
+        return Err(AppResponse::Error(format!("NO UPLOAD SESSION - {}", id)));
+    }
+
+    Ok(session.unwrap())
+}
+
+fn session_key(project_id: &Uuid, id: &Uuid, session: &Row) -> String {
+    let image_type: ImageType = session.get("type");
+    let kind: AssetKind = session.get("kind");
+    let mime_type: String = session.get("mime_type");
+
+    asset_key(project_id, &image_type, &kind, id, &mime_type)
+}
+
+fn session_status(id: &Uuid, size_bytes: i64, received_bytes: i64) -> Value {
+    json!({
+        "id": id,
+        "size_bytes": size_bytes,
+        "received_bytes": received_bytes,
+        "chunk_size": UPLOAD_CHUNK_SIZE,
+    })
+}
+
+// Starts a resumable upload for files too large (or connections too flaky) for a single
+// request. The file is sent in UPLOAD_CHUNK_SIZE chunks, each stored as one part of a
+// multipart upload, and is checked like a presigned upload once complete.
+#[utoipa::path(
+    post,
+    path = "/upload/sessions/{project_id}",
+    tag = "uploads",
+    params(("project_id" = Uuid, Path)),
+    request_body = SessionPayload,
+    responses(
+        (status = 200, description = "The session and its chunk size", body = ResponsePayload),
+        (
+            status = 422,
+            description = "The file breaks a rule of the image type",
+            body = ResponsePayload,
+        )
+    )
+)]
+async fn start_upload_session(
+    State(state): State<AppState>,
+    AuthenticatedUser(claims): AuthenticatedUser,
+    ExtractPath(project_id): ExtractPath<Uuid>,
+    Json(payload): Json<SessionPayload>
+) -> impl IntoResponse {
+    if payload.size_bytes <= 0 || payload.size_bytes > MAX_RESUMABLE_FILE_SIZE {
+        return AppResponse::Error(format!("INVALID UPLOAD SIZE - {}", payload.size_bytes));
+    }
+
+    let media_type = direct_media_type(
+        &state,
+        &project_id,
+        &claims,
+        &payload.image_type,
+        &payload.content_type,
+        Some(payload.size_bytes as u64)
+    );
+
+    if media_type.is_err() {
+        return media_type.err().unwrap();
+    }
+
+    let media_type = media_type.unwrap();
+
+    let can_upload = check_project_permission(
+        &state,
+        &claims.user_id,
+        &project_id,
+        RequiredPermission::Upload
+    ).await;
+
+    if can_upload.is_err() {
+        return can_upload.err().unwrap();
+    }
+
+    if !can_upload.unwrap() {
+        return AppResponse::Auth;
+    }
+
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let mut client = client.unwrap();
+
+    let target = resolve_target(&state, &project_id).await;
+
+    if target.is_err() {
+        return target.err().unwrap();
+    }
+
+    let target = target.unwrap();
+
+    let id = Uuid::new_v4();
+    let key = asset_key(
+        &project_id,
+        &payload.image_type,
+        &media_type.kind,
+        &id,
+        media_type.mime_type
+    );
+
+    let upload_id = target.create_multipart(&key, &PutOptions {
+        content_type: media_type.mime_type,
+        cache_control: state.config.cache_control.for_image_type(&payload.image_type),
+        visibility: AssetVisibility::Private,
+    }).await;
+
+    if upload_id.is_err() {
+        return AppResponse::Error(upload_id.err().unwrap());
+    }
+
+    let upload_id = upload_id.unwrap();
+
+    let res = insert_upload_session(
+        &mut client,
+        &claims,
+        &project_id,
+        &id,
+        &payload,
+        &media_type,
+        &upload_id
+    ).await;
+
+    if res.is_err() {
+        let abort = target.abort_multipart(&key, &upload_id).await;
+
+        if abort.is_err() {
+            tracing::error!("ERROR ABORTING MULTIPART UPLOAD - {}", abort.err().unwrap());
+        }
+
+        return res.err().unwrap();
+    }
+
+    return AppResponse::SuccessData(
+        "Upload session".to_owned(),
+        crate::enums::SuccessActions::Create,
+        session_status(&id, payload.size_bytes, 0)
+    );
+}
+
+// The reserved images row and the session are created together, so neither exists alone
+async fn insert_upload_session(
+    client: &mut Object,
+    claims: &Claims,
+    project_id: &Uuid,
+    id: &Uuid,
+    payload: &SessionPayload,
+    media_type: &SniffedAsset,
+    upload_id: &str
+) -> Result<(), AppResponse> {
+    let transaction = client.transaction().await;
+
+    if transaction.is_err() {
+        return Err(AppResponse::Error(transaction.err().unwrap().to_string()));
+    }
+
+    let transaction = transaction.unwrap();
+
+    let res = transaction.execute(
+        "INSERT INTO images (id, title, project_id, type, owner_id, pending, awaiting_upload, kind, mime_type)
+         VALUES ($1, $2, $3, $4, $5, TRUE, TRUE, $6, $7);",
+        &[
+            &id,
+            &payload.title,
+            &project_id,
+            &payload.image_type,
+            &claims.user_id,
+            &media_type.kind,
+            &media_type.mime_type,
+        ]
+    ).await;
+
+    if res.is_err() {
+        return Err(AppResponse::Error(res.err().unwrap().to_string()));
+    }
+
+    let res = transaction.execute(
+        "INSERT INTO upload_sessions (id, project_id, owner_id, upload_id, size_bytes, received_bytes, part_etags, expires_at)
+         VALUES ($1, $2, $3, $4, $5, 0, '{}', NOW() + make_interval(hours => $6));",
+        &[
+            &id,
+            &project_id,
+            &claims.user_id,
+            &upload_id,
+            &payload.size_bytes,
+            &UPLOAD_SESSION_TTL_HOURS,
+        ]
+    ).await;
+
+    if res.is_err() {
+        return Err(AppResponse::Error(res.err().unwrap().to_string()));
+    }
+
+    let res = transaction.commit().await;
+
+    if res.is_err() {
+        return Err(AppResponse::Error(res.err().unwrap().to_string()));
+    }
+
+    Ok(())
+}
+
+async fn get_upload_session(
+    State(state): State<AppState>,
+    AuthenticatedUser(claims): AuthenticatedUser,
+    ExtractPath((project_id, id)): ExtractPath<(Uuid, Uuid)>
+) -> impl IntoResponse {
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    let session = get_session(&client, &claims, &project_id, &id).await;
+
+    if session.is_err() {
+        return session.err().unwrap();
+    }
+
+    let session = session.unwrap();
+
+    return AppResponse::SuccessData(
+        "Upload session".to_owned(),
+        crate::enums::SuccessActions::Read,
+        session_status(&id, session.get("size_bytes"), session.get("received_bytes"))
+    );
+}
+
+// Appends one chunk. Upload-Offset has to match the bytes received so far, so a chunk that
+// was retried after its response got lost is rejected instead of being stored twice.
+async fn upload_session_chunk(
+    State(state): State<AppState>,
+    AuthenticatedUser(claims): AuthenticatedUser,
+    ExtractPath((project_id, id)): ExtractPath<(Uuid, Uuid)>,
+    headers: HeaderMap,
+    body: Bytes
+) -> impl IntoResponse {
+    let offset = headers
+        .get("Upload-Offset")
+        .and_then(|offset| offset.to_str().ok())
+        .and_then(|offset| offset.parse::<i64>().ok());
+
+    if offset.is_none() {
+        return AppResponse::Error("MISSING UPLOAD-OFFSET HEADER".to_owned());
+    }
+
+    let offset = offset.unwrap();
+
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    let session = get_session(&client, &claims, &project_id, &id).await;
+
+    if session.is_err() {
+        return session.err().unwrap();
+    }
+
+    let session = session.unwrap();
+    let size_bytes: i64 = session.get("size_bytes");
+    let received_bytes: i64 = session.get("received_bytes");
+
+    if offset != received_bytes {
+        return AppResponse::Conflict(
+            "Upload offset does not match the bytes received.".to_owned(),
+            session_status(&id, size_bytes, received_bytes)
+        );
+    }
+
+    let chunk_size = body.len() as i64;
+    let remaining = size_bytes - received_bytes;
+
+    // Every chunk but the last has to be exactly UPLOAD_CHUNK_SIZE, so part numbers follow
+    // from the offset and parts never drop below the storage minimum
+    if chunk_size == 0 || chunk_size > remaining {
+        return AppResponse::Error(format!("INVALID CHUNK SIZE - {}", chunk_size));
+    }
+
+    if chunk_size < remaining && chunk_size != (UPLOAD_CHUNK_SIZE as i64) {
+        return AppResponse::Error(format!("INVALID CHUNK SIZE - {}", chunk_size));
+    }
+
+    let target = resolve_target(&state, &project_id).await;
+
+    if target.is_err() {
+        return target.err().unwrap();
+    }
+
+    let target = target.unwrap();
+
+    let upload_id: String = session.get("upload_id");
+    let part_number = (received_bytes / (UPLOAD_CHUNK_SIZE as i64)) as i32 + 1;
+
+    let etag = target.upload_part(
+        &session_key(&project_id, &id, &session),
+        &upload_id,
+        part_number,
+        body.to_vec()
+    ).await;
+
+    if etag.is_err() {
+        return AppResponse::Error(etag.err().unwrap());
+    }
+
+    let res = client.execute(
+        "UPDATE upload_sessions
+         SET received_bytes = received_bytes + $2, part_etags = array_append(part_etags, $3)
+         WHERE id = $1 AND received_bytes = $4;",
+        &[&id, &chunk_size, &etag.unwrap(), &received_bytes]
+    ).await;
+
+    if res.is_err() {
+        return AppResponse::Error(res.err().unwrap().to_string());
+    }
+
+    // Another request stored the same chunk first
+    if res.unwrap() == 0 {
+        return AppResponse::Conflict(
+            "Upload offset does not match the bytes received.".to_owned(),
+            session_status(&id, size_bytes, received_bytes + chunk_size)
+        );
+    }
+
+    return AppResponse::SuccessData(
+        "Upload chunk".to_owned(),
+        crate::enums::SuccessActions::Upload,
+        session_status(&id, size_bytes, received_bytes + chunk_size)
+    );
+}
+
+#[utoipa::path(
+    post,
+    path = "/upload/sessions/{project_id}/{id}/complete",
+    tag = "uploads",
+    params(("project_id" = Uuid, Path), ("id" = Uuid, Path, description = "Upload session")),
+    responses(
+        (status = 200, description = "The uploaded asset", body = ResponsePayload),
+        (
+            status = 422,
+            description = "The file breaks a rule of the image type",
+            body = ResponsePayload,
+        )
+    )
+)]
+async fn complete_upload_session(
+    State(state): State<AppState>,
+    AuthenticatedUser(claims): AuthenticatedUser,
+    ExtractPath((project_id, id)): ExtractPath<(Uuid, Uuid)>
+) -> impl IntoResponse {
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    let session = get_session(&client, &claims, &project_id, &id).await;
+
+    if session.is_err() {
+        return session.err().unwrap();
+    }
+
+    let session = session.unwrap();
+    let size_bytes: i64 = session.get("size_bytes");
+    let received_bytes: i64 = session.get("received_bytes");
+
+    if received_bytes != size_bytes {
+        return AppResponse::Conflict(
+            "Upload is not complete.".to_owned(),
+            session_status(&id, size_bytes, received_bytes)
+        );
+    }
+
+    let target = resolve_target(&state, &project_id).await;
+
+    if target.is_err() {
+        return target.err().unwrap();
+    }
+
+    let target = target.unwrap();
+
+    let upload_id: String = session.get("upload_id");
+    let part_etags: Vec<String> = session.get("part_etags");
+    let parts: Vec<UploadedPart> = part_etags
+        .into_iter()
+        .enumerate()
+        .map(|(i, etag)| UploadedPart { part_number: (i as i32) + 1, etag })
+        .collect();
+
+    let res = target.complete_multipart(
+        &session_key(&project_id, &id, &session),
+        &upload_id,
+        &parts
+    ).await;
+
+    if res.is_err() {
+        return AppResponse::Error(res.err().unwrap());
+    }
+
+    let res = client.execute("DELETE FROM upload_sessions WHERE id = $1;", &[&id]).await;
+
+    if res.is_err() {
+        return AppResponse::Error(res.err().unwrap().to_string());
+    }
+
+    return finalize_reserved_upload(
+        &state,
+        &client,
+        &claims,
+        &target,
+        &project_id,
+        &id,
+        &session
+    ).await;
+}
+
+async fn abort_upload_session(
+    State(state): State<AppState>,
+    AuthenticatedUser(claims): AuthenticatedUser,
+    ExtractPath((project_id, id)): ExtractPath<(Uuid, Uuid)>
+) -> impl IntoResponse {
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    let session = get_session(&client, &claims, &project_id, &id).await;
+
+    if session.is_err() {
+        return session.err().unwrap();
+    }
+
+    let session = session.unwrap();
+
+    let target = resolve_target(&state, &project_id).await;
+
+    if target.is_err() {
+        return target.err().unwrap();
+    }
+
+    let res = target
+        .unwrap()
+        .abort_multipart(&session_key(&project_id, &id, &session), session.get("upload_id")).await;
+
+    if res.is_err() {
+        return AppResponse::Error(res.err().unwrap());
+    }
+
+    let res = client.execute(
+        "WITH session AS (DELETE FROM upload_sessions WHERE id = $1)
+         DELETE FROM images WHERE id = $1 AND awaiting_upload = TRUE;",
+        &[&id]
+    ).await;
+
+    if res.is_err() {
+        return AppResponse::Error(res.err().unwrap().to_string());
     }
 
-    return AppResponse::Success("Image(s)".to_owned(), crate::enums::SuccessActions::Upload);
+    return AppResponse::Success("Upload session".to_owned(), crate::enums::SuccessActions::Delete);
 }
 
-pub fn upload_routes() -> Router<AppState> {
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        upload_image,
+        upload_from_url,
+        upload_archive,
+        presign_upload,
+        confirm_upload,
+        start_upload_session,
+        complete_upload_session
+    ),
+    components(schemas(UploadResult))
+)]
+pub struct UploadApi;
+
+pub fn upload_routes(state: AppState) -> Router<AppState> {
     Router::new().nest(
         "/upload",
         Router::new()
             .route("/gateway/:project_id/:entity_id", post(upload_gateway_entity))
-            .route("/:project_id/:image_type", post(upload_image))
+            .route(
+                "/:project_id/:image_type",
+                post(upload_image).layer(from_fn_with_state(state.clone(), tenant_middleware))
+            )
+            .route(
+                "/from-url/:project_id/:image_type",
+                post(upload_from_url).layer(from_fn_with_state(state.clone(), tenant_middleware))
+            )
+            .merge(
+                Router::new()
+                    .route("/archive/:project_id/:image_type", post(upload_archive))
+                    .layer(from_fn_with_state(state.clone(), tenant_middleware))
+                    .layer(DefaultBodyLimit::max(MAX_ARCHIVE_SIZE))
+            )
+            .route(
+                "/presign/:project_id/:image_type",
+                post(presign_upload).layer(from_fn_with_state(state.clone(), tenant_middleware))
+            )
+            .merge(
+                Router::new()
+                    .route("/sessions/:project_id", post(start_upload_session))
+                    .route(
+                        "/sessions/:project_id/:id",
+                        get(get_upload_session)
+                            .patch(upload_session_chunk)
+                            .delete(abort_upload_session)
+                    )
+                    .route("/sessions/:project_id/:id/complete", post(complete_upload_session))
+                    .layer(from_fn_with_state(state.clone(), tenant_middleware))
+                    .layer(DefaultBodyLimit::max(UPLOAD_CHUNK_SIZE))
+            )
+            .route(
+                "/confirm/:project_id/:id",
+                post(confirm_upload).layer(from_fn_with_state(state.clone(), tenant_middleware))
+            )
+            .route("/status/:upload_id", get(get_upload_status_route))
+            .route("/progress/:session_id", get(upload_progress_stream))
             .route("/users/avatar", post(upload_user_avatar))
+            .route("/users/avatar/gravatar", post(fetch_gravatar_avatar))
+            .layer(from_fn_with_state(state, maintenance_middleware))
             .layer(DefaultBodyLimit::max(MAX_FILE_SIZE))
     )
 }
@@ -0,0 +1,324 @@
+use std::io::BufWriter;
+
+use axum::{
+    extract::State,
+    http::HeaderValue,
+    response::{ IntoResponse, Response },
+    routing::{ get, post },
+    Json,
+    Router,
+};
+use reqwest::{ header::{ CONTENT_DISPOSITION, CONTENT_TYPE }, StatusCode };
+use serde::Deserialize;
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::{
+    enums::{ AppResponse, AssetKind, ImageType },
+    jobs::asset_job::{ deleted_asset_columns, deletion_jobs, enqueue_jobs, notify_job_worker },
+    services::audit_service::forget_audit_user,
+    state::models::AppState,
+    storage::resolve_target,
+    utils::{
+        asset_utils::asset_key,
+        avatar_utils::delete_avatar,
+        db_utils::get_client,
+        dedup_utils::OBJECT_ID,
+        extractors::AuthenticatedUser,
+        trash_utils::{ is_in_trash, stored_key },
+        webhook_utils::{ enqueue_deleted_events, notify_webhook_worker },
+        zip_utils::{ body_channel, ZipStream },
+    },
+};
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ErasureMode {
+    // Removes the user's images entirely
+    Delete,
+    // Keeps the images in their projects but hands ownership to the project owner
+    Anonymize,
+}
+
+#[derive(Deserialize)]
+struct ErasurePayload {
+    mode: ErasureMode,
+}
+
+async fn export_user_data(
+    AuthenticatedUser(claims): AuthenticatedUser,
+    State(state): State<AppState>
+) -> Response {
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap().into_response();
+    }
+    let client = client.unwrap();
+
+    let rows = client.query(
+        &format!(
+            "SELECT id, title, description, project_id, type, kind, mime_type, {},
+                deleted_at IS NOT NULL AS trashed
+             FROM images WHERE owner_id = $1;",
+            OBJECT_ID
+        ),
+        &[&claims.user_id]
+    ).await;
+
+    if rows.is_err() {
+        return AppResponse::Error(rows.err().unwrap().to_string()).into_response();
+    }
+
+    let rows = rows.unwrap();
+    let user_id = claims.user_id;
+    let (writer, body) = body_channel();
+    let runtime = tokio::runtime::Handle::current();
+
+    // Objects are fetched one at a time while the archive is streamed, so the export of a large
+    // account is never held in memory as a whole
+    state.tasks.clone().spawn_blocking(move || {
+        let mut archive = ZipStream::new(BufWriter::new(writer));
+        let mut metadata: Vec<serde_json::Value> = vec![];
+
+        for row in rows {
+            let id: Uuid = row.get("id");
+            let title: Option<String> = row.get("title");
+            let description: Option<String> = row.get("description");
+            let project_id: Uuid = row.get("project_id");
+            let image_type: ImageType = row.get("type");
+            let kind: AssetKind = row.get("kind");
+            let mime_type: String = row.get("mime_type");
+            let trashed: bool = row.get("trashed");
+            let object_id: Uuid = row.get("object_id");
+
+            let target = runtime.block_on(resolve_target(&state, &project_id));
+
+            if target.is_err() {
+                tracing::error!("ERROR RESOLVING STORAGE TARGET - {:?}", target.err().unwrap());
+                continue;
+            }
+
+            let target = target.unwrap();
+            let key = asset_key(&project_id, &image_type, &kind, &object_id, &mime_type);
+            let in_trash = trashed && runtime.block_on(is_in_trash(&target, &key));
+            let key = stored_key(key, in_trash);
+
+            metadata.push(
+                json!({
+                    "id": id,
+                    "title": title,
+                    "description": description,
+                    "project_id": project_id,
+                    "type": image_type.to_string(),
+                    "kind": kind,
+                    "trashed": trashed,
+                    "file": key,
+                })
+            );
+
+            let data = runtime.block_on(target.get(&key));
+
+            if data.is_err() {
+                tracing::error!("ERROR GETTING IMAGE DATA - {}", data.err().unwrap());
+                continue;
+            }
+
+            let written = archive.add_file(&key, &data.unwrap());
+
+            if written.is_err() {
+                // The client went away, nothing left to stream to
+                tracing::error!("EXPORT ABORTED - {}", written.err().unwrap());
+                return;
+            }
+        }
+
+        let metadata = json!({ "user_id": user_id, "images": metadata }).to_string();
+        let finished = archive
+            .add_file("metadata.json", metadata.as_bytes())
+            .and_then(|_| archive.finish());
+
+        if finished.is_err() {
+            tracing::error!("EXPORT ABORTED - {}", finished.err().unwrap());
+        }
+    });
+
+    return (
+        StatusCode::OK,
+        [
+            (CONTENT_TYPE, HeaderValue::from_str("application/zip").unwrap()),
+            (
+                CONTENT_DISPOSITION,
+                HeaderValue::from_str(
+                    &format!("attachment; filename=\"arkive-export-{}.zip\"", user_id)
+                ).unwrap(),
+            ),
+        ],
+        body,
+    ).into_response();
+}
+
+async fn erase_user_data(
+    AuthenticatedUser(claims): AuthenticatedUser,
+    State(state): State<AppState>,
+    Json(payload): Json<ErasurePayload>
+) -> impl IntoResponse {
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let mut client = client.unwrap();
+
+    let mut deleted_images: Vec<Uuid> = vec![];
+    let mut anonymized_images: Vec<Uuid> = vec![];
+    let mut failed_keys: Vec<String> = vec![];
+
+    match payload.mode {
+        ErasureMode::Delete => {
+            let transaction = client.transaction().await;
+
+            if transaction.is_err() {
+                return AppResponse::Error(transaction.err().unwrap().to_string());
+            }
+            let transaction = transaction.unwrap();
+
+            let res = transaction.query(
+                &format!(
+                    "DELETE FROM images WHERE owner_id = $1 RETURNING id, {};",
+                    deleted_asset_columns()
+                ),
+                &[&claims.user_id]
+            ).await;
+
+            if res.is_err() {
+                return AppResponse::Error(res.err().unwrap().to_string());
+            }
+
+            let rows = res.unwrap();
+
+            // Objects other users' assets were deduplicated against stay for them
+            let jobs = deletion_jobs(&transaction, &rows).await;
+
+            if jobs.is_err() {
+                return jobs.err().unwrap();
+            }
+
+            let enqueued = enqueue_jobs(&transaction, &jobs.unwrap()).await;
+
+            if enqueued.is_err() {
+                return enqueued.err().unwrap();
+            }
+
+            let events = enqueue_deleted_events(&transaction, &rows).await;
+
+            if events.is_err() {
+                return events.err().unwrap();
+            }
+
+            let forgotten = forget_audit_user(&transaction, &claims.user_id).await;
+
+            if forgotten.is_err() {
+                return forgotten.err().unwrap();
+            }
+
+            let committed = transaction.commit().await;
+
+            if committed.is_err() {
+                return AppResponse::Error(committed.err().unwrap().to_string());
+            }
+
+            notify_job_worker(&state);
+            notify_webhook_worker(&state);
+
+            deleted_images = rows
+                .iter()
+                .map(|row| row.get("id"))
+                .collect();
+        }
+        ErasureMode::Anonymize => {
+            let transaction = client.transaction().await;
+
+            if transaction.is_err() {
+                return AppResponse::Error(transaction.err().unwrap().to_string());
+            }
+            let transaction = transaction.unwrap();
+
+            let res = transaction.query(
+                "UPDATE images SET owner_id = projects.owner_id
+                 FROM projects
+                 WHERE images.project_id = projects.id AND images.owner_id = $1
+                 RETURNING images.id;",
+                &[&claims.user_id]
+            ).await;
+
+            if res.is_err() {
+                return AppResponse::Error(res.err().unwrap().to_string());
+            }
+
+            let forgotten = forget_audit_user(&transaction, &claims.user_id).await;
+
+            if forgotten.is_err() {
+                return forgotten.err().unwrap();
+            }
+
+            let committed = transaction.commit().await;
+
+            if committed.is_err() {
+                return AppResponse::Error(committed.err().unwrap().to_string());
+            }
+
+            anonymized_images = res
+                .unwrap()
+                .iter()
+                .map(|row| row.get("id"))
+                .collect();
+        }
+    }
+
+    let mut avatar_removed = false;
+
+    let user = client.query_one(
+        "SELECT users.image FROM users WHERE users.id = $1;",
+        &[&claims.user_id]
+    ).await;
+
+    if user.is_ok() {
+        let user_image: Option<String> = user.unwrap().get("image");
+
+        if let Some(img) = user_image {
+            let failed = delete_avatar(&state, &img).await;
+
+            if !failed.is_empty() {
+                failed_keys.extend(failed);
+            } else {
+                let res = client.query(
+                    "UPDATE users SET image = NULL WHERE users.id = $1;",
+                    &[&claims.user_id]
+                ).await;
+
+                avatar_removed = res.is_ok();
+            }
+        }
+    }
+
+    return AppResponse::SuccessData(
+        "User data".to_owned(),
+        crate::enums::SuccessActions::Delete,
+        json!({
+            "deleted_images": deleted_images,
+            "anonymized_images": anonymized_images,
+            "avatar_removed": avatar_removed,
+            "failed_keys": failed_keys,
+        })
+    );
+}
+
+pub fn user_routes() -> Router<AppState> {
+    Router::new().nest(
+        "/users",
+        Router::new()
+            .route("/export", get(export_user_data))
+            .route("/erase", post(erase_user_data))
+    )
+}
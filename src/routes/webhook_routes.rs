@@ -0,0 +1,298 @@
+use axum::{
+    extract::{ Query, State },
+    middleware::from_fn_with_state,
+    response::IntoResponse,
+    routing::{ delete, get },
+    Json,
+    Router,
+};
+use serde::Deserialize;
+use serde_json::json;
+use url::Url;
+use uuid::Uuid;
+
+use crate::{
+    enums::{ AppResponse, WebhookDeliveryStatus, WebhookEvent },
+    state::models::AppState,
+    utils::{
+        db_utils::get_client,
+        extractors::ExtractPath,
+        tenant_utils::{ owner_middleware, tenant_middleware },
+        webhook_utils::generate_secret,
+    },
+};
+
+const MAX_WEBHOOKS_PER_PROJECT: i64 = 10;
+
+#[derive(Deserialize)]
+struct WebhookPayload {
+    url: String,
+    // Subscribes to every event when omitted
+    events: Option<Vec<WebhookEvent>>,
+}
+
+#[derive(Deserialize)]
+struct DeliveriesQuery {
+    status: Option<WebhookDeliveryStatus>,
+    limit: Option<i64>,
+}
+
+async fn list_webhooks(
+    State(state): State<AppState>,
+    ExtractPath(project_id): ExtractPath<Uuid>
+) -> impl IntoResponse {
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    let secret = client.query_opt(
+        "SELECT webhook_secret FROM projects WHERE id = $1;",
+        &[&project_id]
+    ).await;
+
+    if secret.is_err() {
+        return AppResponse::Error(secret.err().unwrap().to_string());
+    }
+
+    let secret: Option<String> = secret
+        .unwrap()
+        .and_then(|row| row.get("webhook_secret"));
+
+    let rows = client.query(
+        "SELECT id, url, events, (EXTRACT(EPOCH FROM created_at) * 1000)::BIGINT AS created_at
+         FROM webhooks
+         WHERE project_id = $1
+         ORDER BY created_at;",
+        &[&project_id]
+    ).await;
+
+    if rows.is_err() {
+        return AppResponse::Error(rows.err().unwrap().to_string());
+    }
+
+    let webhooks: Vec<serde_json::Value> = rows
+        .unwrap()
+        .iter()
+        .map(|row| {
+            let id: Uuid = row.get("id");
+            let url: String = row.get("url");
+            let events: Vec<WebhookEvent> = row.get("events");
+            let created_at: i64 = row.get("created_at");
+
+            json!({
+                "id": id,
+                "url": url,
+                "events": events,
+                "created_at": created_at,
+            })
+        })
+        .collect();
+
+    return AppResponse::SuccessData(
+        "Webhooks".to_owned(),
+        crate::enums::SuccessActions::Read,
+        json!({ "secret": secret, "webhooks": webhooks })
+    );
+}
+
+async fn create_webhook(
+    State(state): State<AppState>,
+    ExtractPath(project_id): ExtractPath<Uuid>,
+    Json(payload): Json<WebhookPayload>
+) -> impl IntoResponse {
+    let url = payload.url.trim().to_owned();
+
+    if !Url::parse(&url).is_ok_and(|parsed| matches!(parsed.scheme(), "http" | "https")) {
+        return AppResponse::Error(format!("INVALID WEBHOOK URL - {}", url));
+    }
+
+    let requested = payload.events.unwrap_or(WebhookEvent::ALL.to_vec());
+    let events: Vec<WebhookEvent> = WebhookEvent::ALL.into_iter()
+        .filter(|event| requested.contains(event))
+        .collect();
+
+    if events.is_empty() {
+        return AppResponse::Error("WEBHOOK HAS NO EVENTS".to_owned());
+    }
+
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let mut client = client.unwrap();
+
+    let transaction = client.transaction().await;
+
+    if transaction.is_err() {
+        return AppResponse::Error(transaction.err().unwrap().to_string());
+    }
+    let transaction = transaction.unwrap();
+
+    // Locks the project row, so concurrent requests can't go over the limit
+    let secret = transaction.query_one(
+        "UPDATE projects SET webhook_secret = COALESCE(webhook_secret, $2) WHERE id = $1
+         RETURNING webhook_secret;",
+        &[&project_id, &generate_secret()]
+    ).await;
+
+    if secret.is_err() {
+        return AppResponse::Error(secret.err().unwrap().to_string());
+    }
+
+    let secret: String = secret.unwrap().get("webhook_secret");
+
+    let count = transaction.query_one(
+        "SELECT COUNT(*) AS count FROM webhooks WHERE project_id = $1;",
+        &[&project_id]
+    ).await;
+
+    if count.is_err() {
+        return AppResponse::Error(count.err().unwrap().to_string());
+    }
+
+    let count: i64 = count.unwrap().get("count");
+
+    if count >= MAX_WEBHOOKS_PER_PROJECT {
+        return AppResponse::Error(
+            format!("PROJECT ALREADY HAS {} WEBHOOKS", MAX_WEBHOOKS_PER_PROJECT)
+        );
+    }
+
+    let row = transaction.query_one(
+        "INSERT INTO webhooks (project_id, url, events) VALUES ($1, $2, $3) RETURNING id;",
+        &[&project_id, &url, &events]
+    ).await;
+
+    if row.is_err() {
+        return AppResponse::Error(row.err().unwrap().to_string());
+    }
+
+    let id: Uuid = row.unwrap().get("id");
+
+    let committed = transaction.commit().await;
+
+    if committed.is_err() {
+        return AppResponse::Error(committed.err().unwrap().to_string());
+    }
+
+    return AppResponse::SuccessData(
+        "Webhook".to_owned(),
+        crate::enums::SuccessActions::Create,
+        json!({ "id": id, "url": url, "events": events, "secret": secret })
+    );
+}
+
+async fn delete_webhook(
+    State(state): State<AppState>,
+    ExtractPath((project_id, id)): ExtractPath<(Uuid, Uuid)>
+) -> impl IntoResponse {
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    // Pending deliveries go with it
+    let res = client.execute(
+        "DELETE FROM webhooks WHERE id = $1 AND project_id = $2;",
+        &[&id, &project_id]
+    ).await;
+
+    if res.is_err() {
+        return AppResponse::Error(res.err().unwrap().to_string());
+    }
+
+    if res.unwrap() == 0 {
+        return AppResponse::Error(format!("NO WEBHOOK - {}", id));
+    }
+
+    return AppResponse::Success("Webhook".to_owned(), crate::enums::SuccessActions::Delete);
+}
+
+async fn get_webhook_deliveries(
+    State(state): State<AppState>,
+    query: Query<DeliveriesQuery>,
+    ExtractPath((project_id, id)): ExtractPath<(Uuid, Uuid)>
+) -> impl IntoResponse {
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap();
+    }
+    let client = client.unwrap();
+
+    let limit = query.limit.unwrap_or(50).clamp(1, 200);
+
+    let rows = client.query(
+        "SELECT webhook_deliveries.id, event, payload, status, attempts, response_status, last_error,
+            (EXTRACT(EPOCH FROM webhook_deliveries.created_at) * 1000)::BIGINT AS created_at,
+            (EXTRACT(EPOCH FROM delivered_at) * 1000)::BIGINT AS delivered_at,
+            (EXTRACT(EPOCH FROM run_after) * 1000)::BIGINT AS run_after
+         FROM webhook_deliveries
+         JOIN webhooks ON webhooks.id = webhook_deliveries.webhook_id
+         WHERE webhook_id = $1 AND webhooks.project_id = $2
+            AND ($3::\"WebhookDeliveryStatus\" IS NULL OR status = $3)
+         ORDER BY webhook_deliveries.created_at DESC
+         LIMIT $4;",
+        &[&id, &project_id, &query.status, &limit]
+    ).await;
+
+    if rows.is_err() {
+        return AppResponse::Error(rows.err().unwrap().to_string());
+    }
+
+    let deliveries: Vec<serde_json::Value> = rows
+        .unwrap()
+        .iter()
+        .map(|row| {
+            let id: Uuid = row.get("id");
+            let event: WebhookEvent = row.get("event");
+            let payload: serde_json::Value = row.get("payload");
+            let status: WebhookDeliveryStatus = row.get("status");
+            let attempts: i32 = row.get("attempts");
+            let response_status: Option<i32> = row.get("response_status");
+            let last_error: Option<String> = row.get("last_error");
+            let created_at: i64 = row.get("created_at");
+            let delivered_at: Option<i64> = row.get("delivered_at");
+            let run_after: i64 = row.get("run_after");
+
+            json!({
+                "id": id,
+                "event": event,
+                "payload": payload,
+                "status": status,
+                "attempts": attempts,
+                "response_status": response_status,
+                "last_error": last_error,
+                "created_at": created_at,
+                "delivered_at": delivered_at,
+                // Next attempt of a pending delivery
+                "run_after": run_after,
+            })
+        })
+        .collect();
+
+    return AppResponse::SuccessData(
+        "Webhook deliveries".to_owned(),
+        crate::enums::SuccessActions::Read,
+        json!(deliveries)
+    );
+}
+
+pub fn webhook_routes(state: AppState) -> Router<AppState> {
+    Router::new().nest(
+        "/webhooks",
+        Router::new()
+            .route("/:project_id", get(list_webhooks).post(create_webhook))
+            .route("/:project_id/:id", delete(delete_webhook))
+            .route("/:project_id/:id/deliveries", get(get_webhook_deliveries))
+            // Webhooks carry the project's signing secret, so only owners can manage them
+            .layer(from_fn_with_state(state.clone(), owner_middleware))
+            .layer(from_fn_with_state(state, tenant_middleware))
+    )
+}
@@ -0,0 +1,282 @@
+use std::path::Path;
+
+use deadpool_postgres::Object;
+use uuid::Uuid;
+
+use crate::{
+    enums::{
+        AppResponse,
+        AssetKind,
+        AssetVisibility,
+        ImageType,
+        ModerationStatus,
+        OutputFormat,
+        UploadStage,
+    },
+    jobs::moderation_job::{ notify_moderation_worker, requires_moderation },
+    state::models::AppState,
+    storage::{ backend::PutOptions, StorageTarget },
+    utils::{
+        asset_utils::{ asset_key, supported_media_type, SniffedAsset },
+        dedup_utils::{ content_hash, find_duplicate },
+        image_utils::{
+            encode_upload,
+            run_image_task,
+            DecodedImage,
+            EncodeOptions,
+            ImageMetadata,
+        },
+        progress_utils::UploadProgress,
+        variant_utils::queue_variants,
+    },
+};
+
+pub enum AssetBody<'a> {
+    // Encoded into `img.storage_format(format)`. `head` is the start of the file as uploaded.
+    Image {
+        img: DecodedImage,
+        head: &'a [u8],
+        format: OutputFormat,
+        options: &'a EncodeOptions,
+    },
+    // Kinds that aren't re-encoded are stored as uploaded
+    File {
+        path: &'a Path,
+        size: u64,
+        asset: SniffedAsset,
+    },
+}
+
+pub struct NewAsset<'a> {
+    pub id: Uuid,
+    // Stores the object under another id than the row's, gateway entity images are addressed
+    // by their entity
+    pub key_id: Option<Uuid>,
+    pub title: &'a str,
+    pub project_id: &'a Uuid,
+    pub image_type: &'a ImageType,
+    pub owner_id: &'a Uuid,
+    pub pending: bool,
+    pub visibility: AssetVisibility,
+    // Point the row at identical content already in the project instead of storing it again
+    pub dedupe: bool,
+    // What is left of the project's quota, checked against the encoded size
+    pub max_size_bytes: Option<i64>,
+}
+
+pub struct StoredAsset {
+    pub asset: SniffedAsset,
+    pub size_bytes: i64,
+    pub metadata: Option<ImageMetadata>,
+    // Set when the content was already stored for another row
+    pub object_id: Option<Uuid>,
+}
+
+#[derive(Debug)]
+pub enum StoreError {
+    Encode(String),
+    QuotaExceeded,
+    Duplicates(AppResponse),
+    Put(String),
+    Insert(String),
+}
+
+impl StoreError {
+    // Safe to show to the uploader, the details are only logged
+    pub fn reason(&self) -> &'static str {
+        match self {
+            StoreError::Encode(_) => "COULD NOT ENCODE IMAGE",
+            StoreError::QuotaExceeded => "STORAGE QUOTA EXCEEDED",
+            StoreError::Duplicates(_) => "COULD NOT CHECK FOR DUPLICATES",
+            StoreError::Put(_) => "COULD NOT STORE FILE",
+            StoreError::Insert(_) => "COULD NOT SAVE ASSET",
+        }
+    }
+}
+
+impl From<StoreError> for AppResponse {
+    fn from(err: StoreError) -> Self {
+        match err {
+            StoreError::Encode(err) | StoreError::Put(err) | StoreError::Insert(err) =>
+                AppResponse::Error(err),
+            StoreError::QuotaExceeded => AppResponse::Error(err.reason().to_owned()),
+            StoreError::Duplicates(err) => err,
+        }
+    }
+}
+
+// Encodes, stores and inserts the row for one asset. When the insert fails the object that
+// was stored for it is deleted again, so a failed upload never leaves an orphan behind.
+pub async fn store_asset(
+    state: &AppState,
+    client: &Object,
+    target: &StorageTarget,
+    new: &NewAsset<'_>,
+    body: AssetBody<'_>,
+    progress: Option<&UploadProgress>
+) -> Result<StoredAsset, StoreError> {
+    let stage = |stage: UploadStage| {
+        if let Some(progress) = progress {
+            progress.stage(stage, new.title);
+        }
+    };
+
+    let (asset, data, path, size_bytes, metadata, hash) = match body {
+        AssetBody::Image { img, head, format, options } => {
+            let metadata = ImageMetadata::read(&img, head);
+            let asset = supported_media_type(img.storage_format(format).content_type()).unwrap();
+
+            stage(UploadStage::Encoding);
+
+            let options = *options;
+            let encoded = run_image_task(&state.encode_permits, "encode", move || {
+                encode_upload(img, format, &options)
+            }).await;
+
+            if encoded.is_err() {
+                return Err(StoreError::Encode(encoded.err().unwrap()));
+            }
+
+            let encoded = encoded.unwrap();
+
+            if encoded.is_err() {
+                return Err(StoreError::Encode(encoded.err().unwrap()));
+            }
+
+            let encoded = encoded.unwrap();
+
+            if let Some(progress) = progress {
+                progress.file_encoded(new.title);
+            }
+
+            let size_bytes = encoded.len() as i64;
+            let hash = content_hash(&encoded);
+
+            (asset, Some(encoded), None, size_bytes, Some(metadata), Some(hash))
+        }
+        AssetBody::File { path, size, asset } =>
+            (asset, None, Some(path), size as i64, None, None),
+    };
+
+    if new.max_size_bytes.is_some_and(|max_size_bytes| size_bytes > max_size_bytes) {
+        return Err(StoreError::QuotaExceeded);
+    }
+
+    let object_id = match (&hash, new.dedupe) {
+        (Some(hash), true) => {
+            let duplicate = find_duplicate(
+                state,
+                client,
+                new.project_id,
+                new.owner_id,
+                new.image_type,
+                hash
+            ).await;
+
+            if duplicate.is_err() {
+                return Err(StoreError::Duplicates(duplicate.err().unwrap()));
+            }
+
+            duplicate.unwrap()
+        }
+        _ => None,
+    };
+
+    let key = asset_key(
+        new.project_id,
+        new.image_type,
+        &asset.kind,
+        &new.key_id.unwrap_or(new.id),
+        asset.mime_type
+    );
+
+    // Images held for moderation stay private until the moderation service approves them
+    let moderation_status = match asset.kind {
+        AssetKind::Image if requires_moderation(state, client, new.project_id).await =>
+            Some(ModerationStatus::Pending),
+        _ => None,
+    };
+
+    // Identical content is already stored, the new row just points at it
+    if object_id.is_none() {
+        stage(UploadStage::Storing);
+
+        let options = PutOptions {
+            content_type: asset.mime_type,
+            cache_control: state.config.cache_control.for_image_type(new.image_type),
+            visibility: match moderation_status {
+                Some(_) => AssetVisibility::Private,
+                None => new.visibility,
+            },
+        };
+
+        let upload = match (data, path) {
+            (Some(data), _) => target.put(&key, data, &options).await.map(|_| ()),
+            (None, Some(path)) => target.put_file(&key, path, &options).await,
+            (None, None) => Ok(()),
+        };
+
+        if upload.is_err() {
+            return Err(StoreError::Put(upload.err().unwrap()));
+        }
+    }
+
+    if let Some(progress) = progress {
+        progress.file_stored(new.title);
+    }
+
+    let res = client.query(
+        "INSERT INTO images (id, title, project_id, type, owner_id, size_bytes, pending, kind, mime_type, width, height, original_format, is_animated, content_hash, object_id, moderation_status, perceptual_hash, dominant_color, thumbhash)
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19);",
+        &[
+            &new.id,
+            &new.title,
+            &new.project_id,
+            &new.image_type,
+            &new.owner_id,
+            &size_bytes,
+            &new.pending,
+            &asset.kind,
+            &asset.mime_type,
+            &metadata.as_ref().map(|metadata| metadata.width),
+            &metadata.as_ref().map(|metadata| metadata.height),
+            &metadata.as_ref().and_then(|metadata| metadata.original_format.clone()),
+            &metadata.as_ref().is_some_and(|metadata| metadata.is_animated),
+            &hash,
+            &object_id,
+            &moderation_status,
+            &metadata.as_ref().map(|metadata| metadata.perceptual_hash),
+            &metadata.as_ref().and_then(|metadata| metadata.dominant_color.clone()),
+            &metadata.as_ref().map(|metadata| metadata.thumbhash.clone()),
+        ]
+    ).await;
+
+    if res.is_err() {
+        if object_id.is_none() {
+            let del_res = target.delete(&key).await;
+
+            if del_res.is_err() {
+                tracing::error!("{}", del_res.err().unwrap());
+            }
+        }
+
+        return Err(StoreError::Insert(res.err().unwrap().to_string()));
+    }
+
+    if moderation_status.is_some() {
+        notify_moderation_worker(state);
+    }
+
+    // Deduplicated rows share the variants of the object they point at
+    if asset.kind == AssetKind::Image && object_id.is_none() {
+        queue_variants(
+            state,
+            *new.project_id,
+            *new.image_type,
+            new.key_id.unwrap_or(new.id),
+            asset.mime_type.to_owned()
+        );
+    }
+
+    Ok(StoredAsset { asset, size_bytes, metadata, object_id })
+}
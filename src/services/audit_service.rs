@@ -0,0 +1,71 @@
+use deadpool_postgres::GenericClient;
+use uuid::Uuid;
+
+use crate::{
+    enums::{ AppResponse, AuditAction, AuditSource },
+    state::models::{ ApiKeyProject, Claims },
+};
+
+// Who made an audited request and through what
+pub struct AuditActor {
+    pub source: AuditSource,
+    // Not known for requests made with an API key or by the gateway
+    pub user_id: Option<Uuid>,
+    pub api_key_id: Option<Uuid>,
+}
+
+impl AuditActor {
+    pub fn user(claims: &Claims) -> Self {
+        AuditActor { source: AuditSource::Ui, user_id: Some(claims.user_id), api_key_id: None }
+    }
+
+    pub fn api_key(api_project: &ApiKeyProject, source: AuditSource) -> Self {
+        AuditActor { source, user_id: None, api_key_id: Some(api_project.key_id) }
+    }
+
+    pub fn gateway() -> Self {
+        AuditActor { source: AuditSource::Gateway, user_id: None, api_key_id: None }
+    }
+}
+
+// Records one entry per asset. Like webhook events the log is best effort, a failure is logged
+// instead of failing a change that already happened.
+pub async fn record_audit(
+    client: &impl GenericClient,
+    actor: &AuditActor,
+    project_id: &Uuid,
+    action: AuditAction,
+    asset_ids: &[Uuid]
+) {
+    if asset_ids.is_empty() {
+        return;
+    }
+
+    let res = client.execute(
+        "INSERT INTO asset_audit_log (project_id, asset_id, action, source, user_id, api_key_id)
+         SELECT $1, asset_id, $3, $4, $5, $6 FROM UNNEST($2::UUID[]) AS asset_id;",
+        &[&project_id, &asset_ids, &action, &actor.source, &actor.user_id, &actor.api_key_id]
+    ).await;
+
+    if res.is_err() {
+        tracing::error!("ERROR RECORDING AUDIT LOG - {}", res.err().unwrap());
+    }
+}
+
+// Used by data erasure, entries stay for the project's history but no longer name the user.
+// Unlike recording this isn't best effort, erasure has to fail if the user is still referenced.
+pub async fn forget_audit_user(
+    client: &impl GenericClient,
+    user_id: &Uuid
+) -> Result<u64, AppResponse> {
+    let res = client.execute(
+        "UPDATE asset_audit_log SET user_id = NULL WHERE user_id = $1;",
+        &[&user_id]
+    ).await;
+
+    if res.is_err() {
+        return Err(AppResponse::Error(res.err().unwrap().to_string()));
+    }
+
+    return Ok(res.unwrap());
+}
@@ -0,0 +1,2 @@
+pub mod asset_service;
+pub mod audit_service;
@@ -1,30 +1,136 @@
-// use std::collections::HashMap;
+use std::{ collections::HashMap, sync::{ Arc, Mutex }, time::Instant };
 
-use aws_sdk_s3::Client;
 use deadpool_postgres::Pool;
+use metrics_exporter_prometheus::PrometheusHandle;
 use reqwest::Client as ReqwestClient;
 use serde::Deserialize;
+use tokio::sync::{ Notify, Semaphore };
+use tokio_util::{ sync::CancellationToken, task::TaskTracker };
 use uuid::Uuid;
 
+use crate::{
+    config::Config,
+    enums::{ Feature, ImageType },
+    jobs::acl_job::AclReport,
+    moderation::ModerationBackend,
+    storage::Storage,
+    utils::{
+        auth_utils::AuthCache,
+        maintenance_utils::Maintenance,
+        progress_utils::{ UploadEvents, UploadTracker },
+    },
+};
+
+#[derive(Clone)]
+pub struct CacheControlConfig {
+    pub images: String,
+    pub map_images: String,
+    pub avatars: String,
+    // Rendition keys are suffixed with a content hash, so they can be cached as immutable
+    pub renditions: String,
+}
+
+impl CacheControlConfig {
+    pub fn for_image_type(&self, image_type: &ImageType) -> &str {
+        match image_type {
+            &ImageType::Images => &self.images,
+            &ImageType::MapImages => &self.map_images,
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+pub struct FlagRule {
+    // Turns the feature on for everyone, projects/users only matter when this is false
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default)]
+    pub projects: Vec<Uuid>,
+    #[serde(default)]
+    pub users: Vec<Uuid>,
+    // Percentage of projects (0-100) that get the feature, bucketed by project id
+    #[serde(default)]
+    pub rollout_percent: u8,
+}
+
+#[derive(Deserialize, Default)]
+pub struct FeatureFlags(pub HashMap<Feature, FlagRule>);
+
+impl FeatureFlags {
+    pub fn is_enabled(&self, feature: Feature, project_id: &Uuid, user_id: Option<&Uuid>) -> bool {
+        let rule = self.0.get(&feature);
+
+        if rule.is_none() {
+            return false;
+        }
+
+        let rule = rule.unwrap();
+
+        if rule.enabled || rule.projects.contains(project_id) {
+            return true;
+        }
+
+        if user_id.is_some_and(|user_id| rule.users.contains(user_id)) {
+            return true;
+        }
+
+        return project_id.as_u128() % 100 < (rule.rollout_percent as u128);
+    }
+}
+
 #[derive(Clone)]
 pub struct AppState {
-    pub client: Client,
-    pub bucket: String,
+    pub storage: Arc<Storage>,
     pub reqwest_client: ReqwestClient,
-    pub auth_service_url: String,
-    pub thumbnail_secret: String,
-    pub thumbnail_service_url: String,
-    // pub discord_service_url: String,
-    // pub discord_service_api_key: String,
+    // Only connects to public addresses, for uploads by URL
+    pub remote_client: ReqwestClient,
+    pub config: Arc<Config>,
+    pub view_counter: Arc<Mutex<HashMap<Uuid, i64>>>,
+    pub upload_tracker: UploadTracker,
+    pub upload_events: UploadEvents,
+    pub auth_cache: AuthCache,
+    pub acl_reports: Arc<Mutex<HashMap<Uuid, AclReport>>>,
+    pub job_notify: Arc<Notify>,
+    // None when uploads are published without moderation
+    pub moderation: Option<Arc<dyn ModerationBackend>>,
+    pub moderation_notify: Arc<Notify>,
+    pub webhook_notify: Arc<Notify>,
+    // Last thumbnail service probe and whether it answered
+    pub thumbnail_health: Arc<Mutex<Option<(Instant, bool)>>>,
+    // Toggled by admins, rejects new uploads while set
+    pub maintenance: Maintenance,
+    // Bounds the image tasks running on the blocking pool
+    pub encode_permits: Arc<Semaphore>,
+    // Background work that has to finish before the process exits
+    pub tasks: TaskTracker,
+    pub shutdown: CancellationToken,
+    pub metrics: PrometheusHandle,
     pub pool: Pool,
+    // Pool of the read replica, if one is configured. Go through db_pool instead of using it
+    // directly.
+    pub read_pool: Option<Pool>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize)]
 pub struct Claims {
     pub user_id: Uuid,
     pub project_id: Uuid,
 }
 
+pub struct ApiKeyProject {
+    pub project_id: Uuid,
+    pub owner_id: Uuid,
+    // The project_api_keys row the request was made with
+    pub key_id: Uuid,
+}
+
+pub struct ProjectUsage {
+    pub upload_count: i64,
+    pub monthly_upload_count: i64,
+    pub bytes_stored: i64,
+    pub quota_bytes: i64,
+}
+
 #[derive(Deserialize)]
 pub struct VerifyJWTResponse {
     pub claims: Option<Claims>,
@@ -0,0 +1,174 @@
+use std::{ collections::HashMap, path::Path, time::Duration };
+
+use async_trait::async_trait;
+use axum::body::{ Body, Bytes };
+
+use crate::enums::AssetVisibility;
+
+pub struct PutOptions<'a> {
+    pub content_type: &'a str,
+    pub cache_control: &'a str,
+    pub visibility: AssetVisibility,
+}
+
+// Copies keep the source's content type and cache headers unless they are replaced
+pub struct CopyOptions<'a> {
+    pub visibility: AssetVisibility,
+    pub content_type: Option<&'a str>,
+    pub cache_control: Option<&'a str>,
+}
+
+pub struct ObjectInfo {
+    pub size: i64,
+    // Changes whenever the object is replaced, not necessarily a hash of the content
+    pub etag: String,
+}
+
+pub struct ObjectVersion {
+    pub version_id: String,
+    pub is_latest: bool,
+    pub size: i64,
+    pub last_modified: Option<i64>,
+}
+
+// A presigned upload only succeeds when the client sends these headers as they are
+pub struct PresignedRequest {
+    pub url: String,
+    pub headers: HashMap<String, String>,
+}
+
+// Body of a whole object or of the byte range that was asked for
+pub struct ObjectStream {
+    pub body: Body,
+    pub content_type: Option<String>,
+    pub cache_control: Option<String>,
+}
+
+pub struct UploadedPart {
+    pub part_number: i32,
+    pub etag: String,
+}
+
+// Everything the service needs from object storage. Keys are passed as stored, applying a
+// target's prefix is up to StorageTarget.
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    // Returns the version id when the backend keeps versions
+    async fn put(
+        &self,
+        bucket: &str,
+        key: &str,
+        data: Vec<u8>,
+        options: &PutOptions<'_>
+    ) -> Result<Option<String>, String>;
+
+    // For uploads spooled to disk, without reading the whole file into memory
+    async fn put_file(
+        &self,
+        bucket: &str,
+        key: &str,
+        path: &Path,
+        options: &PutOptions<'_>
+    ) -> Result<(), String>;
+
+    // Multipart uploads, for objects that arrive in chunks. Returns the upload id.
+    async fn create_multipart(
+        &self,
+        bucket: &str,
+        key: &str,
+        options: &PutOptions<'_>
+    ) -> Result<String, String>;
+
+    // Part numbers start at 1, every part but the last has to be at least 5MB.
+    // Returns the part's etag.
+    async fn upload_part(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        part_number: i32,
+        data: Vec<u8>
+    ) -> Result<String, String>;
+
+    async fn complete_multipart(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        parts: &[UploadedPart]
+    ) -> Result<(), String>;
+
+    async fn abort_multipart(&self, bucket: &str, key: &str, upload_id: &str) -> Result<(), String>;
+
+    async fn get(&self, bucket: &str, key: &str) -> Result<Bytes, String>;
+
+    // At most the first `len` bytes
+    async fn get_range(&self, bucket: &str, key: &str, len: u64) -> Result<Bytes, String>;
+
+    // Streamed instead of buffered. `range` is inclusive and has to lie within the object.
+    async fn get_stream(
+        &self,
+        bucket: &str,
+        key: &str,
+        range: Option<(u64, u64)>
+    ) -> Result<ObjectStream, String>;
+
+    async fn get_version(
+        &self,
+        bucket: &str,
+        key: &str,
+        version_id: &str
+    ) -> Result<Bytes, String>;
+
+    async fn head(&self, bucket: &str, key: &str) -> Result<ObjectInfo, String>;
+
+    // Deleting a missing object is not an error
+    async fn delete(&self, bucket: &str, key: &str) -> Result<(), String>;
+
+    // Returns how many objects were deleted
+    async fn delete_prefix(&self, bucket: &str, prefix: &str) -> Result<usize, String>;
+
+    async fn list(&self, bucket: &str, prefix: &str) -> Result<Vec<String>, String>;
+
+    async fn copy(
+        &self,
+        from_bucket: &str,
+        from_key: &str,
+        bucket: &str,
+        key: &str,
+        options: &CopyOptions<'_>
+    ) -> Result<(), String>;
+
+    async fn set_visibility(
+        &self,
+        bucket: &str,
+        key: &str,
+        visibility: AssetVisibility
+    ) -> Result<(), String>;
+
+    async fn get_visibility(&self, bucket: &str, key: &str) -> Result<AssetVisibility, String>;
+
+    // Newest first, empty when the backend doesn't keep versions
+    async fn list_versions(&self, bucket: &str, key: &str) -> Result<Vec<ObjectVersion>, String>;
+
+    async fn presign_get(
+        &self,
+        bucket: &str,
+        key: &str,
+        expires_in: Duration
+    ) -> Result<String, String>;
+
+    async fn presign_put(
+        &self,
+        bucket: &str,
+        key: &str,
+        options: &PutOptions<'_>,
+        expires_in: Duration
+    ) -> Result<PresignedRequest, String>;
+
+    // Where public objects can be fetched without signing
+    fn public_url(&self, bucket: &str, key: &str) -> String;
+
+    // Cheapest request that proves the bucket is reachable, for readiness checks
+    async fn ping(&self, bucket: &str) -> Result<(), String>;
+}
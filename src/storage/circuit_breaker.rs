@@ -0,0 +1,276 @@
+use std::{ future::Future, path::Path, sync::{ Arc, Mutex }, time::{ Duration, Instant } };
+
+use async_trait::async_trait;
+use axum::body::Bytes;
+
+use crate::{
+    enums::AssetVisibility,
+    storage::backend::{
+        CopyOptions,
+        ObjectInfo,
+        ObjectStream,
+        ObjectVersion,
+        PresignedRequest,
+        PutOptions,
+        StorageBackend,
+        UploadedPart,
+    },
+};
+
+// Returned instead of calling the backend while the breaker is open, AppResponse answers any
+// error containing it with a 503
+pub const STORAGE_UNAVAILABLE: &str = "STORAGE BACKEND UNAVAILABLE";
+
+// Consecutive failures that open the breaker
+const FAILURE_THRESHOLD: u32 = 5;
+// How long requests fail fast before one is let through to check on the backend
+const OPEN_DURATION: Duration = Duration::from_secs(30);
+
+// Display of the S3 SDK's errors for requests that never got an answer. Other errors (missing
+// objects, denied requests) mean the backend is up and don't count.
+const DEGRADED_ERRORS: [&str; 3] = ["request has timed out", "dispatch failure", "response error"];
+
+fn is_degraded(err: &str) -> bool {
+    DEGRADED_ERRORS.iter().any(|degraded| err.starts_with(degraded))
+}
+
+#[derive(Default)]
+struct BreakerState {
+    failures: u32,
+    open_until: Option<Instant>,
+    // Set while the single request that checks on an open backend is in flight
+    probe_started: Option<Instant>,
+}
+
+#[derive(Default)]
+pub struct CircuitBreaker {
+    state: Mutex<BreakerState>,
+}
+
+impl CircuitBreaker {
+    fn allow(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+
+        // A probe that was dropped before it finished doesn't keep the breaker open forever
+        let probing = state.probe_started.is_some_and(|started| started.elapsed() < OPEN_DURATION);
+
+        match state.open_until {
+            None => true,
+            Some(open_until) if Instant::now() < open_until => false,
+            Some(_) if probing => false,
+            Some(_) => {
+                state.probe_started = Some(Instant::now());
+                true
+            }
+        }
+    }
+
+    fn record(&self, label: &str, err: Option<&str>) {
+        let mut state = self.state.lock().unwrap();
+
+        if !err.is_some_and(is_degraded) {
+            if state.open_until.is_some() {
+                tracing::info!("STORAGE BACKEND {} RECOVERED", label);
+            }
+
+            *state = BreakerState::default();
+            return;
+        }
+
+        state.failures += 1;
+
+        if state.probe_started.is_some() || state.failures >= FAILURE_THRESHOLD {
+            tracing::warn!(
+                "STORAGE BACKEND {} DEGRADED AFTER {} FAILURES - {}",
+                label,
+                state.failures,
+                err.unwrap()
+            );
+
+            state.open_until = Some(Instant::now() + OPEN_DURATION);
+            state.probe_started = None;
+        }
+    }
+}
+
+// Wraps a remote backend so that, once it stops answering, requests fail right away instead of
+// each waiting for its own timeouts and retries
+pub struct BreakerBackend {
+    inner: Arc<dyn StorageBackend>,
+    breaker: CircuitBreaker,
+    // Region the backend talks to, for the logs
+    label: String,
+}
+
+impl BreakerBackend {
+    pub fn new(inner: Arc<dyn StorageBackend>, label: &str) -> Self {
+        BreakerBackend { inner, breaker: CircuitBreaker::default(), label: label.to_owned() }
+    }
+
+    async fn call<T>(&self, request: impl Future<Output = Result<T, String>>) -> Result<T, String> {
+        if !self.breaker.allow() {
+            return Err(STORAGE_UNAVAILABLE.to_owned());
+        }
+
+        let res = request.await;
+
+        self.breaker.record(&self.label, res.as_ref().err().map(|err| err.as_str()));
+
+        res
+    }
+}
+
+#[async_trait]
+impl StorageBackend for BreakerBackend {
+    async fn put(
+        &self,
+        bucket: &str,
+        key: &str,
+        data: Vec<u8>,
+        options: &PutOptions<'_>
+    ) -> Result<Option<String>, String> {
+        self.call(self.inner.put(bucket, key, data, options)).await
+    }
+
+    async fn put_file(
+        &self,
+        bucket: &str,
+        key: &str,
+        path: &Path,
+        options: &PutOptions<'_>
+    ) -> Result<(), String> {
+        self.call(self.inner.put_file(bucket, key, path, options)).await
+    }
+
+    async fn create_multipart(
+        &self,
+        bucket: &str,
+        key: &str,
+        options: &PutOptions<'_>
+    ) -> Result<String, String> {
+        self.call(self.inner.create_multipart(bucket, key, options)).await
+    }
+
+    async fn upload_part(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        part_number: i32,
+        data: Vec<u8>
+    ) -> Result<String, String> {
+        self.call(self.inner.upload_part(bucket, key, upload_id, part_number, data)).await
+    }
+
+    async fn complete_multipart(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        parts: &[UploadedPart]
+    ) -> Result<(), String> {
+        self.call(self.inner.complete_multipart(bucket, key, upload_id, parts)).await
+    }
+
+    async fn abort_multipart(&self, bucket: &str, key: &str, upload_id: &str) -> Result<(), String> {
+        self.call(self.inner.abort_multipart(bucket, key, upload_id)).await
+    }
+
+    async fn get(&self, bucket: &str, key: &str) -> Result<Bytes, String> {
+        self.call(self.inner.get(bucket, key)).await
+    }
+
+    async fn get_range(&self, bucket: &str, key: &str, len: u64) -> Result<Bytes, String> {
+        self.call(self.inner.get_range(bucket, key, len)).await
+    }
+
+    async fn get_stream(
+        &self,
+        bucket: &str,
+        key: &str,
+        range: Option<(u64, u64)>
+    ) -> Result<ObjectStream, String> {
+        self.call(self.inner.get_stream(bucket, key, range)).await
+    }
+
+    async fn get_version(
+        &self,
+        bucket: &str,
+        key: &str,
+        version_id: &str
+    ) -> Result<Bytes, String> {
+        self.call(self.inner.get_version(bucket, key, version_id)).await
+    }
+
+    async fn head(&self, bucket: &str, key: &str) -> Result<ObjectInfo, String> {
+        self.call(self.inner.head(bucket, key)).await
+    }
+
+    async fn delete(&self, bucket: &str, key: &str) -> Result<(), String> {
+        self.call(self.inner.delete(bucket, key)).await
+    }
+
+    async fn delete_prefix(&self, bucket: &str, prefix: &str) -> Result<usize, String> {
+        self.call(self.inner.delete_prefix(bucket, prefix)).await
+    }
+
+    async fn list(&self, bucket: &str, prefix: &str) -> Result<Vec<String>, String> {
+        self.call(self.inner.list(bucket, prefix)).await
+    }
+
+    async fn copy(
+        &self,
+        from_bucket: &str,
+        from_key: &str,
+        bucket: &str,
+        key: &str,
+        options: &CopyOptions<'_>
+    ) -> Result<(), String> {
+        self.call(self.inner.copy(from_bucket, from_key, bucket, key, options)).await
+    }
+
+    async fn set_visibility(
+        &self,
+        bucket: &str,
+        key: &str,
+        visibility: AssetVisibility
+    ) -> Result<(), String> {
+        self.call(self.inner.set_visibility(bucket, key, visibility)).await
+    }
+
+    async fn get_visibility(&self, bucket: &str, key: &str) -> Result<AssetVisibility, String> {
+        self.call(self.inner.get_visibility(bucket, key)).await
+    }
+
+    async fn list_versions(&self, bucket: &str, key: &str) -> Result<Vec<ObjectVersion>, String> {
+        self.call(self.inner.list_versions(bucket, key)).await
+    }
+
+    // Presigning is done locally, the backend isn't contacted
+    async fn presign_get(
+        &self,
+        bucket: &str,
+        key: &str,
+        expires_in: Duration
+    ) -> Result<String, String> {
+        self.inner.presign_get(bucket, key, expires_in).await
+    }
+
+    async fn presign_put(
+        &self,
+        bucket: &str,
+        key: &str,
+        options: &PutOptions<'_>,
+        expires_in: Duration
+    ) -> Result<PresignedRequest, String> {
+        self.inner.presign_put(bucket, key, options, expires_in).await
+    }
+
+    fn public_url(&self, bucket: &str, key: &str) -> String {
+        self.inner.public_url(bucket, key)
+    }
+
+    async fn ping(&self, bucket: &str) -> Result<(), String> {
+        self.call(self.inner.ping(bucket)).await
+    }
+}
@@ -0,0 +1,699 @@
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    path::{ Path, PathBuf },
+    time::{ Duration, SystemTime, UNIX_EPOCH },
+};
+
+use async_trait::async_trait;
+use axum::body::{ Body, Bytes };
+use hmac::{ Hmac, Mac };
+use serde::{ Deserialize, Serialize };
+use sha2::{ Digest, Sha256 };
+use tokio::{ fs::File, io::{ AsyncReadExt, AsyncSeekExt, AsyncWriteExt, SeekFrom } };
+use url::Url;
+use uuid::Uuid;
+
+use crate::{
+    enums::AssetVisibility,
+    storage::backend::{
+        CopyOptions,
+        ObjectInfo,
+        ObjectStream,
+        ObjectVersion,
+        PresignedRequest,
+        PutOptions,
+        StorageBackend,
+        UploadedPart,
+    },
+};
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Read size when an object is streamed from disk
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+// What S3 keeps next to the object, stored as JSON under <root>/.metadata/<bucket>/<key>.json
+#[derive(Serialize, Deserialize)]
+pub struct ObjectMetadata {
+    pub content_type: String,
+    pub cache_control: String,
+    pub public: bool,
+}
+
+// Query of the URLs handed out by presign_get and presign_put
+#[derive(Deserialize)]
+pub struct SignedQuery {
+    pub expires: i64,
+    pub signature: String,
+    pub content_type: Option<String>,
+    pub cache_control: Option<String>,
+}
+
+// Objects on local disk, for development and self-hosted installs without S3. Buckets are
+// directories under the root and objects are served by storage_routes, so every URL points
+// back at this service.
+pub struct LocalBackend {
+    root: PathBuf,
+    // https://<this service>, without a trailing slash
+    base_url: String,
+    secret: String,
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+fn is_valid_segment(segment: &str) -> bool {
+    !segment.is_empty() && segment != "." && segment != ".." && !segment.contains(['\\', '\0'])
+}
+
+fn not_found(key: &str, err: std::io::Error) -> String {
+    match err.kind() {
+        ErrorKind::NotFound => format!("OBJECT NOT FOUND - {}", key),
+        _ => err.to_string(),
+    }
+}
+
+fn decode_hex(value: &str) -> Option<Vec<u8>> {
+    if !value.len().is_multiple_of(2) || !value.is_ascii() {
+        return None;
+    }
+
+    (0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&value[i..i + 2], 16).ok())
+        .collect()
+}
+
+impl LocalBackend {
+    pub fn new(root: PathBuf, base_url: String, secret: String) -> Self {
+        LocalBackend { root, base_url, secret }
+    }
+
+    // Dot directories under the root hold metadata and temp files, so no bucket may start with one
+    fn bucket_path(&self, bucket: &str) -> Result<PathBuf, String> {
+        if !is_valid_segment(bucket) || bucket.starts_with('.') || bucket.contains('/') {
+            return Err(format!("INVALID BUCKET - {}", bucket));
+        }
+
+        Ok(self.root.join(bucket))
+    }
+
+    // Keys come from requests, so anything that could leave the bucket's directory is rejected
+    fn object_path(&self, bucket: &str, key: &str) -> Result<PathBuf, String> {
+        if !key.split('/').all(is_valid_segment) {
+            return Err(format!("INVALID KEY - {}", key));
+        }
+
+        Ok(self.bucket_path(bucket)?.join(key))
+    }
+
+    fn metadata_path(&self, bucket: &str, key: &str) -> Result<PathBuf, String> {
+        self.object_path(bucket, key)?;
+
+        Ok(self.root.join(".metadata").join(bucket).join(format!("{}.json", key)))
+    }
+
+    // Written to a temp file first, so readers never see a half written object
+    async fn write_atomic(&self, path: &Path, data: &[u8]) -> Result<(), String> {
+        let tmp_dir = self.root.join(".tmp");
+        let tmp_path = tmp_dir.join(Uuid::new_v4().to_string());
+
+        tokio::fs::create_dir_all(&tmp_dir).await.map_err(|err| err.to_string())?;
+
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await.map_err(|err| err.to_string())?;
+        }
+
+        tokio::fs::write(&tmp_path, data).await.map_err(|err| err.to_string())?;
+
+        let res = tokio::fs::rename(&tmp_path, path).await;
+
+        if res.is_err() {
+            let _ = tokio::fs::remove_file(&tmp_path).await;
+            return Err(res.err().unwrap().to_string());
+        }
+
+        Ok(())
+    }
+
+    async fn write_metadata(
+        &self,
+        bucket: &str,
+        key: &str,
+        metadata: &ObjectMetadata
+    ) -> Result<(), String> {
+        let data = serde_json::to_vec(metadata).map_err(|err| err.to_string())?;
+
+        self.write_atomic(&self.metadata_path(bucket, key)?, &data).await
+    }
+
+    pub async fn read_metadata(&self, bucket: &str, key: &str) -> Result<ObjectMetadata, String> {
+        let data = tokio::fs::read(self.metadata_path(bucket, key)?).await;
+
+        if data.is_err() {
+            return Err(not_found(key, data.err().unwrap()));
+        }
+
+        serde_json::from_slice(&data.unwrap()).map_err(|err| err.to_string())
+    }
+
+    // Parts of a multipart upload wait here until it is completed, next to the options it
+    // was created with
+    fn multipart_path(&self, upload_id: &str) -> Result<PathBuf, String> {
+        let upload_id = Uuid::parse_str(upload_id);
+
+        if upload_id.is_err() {
+            return Err(format!("INVALID UPLOAD ID - {}", upload_id.err().unwrap()));
+        }
+
+        Ok(self.root.join(".multipart").join(upload_id.unwrap().to_string()))
+    }
+
+    fn sign(
+        &self,
+        method: &str,
+        bucket: &str,
+        key: &str,
+        expires: i64,
+        content_type: Option<&str>,
+        cache_control: Option<&str>
+    ) -> HmacSha256 {
+        let mut hmac = HmacSha256::new_from_slice(self.secret.as_bytes()).unwrap();
+        hmac.update(
+            format!(
+                "{}\n{}/{}\n{}\n{}\n{}",
+                method,
+                bucket,
+                key,
+                expires,
+                content_type.unwrap_or_default(),
+                cache_control.unwrap_or_default()
+            ).as_bytes()
+        );
+
+        hmac
+    }
+
+    fn signed_url(
+        &self,
+        method: &str,
+        bucket: &str,
+        key: &str,
+        expires_in: Duration,
+        content_type: Option<&str>,
+        cache_control: Option<&str>
+    ) -> Result<String, String> {
+        let expires = now() + (expires_in.as_secs() as i64);
+        let signature = self
+            .sign(method, bucket, key, expires, content_type, cache_control)
+            .finalize()
+            .into_bytes();
+
+        let mut params = vec![
+            ("expires", expires.to_string()),
+            ("signature", format!("{:x}", signature))
+        ];
+
+        if let Some(content_type) = content_type {
+            params.push(("content_type", content_type.to_owned()));
+        }
+
+        if let Some(cache_control) = cache_control {
+            params.push(("cache_control", cache_control.to_owned()));
+        }
+
+        Url::parse_with_params(&self.public_url(bucket, key), params)
+            .map(|url| url.to_string())
+            .map_err(|err| err.to_string())
+    }
+
+    // Checks a URL from presign_get ("GET") or presign_put ("PUT")
+    pub fn verify(&self, method: &str, bucket: &str, key: &str, query: &SignedQuery) -> bool {
+        if query.expires < now() {
+            return false;
+        }
+
+        let signature = decode_hex(&query.signature);
+
+        if signature.is_none() {
+            return false;
+        }
+
+        self.sign(
+            method,
+            bucket,
+            key,
+            query.expires,
+            query.content_type.as_deref(),
+            query.cache_control.as_deref()
+        )
+            .verify_slice(&signature.unwrap())
+            .is_ok()
+    }
+}
+
+#[async_trait]
+impl StorageBackend for LocalBackend {
+    async fn put(
+        &self,
+        bucket: &str,
+        key: &str,
+        data: Vec<u8>,
+        options: &PutOptions<'_>
+    ) -> Result<Option<String>, String> {
+        self.write_atomic(&self.object_path(bucket, key)?, &data).await?;
+        self.write_metadata(bucket, key, &ObjectMetadata {
+            content_type: options.content_type.to_owned(),
+            cache_control: options.cache_control.to_owned(),
+            public: options.visibility == AssetVisibility::Public,
+        }).await?;
+
+        Ok(None)
+    }
+
+    async fn put_file(
+        &self,
+        bucket: &str,
+        key: &str,
+        path: &Path,
+        options: &PutOptions<'_>
+    ) -> Result<(), String> {
+        let object_path = self.object_path(bucket, key)?;
+        let tmp_dir = self.root.join(".tmp");
+        let tmp_path = tmp_dir.join(Uuid::new_v4().to_string());
+
+        tokio::fs::create_dir_all(&tmp_dir).await.map_err(|err| err.to_string())?;
+
+        if let Some(parent) = object_path.parent() {
+            tokio::fs::create_dir_all(parent).await.map_err(|err| err.to_string())?;
+        }
+
+        tokio::fs::copy(path, &tmp_path).await.map_err(|err| err.to_string())?;
+
+        let res = tokio::fs::rename(&tmp_path, &object_path).await;
+
+        if res.is_err() {
+            let _ = tokio::fs::remove_file(&tmp_path).await;
+            return Err(res.err().unwrap().to_string());
+        }
+
+        self.write_metadata(bucket, key, &ObjectMetadata {
+            content_type: options.content_type.to_owned(),
+            cache_control: options.cache_control.to_owned(),
+            public: options.visibility == AssetVisibility::Public,
+        }).await
+    }
+
+    async fn create_multipart(
+        &self,
+        bucket: &str,
+        key: &str,
+        options: &PutOptions<'_>
+    ) -> Result<String, String> {
+        self.object_path(bucket, key)?;
+
+        let upload_id = Uuid::new_v4().to_string();
+        let metadata = serde_json::to_vec(&ObjectMetadata {
+            content_type: options.content_type.to_owned(),
+            cache_control: options.cache_control.to_owned(),
+            public: options.visibility == AssetVisibility::Public,
+        }).map_err(|err| err.to_string())?;
+
+        let path = self.multipart_path(&upload_id)?;
+
+        self.write_atomic(&path.join("metadata.json"), &metadata).await?;
+
+        Ok(upload_id)
+    }
+
+    async fn upload_part(
+        &self,
+        _bucket: &str,
+        _key: &str,
+        upload_id: &str,
+        part_number: i32,
+        data: Vec<u8>
+    ) -> Result<String, String> {
+        let path = self.multipart_path(upload_id)?;
+
+        if !tokio::fs::try_exists(&path).await.unwrap_or(false) {
+            return Err(format!("MULTIPART UPLOAD NOT FOUND - {}", upload_id));
+        }
+
+        let etag = format!("{:x}", Sha256::digest(&data));
+
+        self.write_atomic(&path.join(part_number.to_string()), &data).await?;
+
+        Ok(etag)
+    }
+
+    async fn complete_multipart(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        parts: &[UploadedPart]
+    ) -> Result<(), String> {
+        let path = self.multipart_path(upload_id)?;
+        let metadata = tokio::fs::read(path.join("metadata.json")).await;
+
+        if metadata.is_err() {
+            return Err(format!("MULTIPART UPLOAD NOT FOUND - {}", upload_id));
+        }
+
+        let metadata: ObjectMetadata = serde_json
+            ::from_slice(&metadata.unwrap())
+            .map_err(|err| err.to_string())?;
+
+        let object_path = self.object_path(bucket, key)?;
+        let tmp_dir = self.root.join(".tmp");
+        let tmp_path = tmp_dir.join(Uuid::new_v4().to_string());
+
+        tokio::fs::create_dir_all(&tmp_dir).await.map_err(|err| err.to_string())?;
+
+        // Parts are appended one at a time, the whole object is never held in memory
+        let mut file = File::create(&tmp_path).await.map_err(|err| err.to_string())?;
+
+        for part in parts {
+            let part_data = tokio::fs::read(path.join(part.part_number.to_string())).await;
+
+            if part_data.is_err() {
+                let _ = tokio::fs::remove_file(&tmp_path).await;
+                return Err(format!("PART {} NOT FOUND - {}", part.part_number, upload_id));
+            }
+
+            let part_data = part_data.unwrap();
+
+            if format!("{:x}", Sha256::digest(&part_data)) != part.etag {
+                let _ = tokio::fs::remove_file(&tmp_path).await;
+                return Err(format!("PART {} DOES NOT MATCH - {}", part.part_number, upload_id));
+            }
+
+            let res = file.write_all(&part_data).await;
+
+            if res.is_err() {
+                let _ = tokio::fs::remove_file(&tmp_path).await;
+                return Err(res.err().unwrap().to_string());
+            }
+        }
+
+        if let Some(parent) = object_path.parent() {
+            tokio::fs::create_dir_all(parent).await.map_err(|err| err.to_string())?;
+        }
+
+        let res = tokio::fs::rename(&tmp_path, &object_path).await;
+
+        if res.is_err() {
+            let _ = tokio::fs::remove_file(&tmp_path).await;
+            return Err(res.err().unwrap().to_string());
+        }
+
+        self.write_metadata(bucket, key, &metadata).await?;
+
+        tokio::fs::remove_dir_all(&path).await.map_err(|err| err.to_string())
+    }
+
+    async fn abort_multipart(
+        &self,
+        _bucket: &str,
+        _key: &str,
+        upload_id: &str
+    ) -> Result<(), String> {
+        let res = tokio::fs::remove_dir_all(self.multipart_path(upload_id)?).await;
+
+        if res.as_ref().is_err_and(|err| err.kind() != ErrorKind::NotFound) {
+            return Err(res.err().unwrap().to_string());
+        }
+
+        Ok(())
+    }
+
+    async fn get(&self, bucket: &str, key: &str) -> Result<Bytes, String> {
+        let data = tokio::fs::read(self.object_path(bucket, key)?).await;
+
+        if data.is_err() {
+            return Err(not_found(key, data.err().unwrap()));
+        }
+
+        Ok(Bytes::from(data.unwrap()))
+    }
+
+    async fn get_range(&self, bucket: &str, key: &str, len: u64) -> Result<Bytes, String> {
+        let file = File::open(self.object_path(bucket, key)?).await;
+
+        if file.is_err() {
+            return Err(not_found(key, file.err().unwrap()));
+        }
+
+        let mut data = vec![];
+        let read = file.unwrap().take(len).read_to_end(&mut data).await;
+
+        if read.is_err() {
+            return Err(read.err().unwrap().to_string());
+        }
+
+        Ok(Bytes::from(data))
+    }
+
+    async fn get_stream(
+        &self,
+        bucket: &str,
+        key: &str,
+        range: Option<(u64, u64)>
+    ) -> Result<ObjectStream, String> {
+        let file = File::open(self.object_path(bucket, key)?).await;
+
+        if file.is_err() {
+            return Err(not_found(key, file.err().unwrap()));
+        }
+
+        let mut file = file.unwrap();
+
+        let len = match range {
+            Some((start, end)) => {
+                let seeked = file.seek(SeekFrom::Start(start)).await;
+
+                if seeked.is_err() {
+                    return Err(seeked.err().unwrap().to_string());
+                }
+
+                end - start + 1
+            }
+            None => u64::MAX,
+        };
+
+        let metadata = self.read_metadata(bucket, key).await.ok();
+
+        let stream = futures::stream::unfold(file.take(len), |mut reader| async move {
+            let mut chunk = vec![0; STREAM_CHUNK_SIZE];
+
+            match reader.read(&mut chunk).await {
+                Ok(0) => None,
+                Ok(read) => {
+                    chunk.truncate(read);
+                    Some((Ok(Bytes::from(chunk)), reader))
+                }
+                Err(err) => Some((Err(err), reader)),
+            }
+        });
+
+        Ok(ObjectStream {
+            body: Body::from_stream(stream),
+            content_type: metadata.as_ref().map(|metadata| metadata.content_type.clone()),
+            cache_control: metadata.map(|metadata| metadata.cache_control),
+        })
+    }
+
+    async fn get_version(
+        &self,
+        _bucket: &str,
+        key: &str,
+        _version_id: &str
+    ) -> Result<Bytes, String> {
+        Err(format!("LOCAL STORAGE DOES NOT KEEP VERSIONS - {}", key))
+    }
+
+    async fn head(&self, bucket: &str, key: &str) -> Result<ObjectInfo, String> {
+        let metadata = tokio::fs::metadata(self.object_path(bucket, key)?).await;
+
+        if metadata.is_err() {
+            return Err(not_found(key, metadata.err().unwrap()));
+        }
+
+        let metadata = metadata.unwrap();
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|duration| duration.as_nanos())
+            .unwrap_or(0);
+
+        Ok(ObjectInfo {
+            size: metadata.len() as i64,
+            etag: format!("{:x}-{:x}", modified, metadata.len()),
+        })
+    }
+
+    async fn delete(&self, bucket: &str, key: &str) -> Result<(), String> {
+        for path in [self.object_path(bucket, key)?, self.metadata_path(bucket, key)?] {
+            let res = tokio::fs::remove_file(&path).await;
+
+            if res.as_ref().is_err_and(|err| err.kind() != ErrorKind::NotFound) {
+                return Err(res.err().unwrap().to_string());
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn delete_prefix(&self, bucket: &str, prefix: &str) -> Result<usize, String> {
+        let keys = self.list(bucket, prefix).await?;
+
+        for key in &keys {
+            self.delete(bucket, key).await?;
+        }
+
+        Ok(keys.len())
+    }
+
+    async fn list(&self, bucket: &str, prefix: &str) -> Result<Vec<String>, String> {
+        let bucket_path = self.bucket_path(bucket)?;
+        // Only the directory the prefix points into has to be walked
+        let start = match prefix.rsplit_once('/') {
+            Some((dir, _)) if !dir.is_empty() => self.object_path(bucket, dir)?,
+            _ => bucket_path.clone(),
+        };
+
+        let mut keys: Vec<String> = vec![];
+        let mut dirs = vec![start];
+
+        while let Some(dir) = dirs.pop() {
+            let entries = tokio::fs::read_dir(&dir).await;
+
+            if entries.as_ref().is_err_and(|err| err.kind() == ErrorKind::NotFound) {
+                continue;
+            }
+
+            let mut entries = entries.map_err(|err| err.to_string())?;
+
+            while let Some(entry) = entries.next_entry().await.map_err(|err| err.to_string())? {
+                let path = entry.path();
+                let file_type = entry.file_type().await.map_err(|err| err.to_string())?;
+
+                if file_type.is_dir() {
+                    dirs.push(path);
+                    continue;
+                }
+
+                let key = path
+                    .strip_prefix(&bucket_path)
+                    .ok()
+                    .and_then(|key| key.to_str())
+                    .map(|key| key.replace(std::path::MAIN_SEPARATOR, "/"));
+
+                if let Some(key) = key.filter(|key| key.starts_with(prefix)) {
+                    keys.push(key);
+                }
+            }
+        }
+
+        keys.sort();
+
+        Ok(keys)
+    }
+
+    async fn copy(
+        &self,
+        from_bucket: &str,
+        from_key: &str,
+        bucket: &str,
+        key: &str,
+        options: &CopyOptions<'_>
+    ) -> Result<(), String> {
+        let data = self.get(from_bucket, from_key).await?;
+        let metadata = self.read_metadata(from_bucket, from_key).await?;
+
+        self.write_atomic(&self.object_path(bucket, key)?, &data).await?;
+        self.write_metadata(bucket, key, &ObjectMetadata {
+            content_type: options.content_type
+                .map(str::to_owned)
+                .unwrap_or(metadata.content_type),
+            cache_control: options.cache_control
+                .map(str::to_owned)
+                .unwrap_or(metadata.cache_control),
+            public: options.visibility == AssetVisibility::Public,
+        }).await
+    }
+
+    async fn set_visibility(
+        &self,
+        bucket: &str,
+        key: &str,
+        visibility: AssetVisibility
+    ) -> Result<(), String> {
+        let mut metadata = self.read_metadata(bucket, key).await?;
+        metadata.public = visibility == AssetVisibility::Public;
+
+        self.write_metadata(bucket, key, &metadata).await
+    }
+
+    async fn get_visibility(&self, bucket: &str, key: &str) -> Result<AssetVisibility, String> {
+        let metadata = self.read_metadata(bucket, key).await?;
+
+        match metadata.public {
+            true => Ok(AssetVisibility::Public),
+            false => Ok(AssetVisibility::Private),
+        }
+    }
+
+    async fn list_versions(&self, _bucket: &str, _key: &str) -> Result<Vec<ObjectVersion>, String> {
+        Ok(vec![])
+    }
+
+    async fn presign_get(
+        &self,
+        bucket: &str,
+        key: &str,
+        expires_in: Duration
+    ) -> Result<String, String> {
+        self.object_path(bucket, key)?;
+        self.signed_url("GET", bucket, key, expires_in, None, None)
+    }
+
+    async fn presign_put(
+        &self,
+        bucket: &str,
+        key: &str,
+        options: &PutOptions<'_>,
+        expires_in: Duration
+    ) -> Result<PresignedRequest, String> {
+        self.object_path(bucket, key)?;
+
+        // Content type and cache headers are part of the signed query, not request headers
+        Ok(PresignedRequest {
+            url: self.signed_url(
+                "PUT",
+                bucket,
+                key,
+                expires_in,
+                Some(options.content_type),
+                Some(options.cache_control)
+            )?,
+            headers: HashMap::new(),
+        })
+    }
+
+    fn public_url(&self, bucket: &str, key: &str) -> String {
+        format!("{}/storage/{}/{}", self.base_url, bucket, key)
+    }
+
+    // Creates the bucket's directory on first use, which also proves the root is writable
+    async fn ping(&self, bucket: &str) -> Result<(), String> {
+        tokio::fs::create_dir_all(self.bucket_path(bucket)?).await.map_err(|err| err.to_string())
+    }
+}
@@ -0,0 +1,408 @@
+use std::{
+    collections::HashMap,
+    path::{ Path, PathBuf },
+    sync::{ Arc, Mutex },
+    time::{ Duration, Instant },
+};
+
+use aws_sdk_s3::Client;
+use axum::body::Bytes;
+use uuid::Uuid;
+
+use crate::{
+    config::Config,
+    enums::{ AppResponse, AssetVisibility },
+    state::models::AppState,
+    storage::{
+        backend::{
+            CopyOptions,
+            ObjectInfo,
+            ObjectStream,
+            ObjectVersion,
+            PresignedRequest,
+            PutOptions,
+            StorageBackend,
+            UploadedPart,
+        },
+        circuit_breaker::BreakerBackend,
+        local::LocalBackend,
+        s3::S3Backend,
+    },
+    utils::db_utils::get_client,
+};
+
+pub mod backend;
+pub mod circuit_breaker;
+pub mod local;
+pub mod s3;
+
+// Mappings rarely change, a moved project picks up its new target within this long
+const TARGET_CACHE_TTL: Duration = Duration::from_secs(60);
+
+// Where a project's objects live. Keys passed around the service are always the logical
+// assets/... keys, the target adds its prefix when talking to the backend.
+#[derive(Clone)]
+pub struct StorageTarget {
+    backend: Arc<dyn StorageBackend>,
+    pub bucket: String,
+    // Empty or ending in a slash
+    pub prefix: String,
+    pub region: String,
+    is_default: bool,
+}
+
+impl StorageTarget {
+    pub fn key(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+
+    // For objects outside of any target, like the source bucket of an import
+    pub fn backend(&self) -> &dyn StorageBackend {
+        self.backend.as_ref()
+    }
+
+    // The thumbnail service only reads the default bucket of the S3 backend
+    pub fn is_default(&self) -> bool {
+        self.is_default
+    }
+
+    pub fn public_url(&self, key: &str) -> String {
+        self.backend.public_url(&self.bucket, &self.key(key))
+    }
+
+    pub async fn put(
+        &self,
+        key: &str,
+        data: Vec<u8>,
+        options: &PutOptions<'_>
+    ) -> Result<Option<String>, String> {
+        self.backend.put(&self.bucket, &self.key(key), data, options).await
+    }
+
+    pub async fn put_file(
+        &self,
+        key: &str,
+        path: &Path,
+        options: &PutOptions<'_>
+    ) -> Result<(), String> {
+        self.backend.put_file(&self.bucket, &self.key(key), path, options).await
+    }
+
+    pub async fn get(&self, key: &str) -> Result<Bytes, String> {
+        self.backend.get(&self.bucket, &self.key(key)).await
+    }
+
+    pub async fn get_range(&self, key: &str, len: u64) -> Result<Bytes, String> {
+        self.backend.get_range(&self.bucket, &self.key(key), len).await
+    }
+
+    pub async fn get_stream(
+        &self,
+        key: &str,
+        range: Option<(u64, u64)>
+    ) -> Result<ObjectStream, String> {
+        self.backend.get_stream(&self.bucket, &self.key(key), range).await
+    }
+
+    pub async fn get_version(&self, key: &str, version_id: &str) -> Result<Bytes, String> {
+        self.backend.get_version(&self.bucket, &self.key(key), version_id).await
+    }
+
+    pub async fn head(&self, key: &str) -> Result<ObjectInfo, String> {
+        self.backend.head(&self.bucket, &self.key(key)).await
+    }
+
+    pub async fn delete(&self, key: &str) -> Result<(), String> {
+        self.backend.delete(&self.bucket, &self.key(key)).await
+    }
+
+    pub async fn delete_prefix(&self, prefix: &str) -> Result<usize, String> {
+        self.backend.delete_prefix(&self.bucket, &self.key(prefix)).await
+    }
+
+    // Keys come back without the target's prefix
+    pub async fn list(&self, prefix: &str) -> Result<Vec<String>, String> {
+        let keys = self.backend.list(&self.bucket, &self.key(prefix)).await?;
+
+        Ok(
+            keys
+                .into_iter()
+                .map(|key| key.strip_prefix(&self.prefix).unwrap_or(&key).to_owned())
+                .collect()
+        )
+    }
+
+    // `from_key` is taken as stored, without applying any prefix
+    pub async fn copy(
+        &self,
+        from_bucket: &str,
+        from_key: &str,
+        key: &str,
+        options: &CopyOptions<'_>
+    ) -> Result<(), String> {
+        self.backend.copy(from_bucket, from_key, &self.bucket, &self.key(key), options).await
+    }
+
+    // Goes through this target's backend, so S3 copies only work within a region
+    pub async fn copy_from(
+        &self,
+        source: &StorageTarget,
+        from_key: &str,
+        key: &str,
+        options: &CopyOptions<'_>
+    ) -> Result<(), String> {
+        self.copy(&source.bucket, &source.key(from_key), key, options).await
+    }
+
+    pub async fn set_visibility(
+        &self,
+        key: &str,
+        visibility: AssetVisibility
+    ) -> Result<(), String> {
+        self.backend.set_visibility(&self.bucket, &self.key(key), visibility).await
+    }
+
+    pub async fn get_visibility(&self, key: &str) -> Result<AssetVisibility, String> {
+        self.backend.get_visibility(&self.bucket, &self.key(key)).await
+    }
+
+    pub async fn list_versions(&self, key: &str) -> Result<Vec<ObjectVersion>, String> {
+        self.backend.list_versions(&self.bucket, &self.key(key)).await
+    }
+
+    pub async fn presign_get(&self, key: &str, expires_in: Duration) -> Result<String, String> {
+        self.backend.presign_get(&self.bucket, &self.key(key), expires_in).await
+    }
+
+    pub async fn presign_put(
+        &self,
+        key: &str,
+        options: &PutOptions<'_>,
+        expires_in: Duration
+    ) -> Result<PresignedRequest, String> {
+        self.backend.presign_put(&self.bucket, &self.key(key), options, expires_in).await
+    }
+
+    pub async fn create_multipart(
+        &self,
+        key: &str,
+        options: &PutOptions<'_>
+    ) -> Result<String, String> {
+        self.backend.create_multipart(&self.bucket, &self.key(key), options).await
+    }
+
+    pub async fn upload_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        part_number: i32,
+        data: Vec<u8>
+    ) -> Result<String, String> {
+        self.backend.upload_part(&self.bucket, &self.key(key), upload_id, part_number, data).await
+    }
+
+    pub async fn complete_multipart(
+        &self,
+        key: &str,
+        upload_id: &str,
+        parts: &[UploadedPart]
+    ) -> Result<(), String> {
+        self.backend.complete_multipart(&self.bucket, &self.key(key), upload_id, parts).await
+    }
+
+    pub async fn abort_multipart(&self, key: &str, upload_id: &str) -> Result<(), String> {
+        self.backend.abort_multipart(&self.bucket, &self.key(key), upload_id).await
+    }
+
+    pub async fn ping(&self) -> Result<(), String> {
+        self.backend.ping(&self.bucket).await
+    }
+}
+
+#[derive(Clone)]
+struct TargetMapping {
+    bucket: String,
+    prefix: String,
+    region: String,
+}
+
+enum Backends {
+    S3 {
+        default: S3Backend,
+        // Host of the default region, other regions swap the first label of the host
+        endpoint_host: String,
+        regions: Mutex<HashMap<String, Arc<dyn StorageBackend>>>,
+    },
+    // A single directory tree, regions don't apply
+    Local(Arc<LocalBackend>),
+}
+
+pub struct Storage {
+    default: StorageTarget,
+    backends: Backends,
+    // None caches "no mapping", so projects on the default bucket don't query every time
+    mappings: Mutex<HashMap<Uuid, (Instant, Option<TargetMapping>)>>,
+}
+
+impl Storage {
+    pub fn s3(client: Client, config: &Config) -> Self {
+        let endpoint_host = config.spaces_endpoint
+            .replace("https://", "")
+            .replace("http://", "");
+        let default = S3Backend::new(client, endpoint_host.clone());
+        let backend: Arc<dyn StorageBackend> = Arc::new(
+            BreakerBackend::new(Arc::new(default.clone()), &config.spaces_region)
+        );
+
+        Storage {
+            default: StorageTarget {
+                backend: backend.clone(),
+                bucket: config.default_bucket.clone(),
+                prefix: String::new(),
+                region: config.spaces_region.clone(),
+                is_default: true,
+            },
+            backends: Backends::S3 {
+                default,
+                endpoint_host,
+                regions: Mutex::new(HashMap::from([(config.spaces_region.clone(), backend)])),
+            },
+            mappings: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn local(config: &Config) -> Self {
+        let backend = Arc::new(
+            LocalBackend::new(
+                PathBuf::from(&config.local_storage_path),
+                config.local_storage_url.clone(),
+                config.local_storage_secret.clone()
+            )
+        );
+
+        Storage {
+            default: StorageTarget {
+                backend: backend.clone(),
+                bucket: config.default_bucket.clone(),
+                prefix: String::new(),
+                region: config.spaces_region.clone(),
+                // Nothing outside this service can read local objects
+                is_default: false,
+            },
+            backends: Backends::Local(backend),
+            mappings: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Objects that don't belong to a project (avatars, sitemaps) and projects without a mapping
+    pub fn default_target(&self) -> StorageTarget {
+        self.default.clone()
+    }
+
+    // storage_routes serves objects only when they are stored on local disk
+    pub fn local_backend(&self) -> Option<&LocalBackend> {
+        match &self.backends {
+            Backends::Local(backend) => Some(backend.as_ref()),
+            Backends::S3 { .. } => None,
+        }
+    }
+
+    fn backend_for(&self, region: &str) -> Arc<dyn StorageBackend> {
+        match &self.backends {
+            Backends::Local(backend) => backend.clone(),
+            Backends::S3 { default, endpoint_host, regions } => {
+                let mut regions = regions.lock().unwrap();
+
+                if let Some(backend) = regions.get(region) {
+                    return backend.clone();
+                }
+
+                // e.g. fra1.digitaloceanspaces.com -> ams3.digitaloceanspaces.com
+                let host = match endpoint_host.split_once('.') {
+                    Some((_, domain)) => format!("{}.{}", region, domain),
+                    None => endpoint_host.clone(),
+                };
+                // Every region gets its own breaker, one being down doesn't fail the others
+                let backend: Arc<dyn StorageBackend> = Arc::new(
+                    BreakerBackend::new(Arc::new(default.for_region(region, host)), region)
+                );
+
+                regions.insert(region.to_owned(), backend.clone());
+
+                backend
+            }
+        }
+    }
+
+    fn target(&self, mapping: Option<TargetMapping>) -> StorageTarget {
+        if mapping.is_none() {
+            return self.default_target();
+        }
+
+        let mapping = mapping.unwrap();
+        let is_default =
+            self.default.is_default &&
+            mapping.bucket == self.default.bucket &&
+            mapping.region == self.default.region;
+
+        StorageTarget {
+            backend: self.backend_for(&mapping.region),
+            bucket: mapping.bucket,
+            prefix: mapping.prefix,
+            region: mapping.region,
+            is_default,
+        }
+    }
+}
+
+fn normalize_prefix(prefix: &str) -> String {
+    let prefix = prefix.trim_matches('/');
+
+    match prefix.is_empty() {
+        true => String::new(),
+        false => format!("{}/", prefix),
+    }
+}
+
+// Looks the project up in storage_targets, falling back to the default bucket
+pub async fn resolve_target(state: &AppState, project_id: &Uuid) -> Result<StorageTarget, AppResponse> {
+    let cached = state.storage.mappings
+        .lock()
+        .unwrap()
+        .get(project_id)
+        .filter(|(cached_at, _)| cached_at.elapsed() < TARGET_CACHE_TTL)
+        .map(|(_, mapping)| mapping.clone());
+
+    if let Some(mapping) = cached {
+        return Ok(state.storage.target(mapping));
+    }
+
+    let client = get_client(&state.pool).await?;
+
+    let row = client.query_opt(
+        "SELECT bucket, prefix, region FROM storage_targets WHERE project_id = $1;",
+        &[&project_id]
+    ).await;
+
+    if row.is_err() {
+        return Err(AppResponse::Error(row.err().unwrap().to_string()));
+    }
+
+    let mapping = row.unwrap().map(|row| {
+        let prefix: Option<String> = row.get("prefix");
+        let region: Option<String> = row.get("region");
+
+        TargetMapping {
+            bucket: row.get("bucket"),
+            prefix: normalize_prefix(&prefix.unwrap_or_default()),
+            region: region.unwrap_or(state.storage.default.region.clone()),
+        }
+    });
+
+    state.storage.mappings
+        .lock()
+        .unwrap()
+        .insert(*project_id, (Instant::now(), mapping.clone()));
+
+    Ok(state.storage.target(mapping))
+}
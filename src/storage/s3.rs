@@ -0,0 +1,643 @@
+use std::{ path::Path, time::Duration };
+
+use async_trait::async_trait;
+use aws_sdk_s3::{
+    config::Region,
+    presigning::PresigningConfig,
+    primitives::ByteStream,
+    types::{
+        CompletedMultipartUpload,
+        CompletedPart,
+        Delete,
+        MetadataDirective,
+        ObjectIdentifier,
+        Permission,
+        Type,
+    },
+    Client,
+};
+use axum::body::{ Body, Bytes };
+use tokio::{ fs::File, io::AsyncReadExt };
+
+use crate::{
+    enums::AssetVisibility,
+    storage::backend::{
+        CopyOptions,
+        ObjectInfo,
+        ObjectStream,
+        ObjectVersion,
+        PresignedRequest,
+        PutOptions,
+        StorageBackend,
+        UploadedPart,
+    },
+};
+
+// S3 requires every part except the last to be at least 5MB
+const PART_SIZE: usize = 8 * 1024 * 1024;
+const ALL_USERS_GROUP: &str = "http://acs.amazonaws.com/groups/global/AllUsers";
+// Keys delete_objects reports as failed for a transient reason are sent again this many times
+const DELETE_RETRIES: u32 = 3;
+const DELETE_RETRY_DELAY: Duration = Duration::from_millis(500);
+// Error codes of delete_objects that are worth retrying, anything else fails right away
+const TRANSIENT_DELETE_ERRORS: [&str; 4] = [
+    "InternalError",
+    "ServiceUnavailable",
+    "SlowDown",
+    "RequestTimeout",
+];
+
+// DigitalOcean Spaces, or anything else speaking the S3 API
+#[derive(Clone)]
+pub struct S3Backend {
+    client: Client,
+    // Endpoint host without the scheme, public URLs are https://<bucket>.<host>
+    host: String,
+}
+
+impl S3Backend {
+    pub fn new(client: Client, host: String) -> Self {
+        S3Backend { client, host }
+    }
+
+    // Same credentials and interceptors, talking to another region's endpoint
+    pub fn for_region(&self, region: &str, host: String) -> Self {
+        let config = self.client
+            .config()
+            .to_builder()
+            .region(Region::new(region.to_owned()))
+            .endpoint_url(format!("https://{}", host))
+            .build();
+
+        S3Backend { client: Client::from_conf(config), host }
+    }
+}
+
+#[async_trait]
+impl StorageBackend for S3Backend {
+    async fn put(
+        &self,
+        bucket: &str,
+        key: &str,
+        data: Vec<u8>,
+        options: &PutOptions<'_>
+    ) -> Result<Option<String>, String> {
+        let res = self.client
+            .put_object()
+            .bucket(bucket)
+            .key(key)
+            .body(ByteStream::from(data))
+            .acl(options.visibility.acl())
+            .content_type(options.content_type)
+            .cache_control(options.cache_control)
+            .send().await;
+
+        if res.is_err() {
+            return Err(res.err().unwrap().to_string());
+        }
+
+        Ok(res.unwrap().version_id)
+    }
+
+    // Multipart upload, holding at most one part in memory
+    async fn put_file(
+        &self,
+        bucket: &str,
+        key: &str,
+        path: &Path,
+        options: &PutOptions<'_>
+    ) -> Result<(), String> {
+        let upload_id = self.create_multipart(bucket, key, options).await?;
+        let mut file = File::open(path).await.map_err(|err| err.to_string())?;
+        let mut parts: Vec<UploadedPart> = vec![];
+
+        loop {
+            let mut buffer = Vec::with_capacity(PART_SIZE);
+            let read = (&mut file).take(PART_SIZE as u64).read_to_end(&mut buffer).await;
+
+            if read.is_err() {
+                let _ = self.abort_multipart(bucket, key, &upload_id).await;
+                return Err(read.err().unwrap().to_string());
+            }
+
+            // An empty file still needs a single (empty) part
+            if read.unwrap() == 0 && !parts.is_empty() {
+                break;
+            }
+
+            let part_number = (parts.len() as i32) + 1;
+            let etag = self.upload_part(bucket, key, &upload_id, part_number, buffer).await;
+
+            if etag.is_err() {
+                let abort = self.abort_multipart(bucket, key, &upload_id).await;
+
+                if abort.is_err() {
+                    tracing::error!("ERROR ABORTING MULTIPART UPLOAD - {}", abort.err().unwrap());
+                }
+
+                return Err(etag.err().unwrap());
+            }
+
+            parts.push(UploadedPart { part_number, etag: etag.unwrap() });
+        }
+
+        self.complete_multipart(bucket, key, &upload_id, &parts).await
+    }
+
+    async fn create_multipart(
+        &self,
+        bucket: &str,
+        key: &str,
+        options: &PutOptions<'_>
+    ) -> Result<String, String> {
+        let multipart = self.client
+            .create_multipart_upload()
+            .bucket(bucket)
+            .key(key)
+            .acl(options.visibility.acl())
+            .content_type(options.content_type)
+            .cache_control(options.cache_control)
+            .send().await;
+
+        if multipart.is_err() {
+            return Err(multipart.err().unwrap().to_string());
+        }
+
+        Ok(multipart.unwrap().upload_id.unwrap_or_default())
+    }
+
+    async fn upload_part(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        part_number: i32,
+        data: Vec<u8>
+    ) -> Result<String, String> {
+        let part = self.client
+            .upload_part()
+            .bucket(bucket)
+            .key(key)
+            .upload_id(upload_id)
+            .part_number(part_number)
+            .body(ByteStream::from(data))
+            .send().await;
+
+        if part.is_err() {
+            return Err(part.err().unwrap().to_string());
+        }
+
+        Ok(part.unwrap().e_tag.unwrap_or_default())
+    }
+
+    async fn complete_multipart(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        parts: &[UploadedPart]
+    ) -> Result<(), String> {
+        let parts = parts
+            .iter()
+            .map(|part| {
+                CompletedPart::builder()
+                    .e_tag(&part.etag)
+                    .part_number(part.part_number)
+                    .build()
+            })
+            .collect();
+
+        let complete = self.client
+            .complete_multipart_upload()
+            .bucket(bucket)
+            .key(key)
+            .upload_id(upload_id)
+            .multipart_upload(CompletedMultipartUpload::builder().set_parts(Some(parts)).build())
+            .send().await;
+
+        if complete.is_err() {
+            return Err(complete.err().unwrap().to_string());
+        }
+
+        Ok(())
+    }
+
+    async fn abort_multipart(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str
+    ) -> Result<(), String> {
+        let abort = self.client
+            .abort_multipart_upload()
+            .bucket(bucket)
+            .key(key)
+            .upload_id(upload_id)
+            .send().await;
+
+        if abort.is_err() {
+            return Err(abort.err().unwrap().to_string());
+        }
+
+        Ok(())
+    }
+
+    async fn get(&self, bucket: &str, key: &str) -> Result<Bytes, String> {
+        let data = self.client.get_object().bucket(bucket).key(key).send().await;
+
+        if data.is_err() {
+            return Err(data.err().unwrap().to_string());
+        }
+
+        let data = data.unwrap().body.collect().await;
+
+        if data.is_err() {
+            return Err(data.err().unwrap().to_string());
+        }
+
+        Ok(data.unwrap().into_bytes())
+    }
+
+    async fn get_range(&self, bucket: &str, key: &str, len: u64) -> Result<Bytes, String> {
+        let data = self.client
+            .get_object()
+            .bucket(bucket)
+            .key(key)
+            .range(format!("bytes=0-{}", len.saturating_sub(1)))
+            .send().await;
+
+        if data.is_err() {
+            return Err(data.err().unwrap().to_string());
+        }
+
+        let data = data.unwrap().body.collect().await;
+
+        if data.is_err() {
+            return Err(data.err().unwrap().to_string());
+        }
+
+        Ok(data.unwrap().into_bytes())
+    }
+
+    async fn get_stream(
+        &self,
+        bucket: &str,
+        key: &str,
+        range: Option<(u64, u64)>
+    ) -> Result<ObjectStream, String> {
+        let data = self.client
+            .get_object()
+            .bucket(bucket)
+            .key(key)
+            .set_range(range.map(|(start, end)| format!("bytes={}-{}", start, end)))
+            .send().await;
+
+        if data.is_err() {
+            return Err(data.err().unwrap().to_string());
+        }
+
+        let data = data.unwrap();
+
+        let stream = futures::stream::unfold(data.body, |mut body| async move {
+            body.next().await.map(|chunk| (chunk, body))
+        });
+
+        Ok(ObjectStream {
+            body: Body::from_stream(stream),
+            content_type: data.content_type,
+            cache_control: data.cache_control,
+        })
+    }
+
+    // Needs versioning enabled on the bucket, otherwise only the current object exists.
+    async fn get_version(
+        &self,
+        bucket: &str,
+        key: &str,
+        version_id: &str
+    ) -> Result<Bytes, String> {
+        let data = self.client
+            .get_object()
+            .bucket(bucket)
+            .key(key)
+            .version_id(version_id)
+            .send().await;
+
+        if data.is_err() {
+            return Err(data.err().unwrap().to_string());
+        }
+
+        let data = data.unwrap().body.collect().await;
+
+        if data.is_err() {
+            return Err(data.err().unwrap().to_string());
+        }
+
+        Ok(data.unwrap().into_bytes())
+    }
+
+    async fn head(&self, bucket: &str, key: &str) -> Result<ObjectInfo, String> {
+        let head = self.client.head_object().bucket(bucket).key(key).send().await;
+
+        if head.is_err() {
+            return Err(head.err().unwrap().to_string());
+        }
+
+        let head = head.unwrap();
+
+        Ok(ObjectInfo {
+            size: head.content_length.unwrap_or(0),
+            etag: head.e_tag.unwrap_or_default(),
+        })
+    }
+
+    async fn delete(&self, bucket: &str, key: &str) -> Result<(), String> {
+        self.client
+            .delete_object()
+            .bucket(bucket)
+            .key(key)
+            .send().await
+            .map_err(|err| err.to_string())?;
+
+        Ok(())
+    }
+
+    async fn delete_prefix(&self, bucket: &str, prefix: &str) -> Result<usize, String> {
+        let keys = self.list(bucket, prefix).await?;
+
+        // delete_objects takes at most 1000 keys
+        for chunk in keys.chunks(1000) {
+            let mut pending: Vec<String> = chunk.to_vec();
+            let mut attempt = 0;
+
+            // The request succeeds as a whole even when some keys weren't deleted, those are
+            // only listed in its errors
+            loop {
+                let objects: Vec<ObjectIdentifier> = pending
+                    .iter()
+                    .map(|key| ObjectIdentifier::builder().key(key).build().unwrap())
+                    .collect();
+
+                let res = self.client
+                    .delete_objects()
+                    .bucket(bucket)
+                    .delete(Delete::builder().set_objects(Some(objects)).build().unwrap())
+                    .send().await;
+
+                if res.is_err() {
+                    return Err(res.err().unwrap().to_string());
+                }
+
+                let res = res.unwrap();
+
+                if res.errors().is_empty() {
+                    break;
+                }
+
+                let transient = res
+                    .errors()
+                    .iter()
+                    .all(|error| {
+                        error.key().is_some() &&
+                            error.code().is_some_and(|code| TRANSIENT_DELETE_ERRORS.contains(&code))
+                    });
+
+                attempt += 1;
+
+                if !transient || attempt > DELETE_RETRIES {
+                    let failed: Vec<String> = res
+                        .errors()
+                        .iter()
+                        .map(|error| {
+                            format!(
+                                "{} ({})",
+                                error.key().unwrap_or_default(),
+                                error.code().unwrap_or("UNKNOWN")
+                            )
+                        })
+                        .collect();
+
+                    return Err(
+                        format!(
+                            "{} OBJECTS COULD NOT BE DELETED - {}",
+                            failed.len(),
+                            failed.join(", ")
+                        )
+                    );
+                }
+
+                pending = res
+                    .errors()
+                    .iter()
+                    .filter_map(|error| error.key().map(|key| key.to_owned()))
+                    .collect();
+
+                tokio::time::sleep(DELETE_RETRY_DELAY * attempt).await;
+            }
+        }
+
+        Ok(keys.len())
+    }
+
+    async fn list(&self, bucket: &str, prefix: &str) -> Result<Vec<String>, String> {
+        let mut keys: Vec<String> = vec![];
+        let mut continuation_token = None;
+
+        loop {
+            let list_resp = self.client
+                .list_objects_v2()
+                .bucket(bucket)
+                .prefix(prefix)
+                .set_continuation_token(continuation_token)
+                .send().await;
+
+            if list_resp.is_err() {
+                return Err(list_resp.err().unwrap().to_string());
+            }
+
+            let list_resp = list_resp.unwrap();
+
+            keys.extend(
+                list_resp.contents
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter_map(|obj| obj.key)
+            );
+
+            if list_resp.is_truncated.unwrap_or(false) {
+                continuation_token = list_resp.next_continuation_token;
+            } else {
+                break;
+            }
+        }
+
+        Ok(keys)
+    }
+
+    // Copies between buckets only work within a region, the request goes to this backend's
+    // endpoint
+    async fn copy(
+        &self,
+        from_bucket: &str,
+        from_key: &str,
+        bucket: &str,
+        key: &str,
+        options: &CopyOptions<'_>
+    ) -> Result<(), String> {
+        let replace = options.content_type.is_some() || options.cache_control.is_some();
+
+        let mut copy = self.client
+            .copy_object()
+            .copy_source(format!("{}/{}", from_bucket, from_key))
+            .bucket(bucket)
+            .key(key)
+            .acl(options.visibility.acl());
+
+        if replace {
+            copy = copy
+                .set_content_type(options.content_type.map(str::to_owned))
+                .set_cache_control(options.cache_control.map(str::to_owned))
+                .metadata_directive(MetadataDirective::Replace);
+        }
+
+        copy.send().await.map_err(|err| err.to_string())?;
+
+        Ok(())
+    }
+
+    async fn set_visibility(
+        &self,
+        bucket: &str,
+        key: &str,
+        visibility: AssetVisibility
+    ) -> Result<(), String> {
+        self.client
+            .put_object_acl()
+            .bucket(bucket)
+            .key(key)
+            .acl(visibility.acl())
+            .send().await
+            .map_err(|err| err.to_string())?;
+
+        Ok(())
+    }
+
+    async fn get_visibility(&self, bucket: &str, key: &str) -> Result<AssetVisibility, String> {
+        let acl = self.client.get_object_acl().bucket(bucket).key(key).send().await;
+
+        if acl.is_err() {
+            return Err(acl.err().unwrap().to_string());
+        }
+
+        let is_public = acl
+            .unwrap()
+            .grants()
+            .iter()
+            .any(|grant| {
+                let grantee = grant.grantee();
+
+                grantee.is_some_and(
+                    |grantee|
+                        grantee.r#type() == &Type::Group && grantee.uri() == Some(ALL_USERS_GROUP)
+                ) &&
+                    matches!(
+                        grant.permission(),
+                        Some(Permission::Read) | Some(Permission::FullControl)
+                    )
+            });
+
+        if is_public {
+            Ok(AssetVisibility::Public)
+        } else {
+            Ok(AssetVisibility::Private)
+        }
+    }
+
+    async fn list_versions(&self, bucket: &str, key: &str) -> Result<Vec<ObjectVersion>, String> {
+        let versions = self.client.list_object_versions().bucket(bucket).prefix(key).send().await;
+
+        if versions.is_err() {
+            return Err(versions.err().unwrap().to_string());
+        }
+
+        Ok(
+            versions
+                .unwrap()
+                .versions()
+                .iter()
+                .filter(|version| version.key() == Some(key))
+                .map(|version| ObjectVersion {
+                    version_id: version.version_id().unwrap_or_default().to_owned(),
+                    is_latest: version.is_latest().unwrap_or(false),
+                    size: version.size().unwrap_or(0),
+                    last_modified: version.last_modified().map(|date| date.secs()),
+                })
+                .collect()
+        )
+    }
+
+    async fn presign_get(
+        &self,
+        bucket: &str,
+        key: &str,
+        expires_in: Duration
+    ) -> Result<String, String> {
+        let presigning = PresigningConfig::expires_in(expires_in).map_err(|err| err.to_string())?;
+
+        let command = self.client.get_object().bucket(bucket).key(key).presigned(presigning).await;
+
+        if command.is_err() {
+            return Err(command.err().unwrap().to_string());
+        }
+
+        Ok(command.unwrap().uri().to_owned())
+    }
+
+    async fn presign_put(
+        &self,
+        bucket: &str,
+        key: &str,
+        options: &PutOptions<'_>,
+        expires_in: Duration
+    ) -> Result<PresignedRequest, String> {
+        let presigning = PresigningConfig::expires_in(expires_in).map_err(|err| err.to_string())?;
+
+        let command = self.client
+            .put_object()
+            .bucket(bucket)
+            .key(key)
+            .content_type(options.content_type)
+            .cache_control(options.cache_control)
+            .acl(options.visibility.acl())
+            .presigned(presigning).await;
+
+        if command.is_err() {
+            return Err(command.err().unwrap().to_string());
+        }
+
+        let command = command.unwrap();
+
+        Ok(PresignedRequest {
+            url: command.uri().to_owned(),
+            headers: command
+                .headers()
+                .map(|(name, value)| (name.to_owned(), value.to_owned()))
+                .collect(),
+        })
+    }
+
+    fn public_url(&self, bucket: &str, key: &str) -> String {
+        format!("https://{}.{}/{}", bucket, self.host, key)
+    }
+
+    async fn ping(&self, bucket: &str) -> Result<(), String> {
+        self.client
+            .list_objects_v2()
+            .bucket(bucket)
+            .max_keys(1)
+            .send().await
+            .map_err(|err| err.to_string())?;
+
+        Ok(())
+    }
+}
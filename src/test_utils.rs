@@ -0,0 +1,83 @@
+use std::{ env, sync::{ Arc, OnceLock } };
+
+use deadpool_postgres::{ Config as DeadPoolConfig, ManagerConfig, Pool };
+use metrics_exporter_prometheus::PrometheusBuilder;
+use tokio_postgres::NoTls;
+use uuid::Uuid;
+
+use crate::{ app_state, config::Config, state::models::AppState, storage::Storage };
+
+pub const TEST_THUMBNAIL_SECRET: &str = "test-thumbnail-secret";
+
+// The schema lives outside of this repository, so tests that need a database only run when
+// TEST_DATABASE_URL points at a Postgres they may create schemas in. Each test creates the
+// tables it uses in a schema of its own.
+fn test_database_url() -> Option<String> {
+    env::var("TEST_DATABASE_URL").ok().filter(|url| !url.trim().is_empty())
+}
+
+// Built once, the environment is shared by every test of the process
+fn test_config(database_url: &str) -> Arc<Config> {
+    static CONFIG: OnceLock<Arc<Config>> = OnceLock::new();
+
+    CONFIG.get_or_init(|| {
+        let storage_path = env::temp_dir().join(format!("arkive-test-{}", Uuid::new_v4().simple()));
+
+        for (name, value) in [
+            ("PORT", "0"),
+            ("DATABASE_URL", database_url),
+            ("STORAGE_BACKEND", "local"),
+            ("LOCAL_STORAGE_PATH", storage_path.to_str().unwrap()),
+            ("LOCAL_STORAGE_URL", "http://localhost"),
+            ("LOCAL_STORAGE_SECRET", "test-storage-secret"),
+            ("CORS_ORIGINS", "http://localhost"),
+            ("AUTH_SERVICE_URL", "http://localhost"),
+            ("THUMBNAIL_SERVICE", "http://localhost"),
+            ("THUMBNAIL_SECRET", TEST_THUMBNAIL_SECRET),
+            ("GATEWAY_SECRET", "test-gateway-secret"),
+        ] {
+            env::set_var(name, value);
+        }
+
+        Arc::new(Config::from_env().expect("INVALID TEST CONFIGURATION"))
+    }).clone()
+}
+
+// None when no test database is configured, the test is skipped then
+pub async fn test_state() -> Option<AppState> {
+    let database_url = test_database_url();
+
+    if database_url.is_none() {
+        eprintln!("TEST_DATABASE_URL IS NOT SET, SKIPPING");
+        return None;
+    }
+
+    let database_url = database_url.unwrap();
+    let schema = format!("test_{}", Uuid::new_v4().simple());
+
+    let admin = tokio_postgres::connect(&database_url, NoTls).await.unwrap();
+    tokio::spawn(admin.1);
+    admin.0.batch_execute(&format!("CREATE SCHEMA {};", schema)).await.unwrap();
+
+    let mut cfg = DeadPoolConfig::new();
+    cfg.url = Some(database_url.clone());
+    cfg.options = Some(format!("-c search_path={}", schema));
+    cfg.manager = Some(ManagerConfig {
+        recycling_method: deadpool_postgres::RecyclingMethod::Fast,
+    });
+
+    let pool: Pool = cfg.create_pool(Some(deadpool_postgres::Runtime::Tokio1), NoTls).unwrap();
+    let config = test_config(&database_url);
+    let storage = Storage::local(&config);
+    let metrics = PrometheusBuilder::new().build_recorder().handle();
+
+    Some(app_state(config, storage, metrics, pool, None))
+}
+
+// Drops the schema test_state created
+pub async fn drop_test_schema(state: &AppState) {
+    let client = state.pool.get().await.unwrap();
+    let schema: String = client.query_one("SELECT current_schema();", &[]).await.unwrap().get(0);
+
+    client.batch_execute(&format!("DROP SCHEMA {} CASCADE;", schema)).await.unwrap();
+}
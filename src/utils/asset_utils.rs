@@ -0,0 +1,188 @@
+use uuid::Uuid;
+
+use crate::enums::{ AssetKind, ImageType, SupportedImageType };
+
+pub struct SniffedAsset {
+    pub kind: AssetKind,
+    pub mime_type: &'static str,
+    pub extension: &'static str,
+}
+
+// (kind, mime type, extension) for every type the service stores. Images are re-encoded,
+// so only the storage formats are listed for them.
+const MEDIA_TYPES: [(AssetKind, &str, &str); 9] = [
+    (AssetKind::Audio, "audio/mpeg", "mp3"),
+    (AssetKind::Audio, "audio/ogg", "ogg"),
+    (AssetKind::Audio, "audio/wav", "wav"),
+    (AssetKind::Audio, "audio/flac", "flac"),
+    (AssetKind::Document, "application/pdf", "pdf"),
+    (AssetKind::Video, "video/mp4", "mp4"),
+    (AssetKind::Video, "video/webm", "webm"),
+    (AssetKind::Image, "image/webp", "webp"),
+    (AssetKind::Image, "image/avif", "avif"),
+];
+
+// None for content types the service doesn't store
+pub fn supported_media_type(mime_type: &str) -> Option<SniffedAsset> {
+    MEDIA_TYPES.iter()
+        .find(|(_, mime, _)| *mime == mime_type)
+        .map(|(kind, mime_type, extension)| SniffedAsset {
+            kind: *kind,
+            mime_type,
+            extension,
+        })
+}
+
+fn media_type(mime_type: &str) -> SniffedAsset {
+    supported_media_type(mime_type).unwrap_or(supported_media_type("image/webp").unwrap())
+}
+
+// Detects the type from the file's magic bytes, the client supplied name/content type
+// is never trusted. Only the SupportedImageType formats count as images.
+pub fn sniff_asset(data: &[u8]) -> Option<SniffedAsset> {
+    if SupportedImageType::from_magic(data).is_some() {
+        return Some(media_type("image/webp"));
+    }
+
+    let mime_type = match data {
+        [b'I', b'D', b'3', ..] | [0xff, 0xfb, ..] | [0xff, 0xf3, ..] | [0xff, 0xf2, ..] =>
+            "audio/mpeg",
+        [b'O', b'g', b'g', b'S', ..] => "audio/ogg",
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'A', b'V', b'E', ..] => "audio/wav",
+        [b'f', b'L', b'a', b'C', ..] => "audio/flac",
+        [b'%', b'P', b'D', b'F', ..] => "application/pdf",
+        [_, _, _, _, b'f', b't', b'y', b'p', ..] => "video/mp4",
+        [0x1a, 0x45, 0xdf, 0xa3, ..] => "video/webm",
+        _ => {
+            return None;
+        }
+    };
+
+    Some(media_type(mime_type))
+}
+
+// Formats that are turned down by name, so the uploader learns why. SVG and HTML can carry
+// scripts, the other images are formats we don't decode.
+fn rejected_format(head: &[u8]) -> Option<String> {
+    let text = head.strip_prefix(b"\xef\xbb\xbf").unwrap_or(head);
+    let text = String::from_utf8_lossy(text).trim_start().to_lowercase();
+
+    if text.starts_with("<svg") || text.starts_with("<?xml") {
+        return Some("SVG".to_owned());
+    }
+
+    if text.starts_with("<!doctype") || text.starts_with("<html") {
+        return Some("HTML".to_owned());
+    }
+
+    // HEIF brands, these would pass for MP4 otherwise
+    if let [_, _, _, _, b'f', b't', b'y', b'p', brand @ ..] = head {
+        let heif = [b"heic", b"heix", b"hevc", b"hevx", b"mif1", b"msf1"];
+
+        if heif.iter().any(|heif| brand.starts_with(*heif)) {
+            return Some("HEIC".to_owned());
+        }
+    }
+
+    if head.starts_with(b"8BPS") {
+        return Some("PSD".to_owned());
+    }
+
+    match image::guess_format(head) {
+        Ok(format) if SupportedImageType::from_magic(head).is_none() => {
+            Some(
+                format
+                    .extensions_str()
+                    .first()
+                    .map_or("UNKNOWN".to_owned(), |extension| extension.to_uppercase())
+            )
+        }
+        _ => None,
+    }
+}
+
+// The content type the client declared, when it names one of the kinds we store. Generic
+// ones like application/octet-stream say nothing and are ignored.
+fn declared_type(declared: Option<&str>) -> Option<String> {
+    let declared = declared?.split(';').next()?.trim().to_lowercase();
+
+    let specific =
+        declared.starts_with("image/") ||
+        declared.starts_with("audio/") ||
+        declared.starts_with("video/") ||
+        declared == "application/pdf";
+
+    specific.then_some(declared)
+}
+
+// Everything an upload is checked on before it is decoded or stored. The error is the reason
+// reported for the file.
+pub fn check_upload(head: &[u8], declared: Option<&str>) -> Result<SniffedAsset, String> {
+    if let Some(format) = rejected_format(head) {
+        return Err(format!("UNSUPPORTED FORMAT - {}", format));
+    }
+
+    let sniffed = sniff_asset(head);
+
+    if sniffed.is_none() {
+        return Err("UNSUPPORTED FILE TYPE".to_owned());
+    }
+
+    let sniffed = sniffed.unwrap();
+
+    // Images are stored re-encoded, what was uploaded is the detected source format
+    let detected = match sniffed.kind {
+        AssetKind::Image => SupportedImageType::from_magic(head).unwrap().mime_type(),
+        _ => sniffed.mime_type,
+    };
+
+    if let Some(declared) = declared_type(declared) {
+        let matches = match sniffed.kind {
+            AssetKind::Image =>
+                SupportedImageType::from_mime_type(&declared).is_some_and(
+                    |declared| declared.mime_type() == detected
+                ),
+            // Audio and video go by many names (audio/mp3, audio/x-wav...), only the kind has
+            // to agree
+            _ => declared.split('/').next() == detected.split('/').next(),
+        };
+
+        if !matches {
+            return Err(
+                format!("CONTENT TYPE MISMATCH - DECLARED {}, DETECTED {}", declared, detected)
+            );
+        }
+    }
+
+    Ok(sniffed)
+}
+
+pub fn extension_for_mime(mime_type: &str) -> &'static str {
+    media_type(mime_type).extension
+}
+
+// Images keep the original assets/:project_id/:image_type/:id.<webp|avif> layout, other
+// kinds are grouped by kind and keep their original extension.
+pub fn asset_key(
+    project_id: &Uuid,
+    image_type: &ImageType,
+    kind: &AssetKind,
+    id: &Uuid,
+    mime_type: &str
+) -> String {
+    match kind {
+        AssetKind::Image =>
+            format!("assets/{}/{}/{}.{}", project_id, image_type, id, extension_for_mime(mime_type)),
+        _ => format!("assets/{}/{}/{}.{}", project_id, kind, id, extension_for_mime(mime_type)),
+    }
+}
+
+// Library assets belong to no project, they're all kept on the default target
+pub fn library_key(id: &Uuid, mime_type: &str) -> String {
+    format!("library/{}.{}", id, extension_for_mime(mime_type))
+}
+
+// Key of a stored image, the extension follows the stored format
+pub fn image_key(project_id: &Uuid, image_type: &ImageType, id: &Uuid, mime_type: &str) -> String {
+    asset_key(project_id, image_type, &AssetKind::Image, id, mime_type)
+}
@@ -1,143 +1,580 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    sync::{ Arc, Mutex },
+    time::{ Duration, Instant, SystemTime, UNIX_EPOCH },
+};
 
-use axum::http::HeaderMap;
+use axum::http::{ HeaderMap, HeaderValue };
 use axum_extra::extract::{ cookie::Cookie, CookieJar };
-use reqwest::{ header::CONTENT_TYPE, Client, StatusCode };
+use deadpool_postgres::Object;
+use hmac::{ Hmac, Mac };
+use reqwest::{ header::CONTENT_TYPE, StatusCode };
+use sha2::{ Digest, Sha256 };
+use tokio_postgres::Row;
+use uuid::Uuid;
 
 use crate::{
-    enums::AppResponse,
-    state::models::{ AppState, PermissionUpdateType, VerifyJWTResponse },
+    enums::{ ApiKeyScope, AppResponse, RequiredPermission },
+    state::models::{
+        ApiKeyProject,
+        AppState,
+        Claims,
+        PermissionCheckResponse,
+        PermissionUpdateType,
+        VerifyJWTResponse,
+    },
+    API_KEY_PREFIX_LEN,
 };
 
-use super::db_utils::get_client;
+use super::{
+    db_utils::get_client,
+    metrics_utils::record_auth_cache,
+    request_id_utils::ForwardRequestId,
+};
 
-pub async fn check_auth(
-    cookie_jar: CookieJar,
-    client: &Client,
-    auth_service_url: String,
-    headers: HeaderMap
-) -> Result<VerifyJWTResponse, (StatusCode, String)> {
-    let module = headers.get("module");
+// Verified claims keyed by a hash of the module and tokens they were verified for
+pub type AuthCache = Arc<Mutex<HashMap<String, (Instant, Claims)>>>;
 
-    if module.is_none() {
-        return Err((StatusCode::UNAUTHORIZED, "UNAUTHORIZED".to_string()));
+// Drops expired claims once per TTL instead of on every cache miss, so the cache never outgrows
+// the sessions seen within one TTL and a miss doesn't hold the lock while it scans every entry
+pub async fn run_auth_cache_sweep(state: AppState) {
+    let ttl = Duration::from_secs(state.config.auth_cache_ttl_secs);
+
+    if ttl.is_zero() {
+        return;
     }
 
-    let module = module.unwrap().to_str().unwrap();
+    let mut interval = tokio::time::interval(ttl);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = state.shutdown.cancelled() => {
+                return;
+            }
+        }
+
+        state.auth_cache.lock().unwrap().retain(|_, (cached_at, _)| cached_at.elapsed() < ttl);
+    }
+}
+
+// Only the length leaks, the time taken doesn't depend on where the inputs differ
+pub fn constant_time_eq(provided: &[u8], expected: &[u8]) -> bool {
+    if provided.len() != expected.len() {
+        return false;
+    }
+
+    provided
+        .iter()
+        .zip(expected.iter())
+        .fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+pub fn check_admin_key(headers: &HeaderMap, admin_api_key: &str) -> bool {
+    let provided = headers.get("x-admin-key");
+
+    if provided.is_none() || admin_api_key.is_empty() {
+        return false;
+    }
+
+    constant_time_eq(provided.unwrap().as_bytes(), admin_api_key.as_bytes())
+}
 
+pub const GATEWAY_SIGNATURE_HEADER: &str = "x-gateway-signature";
+pub const GATEWAY_TIMESTAMP_HEADER: &str = "x-gateway-timestamp";
+// Signed requests older (or newer) than this are rejected, so captured ones can't be replayed
+const GATEWAY_SIGNATURE_TOLERANCE_SECS: u64 = 300;
+
+// The gateway signs "<timestamp>.<project_id>.<entity_id>.<hex SHA-256 of the body>" with the
+// shared secret and sends the result in GATEWAY_SIGNATURE_HEADER, the timestamp (unix seconds)
+// in GATEWAY_TIMESTAMP_HEADER. Covering the body keeps a captured signature from being reused
+// with other files.
+pub fn sign_gateway_request(
+    secret: &str,
+    timestamp: i64,
+    project_id: &Uuid,
+    entity_id: &Uuid,
+    body: &[u8]
+) -> String {
+    let body_hash = format!("{:x}", Sha256::digest(body));
+    let mut hmac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    hmac.update(format!("{}.{}.{}.{}", timestamp, project_id, entity_id, body_hash).as_bytes());
+
+    format!("sha256={:x}", hmac.finalize().into_bytes())
+}
+
+pub fn check_gateway_signature(
+    headers: &HeaderMap,
+    secret: &str,
+    project_id: &Uuid,
+    entity_id: &Uuid,
+    body: &[u8]
+) -> bool {
+    let header = |name| headers.get(name).and_then(|value: &HeaderValue| value.to_str().ok());
+    let signature = header(GATEWAY_SIGNATURE_HEADER);
+    let timestamp = header(GATEWAY_TIMESTAMP_HEADER).and_then(|value| value.parse::<i64>().ok());
+
+    if signature.is_none() || timestamp.is_none() || secret.is_empty() {
+        return false;
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs() as i64);
+
+    // The timestamp is whatever the caller sent, abs_diff can't overflow on extreme values
+    if now.abs_diff(timestamp.unwrap()) > GATEWAY_SIGNATURE_TOLERANCE_SECS {
+        return false;
+    }
+
+    let expected = sign_gateway_request(secret, timestamp.unwrap(), project_id, entity_id, body);
+
+    constant_time_eq(signature.unwrap().as_bytes(), expected.as_bytes())
+}
+
+// Verifies the caller's access/refresh cookies with the auth service. Handlers get the
+// claims through the AuthenticatedUser extractor, which calls this at most once per request.
+pub async fn check_auth(state: &AppState, headers: &HeaderMap) -> Result<Claims, AppResponse> {
+    let module = headers.get("module").and_then(|module| module.to_str().ok());
+
+    if module.is_none() {
+        return Err(AppResponse::Unauthorized);
+    }
+
+    let cookie_jar = CookieJar::from_headers(headers);
     let access_token = cookie_jar.get("access").unwrap_or(&Cookie::new("access", "")).to_string();
     let refresh_token = cookie_jar
         .get("refresh")
         .unwrap_or(&Cookie::new("refresh", ""))
         .to_string();
 
+    let ttl = Duration::from_secs(state.config.auth_cache_ttl_secs);
+    let cache_key = auth_cache_key(module.unwrap(), &access_token, &refresh_token);
+
+    if !ttl.is_zero() {
+        let cached = state.auth_cache
+            .lock()
+            .unwrap()
+            .get(&cache_key)
+            .filter(|(cached_at, _)| cached_at.elapsed() < ttl)
+            .map(|(_, claims)| claims.clone());
+
+        record_auth_cache(cached.is_some());
+
+        if let Some(claims) = cached {
+            return Ok(claims);
+        }
+    }
+
     let mut map = HashMap::new();
 
     map.insert("access", access_token);
     map.insert("refresh", refresh_token);
 
-    let res = client
-        .post(format!("{}/verify", &auth_service_url))
+    let res = state.reqwest_client
+        .post(format!("{}/verify", &state.config.auth_service_url))
         .header(CONTENT_TYPE, "application/json")
-        .header("module", module)
+        .header("module", module.unwrap())
+        .forward_request_id()
         .json(&map)
-        .send().await
-        .unwrap();
+        .send().await;
 
-    if res.status() == StatusCode::OK {
-        let data = res.json::<VerifyJWTResponse>().await;
+    if res.is_err() {
+        tracing::error!("ERROR VERIFYING TOKEN - {}", res.err().unwrap());
+        return Err(AppResponse::Unauthorized);
+    }
 
-        if data.is_err() {
-            return Err((StatusCode::UNAUTHORIZED, "UNAUTHORIZED".to_string()));
-        }
+    let res = res.unwrap();
 
-        let data = data.unwrap();
+    if res.status() != StatusCode::OK {
+        return Err(AppResponse::Unauthorized);
+    }
 
-        return Ok(data);
+    let data = res.json::<VerifyJWTResponse>().await;
+
+    if data.is_err() {
+        return Err(AppResponse::Unauthorized);
     }
-    return Err((StatusCode::UNAUTHORIZED, "UNAUTHORIZED".to_string()));
+
+    let claims = data.unwrap().claims;
+
+    if claims.is_none() {
+        return Err(AppResponse::Unauthorized);
+    }
+
+    let claims = claims.unwrap();
+
+    if !ttl.is_zero() {
+        // Expired entries are dropped by run_auth_cache_sweep
+        state.auth_cache.lock().unwrap().insert(cache_key, (Instant::now(), claims.clone()));
+    }
+
+    Ok(claims)
+}
+
+// The tokens themselves are never kept in memory longer than the request
+fn auth_cache_key(module: &str, access_token: &str, refresh_token: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(module.as_bytes());
+    hasher.update([0]);
+    hasher.update(access_token.as_bytes());
+    hasher.update([0]);
+    hasher.update(refresh_token.as_bytes());
+
+    format!("{:x}", hasher.finalize())
 }
 
+// Permissions of a user on any project, not only the one their token was issued for.
+// `permission` is e.g. "upload_images".
+pub async fn get_project_permissions(
+    state: &AppState,
+    user_id: &Uuid,
+    project_id: &Uuid,
+    permission: &str
+) -> Result<PermissionCheckResponse, AppResponse> {
+    let res = state.reqwest_client
+        .get(format!("{}/auth/permission/{}", &state.config.auth_service_url, permission))
+        .header(CONTENT_TYPE, "application/json")
+        .header("user-id", user_id.to_string())
+        .header("project-id", project_id.to_string())
+        .forward_request_id()
+        .send().await;
+
+    if res.is_err() {
+        return Err(AppResponse::Error(res.err().unwrap().to_string()));
+    }
+
+    let permissions = res.unwrap().json::<PermissionCheckResponse>().await;
+
+    if permissions.is_err() {
+        return Err(AppResponse::Error(permissions.err().unwrap().to_string()));
+    }
+
+    Ok(permissions.unwrap())
+}
+
+pub async fn check_project_owner(state: &AppState, claims: &Claims) -> Result<bool, AppResponse> {
+    let permissions = get_project_permissions(
+        state,
+        &claims.user_id,
+        &claims.project_id,
+        "update_images"
+    ).await?;
+
+    Ok(permissions.is_project_owner)
+}
+
+// The ids out of `ids` the user has no access to: assets of other projects, and for non-owners
+// assets they neither own nor hold an entity permission on. Same rules as the permission
+// middleware, for handlers that act on many assets at once.
+pub async fn denied_asset_ids(
+    client: &Object,
+    ids: &Vec<Uuid>,
+    project_id: &Uuid,
+    user_id: &Uuid,
+    permissions: &PermissionCheckResponse
+) -> Result<Vec<Uuid>, AppResponse> {
+    let rows = client.query(
+        "SELECT images.id FROM images
+         WHERE images.id = ANY($1)
+            AND (images.project_id <> $2
+                OR (NOT $3 AND images.owner_id <> $4
+                    AND NOT EXISTS (
+                        SELECT 1 FROM entity_permissions
+                        WHERE entity_permissions.related_id = images.id
+                            AND (entity_permissions.role_id = $5
+                                OR (entity_permissions.user_id = $4 AND entity_permissions.permission_id = $6))
+                    )));",
+        &[
+            &ids,
+            &project_id,
+            &permissions.is_project_owner,
+            &user_id,
+            &permissions.role_id,
+            &permissions.permission_id,
+        ]
+    ).await;
+
+    if rows.is_err() {
+        return Err(AppResponse::Error(rows.err().unwrap().to_string()));
+    }
+
+    Ok(
+        rows
+            .unwrap()
+            .iter()
+            .map(|row| row.get("id"))
+            .collect()
+    )
+}
+
+// For routes that take the asset ids in the body, where the permission middleware can't see them
+pub async fn check_asset_permissions(
+    state: &AppState,
+    client: &Object,
+    claims: &Claims,
+    permission: RequiredPermission,
+    ids: &Vec<Uuid>
+) -> Result<(), AppResponse> {
+    let permissions = get_project_permissions(
+        state,
+        &claims.user_id,
+        &claims.project_id,
+        permission.name()
+    ).await?;
+
+    let denied = denied_asset_ids(
+        client,
+        ids,
+        &claims.project_id,
+        &claims.user_id,
+        &permissions
+    ).await?;
+
+    if !denied.is_empty() {
+        return Err(AppResponse::Auth);
+    }
+
+    Ok(())
+}
+
+// Project level permissions like uploading, which no single asset carries. The auth service
+// only returns a permission id when the user holds the permission on the project.
+pub async fn check_project_permission(
+    state: &AppState,
+    user_id: &Uuid,
+    project_id: &Uuid,
+    permission: RequiredPermission
+) -> Result<bool, AppResponse> {
+    let permissions = get_project_permissions(state, user_id, project_id, permission.name()).await?;
+
+    Ok(permissions.is_project_owner || permissions.permission_id.is_some())
+}
+
+pub struct GeneratedApiKey {
+    // Only shown once, when the key is created or rotated
+    pub key: String,
+    pub prefix: String,
+    pub hash: String,
+}
+
+// ark_ followed by two v4 UUIDs. The first API_KEY_PREFIX_LEN characters are stored as they
+// are to find the key and tell keys apart in lists, the key itself only as a hash.
+pub fn generate_api_key() -> GeneratedApiKey {
+    let key = format!("ark_{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+
+    GeneratedApiKey {
+        prefix: key[..API_KEY_PREFIX_LEN].to_owned(),
+        hash: hash_api_key(&key),
+        key,
+    }
+}
+
+pub fn hash_api_key(key: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(key.as_bytes());
+
+    format!("{:x}", hasher.finalize())
+}
+
+// Keys from before hashed keys were stored as they are on the project. The first time one is
+// used it's moved into project_api_keys with every scope, so existing integrations keep working
+// and the key can be rotated or revoked like any other. Keys shorter than API_KEY_PREFIX_LEN
+// are stored with the whole key as the prefix.
+async fn migrate_legacy_api_key(
+    client: &Object,
+    api_key: &str,
+    prefix: &str
+) -> Result<Option<Row>, AppResponse> {
+    let scopes = vec![ApiKeyScope::Read, ApiKeyScope::Upload, ApiKeyScope::Manage];
+
+    // A single statement, the key can't end up in both places or be migrated twice
+    let res = client.query_opt(
+        "WITH legacy AS (
+            UPDATE projects SET api_key = NULL WHERE api_key = $1 RETURNING id, owner_id
+         ), migrated AS (
+            INSERT INTO project_api_keys (project_id, name, prefix, key_hash, scopes)
+            SELECT legacy.id, 'Legacy key', $2, $3, $4 FROM legacy
+            RETURNING id, key_hash, scopes, project_id
+         )
+         SELECT migrated.id, migrated.key_hash, migrated.scopes, migrated.project_id,
+            legacy.owner_id
+         FROM migrated
+         JOIN legacy ON legacy.id = migrated.project_id;",
+        &[&api_key, &prefix, &hash_api_key(api_key), &scopes]
+    ).await;
+
+    if res.is_err() {
+        return Err(AppResponse::Error(res.err().unwrap().to_string()));
+    }
+
+    let row = res.unwrap();
+
+    if row.is_some() {
+        tracing::warn!(
+            "MIGRATED LEGACY API KEY FOR PROJECT {}",
+            row.as_ref().unwrap().get::<_, Uuid>("project_id")
+        );
+    }
+
+    return Ok(row);
+}
+
+// Revoked, expired and unknown keys as well as keys without the scope are all rejected the
+// same way, so callers can't tell them apart.
+pub async fn check_api_key(
+    headers: &HeaderMap,
+    state: &AppState,
+    scope: ApiKeyScope
+) -> Result<ApiKeyProject, AppResponse> {
+    let api_key = headers.get("x-api-key").and_then(|api_key| api_key.to_str().ok());
+
+    if api_key.is_none() || api_key.unwrap().is_empty() {
+        return Err(AppResponse::Unauthorized);
+    }
+
+    let api_key = api_key.unwrap();
+    let prefix = &api_key[..api_key.len().min(API_KEY_PREFIX_LEN)];
+    let client = get_client(&state.pool).await?;
+
+    let candidates = client.query(
+        "SELECT project_api_keys.id, project_api_keys.key_hash, project_api_keys.scopes, projects.id AS project_id,
+            projects.owner_id
+         FROM project_api_keys
+         JOIN projects ON projects.id = project_api_keys.project_id
+         WHERE project_api_keys.prefix = $1 AND project_api_keys.revoked_at IS NULL
+            AND (project_api_keys.expires_at IS NULL OR project_api_keys.expires_at > NOW());",
+        &[&prefix]
+    ).await;
+
+    if candidates.is_err() {
+        return Err(AppResponse::Error(candidates.err().unwrap().to_string()));
+    }
+
+    let hash = hash_api_key(api_key);
+
+    let key = candidates
+        .unwrap()
+        .into_iter()
+        .find(|row| constant_time_eq(hash.as_bytes(), row.get::<_, &str>("key_hash").as_bytes()));
+
+    let key = match key {
+        Some(key) => Some(key),
+        None => migrate_legacy_api_key(&client, api_key, prefix).await?,
+    };
+
+    if key.is_none() {
+        return Err(AppResponse::Unauthorized);
+    }
+
+    let key = key.unwrap();
+    let scopes: Vec<ApiKeyScope> = key.get("scopes");
+
+    if !scopes.contains(&scope) {
+        return Err(AppResponse::Unauthorized);
+    }
+
+    let key_id: Uuid = key.get("id");
+    let res = client.execute(
+        "UPDATE project_api_keys SET last_used_at = NOW() WHERE id = $1;",
+        &[&key_id]
+    ).await;
+
+    if res.is_err() {
+        tracing::error!("ERROR RECORDING API KEY USE - {}", res.err().unwrap());
+    }
+
+    Ok(ApiKeyProject { project_id: key.get("project_id"), owner_id: key.get("owner_id"), key_id })
+}
+
+// `permissions` is the JSON encoded list sent with an update
+pub fn parse_permissions(permissions: &str) -> Result<Vec<PermissionUpdateType>, AppResponse> {
+    let parsed = serde_json::from_str::<Vec<PermissionUpdateType>>(permissions);
+
+    if parsed.is_err() {
+        return Err(AppResponse::Error(format!("INVALID PERMISSIONS - {}", parsed.err().unwrap())));
+    }
+
+    Ok(parsed.unwrap())
+}
+
+// Replaces every grant on `related_ids` with the ones in `permissions`, in a single transaction.
+// Entries with a role are role grants, entries with a user and a permission are user grants,
+// anything else is skipped.
 pub async fn insert_permissions(
-    permissions: Option<String>,
-    state: &AppState
+    client: &mut Object,
+    related_ids: &[Uuid],
+    permissions: &[PermissionUpdateType]
 ) -> Result<(), AppResponse> {
-    if permissions.is_some() {
-        let permissions = permissions.unwrap();
-
-        let permissions: Vec<PermissionUpdateType> = serde_json::from_str(&permissions).unwrap();
-
-        for perm in permissions {
-            if perm.role_id.is_some() && perm.permission_id.is_none() && perm.user_id.is_none() {
-                let client = get_client(&state.pool).await;
-                if client.is_err() {
-                    return Err(client.err().unwrap());
-                }
-                let mut client = client.unwrap();
-
-                let transaction = client.transaction().await.unwrap();
-
-                let del_res = transaction.execute(
-                    "DELETE FROM entity_permissions WHERE id = $1",
-                    &[&perm.related_id]
-                ).await;
-
-                if del_res.is_ok() {
-                    let insert_res = transaction.execute(
-                        "INSERT INTO entity_permissions (related_id, role_id) VALUES ($1, $2) ON CONFLICT (related_id, role_id) DO UPDATE SET role_id = $2;",
-                        &[&perm.related_id, &perm.role_id.unwrap()]
-                    ).await;
-
-                    if insert_res.is_ok() {
-                        let transaction_result = transaction.commit().await;
-
-                        if transaction_result.is_err() {
-                            return Err(
-                                AppResponse::Error(transaction_result.err().unwrap().to_string())
-                            );
-                        }
-                    } else {
-                        return Err(AppResponse::Error(insert_res.err().unwrap().to_string()));
-                    }
-                } else {
-                    return Err(AppResponse::Error(del_res.err().unwrap().to_string()));
-                }
-            } else if perm.permission_id.is_some() && perm.user_id.is_some() {
-                let client = get_client(&state.pool).await;
-                if client.is_err() {
-                    return Err(client.err().unwrap());
-                }
-                let mut client = client.unwrap();
-
-                let transaction = client.transaction().await.unwrap();
-
-                let del_res = transaction.execute(
-                    "DELETE FROM entity_permissions WHERE id = $1",
-                    &[&perm.related_id]
-                ).await;
-
-                if del_res.is_ok() {
-                    let insert_res = transaction.execute(
-                        "INSERT INTO entity_permissions (related_id, permission_id, user_id) VALUES ($1, $2, $3) ON CONFLICT (user_id, related_id, permission_id) DO NOTHING;",
-                        &[&perm.related_id, &perm.permission_id.unwrap(), &perm.user_id.unwrap()]
-                    ).await;
-
-                    if insert_res.is_ok() {
-                        let transaction_result = transaction.commit().await;
-
-                        if transaction_result.is_err() {
-                            return Err(
-                                AppResponse::Error(transaction_result.err().unwrap().to_string())
-                            );
-                        }
-                    } else {
-                        return Err(AppResponse::Error(insert_res.err().unwrap().to_string()));
-                    }
-                } else {
-                    return Err(AppResponse::Error(del_res.err().unwrap().to_string()));
-                }
+    let mut role_related_ids: Vec<Uuid> = vec![];
+    let mut role_ids: Vec<Uuid> = vec![];
+    let mut user_related_ids: Vec<Uuid> = vec![];
+    let mut user_ids: Vec<Uuid> = vec![];
+    let mut permission_ids: Vec<Uuid> = vec![];
+
+    for perm in permissions.iter().filter(|perm| related_ids.contains(&perm.related_id)) {
+        match (perm.role_id, perm.user_id, perm.permission_id) {
+            (Some(role_id), None, None) => {
+                role_related_ids.push(perm.related_id);
+                role_ids.push(role_id);
             }
+            (_, Some(user_id), Some(permission_id)) => {
+                user_related_ids.push(perm.related_id);
+                user_ids.push(user_id);
+                permission_ids.push(permission_id);
+            }
+            _ => {}
+        }
+    }
+
+    let transaction = client.transaction().await;
+
+    if transaction.is_err() {
+        return Err(AppResponse::Error(transaction.err().unwrap().to_string()));
+    }
+
+    let transaction = transaction.unwrap();
+
+    let res = transaction.execute(
+        "DELETE FROM entity_permissions WHERE related_id = ANY($1);",
+        &[&related_ids]
+    ).await;
+
+    if res.is_err() {
+        return Err(AppResponse::Error(res.err().unwrap().to_string()));
+    }
+
+    if !role_ids.is_empty() {
+        let res = transaction.execute(
+            "INSERT INTO entity_permissions (related_id, role_id)
+             SELECT * FROM UNNEST($1::UUID[], $2::UUID[])
+             ON CONFLICT (related_id, role_id) DO NOTHING;",
+            &[&role_related_ids, &role_ids]
+        ).await;
+
+        if res.is_err() {
+            return Err(AppResponse::Error(res.err().unwrap().to_string()));
+        }
+    }
+
+    if !user_ids.is_empty() {
+        let res = transaction.execute(
+            "INSERT INTO entity_permissions (related_id, user_id, permission_id)
+             SELECT * FROM UNNEST($1::UUID[], $2::UUID[], $3::UUID[])
+             ON CONFLICT (user_id, related_id, permission_id) DO NOTHING;",
+            &[&user_related_ids, &user_ids, &permission_ids]
+        ).await;
+
+        if res.is_err() {
+            return Err(AppResponse::Error(res.err().unwrap().to_string()));
         }
     }
-    return Ok(());
+
+    let res = transaction.commit().await;
+
+    if res.is_err() {
+        return Err(AppResponse::Error(res.err().unwrap().to_string()));
+    }
+
+    Ok(())
 }
@@ -0,0 +1,80 @@
+use image::{ imageops::FilterType, DynamicImage };
+use tokio::sync::Semaphore;
+use uuid::Uuid;
+
+use crate::{
+    enums::AppResponse,
+    state::models::AppState,
+    utils::image_utils::{ encode_webp, run_image_task, EncodeOptions },
+};
+
+// Every avatar is stored in all of these, users.image points at the canonical one
+pub const AVATAR_SIZES: [u32; 3] = [64, 128, 256];
+pub const AVATAR_CANONICAL_SIZE: u32 = 256;
+
+// The variants of an avatar only differ in the size suffix, so any of their URLs leads to the rest
+pub fn avatar_key(user_id: &Uuid, avatar_id: &Uuid, size: u32) -> String {
+    format!("assets/avatars/{}-{}-{}.webp", user_id, avatar_id, size)
+}
+
+// Keys of every stored variant of the avatar behind `url`. Avatars from before the resizing
+// were a single object.
+pub fn avatar_keys(url: &str) -> Vec<String> {
+    let file = url.split('/').next_back().unwrap_or_default();
+    let stem = file.strip_suffix(".webp").unwrap_or(file);
+
+    let is_variant = stem
+        .rsplit_once('-')
+        .and_then(|(_, size)| size.parse::<u32>().ok())
+        .is_some_and(|size| AVATAR_SIZES.contains(&size));
+
+    if !is_variant {
+        return vec![format!("assets/avatars/{}", file)];
+    }
+
+    let (base, _) = stem.rsplit_once('-').unwrap();
+
+    AVATAR_SIZES.iter()
+        .map(|size| format!("assets/avatars/{}-{}.webp", base, size))
+        .collect()
+}
+
+// Crops to a centered square and scales to every avatar size, small images are scaled up so
+// each key always holds the size it names
+pub async fn resize_avatar(
+    permits: &Semaphore,
+    img: DynamicImage
+) -> Result<Vec<(u32, Vec<u8>)>, AppResponse> {
+    let variants = run_image_task(permits, "avatar", move || {
+        AVATAR_SIZES.iter()
+            .map(|size| {
+                let resized = img.resize_to_fill(*size, *size, FilterType::Lanczos3);
+
+                (*size, encode_webp(resized, &EncodeOptions::default()))
+            })
+            .collect()
+    }).await;
+
+    if variants.is_err() {
+        return Err(AppResponse::Error(variants.err().unwrap()));
+    }
+
+    Ok(variants.unwrap())
+}
+
+// Returns the keys that could not be deleted
+pub async fn delete_avatar(state: &AppState, url: &str) -> Vec<String> {
+    let target = state.storage.default_target();
+    let mut failed: Vec<String> = vec![];
+
+    for key in avatar_keys(url) {
+        let del_res = target.delete(&key).await;
+
+        if del_res.is_err() {
+            tracing::error!("ERROR DELETING AVATAR {} - {}", key, del_res.err().unwrap());
+            failed.push(key);
+        }
+    }
+
+    failed
+}
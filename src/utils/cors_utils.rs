@@ -0,0 +1,132 @@
+use std::str::FromStr;
+
+use tower_http::cors::AllowOrigin;
+use url::Url;
+
+#[derive(Debug, Clone)]
+pub enum OriginPattern {
+    // Serialized origin, e.g. https://editor.example.com
+    Exact(String),
+    // https://*.example.com, subdomains at any depth but not example.com itself
+    Subdomains {
+        scheme: String,
+        suffix: String,
+        port: Option<u16>,
+    },
+}
+
+// Scheme, host and port of an origin, the port is left out when it's the scheme's default
+fn origin_parts(value: &str) -> Result<(String, String, Option<u16>), String> {
+    let parsed = Url::parse(value).map_err(|err| format!("{} - {}", value, err))?;
+
+    if !matches!(parsed.scheme(), "http" | "https") {
+        return Err(format!("{} - EXPECTED AN HTTP(S) ORIGIN", value));
+    }
+
+    // Origins are compared as a whole, a path would never match anything
+    if parsed.path() != "/" || parsed.query().is_some() || parsed.fragment().is_some() {
+        return Err(format!("{} - ORIGINS HAVE NO PATH", value));
+    }
+
+    match parsed.host_str() {
+        Some(host) => Ok((parsed.scheme().to_owned(), host.to_owned(), parsed.port())),
+        None => Err(format!("{} - ORIGIN HAS NO HOST", value)),
+    }
+}
+
+fn serialize_origin(scheme: &str, host: &str, port: Option<u16>) -> String {
+    match port {
+        Some(port) => format!("{}://{}:{}", scheme, host, port),
+        None => format!("{}://{}", scheme, host),
+    }
+}
+
+impl FromStr for OriginPattern {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value.trim().trim_end_matches('/');
+
+        match value.split_once("://*.") {
+            Some((scheme, rest)) => {
+                let (scheme, host, port) = origin_parts(&format!("{}://{}", scheme, rest))?;
+
+                Ok(OriginPattern::Subdomains { scheme, suffix: format!(".{}", host), port })
+            }
+            None => {
+                let (scheme, host, port) = origin_parts(value)?;
+
+                Ok(OriginPattern::Exact(serialize_origin(&scheme, &host, port)))
+            }
+        }
+    }
+}
+
+impl OriginPattern {
+    pub fn matches(&self, origin: &str) -> bool {
+        let parts = origin_parts(origin);
+
+        if parts.is_err() {
+            return false;
+        }
+
+        let (origin_scheme, host, origin_port) = parts.unwrap();
+
+        match self {
+            OriginPattern::Exact(exact) =>
+                serialize_origin(&origin_scheme, &host, origin_port) == *exact,
+            OriginPattern::Subdomains { scheme, suffix, port } =>
+                origin_scheme == *scheme &&
+                    origin_port == *port &&
+                    host.len() > suffix.len() &&
+                    host.ends_with(suffix.as_str()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum AllowedOrigins {
+    Any,
+    List(Vec<OriginPattern>),
+}
+
+impl FromStr for AllowedOrigins {
+    type Err = String;
+
+    // "*" or a comma separated list of origins and wildcard patterns
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if value.trim() == "*" {
+            return Ok(AllowedOrigins::Any);
+        }
+
+        let patterns = value
+            .split(',')
+            .filter(|pattern| !pattern.trim().is_empty())
+            .map(OriginPattern::from_str)
+            .collect::<Result<Vec<OriginPattern>, String>>()?;
+
+        if patterns.is_empty() {
+            return Err("NO ORIGINS".to_owned());
+        }
+
+        Ok(AllowedOrigins::List(patterns))
+    }
+}
+
+impl AllowedOrigins {
+    // The matching origin is echoed back rather than "*", so this also works with credentials
+    pub fn allow_origin(&self) -> AllowOrigin {
+        match self {
+            AllowedOrigins::Any => AllowOrigin::any(),
+            AllowedOrigins::List(patterns) => {
+                let patterns = patterns.clone();
+
+                AllowOrigin::predicate(move |origin, _| {
+                    origin
+                        .to_str()
+                        .is_ok_and(|origin| patterns.iter().any(|pattern| pattern.matches(origin)))
+                })
+            }
+        }
+    }
+}
@@ -1,6 +1,14 @@
 use deadpool_postgres::{ Object, Pool };
+use tokio_postgres::types::ToSql;
+use uuid::Uuid;
+
+use crate::{
+    enums::{ AppResponse, DbAccess },
+    state::models::{ AppState, ProjectUsage },
+    utils::image_utils::EncodeOptions,
+    DEFAULT_STORAGE_QUOTA,
+};
 
-use crate::enums::AppResponse;
 pub async fn get_client(pool: &Pool) -> Result<Object, AppResponse> {
     let client = pool.get().await;
 
@@ -10,3 +18,174 @@ pub async fn get_client(pool: &Pool) -> Result<Object, AppResponse> {
 
     Ok(client.unwrap())
 }
+
+// The read replica for reads when one is configured, the primary otherwise
+pub fn db_pool(state: &AppState, access: DbAccess) -> &Pool {
+    match (access, &state.read_pool) {
+        (DbAccess::Read, Some(read_pool)) => read_pool,
+        _ => &state.pool,
+    }
+}
+
+// SET clause of an update that only touches the fields that were sent
+#[derive(Default)]
+pub struct SetClause<'a> {
+    columns: Vec<&'static str>,
+    params: Vec<&'a (dyn ToSql + Sync)>,
+    // Set to NOW() whenever anything else is
+    touched: Vec<&'static str>,
+}
+
+impl<'a> SetClause<'a> {
+    // Left out when `value` is None
+    pub fn set<T: ToSql + Sync>(mut self, column: &'static str, value: &'a Option<T>) -> Self {
+        if let Some(value) = value {
+            self.columns.push(column);
+            self.params.push(value);
+        }
+
+        self
+    }
+
+    pub fn touch(mut self, column: &'static str) -> Self {
+        self.touched.push(column);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.columns.is_empty()
+    }
+
+    // e.g. UPDATE images SET title = $1, owner_id = $2 WHERE id = $3, with `id` bound last
+    pub fn update_by_id(
+        &self,
+        table: &str,
+        id: &'a Uuid
+    ) -> (String, Vec<&'a (dyn ToSql + Sync)>) {
+        let assignments: Vec<String> = self.columns
+            .iter()
+            .enumerate()
+            .map(|(index, column)| format!("{} = ${}", column, index + 1))
+            .chain(self.touched.iter().map(|column| format!("{} = NOW()", column)))
+            .collect();
+
+        let mut params = self.params.clone();
+        params.push(id);
+
+        (
+            format!(
+                "UPDATE {} SET {} WHERE id = ${};",
+                table,
+                assignments.join(", "),
+                params.len()
+            ),
+            params,
+        )
+    }
+}
+
+pub async fn get_encode_options(
+    client: &Object,
+    project_id: &Uuid
+) -> Result<EncodeOptions, AppResponse> {
+    let row = client.query_opt(
+        "SELECT webp_quality, webp_lossless FROM projects WHERE id = $1;",
+        &[&project_id]
+    ).await;
+
+    if row.is_err() {
+        return Err(AppResponse::Error(row.err().unwrap().to_string()));
+    }
+
+    let options = match row.unwrap() {
+        Some(row) => {
+            let quality: Option<f32> = row.get("webp_quality");
+            let lossless: Option<bool> = row.get("webp_lossless");
+
+            EncodeOptions::default().with_overrides(quality, lossless)
+        }
+        None => EncodeOptions::default(),
+    };
+
+    Ok(options)
+}
+
+// Locked assets of the project, limited to `ids` when provided.
+pub async fn get_locked_ids(
+    client: &Object,
+    project_id: &Uuid,
+    ids: Option<&Vec<Uuid>>
+) -> Result<Vec<Uuid>, AppResponse> {
+    let rows = client.query(
+        "SELECT id FROM images
+         WHERE project_id = $1 AND locked = TRUE AND ($2::UUID[] IS NULL OR id = ANY($2));",
+        &[&project_id, &ids]
+    ).await;
+
+    if rows.is_err() {
+        return Err(AppResponse::Error(rows.err().unwrap().to_string()));
+    }
+
+    Ok(
+        rows
+            .unwrap()
+            .iter()
+            .map(|row| row.get("id"))
+            .collect()
+    )
+}
+
+pub fn locked_conflict(locked_ids: Vec<Uuid>) -> AppResponse {
+    AppResponse::Conflict(
+        "Some of the selected images are locked.".to_owned(),
+        serde_json::json!({ "locked_ids": locked_ids })
+    )
+}
+
+pub async fn record_bandwidth(pool: &Pool, project_id: &Uuid, bytes: i64) {
+    let client = get_client(pool).await;
+
+    if client.is_err() {
+        return;
+    }
+
+    let res = client
+        .unwrap()
+        .execute(
+            "INSERT INTO asset_bandwidth (project_id, day, bytes) VALUES ($1, CURRENT_DATE, $2)
+             ON CONFLICT (project_id, day) DO UPDATE SET bytes = asset_bandwidth.bytes + $2;",
+            &[&project_id, &bytes]
+        ).await;
+
+    if res.is_err() {
+        tracing::error!("ERROR RECORDING BANDWIDTH - {}", res.err().unwrap());
+    }
+}
+
+pub async fn get_project_usage(state: &AppState, project_id: &Uuid) -> Result<ProjectUsage, AppResponse> {
+    let client = get_client(&state.pool).await?;
+
+    let usage = client.query_one(
+        "SELECT
+            (SELECT COUNT(*) FROM images WHERE project_id = $1) AS upload_count,
+            (SELECT COUNT(*) FROM images
+                WHERE project_id = $1 AND created_at >= date_trunc('month', CURRENT_DATE)) AS monthly_upload_count,
+            (SELECT COALESCE(SUM(size_bytes), 0)::BIGINT FROM images WHERE project_id = $1) AS bytes_stored,
+            (SELECT storage_quota_bytes FROM projects WHERE id = $1) AS quota_bytes;",
+        &[&project_id]
+    ).await;
+
+    if usage.is_err() {
+        return Err(AppResponse::Error(usage.err().unwrap().to_string()));
+    }
+
+    let usage = usage.unwrap();
+    let quota_bytes: Option<i64> = usage.get("quota_bytes");
+
+    Ok(ProjectUsage {
+        upload_count: usage.get("upload_count"),
+        monthly_upload_count: usage.get("monthly_upload_count"),
+        bytes_stored: usage.get("bytes_stored"),
+        quota_bytes: quota_bytes.unwrap_or(DEFAULT_STORAGE_QUOTA),
+    })
+}
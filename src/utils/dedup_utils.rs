@@ -0,0 +1,230 @@
+use std::collections::{ HashMap, HashSet };
+
+use deadpool_postgres::{ GenericClient, Object };
+use sha2::{ Digest, Sha256 };
+use uuid::Uuid;
+
+use crate::{
+    enums::{ AppResponse, AssetKind, Feature, ImageType, OutputFormat },
+    state::models::AppState,
+    storage::{ backend::CopyOptions, StorageTarget },
+    utils::{
+        asset_utils::image_key,
+        trash_utils::live_visibility,
+        variant_utils::queue_variants,
+    },
+};
+
+// Rows created from a duplicate upload point at the row that stored the object through
+// `object_id`, so every key has to be built from COALESCE(object_id, id).
+pub const OBJECT_ID: &str = "COALESCE(object_id, id) AS object_id";
+
+// SHA-256 of the stored (encoded) bytes
+pub fn content_hash(data: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(data);
+
+    format!("{:x}", hasher.finalize())
+}
+
+fn find_root(parents: &mut [usize], mut index: usize) -> usize {
+    while parents[index] != index {
+        parents[index] = parents[parents[index]];
+        index = parents[index];
+    }
+
+    index
+}
+
+// Groups of hashes at most `max_distance` bits apart, linked transitively. Returns indices into
+// `hashes`, only for groups of two or more. Compares every pair, so it's run off the runtime.
+pub fn group_similar(hashes: &[i64], max_distance: u32) -> Vec<Vec<usize>> {
+    let mut parents: Vec<usize> = (0..hashes.len()).collect();
+
+    for a in 0..hashes.len() {
+        for b in a + 1..hashes.len() {
+            if (hashes[a] ^ hashes[b]).count_ones() <= max_distance {
+                let (root_a, root_b) = (find_root(&mut parents, a), find_root(&mut parents, b));
+                parents[root_b] = root_a;
+            }
+        }
+    }
+
+    let mut groups: HashMap<usize, Vec<usize>> = HashMap::new();
+
+    for index in 0..hashes.len() {
+        let root = find_root(&mut parents, index);
+        groups.entry(root).or_default().push(index);
+    }
+
+    groups
+        .into_values()
+        .filter(|group| group.len() > 1)
+        .collect()
+}
+
+// An object of the project with the same content that a new row can reuse. Only approved,
+// live images count, so the object is never less visible than the new upload would be.
+// Hashes are recorded either way, so turning the flag on covers earlier uploads too.
+pub async fn find_duplicate(
+    state: &AppState,
+    client: &Object,
+    project_id: &Uuid,
+    user_id: &Uuid,
+    image_type: &ImageType,
+    hash: &str
+) -> Result<Option<Uuid>, AppResponse> {
+    if !state.config.feature_flags.is_enabled(Feature::Dedupe, project_id, Some(user_id)) {
+        return Ok(None);
+    }
+
+    let row = client.query_opt(
+        &format!(
+            "SELECT {} FROM images
+             WHERE project_id = $1 AND type = $2 AND kind = $3 AND content_hash = $4
+                AND pending = FALSE AND deleted_at IS NULL
+             LIMIT 1;",
+            OBJECT_ID
+        ),
+        &[&project_id, &image_type, &AssetKind::Image, &hash]
+    ).await;
+
+    if row.is_err() {
+        return Err(AppResponse::Error(row.err().unwrap().to_string()));
+    }
+
+    Ok(row.unwrap().map(|row| row.get("object_id")))
+}
+
+// The object an image's content is stored in
+#[derive(Clone)]
+pub struct StoredObject {
+    pub id: Uuid,
+    pub mime_type: String,
+}
+
+impl StoredObject {
+    // Ids without a row (e.g. gateway entity images) are WebP objects stored under their own id
+    pub fn unresolved(id: &Uuid) -> Self {
+        StoredObject { id: *id, mime_type: OutputFormat::Webp.content_type().to_owned() }
+    }
+
+    pub fn key(&self, project_id: &Uuid, image_type: &ImageType) -> String {
+        image_key(project_id, image_type, &self.id, &self.mime_type)
+    }
+}
+
+// Maps asset ids to the object their content is stored in. Ids without a row are missing
+// from the map.
+pub async fn resolve_objects(
+    client: &Object,
+    ids: &Vec<Uuid>
+) -> Result<HashMap<Uuid, StoredObject>, AppResponse> {
+    let rows = client.query(
+        &format!("SELECT id, mime_type, {} FROM images WHERE id = ANY($1);", OBJECT_ID),
+        &[&ids]
+    ).await;
+
+    if rows.is_err() {
+        return Err(AppResponse::Error(rows.err().unwrap().to_string()));
+    }
+
+    Ok(
+        rows
+            .unwrap()
+            .iter()
+            .map(|row| (
+                row.get("id"),
+                StoredObject { id: row.get("object_id"), mime_type: row.get("mime_type") },
+            ))
+            .collect()
+    )
+}
+
+pub async fn resolve_object(client: &Object, id: &Uuid) -> Result<StoredObject, AppResponse> {
+    let mut objects = resolve_objects(client, &vec![*id]).await?;
+
+    Ok(objects.remove(id).unwrap_or(StoredObject::unresolved(id)))
+}
+
+// The objects out of `object_ids` that some row still points at. Called after rows are
+// deleted, in the same transaction, to decide which objects can go as well.
+pub async fn referenced_objects(
+    client: &impl GenericClient,
+    object_ids: &Vec<Uuid>
+) -> Result<HashSet<Uuid>, AppResponse> {
+    let rows = client.query(
+        &format!("SELECT DISTINCT {} FROM images WHERE id = ANY($1) OR object_id = ANY($1);", OBJECT_ID),
+        &[&object_ids]
+    ).await;
+
+    if rows.is_err() {
+        return Err(AppResponse::Error(rows.err().unwrap().to_string()));
+    }
+
+    Ok(
+        rows
+            .unwrap()
+            .iter()
+            .map(|row| row.get("object_id"))
+            .collect()
+    )
+}
+
+// Before an asset that other rows point at gets a new file, its current object is copied to
+// one of those rows and the rest are repointed there, so they keep the old content.
+pub async fn hand_over_object(
+    state: &AppState,
+    target: &StorageTarget,
+    client: &Object,
+    project_id: &Uuid,
+    image_type: &ImageType,
+    id: &Uuid,
+    mime_type: &str
+) -> Result<(), AppResponse> {
+    let heir = client.query_opt(
+        "SELECT id, pending FROM images WHERE object_id = $1 ORDER BY created_at, id LIMIT 1;",
+        &[&id]
+    ).await;
+
+    if heir.is_err() {
+        return Err(AppResponse::Error(heir.err().unwrap().to_string()));
+    }
+
+    let heir = heir.unwrap();
+
+    if heir.is_none() {
+        return Ok(());
+    }
+
+    let heir = heir.unwrap();
+    let heir_id: Uuid = heir.get("id");
+    let pending: bool = heir.get("pending");
+
+    let visibility = live_visibility(state, project_id, pending).await?;
+
+    let copy = target.copy_from(
+        target,
+        &image_key(project_id, image_type, id, mime_type),
+        &image_key(project_id, image_type, &heir_id, mime_type),
+        &CopyOptions { visibility, content_type: None, cache_control: None }
+    ).await;
+
+    if copy.is_err() {
+        return Err(AppResponse::Error(copy.err().unwrap()));
+    }
+
+    let res = client.execute(
+        "UPDATE images SET object_id = CASE WHEN id = $2 THEN NULL ELSE $2 END WHERE object_id = $1;",
+        &[&id, &heir_id]
+    ).await;
+
+    if res.is_err() {
+        return Err(AppResponse::Error(res.err().unwrap().to_string()));
+    }
+
+    // The heir's copy starts out without variants of its own
+    queue_variants(state, *project_id, *image_type, heir_id, mime_type.to_owned());
+
+    Ok(())
+}
@@ -0,0 +1,54 @@
+use image::{ imageops, DynamicImage, GenericImageView, Rgba, RgbaImage };
+use serde::Deserialize;
+
+const DIFF_GAP: u32 = 8;
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DiffMode {
+    #[default]
+    SideBySide,
+    Heatmap,
+}
+
+pub fn side_by_side(from: &DynamicImage, to: &DynamicImage) -> RgbaImage {
+    let width = from.width() + DIFF_GAP + to.width();
+    let height = from.height().max(to.height());
+
+    let mut sheet = RgbaImage::from_pixel(width, height, Rgba([0, 0, 0, 0]));
+
+    imageops::overlay(&mut sheet, &from.to_rgba8(), 0, 0);
+    imageops::overlay(&mut sheet, &to.to_rgba8(), (from.width() + DIFF_GAP) as i64, 0);
+
+    sheet
+}
+
+// Dims the newer version to grayscale and paints changed pixels red, brighter the
+// larger the change. Versions with different dimensions are compared at the newer size.
+pub fn diff_heatmap(from: &DynamicImage, to: &DynamicImage) -> RgbaImage {
+    let (width, height) = to.dimensions();
+
+    let from = match from.dimensions() == (width, height) {
+        true => from.to_rgba8(),
+        false => from.resize_exact(width, height, imageops::FilterType::Triangle).to_rgba8(),
+    };
+    let to = to.to_rgba8();
+
+    RgbaImage::from_fn(width, height, |x, y| {
+        let a = from.get_pixel(x, y);
+        let b = to.get_pixel(x, y);
+
+        let delta = (0..4)
+            .map(|channel| (a[channel] as i32 - b[channel] as i32).unsigned_abs())
+            .max()
+            .unwrap_or(0) as u8;
+
+        let luma = ((b[0] as u32 * 299 + b[1] as u32 * 587 + b[2] as u32 * 114) / 1000 / 3) as u8;
+
+        if delta == 0 {
+            Rgba([luma, luma, luma, 255])
+        } else {
+            Rgba([luma.saturating_add(delta).max(128), luma / 2, luma / 2, 255])
+        }
+    })
+}
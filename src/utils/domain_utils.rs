@@ -0,0 +1,174 @@
+use deadpool_postgres::Object;
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::{
+    enums::{ AppResponse, ImageType },
+    state::models::AppState,
+    storage::StorageTarget,
+    utils::{ asset_utils::{ extension_for_mime, image_key }, thumbnail_utils::sign_thumbnail_url },
+};
+
+// Owners prove control of a domain with a TXT record at this name before it's served
+pub const VERIFICATION_RECORD_PREFIX: &str = "_arkive-verification";
+
+// Hostnames only - no scheme, port or path, at least one dot and no empty labels.
+pub fn is_valid_domain(domain: &str) -> bool {
+    if domain.len() > 253 || !domain.contains('.') {
+        return false;
+    }
+
+    domain
+        .split('.')
+        .all(|label| {
+            !label.is_empty() &&
+                label.len() <= 63 &&
+                !label.starts_with('-') &&
+                !label.ends_with('-') &&
+                label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+pub fn generate_verification_token() -> String {
+    format!("arkive-domain-verification={}", Uuid::new_v4().simple())
+}
+
+pub fn verification_record_name(domain: &str) -> String {
+    format!("{}.{}", VERIFICATION_RECORD_PREFIX, domain)
+}
+
+#[derive(Deserialize)]
+struct DnsAnswer {
+    data: String,
+}
+
+#[derive(Deserialize)]
+struct DnsResponse {
+    #[serde(rename = "Answer", default)]
+    answer: Vec<DnsAnswer>,
+}
+
+// Looked up over DNS-over-HTTPS (JSON API), TXT values come back quoted and long ones split
+// into several quoted strings
+pub async fn has_verification_record(
+    state: &AppState,
+    domain: &str,
+    token: &str
+) -> Result<bool, String> {
+    let res = state.reqwest_client
+        .get(&state.config.dns_resolver_url)
+        .query(&[("name", verification_record_name(domain).as_str()), ("type", "TXT")])
+        .header("accept", "application/dns-json")
+        .send().await;
+
+    if res.is_err() {
+        return Err(format!("DNS LOOKUP FAILED - {}", res.err().unwrap()));
+    }
+
+    let res = res.unwrap();
+
+    if !res.status().is_success() {
+        return Err(format!("DNS LOOKUP FAILED - {}", res.status()));
+    }
+
+    let answers = res.json::<DnsResponse>().await;
+
+    if answers.is_err() {
+        return Err(format!("DNS LOOKUP FAILED - {}", answers.err().unwrap()));
+    }
+
+    Ok(
+        answers
+            .unwrap()
+            .answer.iter()
+            .any(|answer| answer.data.replace("\" \"", "").trim_matches('"') == token)
+    )
+}
+
+// Only verified domains are handed out in asset URLs
+pub async fn get_custom_domain(client: &Object, project_id: &Uuid) -> Option<String> {
+    let row = client.query_opt(
+        "SELECT custom_domain FROM projects
+         WHERE id = $1 AND custom_domain_verified_at IS NOT NULL;",
+        &[&project_id]
+    ).await;
+
+    if row.is_err() {
+        tracing::error!("{}", row.err().unwrap());
+        return None;
+    }
+
+    row.unwrap().and_then(|row| row.get("custom_domain"))
+}
+
+pub async fn get_project_for_domain(
+    client: &Object,
+    domain: &str
+) -> Result<Option<Uuid>, AppResponse> {
+    // A domain is served for the project that verified it first, a claim by another project
+    // never takes it over
+    let row = client.query_opt(
+        "SELECT id FROM projects
+         WHERE custom_domain = $1 AND custom_domain_verified_at IS NOT NULL
+         ORDER BY custom_domain_verified_at
+         LIMIT 1;",
+        &[&domain.to_lowercase()]
+    ).await;
+
+    if row.is_err() {
+        return Err(AppResponse::Error(row.err().unwrap().to_string()));
+    }
+
+    Ok(row.unwrap().map(|row| row.get("id")))
+}
+
+// Projects with a custom domain get URLs pointing at the host-aware serving route,
+// everyone else keeps the direct storage URL.
+pub fn asset_url(
+    target: &StorageTarget,
+    domain: Option<&str>,
+    project_id: &Uuid,
+    image_type: &ImageType,
+    id: &Uuid,
+    mime_type: &str
+) -> String {
+    match domain {
+        Some(domain) =>
+            format!(
+                "https://{}/serve/{}/{}.{}",
+                domain,
+                image_type,
+                id,
+                extension_for_mime(mime_type)
+            ),
+        None => target.public_url(&image_key(project_id, image_type, id, mime_type)),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn thumbnail_url(
+    state: &AppState,
+    target: &StorageTarget,
+    domain: Option<&str>,
+    project_id: &Uuid,
+    image_type: &ImageType,
+    id: &Uuid,
+    mime_type: &str,
+    width: usize,
+    height: usize
+) -> String {
+    match domain {
+        Some(domain) =>
+            format!(
+                "https://{}/serve/{}/{}.{}?width={}&height={}",
+                domain,
+                image_type,
+                id,
+                extension_for_mime(mime_type),
+                width,
+                height
+            ),
+        None =>
+            sign_thumbnail_url(state, target, project_id, image_type, id, mime_type, width, height),
+    }
+}
@@ -1,11 +1,16 @@
 use axum::{
     async_trait,
-    extract::{ rejection::PathRejection, FromRequestParts },
+    extract::{ rejection::PathRejection, FromRef, FromRequestParts },
     http::request::Parts,
 };
 use serde::de::DeserializeOwned;
+use tracing::Span;
 
-use crate::enums::AppResponse;
+use crate::{
+    enums::AppResponse,
+    state::models::{ AppState, Claims },
+    utils::auth_utils::check_auth,
+};
 
 pub struct ExtractPath<T>(pub T);
 
@@ -41,3 +46,30 @@ impl<S, T> FromRequestParts<S>
         }
     }
 }
+
+// Claims of the caller. The auth service is asked once per request, later extractions (and
+// handlers behind tenant_middleware) reuse the claims cached in the request extensions.
+pub struct AuthenticatedUser(pub Claims);
+
+#[async_trait]
+impl<S> FromRequestParts<S>
+    for AuthenticatedUser
+    where
+        AppState: FromRef<S>,
+        S: Send + Sync
+{
+    type Rejection = AppResponse;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        if let Some(claims) = parts.extensions.get::<Claims>() {
+            return Ok(AuthenticatedUser(claims.clone()));
+        }
+
+        let claims = check_auth(&AppState::from_ref(state), &parts.headers).await?;
+
+        Span::current().record("user_id", tracing::field::display(claims.user_id));
+        parts.extensions.insert(claims.clone());
+
+        Ok(AuthenticatedUser(claims))
+    }
+}
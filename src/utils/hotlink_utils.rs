@@ -0,0 +1,108 @@
+use axum::{
+    extract::{ Request, State },
+    http::{ HeaderMap, HeaderValue },
+    middleware::Next,
+    response::{ IntoResponse, Response },
+};
+use reqwest::{ header::{ CACHE_CONTROL, CONTENT_TYPE, ORIGIN, REFERER }, StatusCode };
+use url::Url;
+use uuid::Uuid;
+
+use crate::{
+    enums::{ HotlinkAction, ImageType, PlaceholderKind },
+    state::models::AppState,
+    utils::{
+        db_utils::get_client,
+        extractors::ExtractPath,
+        placeholder_utils::generate_placeholder_svg,
+    },
+};
+
+fn request_host(headers: &HeaderMap) -> Option<String> {
+    let source = headers.get(ORIGIN).or(headers.get(REFERER))?;
+    let url = Url::parse(source.to_str().ok()?).ok()?;
+
+    url.host_str().map(|host| host.to_lowercase())
+}
+
+// Entries either match the host exactly or, when prefixed with "*.", any subdomain of it.
+pub fn is_host_allowed(host: &str, allowlist: &[String]) -> bool {
+    allowlist.iter().any(|allowed| {
+        let allowed = allowed.to_lowercase();
+
+        match allowed.strip_prefix("*.") {
+            Some(domain) => host == domain || host.ends_with(&format!(".{}", domain)),
+            None => host == allowed,
+        }
+    })
+}
+
+pub async fn hotlink_middleware(
+    State(state): State<AppState>,
+    ExtractPath((project_id, image_type, image_id)): ExtractPath<(Uuid, ImageType, Uuid)>,
+    request: Request,
+    next: Next
+) -> Response {
+    // Requests without an Origin/Referer (direct visits, server to server) are never hotlinks
+    let host = request_host(request.headers());
+
+    if host.is_none() {
+        return next.run(request).await;
+    }
+
+    let host = host.unwrap();
+
+    let client = get_client(&state.pool).await;
+
+    if client.is_err() {
+        return client.err().unwrap().into_response();
+    }
+    let client = client.unwrap();
+
+    let settings = client.query_opt(
+        "SELECT hotlink_allowlist, hotlink_action FROM projects WHERE id = $1;",
+        &[&project_id]
+    ).await;
+
+    if settings.is_err() {
+        tracing::error!("{}", settings.err().unwrap());
+        return next.run(request).await;
+    }
+
+    let settings = settings.unwrap();
+
+    if settings.is_none() {
+        return next.run(request).await;
+    }
+
+    let settings = settings.unwrap();
+    let allowlist: Option<Vec<String>> = settings.get("hotlink_allowlist");
+    let action: Option<HotlinkAction> = settings.get("hotlink_action");
+
+    let allowlist = allowlist.unwrap_or_default();
+
+    if allowlist.is_empty() || is_host_allowed(&host, &allowlist) {
+        return next.run(request).await;
+    }
+
+    tracing::warn!("HOTLINK BLOCKED - {} requested {} from {}", host, image_id, project_id);
+
+    match action.unwrap_or(HotlinkAction::Block) {
+        HotlinkAction::Block => (StatusCode::FORBIDDEN, "Forbidden").into_response(),
+        HotlinkAction::Placeholder => {
+            let kind = match image_type {
+                ImageType::Images => PlaceholderKind::Images,
+                ImageType::MapImages => PlaceholderKind::MapImages,
+            };
+
+            (
+                StatusCode::OK,
+                [
+                    (CONTENT_TYPE, HeaderValue::from_str("image/svg+xml").unwrap()),
+                    (CACHE_CONTROL, HeaderValue::from_str("no-store").unwrap()),
+                ],
+                generate_placeholder_svg(&kind, &image_id, Some("Arkive")),
+            ).into_response()
+        }
+    }
+}
@@ -1,7 +1,829 @@
-use image::DynamicImage;
+use std::{ collections::HashMap, io::{ BufRead, Cursor, Seek, SeekFrom }, time::Instant };
 
-pub fn encode_lossy_webp(img: DynamicImage) -> Vec<u8> {
+use base64::prelude::*;
+use image::{
+    codecs::{ avif::AvifEncoder, gif::GifDecoder, webp::WebPDecoder },
+    imageops::FilterType,
+    metadata::Orientation,
+    AnimationDecoder,
+    DynamicImage,
+    Frame,
+    ImageDecoder,
+    ImageFormat,
+    ImageReader,
+};
+use serde::{ Deserialize, Serialize };
+use tokio::sync::Semaphore;
+
+use crate::{
+    enums::OutputFormat,
+    utils::metrics_utils::{ record_encode_duration, record_image_queue_wait },
+};
+
+// Returned (as a prefix) when the header announces more pixels than uploads are allowed to have
+pub const IMAGE_TOO_LARGE: &str = "IMAGE EXCEEDS THE PIXEL LIMIT";
+
+// 1 (slowest, smallest) to 10 (fastest). AVIF encoding is far slower than WebP, so this
+// trades some compression for upload latency.
+const AVIF_SPEED: u8 = 6;
+// Every frame of an animation is held decoded in memory (4 bytes per pixel) until it's encoded
+const MAX_ANIMATION_PIXELS: u64 = 50_000_000;
+// Browsers play GIF frame delays of 10ms or less at 100ms, WebP players don't
+const MIN_FRAME_DELAY_MS: i32 = 20;
+const DEFAULT_FRAME_DELAY_MS: i32 = 100;
+// Perceptual hashes are taken from the lowest 8x8 frequencies of a 32x32 grayscale copy
+const PHASH_SIZE: usize = 32;
+const PHASH_FREQUENCIES: usize = 8;
+// Dominant colors are counted on a 64x64 copy, in buckets of 4 bits per channel
+const PALETTE_SIZE: u32 = 64;
+const PALETTE_BUCKET_SHIFT: u8 = 4;
+// Pixels more transparent than this don't count, so cutouts get the color of the subject
+const PALETTE_MIN_ALPHA: u8 = 128;
+// ThumbHashes are meant for tiny copies, larger ones only take longer to encode
+const THUMBHASH_SIZE: u32 = 100;
+
+#[derive(Clone, Copy)]
+pub struct EncodeOptions {
+    // 0-100, ignored for lossless encoding
+    pub quality: f32,
+    // Keeps pixel art and map grids crisp at the cost of larger files
+    pub lossless: bool,
+}
+
+impl Default for EncodeOptions {
+    fn default() -> Self {
+        EncodeOptions { quality: 100.0, lossless: false }
+    }
+}
+
+impl EncodeOptions {
+    pub fn with_overrides(self, quality: Option<f32>, lossless: Option<bool>) -> Self {
+        EncodeOptions {
+            quality: quality.unwrap_or(self.quality).clamp(0.0, 100.0),
+            lossless: lossless.unwrap_or(self.lossless),
+        }
+    }
+}
+
+// Read before encoding, so the frontend can size placeholders without fetching the image.
+#[derive(Serialize, Clone)]
+pub struct ImageMetadata {
+    pub width: i32,
+    pub height: i32,
+    // MIME type of the file as uploaded, the stored object is WebP or AVIF
+    pub original_format: Option<String>,
+    pub is_animated: bool,
+    // Of the first frame of an animation
+    #[serde(skip)]
+    pub perceptual_hash: i64,
+    // Hex color placeholders are filled with while the image loads
+    pub dominant_color: Option<String>,
+    pub thumbhash: String,
+}
+
+impl ImageMetadata {
+    pub fn read(img: &DecodedImage, head: &[u8]) -> Self {
+        let (width, height) = img.dimensions();
+
+        ImageMetadata {
+            width: width as i32,
+            height: height as i32,
+            original_format: image::guess_format(head)
+                .ok()
+                .map(|format| format.to_mime_type().to_owned()),
+            is_animated: img.is_animated(),
+            perceptual_hash: img.perceptual_hash(),
+            dominant_color: img.dominant_color(),
+            thumbhash: img.thumbhash(),
+        }
+    }
+}
+
+// Average of the most common bucket rather than of the whole image, which would blend a red
+// subject on a blue background into purple. None for fully transparent images.
+pub fn dominant_color(img: &DynamicImage) -> Option<String> {
+    let pixels = img.resize_exact(PALETTE_SIZE, PALETTE_SIZE, FilterType::Triangle).to_rgba8();
+    let mut buckets: HashMap<(u8, u8, u8), (u32, [u32; 3])> = HashMap::new();
+
+    for pixel in pixels.pixels() {
+        let [r, g, b, a] = pixel.0;
+
+        if a < PALETTE_MIN_ALPHA {
+            continue;
+        }
+
+        let bucket = buckets
+            .entry((r >> PALETTE_BUCKET_SHIFT, g >> PALETTE_BUCKET_SHIFT, b >> PALETTE_BUCKET_SHIFT))
+            .or_insert((0, [0; 3]));
+
+        bucket.0 += 1;
+        bucket.1[0] += r as u32;
+        bucket.1[1] += g as u32;
+        bucket.1[2] += b as u32;
+    }
+
+    let (count, sums) = buckets.into_values().max_by_key(|(count, _)| *count)?;
+
+    Some(format!("#{:02x}{:02x}{:02x}", sums[0] / count, sums[1] / count, sums[2] / count))
+}
+
+// DCT based hash that survives re-encoding, resizing and small edits. Copies of the same image
+// end up a few bits apart, unrelated images around half of the 64 bits.
+pub fn perceptual_hash(img: &DynamicImage) -> i64 {
+    let pixels = img
+        .resize_exact(PHASH_SIZE as u32, PHASH_SIZE as u32, FilterType::Triangle)
+        .to_luma32f();
+
+    let cosines: Vec<Vec<f32>> = (0..PHASH_FREQUENCIES)
+        .map(|frequency| {
+            (0..PHASH_SIZE)
+                .map(|position| {
+                    let angle =
+                        ((2 * position + 1) * frequency) as f32 * std::f32::consts::PI /
+                        ((2 * PHASH_SIZE) as f32);
+
+                    angle.cos()
+                })
+                .collect()
+        })
+        .collect();
+
+    let mut coefficients: Vec<f32> = Vec::with_capacity(PHASH_FREQUENCIES * PHASH_FREQUENCIES);
+
+    for v in 0..PHASH_FREQUENCIES {
+        for u in 0..PHASH_FREQUENCIES {
+            let mut sum = 0.0;
+
+            for (x, y, pixel) in pixels.enumerate_pixels() {
+                sum += pixel.0[0] * cosines[u][x as usize] * cosines[v][y as usize];
+            }
+
+            coefficients.push(sum);
+        }
+    }
+
+    // The first coefficient is the average brightness, it would skew the median
+    let mut sorted = coefficients[1..].to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let median = sorted[sorted.len() / 2];
+
+    coefficients
+        .iter()
+        .enumerate()
+        .fold(0u64, |hash, (bit, coefficient)| {
+            match *coefficient > median {
+                true => hash | (1 << bit),
+                false => hash,
+            }
+        }) as i64
+}
+
+// ThumbHash (https://evanw.github.io/thumbhash/) of a copy at most 100px on each side, base64
+// encoded. Clients decode it into a blurred preview with the image's aspect ratio and alpha.
+pub fn thumbhash(img: &DynamicImage) -> String {
+    let pixels = img.thumbnail(THUMBHASH_SIZE, THUMBHASH_SIZE).to_rgba8();
+    let (w, h) = (pixels.width() as usize, pixels.height() as usize);
+    let rgba = pixels.as_raw();
+
+    // Average color, transparent pixels are composited on top of it
+    let (mut avg_r, mut avg_g, mut avg_b, mut avg_a) = (0.0f32, 0.0f32, 0.0f32, 0.0f32);
+
+    for pixel in rgba.chunks_exact(4) {
+        let alpha = (pixel[3] as f32) / 255.0;
+
+        avg_r += (alpha / 255.0) * (pixel[0] as f32);
+        avg_g += (alpha / 255.0) * (pixel[1] as f32);
+        avg_b += (alpha / 255.0) * (pixel[2] as f32);
+        avg_a += alpha;
+    }
+
+    if avg_a > 0.0 {
+        avg_r /= avg_a;
+        avg_g /= avg_a;
+        avg_b /= avg_a;
+    }
+
+    let has_alpha = avg_a < ((w * h) as f32);
+    // Fewer luminance terms when the alpha channel needs room too
+    let l_limit = match has_alpha {
+        true => 5,
+        false => 7,
+    };
+    let lx = ((((l_limit * w) as f32) / (w.max(h) as f32)).round() as usize).max(1);
+    let ly = ((((l_limit * h) as f32) / (w.max(h) as f32)).round() as usize).max(1);
+
+    // Luminance, yellow-blue, red-green and alpha
+    let mut l: Vec<f32> = Vec::with_capacity(w * h);
+    let mut p: Vec<f32> = Vec::with_capacity(w * h);
+    let mut q: Vec<f32> = Vec::with_capacity(w * h);
+    let mut a: Vec<f32> = Vec::with_capacity(w * h);
+
+    for pixel in rgba.chunks_exact(4) {
+        let alpha = (pixel[3] as f32) / 255.0;
+        let r = avg_r * (1.0 - alpha) + (alpha / 255.0) * (pixel[0] as f32);
+        let g = avg_g * (1.0 - alpha) + (alpha / 255.0) * (pixel[1] as f32);
+        let b = avg_b * (1.0 - alpha) + (alpha / 255.0) * (pixel[2] as f32);
+
+        l.push((r + g + b) / 3.0);
+        p.push((r + g) / 2.0 - b);
+        q.push(r - g);
+        a.push(alpha);
+    }
+
+    // DC term and the AC terms normalized to 0-1, with the scale they were normalized by
+    let encode_channel = |channel: &[f32], nx: usize, ny: usize| -> (f32, Vec<f32>, f32) {
+        let mut dc = 0.0;
+        let mut ac: Vec<f32> = vec![];
+        let mut scale: f32 = 0.0;
+        let mut fx = vec![0.0f32; w];
+
+        for cy in 0..ny {
+            let step_y = (std::f32::consts::PI / (h as f32)) * (cy as f32);
+            let mut cx = 0;
+
+            while cx * ny < nx * (ny - cy) {
+                let step_x = (std::f32::consts::PI / (w as f32)) * (cx as f32);
+
+                for (x, f) in fx.iter_mut().enumerate() {
+                    *f = (step_x * ((x as f32) + 0.5)).cos();
+                }
+
+                let mut f = 0.0;
+
+                for y in 0..h {
+                    let fy = (step_y * ((y as f32) + 0.5)).cos();
+
+                    for (x, fx) in fx.iter().enumerate() {
+                        f += channel[x + y * w] * fx * fy;
+                    }
+                }
+
+                f /= (w * h) as f32;
+
+                if cx > 0 || cy > 0 {
+                    ac.push(f);
+                    scale = scale.max(f.abs());
+                } else {
+                    dc = f;
+                }
+
+                cx += 1;
+            }
+        }
+
+        if scale > 0.0 {
+            for f in ac.iter_mut() {
+                *f = 0.5 + (0.5 / scale) * *f;
+            }
+        }
+
+        (dc, ac, scale)
+    };
+
+    let (l_dc, l_ac, l_scale) = encode_channel(&l, lx.max(3), ly.max(3));
+    let (p_dc, p_ac, p_scale) = encode_channel(&p, 3, 3);
+    let (q_dc, q_ac, q_scale) = encode_channel(&q, 3, 3);
+    let (a_dc, a_ac, a_scale) = match has_alpha {
+        true => encode_channel(&a, 5, 5),
+        false => (1.0, vec![], 1.0),
+    };
+
+    let is_landscape = w > h;
+    let header24 =
+        ((63.0 * l_dc).round() as u32) |
+        (((31.5 + 31.5 * p_dc).round() as u32) << 6) |
+        (((31.5 + 31.5 * q_dc).round() as u32) << 12) |
+        (((31.0 * l_scale).round() as u32) << 18) |
+        ((has_alpha as u32) << 23);
+    let header16 =
+        ((if is_landscape { ly } else { lx }) as u16) |
+        (((63.0 * p_scale).round() as u16) << 3) |
+        (((63.0 * q_scale).round() as u16) << 9) |
+        ((is_landscape as u16) << 15);
+
+    let mut hash: Vec<u8> = vec![
+        (header24 & 255) as u8,
+        ((header24 >> 8) & 255) as u8,
+        (header24 >> 16) as u8,
+        (header16 & 255) as u8,
+        (header16 >> 8) as u8,
+    ];
+
+    if has_alpha {
+        hash.push(((15.0 * a_dc).round() as u8) | (((15.0 * a_scale).round() as u8) << 4));
+    }
+
+    // AC terms are packed as nibbles, low one first
+    let mut is_odd = false;
+
+    for f in l_ac.iter().chain(p_ac.iter()).chain(q_ac.iter()).chain(a_ac.iter()) {
+        let nibble = (15.0 * f).round() as u8;
+
+        match is_odd {
+            true => *hash.last_mut().unwrap() |= nibble << 4,
+            false => hash.push(nibble),
+        }
+
+        is_odd = !is_odd;
+    }
+
+    BASE64_STANDARD.encode(hash)
+}
+
+pub enum DecodedImage {
+    Still(DynamicImage),
+    // Full canvas frames, already composited by the decoder
+    Animated(Vec<Frame>),
+}
+
+impl DecodedImage {
+    pub fn dimensions(&self) -> (u32, u32) {
+        match self {
+            DecodedImage::Still(img) => (img.width(), img.height()),
+            DecodedImage::Animated(frames) => frames[0].buffer().dimensions(),
+        }
+    }
+
+    pub fn is_animated(&self) -> bool {
+        matches!(self, DecodedImage::Animated(_))
+    }
+
+    pub fn perceptual_hash(&self) -> i64 {
+        match self {
+            DecodedImage::Still(img) => perceptual_hash(img),
+            DecodedImage::Animated(frames) =>
+                perceptual_hash(&DynamicImage::ImageRgba8(frames[0].buffer().clone())),
+        }
+    }
+
+    pub fn dominant_color(&self) -> Option<String> {
+        match self {
+            DecodedImage::Still(img) => dominant_color(img),
+            DecodedImage::Animated(frames) =>
+                dominant_color(&DynamicImage::ImageRgba8(frames[0].buffer().clone())),
+        }
+    }
+
+    pub fn thumbhash(&self) -> String {
+        match self {
+            DecodedImage::Still(img) => thumbhash(img),
+            DecodedImage::Animated(frames) =>
+                thumbhash(&DynamicImage::ImageRgba8(frames[0].buffer().clone())),
+        }
+    }
+
+    // The AVIF encoder only handles single frames, so animations are always stored as WebP
+    pub fn storage_format(&self, requested: OutputFormat) -> OutputFormat {
+        match self {
+            DecodedImage::Still(_) => requested,
+            DecodedImage::Animated(_) => OutputFormat::Webp,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum FlipAxis {
+    Horizontal,
+    Vertical,
+}
+
+// Edits from the image editor, applied in order
+#[derive(Deserialize, Clone, Copy)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum ImageTransform {
+    Crop {
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    },
+    // Clockwise, 90, 180 or 270
+    Rotate {
+        degrees: u32,
+    },
+    Flip {
+        axis: FlipAxis,
+    },
+}
+
+impl ImageTransform {
+    fn apply(&self, img: DynamicImage) -> Result<DynamicImage, String> {
+        match self {
+            &ImageTransform::Crop { x, y, width, height } => {
+                let fits =
+                    width > 0 &&
+                    height > 0 &&
+                    (x as u64) + (width as u64) <= (img.width() as u64) &&
+                    (y as u64) + (height as u64) <= (img.height() as u64);
+
+                if !fits {
+                    return Err(
+                        format!(
+                            "CROP {}x{} AT {},{} IS OUTSIDE THE {}x{} IMAGE",
+                            width,
+                            height,
+                            x,
+                            y,
+                            img.width(),
+                            img.height()
+                        )
+                    );
+                }
+
+                Ok(img.crop_imm(x, y, width, height))
+            }
+            &ImageTransform::Rotate { degrees } =>
+                match degrees {
+                    90 => Ok(img.rotate90()),
+                    180 => Ok(img.rotate180()),
+                    270 => Ok(img.rotate270()),
+                    _ => Err(format!("UNSUPPORTED ROTATION - {}", degrees)),
+                }
+            &ImageTransform::Flip { axis: FlipAxis::Horizontal } => Ok(img.fliph()),
+            &ImageTransform::Flip { axis: FlipAxis::Vertical } => Ok(img.flipv()),
+        }
+    }
+}
+
+fn apply_transforms(img: DynamicImage, transforms: &[ImageTransform]) -> Result<DynamicImage, String> {
+    transforms.iter().try_fold(img, |img, transform| transform.apply(img))
+}
+
+// Animations get every frame transformed the same way and keep their timings
+pub fn transform_image(
+    img: DecodedImage,
+    transforms: &[ImageTransform]
+) -> Result<DecodedImage, String> {
+    match img {
+        DecodedImage::Still(img) => Ok(DecodedImage::Still(apply_transforms(img, transforms)?)),
+        DecodedImage::Animated(frames) => {
+            let frames = frames
+                .into_iter()
+                .map(|frame| {
+                    let delay = frame.delay();
+                    let img = apply_transforms(
+                        DynamicImage::ImageRgba8(frame.into_buffer()),
+                        transforms
+                    )?;
+
+                    Ok(Frame::from_parts(img.to_rgba8(), 0, 0, delay))
+                })
+                .collect::<Result<Vec<Frame>, String>>()?;
+
+            Ok(DecodedImage::Animated(frames))
+        }
+    }
+}
+
+fn decode_with_orientation(mut decoder: impl ImageDecoder) -> Result<DynamicImage, String> {
+    let orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
+    let mut img = DynamicImage::from_decoder(decoder).map_err(|err| err.to_string())?;
+
+    img.apply_orientation(orientation);
+
+    Ok(img)
+}
+
+// A few hundred bytes can claim a 500MP image, so the dimensions in the header are checked
+// before anything is decoded. Returns a reader that starts where the given one did.
+fn check_pixels<R: BufRead + Seek>(
+    reader: ImageReader<R>,
+    max_pixels: u64
+) -> Result<ImageReader<R>, String> {
+    let reader = reader.with_guessed_format().map_err(|err| err.to_string())?;
+    let format = reader.format();
+    let mut inner = reader.into_inner();
+    let start = inner.stream_position().map_err(|err| err.to_string())?;
+
+    let mut probe = ImageReader::new(&mut inner);
+
+    if let Some(format) = format {
+        probe.set_format(format);
+    }
+
+    let (width, height) = probe.into_dimensions().map_err(|err| err.to_string())?;
+
+    inner.seek(SeekFrom::Start(start)).map_err(|err| err.to_string())?;
+
+    if (width as u64) * (height as u64) > max_pixels {
+        return Err(format!("{} OF {} - {}x{}", IMAGE_TOO_LARGE, max_pixels, width, height));
+    }
+
+    let mut reader = ImageReader::new(inner);
+
+    if let Some(format) = format {
+        reader.set_format(format);
+    }
+
+    Ok(reader)
+}
+
+// Decodes an upload with its EXIF orientation applied, so photos taken on phones aren't stored
+// sideways. Formats without orientation metadata decode as-is.
+pub fn decode_oriented<R: BufRead + Seek>(
+    reader: ImageReader<R>,
+    max_pixels: u64
+) -> Result<DynamicImage, String> {
+    decode_checked(check_pixels(reader, max_pixels)?)
+}
+
+fn decode_checked<R: BufRead + Seek>(reader: ImageReader<R>) -> Result<DynamicImage, String> {
+    decode_with_orientation(reader.into_decoder().map_err(|err| err.to_string())?)
+}
+
+pub fn load_oriented(data: &[u8], max_pixels: u64) -> Result<DynamicImage, String> {
+    decode_oriented(ImageReader::new(Cursor::new(data)), max_pixels)
+}
+
+fn decode_frames<'a>(decoder: impl AnimationDecoder<'a>) -> Result<Vec<Frame>, String> {
+    let mut frames = vec![];
+    let mut pixels: u64 = 0;
+
+    for frame in decoder.into_frames() {
+        let frame = frame.map_err(|err| err.to_string())?;
+        let (width, height) = frame.buffer().dimensions();
+
+        pixels += (width as u64) * (height as u64);
+
+        if pixels > MAX_ANIMATION_PIXELS {
+            return Err(format!("ANIMATION EXCEEDS {} PIXELS", MAX_ANIMATION_PIXELS));
+        }
+
+        frames.push(frame);
+    }
+
+    if frames.is_empty() {
+        return Err("IMAGE HAS NO FRAMES".to_owned());
+    }
+
+    Ok(frames)
+}
+
+// Like `decode_oriented`, but keeps every frame of animated GIFs and WebPs instead of
+// flattening them to the first one. Single frame GIFs decode as stills.
+pub fn decode_upload<R: BufRead + Seek>(
+    reader: ImageReader<R>,
+    max_pixels: u64
+) -> Result<DecodedImage, String> {
+    let reader = check_pixels(reader, max_pixels)?;
+
+    match reader.format() {
+        Some(ImageFormat::Gif) => {
+            let decoder = GifDecoder::new(reader.into_inner()).map_err(|err| err.to_string())?;
+            let mut frames = decode_frames(decoder)?;
+
+            if frames.len() == 1 {
+                let frame = frames.remove(0);
+                return Ok(DecodedImage::Still(DynamicImage::ImageRgba8(frame.into_buffer())));
+            }
+
+            Ok(DecodedImage::Animated(frames))
+        }
+        Some(ImageFormat::WebP) => {
+            let decoder = WebPDecoder::new(reader.into_inner()).map_err(|err| err.to_string())?;
+
+            if decoder.has_animation() {
+                return Ok(DecodedImage::Animated(decode_frames(decoder)?));
+            }
+
+            Ok(DecodedImage::Still(decode_with_orientation(decoder)?))
+        }
+        _ => Ok(DecodedImage::Still(decode_checked(reader)?)),
+    }
+}
+
+pub fn load_upload(data: &[u8], max_pixels: u64) -> Result<DecodedImage, String> {
+    decode_upload(ImageReader::new(Cursor::new(data)), max_pixels)
+}
+
+// Animated WebPs set the animation flag of the VP8X chunk that follows the RIFF header, so
+// objects that are stored as uploaded can be checked without decoding them
+pub fn is_animated_webp(head: &[u8]) -> bool {
+    matches!(
+        head,
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', b'V', b'P', b'8', b'X', _, _, _, _, flags, ..]
+            if flags & 0x02 != 0
+    )
+}
+
+// The encoders below only ever receive pixel data, so nothing from the uploaded file
+// (EXIF, GPS, XMP, ICC profiles) makes it into the stored object.
+pub fn encode_webp(img: DynamicImage, options: &EncodeOptions) -> Vec<u8> {
+    let started = Instant::now();
     let img = img.to_rgba8();
     let (width, height) = img.dimensions();
-    webp::Encoder::new(&*img, webp::PixelLayout::Rgba, width, height).encode(100.0).to_vec()
+    let encoder = webp::Encoder::new(&img, webp::PixelLayout::Rgba, width, height);
+
+    let encoded = match options.lossless {
+        true => encoder.encode_lossless().to_vec(),
+        false => encoder.encode(options.quality).to_vec(),
+    };
+
+    record_encode_duration("webp", started);
+
+    encoded
+}
+
+fn frame_delay_ms(frame: &Frame) -> i32 {
+    let (numerator, denominator) = frame.delay().numer_denom_ms();
+    let delay = (numerator / denominator.max(1)) as i32;
+
+    match delay < MIN_FRAME_DELAY_MS {
+        true => DEFAULT_FRAME_DELAY_MS,
+        false => delay,
+    }
+}
+
+// Keeps the frames and their timings, the animation loops forever
+pub fn encode_animated_webp(frames: &[Frame], options: &EncodeOptions) -> Result<Vec<u8>, String> {
+    let started = Instant::now();
+    let (width, height) = frames[0].buffer().dimensions();
+
+    let mut config = webp::WebPConfig
+        ::new()
+        .map_err(|_| "COULD NOT CONFIGURE WEBP ENCODER".to_owned())?;
+    config.lossless = options.lossless as i32;
+    config.quality = options.quality;
+
+    let mut encoder = webp::AnimEncoder::new(width, height, &config);
+    let mut timestamp = 0;
+
+    for frame in frames {
+        encoder.add_frame(
+            webp::AnimFrame::from_rgba(frame.buffer().as_raw(), width, height, timestamp)
+        );
+        timestamp += frame_delay_ms(frame);
+    }
+
+    // The encoder closes the animation at timestamp 0, which would cut the last frame to 1ms.
+    // Repeating it at the end gives it its real duration, unchanged frames get merged.
+    let last = frames.last().unwrap();
+    encoder.add_frame(webp::AnimFrame::from_rgba(last.buffer().as_raw(), width, height, timestamp));
+
+    let encoded = encoder
+        .try_encode()
+        .map(|encoded| encoded.to_vec())
+        .map_err(|err| format!("{:?}", err))?;
+
+    record_encode_duration("animated_webp", started);
+
+    Ok(encoded)
+}
+
+// AVIF has no true lossless mode in the encoder, lossless maps to the highest quality.
+// Decoding AVIF needs image's avif-native feature (dav1d), which isn't enabled, so AVIF objects
+// can be served and thumbnailed by the thumbnail service but not processed here
+// (sprite sheets, download renditions, local thumbnails, diffs).
+pub fn encode_avif(img: DynamicImage, options: &EncodeOptions) -> Result<Vec<u8>, String> {
+    let quality = match options.lossless {
+        true => 100,
+        false => options.quality.round().clamp(1.0, 100.0) as u8,
+    };
+
+    let started = Instant::now();
+    let mut output = Vec::new();
+    let encoder = AvifEncoder::new_with_speed_quality(&mut output, AVIF_SPEED, quality);
+
+    DynamicImage::ImageRgba8(img.to_rgba8())
+        .write_with_encoder(encoder)
+        .map_err(|err| err.to_string())?;
+
+    record_encode_duration("avif", started);
+
+    Ok(output)
+}
+
+// Encodes an image for storage, `format` is WebP or AVIF
+pub fn encode_image(
+    img: DynamicImage,
+    format: OutputFormat,
+    options: &EncodeOptions
+) -> Result<Vec<u8>, String> {
+    match format {
+        OutputFormat::Avif => encode_avif(img, options),
+        _ => Ok(encode_webp(img, options)),
+    }
+}
+
+// Encodes an upload for storage, in `img.storage_format(format)`
+pub fn encode_upload(
+    img: DecodedImage,
+    format: OutputFormat,
+    options: &EncodeOptions
+) -> Result<Vec<u8>, String> {
+    match img {
+        DecodedImage::Still(img) => encode_image(img, format, options),
+        DecodedImage::Animated(frames) => encode_animated_webp(&frames, options),
+    }
+}
+
+// Decoding, resizing and encoding run on the blocking pool so they don't starve the runtime,
+// at most as many at a time as `permits` has. A burst of uploads queues up for a permit, the
+// wait is recorded per task and on the task's span.
+pub async fn run_image_task<T: Send + 'static>(
+    permits: &Semaphore,
+    task: &'static str,
+    work: impl FnOnce() -> T + Send + 'static
+) -> Result<T, String> {
+    let span = tracing::info_span!("image_task", task, queue_wait_ms = tracing::field::Empty);
+    let queued = Instant::now();
+    let _permit = permits.acquire().await.map_err(|err| err.to_string())?;
+
+    span.record("queue_wait_ms", queued.elapsed().as_millis() as u64);
+    record_image_queue_wait(task, queued);
+
+    tokio::task::spawn_blocking(move || span.in_scope(work)).await.map_err(|err| err.to_string())
+}
+
+pub fn transcode(data: &[u8], format: OutputFormat, max_pixels: u64) -> Result<Vec<u8>, String> {
+    let img = load_oriented(data, max_pixels)?;
+
+    if format.is_storage_format() {
+        return encode_image(img, format, &EncodeOptions::default());
+    }
+
+    let mut output = Cursor::new(Vec::new());
+
+    let res = match format {
+        OutputFormat::Png => img.write_to(&mut output, ImageFormat::Png),
+        // JPEG has no alpha channel
+        _ => DynamicImage::ImageRgb8(img.to_rgb8()).write_to(&mut output, ImageFormat::Jpeg),
+    };
+
+    res.map_err(|err| err.to_string())?;
+
+    Ok(output.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{ DynamicImage, ImageFormat, Rgb, RgbImage };
+
+    use super::{ encode_webp, load_oriented, EncodeOptions };
+
+    // 16x8, red on the left half and blue on the right one, so every rotation is told apart
+    fn jpeg_with_orientation(orientation: u16) -> Vec<u8> {
+        let img = RgbImage::from_fn(16, 8, |x, _| {
+            match x < 8 {
+                true => Rgb([255, 0, 0]),
+                false => Rgb([0, 0, 255]),
+            }
+        });
+        let mut jpeg = Cursor::new(Vec::new());
+
+        DynamicImage::ImageRgb8(img).write_to(&mut jpeg, ImageFormat::Jpeg).unwrap();
+
+        // Big endian TIFF header followed by an IFD with the orientation tag as its only entry
+        let mut exif = b"Exif\0\0MM\0\x2a\0\0\0\x08\0\x01\x01\x12\0\x03\0\0\0\x01".to_vec();
+        exif.extend_from_slice(&orientation.to_be_bytes());
+        exif.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+
+        let jpeg = jpeg.into_inner();
+        let mut data = jpeg[..2].to_vec();
+
+        data.extend_from_slice(&[0xff, 0xe1]);
+        data.extend_from_slice(&((exif.len() + 2) as u16).to_be_bytes());
+        data.extend_from_slice(&exif);
+        data.extend_from_slice(&jpeg[2..]);
+
+        data
+    }
+
+    fn is_red(img: &DynamicImage, x: u32, y: u32) -> bool {
+        let pixel = img.to_rgb8().get_pixel(x, y).0;
+
+        pixel[0] > 128 && pixel[2] < 128
+    }
+
+    #[test]
+    fn applies_exif_orientation() {
+        let rotated = load_oriented(&jpeg_with_orientation(3), u64::MAX).unwrap();
+        assert_eq!((rotated.width(), rotated.height()), (16, 8));
+        assert!(!is_red(&rotated, 1, 1));
+        assert!(is_red(&rotated, 14, 1));
+
+        let clockwise = load_oriented(&jpeg_with_orientation(6), u64::MAX).unwrap();
+        assert_eq!((clockwise.width(), clockwise.height()), (8, 16));
+        assert!(is_red(&clockwise, 1, 1));
+        assert!(!is_red(&clockwise, 1, 14));
+
+        let counter_clockwise = load_oriented(&jpeg_with_orientation(8), u64::MAX).unwrap();
+        assert_eq!((counter_clockwise.width(), counter_clockwise.height()), (8, 16));
+        assert!(!is_red(&counter_clockwise, 1, 1));
+        assert!(is_red(&counter_clockwise, 1, 14));
+    }
+
+    #[test]
+    fn encoded_webp_has_no_exif() {
+        for orientation in [3, 6, 8] {
+            let img = load_oriented(&jpeg_with_orientation(orientation), u64::MAX).unwrap();
+            let encoded = encode_webp(img, &EncodeOptions::default());
+
+            assert_eq!(&encoded[8..12], b"WEBP");
+            assert!(!encoded.windows(4).any(|chunk| chunk == b"EXIF"));
+        }
+    }
 }
@@ -0,0 +1,99 @@
+use std::{
+    sync::{ atomic::{ AtomicUsize, Ordering }, Arc, Mutex },
+    time::{ SystemTime, UNIX_EPOCH },
+};
+
+use axum::{
+    extract::{ Request, State },
+    http::Method,
+    middleware::Next,
+    response::{ IntoResponse, Response },
+};
+use serde::Serialize;
+
+use crate::{ enums::AppResponse, state::models::AppState };
+
+pub const DEFAULT_MAINTENANCE_MESSAGE: &str =
+    "Uploads are paused for maintenance, please try again later.";
+
+#[derive(Clone, Serialize)]
+pub struct MaintenanceWindow {
+    pub message: String,
+    pub started_at: i64,
+}
+
+// Kept in memory, every instance has to be switched on its own
+#[derive(Default)]
+pub struct MaintenanceMode {
+    window: Mutex<Option<MaintenanceWindow>>,
+    // Uploads that were accepted before maintenance started and haven't finished yet
+    in_flight: AtomicUsize,
+}
+
+pub type Maintenance = Arc<MaintenanceMode>;
+
+// Decrements the count even when the client goes away mid upload
+struct InFlightGuard<'a>(&'a AtomicUsize);
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl MaintenanceMode {
+    pub fn start(&self, message: String) {
+        let started_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis() as i64)
+            .unwrap_or_default();
+
+        let mut window = self.window.lock().unwrap();
+
+        // Switching it on again only replaces the message
+        match window.as_mut() {
+            Some(window) => {
+                window.message = message;
+            }
+            None => {
+                *window = Some(MaintenanceWindow { message, started_at });
+            }
+        }
+    }
+
+    pub fn stop(&self) {
+        *self.window.lock().unwrap() = None;
+    }
+
+    pub fn window(&self) -> Option<MaintenanceWindow> {
+        self.window.lock().unwrap().clone()
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+}
+
+// Layered on the routes that write new objects. Reads never pass through here, so assets and
+// thumbnails keep being served while the bucket is migrated.
+pub async fn maintenance_middleware(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next
+) -> Response {
+    // Upload status polling and other reads nested with the upload routes
+    if matches!(*request.method(), Method::GET | Method::HEAD | Method::OPTIONS) {
+        return next.run(request).await;
+    }
+
+    // Counted before the check, so an upload racing the switch is either rejected or shows up
+    // in the count
+    state.maintenance.in_flight.fetch_add(1, Ordering::SeqCst);
+    let _guard = InFlightGuard(&state.maintenance.in_flight);
+
+    if let Some(window) = state.maintenance.window() {
+        return AppResponse::Unavailable(window.message).into_response();
+    }
+
+    return next.run(request).await;
+}
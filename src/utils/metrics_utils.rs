@@ -0,0 +1,157 @@
+use std::time::{ Duration, Instant };
+
+use aws_sdk_s3::config::{
+    interceptors::FinalizerInterceptorContextRef,
+    ConfigBag,
+    Intercept,
+    RuntimeComponents,
+};
+use aws_smithy_runtime_api::{ box_error::BoxError, client::orchestrator::Metadata };
+use axum::{ extract::{ MatchedPath, Request }, middleware::Next, response::Response };
+use metrics::{ counter, gauge, histogram };
+use metrics_exporter_prometheus::{ BuildError, Matcher, PrometheusBuilder, PrometheusHandle };
+
+use crate::{ enums::AssetKind, state::models::AppState };
+
+// Histograms are aggregated into buckets, anything but these is drained on upkeep
+const METRICS_UPKEEP_INTERVAL: Duration = Duration::from_secs(5);
+const DURATION_BUCKETS: [f64; 12] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+const SIZE_BUCKETS: [f64; 9] = [
+    10_000.0, 50_000.0, 100_000.0, 500_000.0, 1_000_000.0, 2_500_000.0, 5_000_000.0, 10_000_000.0,
+    20_000_000.0,
+];
+
+pub fn install_recorder() -> Result<PrometheusHandle, BuildError> {
+    PrometheusBuilder::new()
+        .set_buckets_for_metric(Matcher::Suffix("_seconds".to_owned()), &DURATION_BUCKETS)?
+        .set_buckets_for_metric(Matcher::Suffix("_bytes".to_owned()), &SIZE_BUCKETS)?
+        .install_recorder()
+}
+
+pub async fn run_metrics_upkeep(state: AppState) {
+    let mut interval = tokio::time::interval(METRICS_UPKEEP_INTERVAL);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = state.shutdown.cancelled() => {
+                return;
+            }
+        }
+
+        state.metrics.run_upkeep();
+    }
+}
+
+// Labelled by the route pattern instead of the URL, so ids don't blow up the label count
+pub async fn track_metrics(request: Request, next: Next) -> Response {
+    let started = Instant::now();
+    let method = request.method().to_string();
+    let path = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|matched_path| matched_path.as_str().to_owned())
+        .unwrap_or("unmatched".to_owned());
+
+    let response = next.run(request).await;
+    let status = response.status().as_u16().to_string();
+
+    counter!(
+        "http_requests_total",
+        "method" => method.clone(),
+        "path" => path.clone(),
+        "status" => status
+    ).increment(1);
+    histogram!(
+        "http_request_duration_seconds",
+        "method" => method,
+        "path" => path
+    ).record(started.elapsed().as_secs_f64());
+
+    response
+}
+
+// Size of the file as received, before it's re-encoded
+pub fn record_upload_size(kind: &AssetKind, size: u64) {
+    histogram!("upload_size_bytes", "kind" => kind.to_string()).record(size as f64);
+}
+
+pub fn record_encode_duration(format: &'static str, started: Instant) {
+    histogram!("image_encode_duration_seconds", "format" => format).record(
+        started.elapsed().as_secs_f64()
+    );
+}
+
+// Time an image task waited for a permit before it could start
+pub fn record_image_queue_wait(task: &'static str, queued: Instant) {
+    histogram!("image_task_queue_wait_seconds", "task" => task).record(
+        queued.elapsed().as_secs_f64()
+    );
+}
+
+// Hit rate is hits / (hits + misses), requests without valid tokens aren't counted
+pub fn record_auth_cache(hit: bool) {
+    let result = match hit {
+        true => "hit",
+        false => "miss",
+    };
+
+    counter!("auth_cache_requests_total", "result" => result).increment(1);
+}
+
+// Read at scrape time instead of on every checkout
+pub fn record_pool_status(state: &AppState) {
+    let status = state.pool.status();
+
+    gauge!("db_pool_max_size").set(status.max_size as f64);
+    gauge!("db_pool_size").set(status.size as f64);
+    gauge!("db_pool_available").set(status.available as f64);
+    gauge!("db_pool_waiting").set(status.waiting as f64);
+
+    if let Some(read_pool) = &state.read_pool {
+        let status = read_pool.status();
+
+        gauge!("db_read_pool_max_size").set(status.max_size as f64);
+        gauge!("db_read_pool_size").set(status.size as f64);
+        gauge!("db_read_pool_available").set(status.available as f64);
+        gauge!("db_read_pool_waiting").set(status.waiting as f64);
+    }
+}
+
+// Counts every S3 call and its failures per operation, so the call sites don't have to
+#[derive(Debug)]
+pub struct S3MetricsInterceptor;
+
+impl Intercept for S3MetricsInterceptor {
+    fn name(&self) -> &'static str {
+        "S3MetricsInterceptor"
+    }
+
+    fn read_after_execution(
+        &self,
+        context: &FinalizerInterceptorContextRef<'_>,
+        _runtime_components: &RuntimeComponents,
+        cfg: &mut ConfigBag
+    ) -> Result<(), BoxError> {
+        let operation = cfg
+            .load::<Metadata>()
+            .map(|metadata| metadata.name().to_owned())
+            .unwrap_or("unknown".to_owned());
+
+        counter!("s3_requests_total", "operation" => operation.clone()).increment(1);
+
+        if context.output_or_error().is_some_and(|res| res.is_err()) {
+            // No status when the request never got a response (timeouts, connection errors)
+            let status = context
+                .response()
+                .map(|response| response.status().as_u16().to_string())
+                .unwrap_or("none".to_owned());
+
+            counter!("s3_errors_total", "operation" => operation, "status" => status).increment(1);
+        }
+
+        Ok(())
+    }
+}
@@ -1,5 +1,29 @@
+pub mod asset_utils;
 pub mod auth_utils;
+pub mod avatar_utils;
 pub mod db_utils;
+pub mod dedup_utils;
+pub mod diff_utils;
+pub mod domain_utils;
 pub mod image_utils;
 pub mod extractors;
+pub mod hotlink_utils;
+pub mod metrics_utils;
+pub mod placeholder_utils;
+pub mod progress_utils;
 pub mod s3_utils;
+pub mod sprite_utils;
+pub mod stream_utils;
+pub mod tag_utils;
+pub mod tenant_utils;
+pub mod thumbnail_utils;
+pub mod trash_utils;
+pub mod usage_utils;
+pub mod validation_utils;
+pub mod variant_utils;
+pub mod zip_utils;
+pub mod webhook_utils;
+pub mod cors_utils;
+pub mod maintenance_utils;
+pub mod remote_utils;
+pub mod request_id_utils;
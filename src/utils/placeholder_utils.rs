@@ -0,0 +1,59 @@
+use uuid::Uuid;
+
+use crate::enums::PlaceholderKind;
+
+pub fn placeholder_initials(name: Option<&str>, id: &Uuid) -> String {
+    let initials: String = name
+        .unwrap_or("")
+        .split_whitespace()
+        .filter_map(|word| word.chars().find(|c| c.is_alphanumeric()))
+        .take(2)
+        .collect::<String>()
+        .to_uppercase();
+
+    if initials.is_empty() {
+        return id.simple().to_string()[..2].to_uppercase();
+    }
+
+    initials
+}
+
+pub fn placeholder_color(id: &Uuid) -> (u16, u8, u8) {
+    let bytes = id.as_bytes();
+    let hue = (u16::from(bytes[0]) << 8 | u16::from(bytes[1])) % 360;
+    let saturation = 45 + (bytes[2] % 20);
+    let lightness = 40 + (bytes[3] % 15);
+
+    (hue, saturation, lightness)
+}
+
+pub fn generate_placeholder_svg(kind: &PlaceholderKind, id: &Uuid, name: Option<&str>) -> String {
+    let (width, height) = kind.dimensions();
+    let (hue, saturation, lightness) = placeholder_color(id);
+    let initials = placeholder_initials(name, id);
+    let font_size = width.min(height) / 3;
+
+    let background = match kind {
+        PlaceholderKind::Avatars =>
+            format!(
+                "<circle cx=\"{}\" cy=\"{}\" r=\"{}\" fill=\"hsl({}, {}%, {}%)\"/>",
+                width / 2,
+                height / 2,
+                width.min(height) / 2,
+                hue,
+                saturation,
+                lightness
+            ),
+        _ =>
+            format!(
+                "<rect width=\"100%\" height=\"100%\" fill=\"hsl({}, {}%, {}%)\"/>",
+                hue,
+                saturation,
+                lightness
+            ),
+    };
+
+    format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{width}\" height=\"{height}\" viewBox=\"0 0 {width} {height}\">{background}<text x=\"50%\" y=\"50%\" dy=\".35em\" text-anchor=\"middle\" font-family=\"sans-serif\" font-size=\"{font_size}\" fill=\"#ffffff\">{initials}</text></svg>"
+    )
+}
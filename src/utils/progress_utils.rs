@@ -0,0 +1,153 @@
+use std::{ collections::HashMap, sync::{ Arc, Mutex }, time::Instant };
+
+use serde::Serialize;
+use tokio::sync::broadcast::Sender;
+use uuid::Uuid;
+
+use crate::{ enums::{ UploadEventKind, UploadStage }, UPLOAD_STATUS_TTL };
+
+pub type UploadTracker = Arc<Mutex<HashMap<Uuid, UploadStatus>>>;
+
+// Shared by every upload, subscribers pick out the events of their own upload_id
+pub type UploadEvents = Sender<UploadEvent>;
+
+#[derive(Clone, Serialize)]
+pub struct UploadEvent {
+    pub upload_id: Uuid,
+    #[serde(skip)]
+    pub user_id: Uuid,
+    pub kind: UploadEventKind,
+    pub file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub asset_id: Option<Uuid>,
+    pub bytes_received: u64,
+}
+
+#[derive(Clone, Serialize)]
+pub struct UploadStatus {
+    #[serde(skip)]
+    pub user_id: Uuid,
+    pub stage: UploadStage,
+    pub bytes_received: u64,
+    pub current_file: Option<String>,
+    pub asset_ids: Vec<Uuid>,
+    pub failed: Vec<String>,
+    #[serde(skip)]
+    pub updated_at: Instant,
+}
+
+// Handle the upload handlers publish through. Uploads started without an
+// upload_id get a handle that silently ignores every update.
+pub struct UploadProgress {
+    tracker: UploadTracker,
+    events: UploadEvents,
+    upload_id: Option<Uuid>,
+    user_id: Uuid,
+}
+
+impl UploadProgress {
+    pub fn start(
+        tracker: &UploadTracker,
+        events: &UploadEvents,
+        upload_id: Option<Uuid>,
+        user_id: Uuid
+    ) -> Self {
+        if let Some(upload_id) = upload_id {
+            let mut tracker = tracker.lock().unwrap();
+
+            tracker.retain(|_, status| status.updated_at.elapsed() < UPLOAD_STATUS_TTL);
+            tracker.insert(upload_id, UploadStatus {
+                user_id,
+                stage: UploadStage::Receiving,
+                bytes_received: 0,
+                current_file: None,
+                asset_ids: vec![],
+                failed: vec![],
+                updated_at: Instant::now(),
+            });
+        }
+
+        UploadProgress { tracker: tracker.clone(), events: events.clone(), upload_id, user_id }
+    }
+
+    fn update(&self, apply: impl FnOnce(&mut UploadStatus)) {
+        if let Some(upload_id) = self.upload_id {
+            let mut tracker = self.tracker.lock().unwrap();
+
+            if let Some(status) = tracker.get_mut(&upload_id) {
+                apply(status);
+                status.updated_at = Instant::now();
+            }
+        }
+    }
+
+    fn publish(&self, kind: UploadEventKind, file: Option<&str>, asset_id: Option<Uuid>) {
+        if let Some(upload_id) = self.upload_id {
+            let bytes_received = self.tracker
+                .lock()
+                .unwrap()
+                .get(&upload_id)
+                .map(|status| status.bytes_received)
+                .unwrap_or_default();
+
+            // Fails when nobody is subscribed, which is the usual case
+            let _ = self.events.send(UploadEvent {
+                upload_id,
+                user_id: self.user_id,
+                kind,
+                file: file.map(|file| file.to_owned()),
+                asset_id,
+                bytes_received,
+            });
+        }
+    }
+
+    // Every byte of the file is in, before it gets decoded
+    pub fn file_received(&self, file: &str) {
+        self.publish(UploadEventKind::Received, Some(file), None);
+    }
+
+    pub fn file_encoded(&self, file: &str) {
+        self.publish(UploadEventKind::Encoded, Some(file), None);
+    }
+
+    pub fn file_stored(&self, file: &str) {
+        self.publish(UploadEventKind::Stored, Some(file), None);
+    }
+
+    pub fn stage(&self, stage: UploadStage, file: &str) {
+        self.update(|status| {
+            status.stage = stage;
+            status.current_file = Some(file.to_owned());
+        });
+    }
+
+    pub fn received(&self, bytes: usize) {
+        self.update(|status| {
+            status.bytes_received += bytes as u64;
+        });
+    }
+
+    // The row of the file is inserted, it's an asset from here on
+    pub fn stored(&self, file: &str, id: Uuid) {
+        self.update(|status| status.asset_ids.push(id));
+        self.publish(UploadEventKind::DbInserted, Some(file), Some(id));
+    }
+
+    pub fn failed(&self, file: &str) {
+        self.update(|status| status.failed.push(file.to_owned()));
+        self.publish(UploadEventKind::Failed, Some(file), None);
+    }
+
+    pub fn finish(&self) {
+        self.update(|status| {
+            status.stage = UploadStage::Completed;
+            status.current_file = None;
+        });
+        self.publish(UploadEventKind::Completed, None, None);
+    }
+}
+
+pub fn get_upload_status(tracker: &UploadTracker, upload_id: &Uuid) -> Option<UploadStatus> {
+    tracker.lock().unwrap().get(upload_id).cloned()
+}
@@ -0,0 +1,260 @@
+use std::{
+    fmt::Display,
+    net::{ IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr },
+    sync::Arc,
+    time::Duration,
+};
+
+use reqwest::{
+    dns::{ Addrs, Name, Resolve, Resolving },
+    header::CONTENT_TYPE,
+    redirect::{ Action, Attempt, Policy },
+    Client,
+};
+use url::{ Host, Url };
+
+use crate::{
+    state::models::AppState,
+    utils::{ asset_utils::supported_media_type, stream_utils::{ spool_response, SpooledFile } },
+};
+
+const REMOTE_FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_REDIRECTS: usize = 5;
+
+#[derive(Debug)]
+pub enum RemoteError {
+    NotAllowed(String),
+    Fetch(String),
+    TooLarge,
+    UnsupportedType(String),
+}
+
+impl RemoteError {
+    // Safe to show to the uploader, the details are only logged
+    pub fn reason(&self) -> &'static str {
+        match self {
+            RemoteError::NotAllowed(_) => "URL IS NOT ALLOWED",
+            RemoteError::Fetch(_) => "COULD NOT DOWNLOAD FILE",
+            RemoteError::TooLarge => "FILE TOO LARGE",
+            RemoteError::UnsupportedType(_) => "UNSUPPORTED FILE TYPE",
+        }
+    }
+}
+
+impl Display for RemoteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RemoteError::NotAllowed(details) |
+            RemoteError::Fetch(details) |
+            RemoteError::UnsupportedType(details) => write!(f, "{} - {}", self.reason(), details),
+            RemoteError::TooLarge => write!(f, "{}", self.reason()),
+        }
+    }
+}
+
+fn is_public_ipv4(ip: &Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+
+    !(
+        ip.is_unspecified() ||
+        ip.is_private() ||
+        ip.is_loopback() ||
+        ip.is_link_local() ||
+        ip.is_broadcast() ||
+        ip.is_documentation() ||
+        ip.is_multicast() ||
+        // 0.0.0.0/8, 100.64.0.0/10 (carrier-grade NAT), 198.18.0.0/15 (benchmarking) and
+        // 240.0.0.0/4 (reserved)
+        a == 0 ||
+        (a == 100 && (b & 0xc0) == 64) ||
+        (a == 198 && (b & 0xfe) == 18) ||
+        a >= 240
+    )
+}
+
+fn is_public_ipv6(ip: &Ipv6Addr) -> bool {
+    if let Some(ipv4) = ip.to_ipv4_mapped() {
+        return is_public_ipv4(&ipv4);
+    }
+
+    let [first, second, ..] = ip.segments();
+
+    !(
+        ip.is_unspecified() ||
+        ip.is_loopback() ||
+        ip.is_multicast() ||
+        // fc00::/7 (unique local), fe80::/10 (link local) and 2001:db8::/32 (documentation)
+        (first & 0xfe00) == 0xfc00 ||
+        (first & 0xffc0) == 0xfe80 ||
+        (first == 0x2001 && second == 0x0db8)
+    )
+}
+
+// Whether an address is reachable from the internet, requests made on behalf of users must not
+// reach this service's own network
+fn is_public_ip(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_ipv4(ip),
+        IpAddr::V6(ip) => is_public_ipv6(ip),
+    }
+}
+
+// Redirect policy of the remote client. Literal addresses are checked here, names are checked by
+// PublicResolver when the next hop connects.
+fn redirect_policy(attempt: Attempt) -> Action {
+    if attempt.previous().len() >= MAX_REDIRECTS {
+        return attempt.error("TOO MANY REDIRECTS");
+    }
+
+    let allowed = matches!(attempt.url().scheme(), "http" | "https") &&
+        match attempt.url().host() {
+            Some(Host::Ipv4(ip)) => is_public_ipv4(&ip),
+            Some(Host::Ipv6(ip)) => is_public_ipv6(&ip),
+            Some(Host::Domain(domain)) => domain != "localhost" && !domain.ends_with(".localhost"),
+            None => false,
+        };
+
+    match allowed {
+        true => attempt.follow(),
+        false => attempt.stop(),
+    }
+}
+
+// Resolver of the remote client. Names are resolved as the connection is made, so the addresses
+// checked are the ones connected to, for the first request and every redirect alike. A name
+// that also resolves to a private address is refused as a whole.
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addresses: Vec<SocketAddr> = tokio::net
+                ::lookup_host((name.as_str(), 0)).await?
+                .collect();
+
+            if let Some(address) = addresses.iter().find(|address| !is_public_ip(&address.ip())) {
+                return Err(format!("{} RESOLVES TO {}", name.as_str(), address.ip()).into());
+            }
+
+            let addresses: Addrs = Box::new(addresses.into_iter());
+
+            Ok(addresses)
+        })
+    }
+}
+
+// For requests to URLs users hand us, everything else goes through the shared client. Proxies
+// are skipped, they would resolve the names themselves.
+pub fn remote_client() -> Client {
+    Client::builder()
+        .redirect(Policy::custom(redirect_policy))
+        .dns_resolver(Arc::new(PublicResolver))
+        .no_proxy()
+        .build()
+        .unwrap()
+}
+
+// Every address the host resolves to has to be public, otherwise a name could point at both.
+// Gives a clear error up front, the resolver is what enforces it on connect.
+async fn check_host(url: &Url) -> Result<(), RemoteError> {
+    let port = url.port_or_known_default().unwrap_or(443);
+
+    let addresses: Vec<IpAddr> = match url.host() {
+        Some(Host::Ipv4(ip)) => vec![IpAddr::V4(ip)],
+        Some(Host::Ipv6(ip)) => vec![IpAddr::V6(ip)],
+        Some(Host::Domain(domain)) => {
+            let resolved = tokio::net::lookup_host((domain, port)).await;
+
+            if resolved.is_err() {
+                return Err(RemoteError::Fetch(resolved.err().unwrap().to_string()));
+            }
+
+            resolved
+                .unwrap()
+                .map(|address| address.ip())
+                .collect()
+        }
+        None => vec![],
+    };
+
+    if addresses.is_empty() {
+        return Err(RemoteError::NotAllowed(format!("{} - NO ADDRESS", url)));
+    }
+
+    if let Some(address) = addresses.iter().find(|address| !is_public_ip(address)) {
+        return Err(RemoteError::NotAllowed(format!("{} - RESOLVES TO {}", url, address)));
+    }
+
+    Ok(())
+}
+
+// Downloads a file a user pointed at into a temp file. Only http(s) URLs of public hosts are
+// fetched, and the body is given up on once it's larger than `max_size`.
+pub async fn fetch_remote(
+    state: &AppState,
+    url: &str,
+    max_size: u64
+) -> Result<SpooledFile, RemoteError> {
+    let url = Url::parse(url.trim());
+
+    if url.is_err() {
+        return Err(RemoteError::NotAllowed(url.err().unwrap().to_string()));
+    }
+
+    let url = url.unwrap();
+
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(RemoteError::NotAllowed(format!("{} - EXPECTED AN HTTP(S) URL", url)));
+    }
+
+    if !url.username().is_empty() || url.password().is_some() {
+        return Err(RemoteError::NotAllowed(format!("{} - URL HAS CREDENTIALS", url)));
+    }
+
+    check_host(&url).await?;
+
+    let response = state.remote_client
+        .get(url.clone())
+        .timeout(REMOTE_FETCH_TIMEOUT)
+        .send().await;
+
+    if response.is_err() {
+        return Err(RemoteError::Fetch(response.err().unwrap().to_string()));
+    }
+
+    let response = response.unwrap();
+
+    // Where the request actually went, also covers literal addresses the resolver never sees
+    if let Some(address) = response.remote_addr().filter(|address| !is_public_ip(&address.ip())) {
+        return Err(RemoteError::NotAllowed(format!("{} - CONNECTED TO {}", url, address)));
+    }
+
+    if !response.status().is_success() {
+        return Err(RemoteError::Fetch(format!("{} - {}", url, response.status())));
+    }
+
+    // The file is sniffed once it's downloaded, this only skips what is obviously not an asset
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .and_then(|content_type| content_type.split(';').next())
+        .map(|content_type| content_type.trim().to_lowercase())
+        .unwrap_or_default();
+
+    if !content_type.starts_with("image/") && supported_media_type(&content_type).is_none() {
+        return Err(RemoteError::UnsupportedType(format!("{} - {}", url, content_type)));
+    }
+
+    if response.content_length().is_some_and(|length| length > max_size) {
+        return Err(RemoteError::TooLarge);
+    }
+
+    let spooled = spool_response(response, max_size).await;
+
+    if spooled.is_err() {
+        return Err(RemoteError::Fetch(spooled.err().unwrap()));
+    }
+
+    spooled.unwrap().ok_or(RemoteError::TooLarge)
+}
@@ -0,0 +1,55 @@
+use axum::{ extract::Request, http::HeaderValue, middleware::Next, response::Response };
+use reqwest::RequestBuilder;
+use tracing::Instrument;
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+// Id of the request being handled. Tasks spawned off the request don't inherit it.
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+// Ids from upstream are kept so a request can be followed across services, anything that
+// doesn't look like one is replaced
+fn incoming_request_id(request: &Request) -> Option<String> {
+    let id = request.headers().get(REQUEST_ID_HEADER)?.to_str().ok()?.trim();
+
+    let valid =
+        !id.is_empty() &&
+        id.len() <= MAX_REQUEST_ID_LENGTH &&
+        id.chars().all(|char| char.is_ascii_alphanumeric() || matches!(char, '-' | '_' | '.'));
+
+    valid.then(|| id.to_owned())
+}
+
+// Outermost layer, so every span of the request and every AppResponse carries the id
+pub async fn request_id_middleware(request: Request, next: Next) -> Response {
+    let id = incoming_request_id(&request).unwrap_or_else(|| Uuid::new_v4().to_string());
+    let span = tracing::info_span!("request", request_id = %id);
+
+    let mut response = REQUEST_ID.scope(id.clone(), next.run(request).instrument(span)).await;
+
+    response.headers_mut().insert(REQUEST_ID_HEADER, HeaderValue::from_str(&id).unwrap());
+
+    response
+}
+
+pub trait ForwardRequestId {
+    fn forward_request_id(self) -> Self;
+}
+
+// For calls to the other services, so their logs can be matched with ours
+impl ForwardRequestId for RequestBuilder {
+    fn forward_request_id(self) -> Self {
+        match current_request_id() {
+            Some(id) => self.header(REQUEST_ID_HEADER, id),
+            None => self,
+        }
+    }
+}
@@ -1,66 +1,146 @@
-use aws_sdk_s3::{ types::ObjectIdentifier, Client };
-
-use crate::enums::AppResponse;
-
-pub async fn recursive_delete(
-    client: &Client,
-    bucket: &str,
-    prefix: &str
-) -> Result<(), AppResponse> {
-    let mut continuation_token = None;
-
-    loop {
-        let list_resp = client
-            .list_objects_v2()
-            .bucket(bucket)
-            .prefix(prefix)
-            .set_continuation_token(continuation_token)
-            .send().await;
-
-        if list_resp.is_err() {
-            break;
-        }
-
-        let list_resp = list_resp.unwrap();
-
-        if let Some(objects) = list_resp.contents {
-            let keys_to_delete: Vec<ObjectIdentifier> = objects
-                .iter()
-                .map(|obj|
-                    ObjectIdentifier::builder().key(obj.key.as_ref().unwrap()).build().unwrap()
-                )
-                .collect();
-
-            if !keys_to_delete.is_empty() {
-                let _ = client
-                    .delete_objects()
-                    .bucket(bucket)
-                    .delete(
-                        aws_sdk_s3::types::Delete
-                            ::builder()
-                            .set_objects(Some(keys_to_delete))
-                            .build()
-                            .unwrap()
-                    )
-                    .send().await
-                    .unwrap();
-
-                println!("Deleted {} objects", objects.len());
-            } else {
-                println!("No objects found with the prefix: {}", prefix);
-            }
-        }
-
-        if let Some(is_truncated) = list_resp.is_truncated {
-            if is_truncated == true {
-                continuation_token = list_resp.next_continuation_token;
-            } else {
-                break;
-            }
-        } else {
-            break;
-        }
+use axum::body::Bytes;
+use sha2::{ Digest, Sha256 };
+use uuid::Uuid;
+
+use crate::{
+    enums::{ AppResponse, AssetVisibility, ImageType, OutputFormat },
+    state::models::AppState,
+    storage::{ backend::PutOptions, StorageTarget },
+    utils::{
+        asset_utils::image_key,
+        image_utils::{ run_image_task, transcode },
+        thumbnail_utils::thumbnail_prefix,
+        variant_utils::variant_prefix,
+    },
+};
+
+pub fn rendition_prefix(project_id: &Uuid, image_type: &ImageType, id: &Uuid) -> String {
+    format!("assets/{}/{}/renditions/{}/", project_id, image_type, id)
+}
+
+// Rendition keys embed a hash of the original object's ETag, so a replaced original
+// never resolves to a stale rendition and renditions can be served as immutable.
+pub fn rendition_key(
+    project_id: &Uuid,
+    image_type: &ImageType,
+    id: &Uuid,
+    content_hash: &str,
+    format: OutputFormat
+) -> String {
+    format!(
+        "{}{}.{}",
+        rendition_prefix(project_id, image_type, id),
+        content_hash,
+        format.extension()
+    )
+}
+
+pub async fn get_content_hash(target: &StorageTarget, key: &str) -> Result<String, AppResponse> {
+    let head = target.head(key).await;
+
+    if head.is_err() {
+        return Err(AppResponse::Error(head.err().unwrap()));
+    }
+
+    let etag = head.unwrap().etag;
+
+    let mut hasher = Sha256::new();
+    hasher.update(etag.as_bytes());
+
+    Ok(format!("{:x}", hasher.finalize())[..16].to_owned())
+}
+
+pub async fn get_object_bytes(target: &StorageTarget, key: &str) -> Result<Bytes, AppResponse> {
+    target.get(key).await.map_err(AppResponse::Error)
+}
+
+// Needs a backend that keeps versions, with versioning enabled on the bucket for S3
+pub async fn get_object_version_bytes(
+    target: &StorageTarget,
+    key: &str,
+    version_id: &str
+) -> Result<Bytes, AppResponse> {
+    target.get_version(key, version_id).await.map_err(AppResponse::Error)
+}
+
+// Returns the asset in the requested format, transcoding and caching it on first use.
+pub async fn get_rendition(
+    state: &AppState,
+    target: &StorageTarget,
+    project_id: &Uuid,
+    image_type: &ImageType,
+    id: &Uuid,
+    mime_type: &str,
+    format: OutputFormat
+) -> Result<Bytes, AppResponse> {
+    let original_key = image_key(project_id, image_type, id, mime_type);
+
+    if format.content_type() == mime_type {
+        return get_object_bytes(target, &original_key).await;
+    }
+
+    let content_hash = get_content_hash(target, &original_key).await?;
+    let key = rendition_key(project_id, image_type, id, &content_hash, format);
+    let cached = get_object_bytes(target, &key).await;
+
+    if cached.is_ok() {
+        return cached;
     }
 
-    Ok(())
+    let original = get_object_bytes(target, &original_key).await?;
+    let max_pixels = state.config.max_image_pixels;
+
+    let data = run_image_task(&state.encode_permits, "transcode", move || {
+        transcode(&original, format, max_pixels)
+    }).await;
+
+    if data.is_err() {
+        return Err(AppResponse::Error(data.err().unwrap()));
+    }
+
+    let data = data.unwrap();
+
+    if data.is_err() {
+        return Err(AppResponse::Error(data.err().unwrap()));
+    }
+
+    let data = data.unwrap();
+
+    let upload = target.put(&key, data.clone(), &PutOptions {
+        content_type: format.content_type(),
+        cache_control: &state.config.cache_control.renditions,
+        visibility: AssetVisibility::Private,
+    }).await;
+
+    if upload.is_err() {
+        tracing::error!("ERROR CACHING RENDITION - {}", upload.err().unwrap());
+    }
+
+    Ok(Bytes::from(data))
+}
+
+pub async fn delete_renditions(
+    target: &StorageTarget,
+    project_id: &Uuid,
+    image_type: &ImageType,
+    id: &Uuid
+) {
+    let res = target.delete_prefix(&rendition_prefix(project_id, image_type, id)).await;
+
+    if res.is_err() {
+        tracing::error!("ERROR DELETING RENDITIONS - {}", res.err().unwrap());
+    }
+
+    // Thumbnails are keyed by size only, so they have to go when the original changes
+    let res = target.delete_prefix(&thumbnail_prefix(project_id, image_type, id)).await;
+
+    if res.is_err() {
+        tracing::error!("ERROR DELETING THUMBNAILS - {}", res.err().unwrap());
+    }
+
+    let res = target.delete_prefix(&variant_prefix(project_id, image_type, id)).await;
+
+    if res.is_err() {
+        tracing::error!("ERROR DELETING VARIANTS - {}", res.err().unwrap());
+    }
 }
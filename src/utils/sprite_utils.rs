@@ -0,0 +1,115 @@
+use image::{ imageops, DynamicImage, GenericImageView, RgbaImage };
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::enums::{ AssetKind, ImageType };
+
+// Sheets are held decoded (4 bytes per pixel) while they're packed and encoded
+pub const MAX_SHEET_PIXELS: u64 = 50_000_000;
+pub const MAX_SPRITES: usize = 100;
+pub const MAX_SPRITE_PADDING: u32 = 64;
+pub const MAX_TILE_SIZE: u32 = 2048;
+
+// The frame layout stored next to a sheet. Sheets are always WebP images, so other assets have
+// no manifest to look for.
+pub fn sprite_manifest_key(
+    project_id: &Uuid,
+    image_type: &ImageType,
+    kind: &AssetKind,
+    id: &Uuid,
+    mime_type: &str
+) -> Option<String> {
+    if *kind != AssetKind::Image || mime_type != "image/webp" {
+        return None;
+    }
+
+    Some(format!("assets/{}/{}/{}.json", project_id, image_type, id))
+}
+
+#[derive(Serialize)]
+pub struct SpriteFrame {
+    pub id: Uuid,
+    pub title: String,
+    pub x: u32,
+    pub y: u32,
+    pub w: u32,
+    pub h: u32,
+}
+
+pub struct SpriteSource {
+    pub id: Uuid,
+    pub title: String,
+    pub image: DynamicImage,
+}
+
+// Simple shelf packing - sprites are sorted by height and placed left to right,
+// starting a new row once the current one would exceed the target width. The layout is worked
+// out in u64 and checked against MAX_SHEET_PIXELS before the sheet is allocated.
+pub fn pack_sprite_sheet(
+    mut sources: Vec<SpriteSource>,
+    padding: u32
+) -> Result<(RgbaImage, Vec<SpriteFrame>), String> {
+    sources.sort_by_key(|source| std::cmp::Reverse(source.image.height()));
+
+    let padding = padding as u64;
+    let total_area: u64 = sources
+        .iter()
+        .map(|s| ((s.image.width() as u64) + padding) * ((s.image.height() as u64) + padding))
+        .sum();
+    let widest = sources
+        .iter()
+        .map(|s| (s.image.width() as u64) + padding)
+        .max()
+        .unwrap_or(0);
+    let max_row_width = ((total_area as f64).sqrt().ceil() as u64).max(widest);
+
+    let mut layout: Vec<(u64, u64)> = vec![];
+    let mut x = 0;
+    let mut y = 0;
+    let mut row_height = 0;
+    let mut sheet_width = 0;
+
+    for source in sources.iter() {
+        let (w, h) = source.image.dimensions();
+        let (w, h) = (w as u64, h as u64);
+
+        if x > 0 && x + w > max_row_width {
+            x = 0;
+            y += row_height + padding;
+            row_height = 0;
+        }
+
+        layout.push((x, y));
+
+        x += w + padding;
+        row_height = row_height.max(h);
+        sheet_width = sheet_width.max(x - padding);
+    }
+
+    let sheet_height = y + row_height;
+
+    if sheet_width.max(1) * sheet_height.max(1) > MAX_SHEET_PIXELS {
+        return Err(format!("SPRITE SHEET EXCEEDS {} PIXELS", MAX_SHEET_PIXELS));
+    }
+
+    // Both sides are below MAX_SHEET_PIXELS, so every position fits in a u32
+    let frames: Vec<SpriteFrame> = sources
+        .iter()
+        .zip(layout.iter())
+        .map(|(source, (x, y))| SpriteFrame {
+            id: source.id,
+            title: source.title.clone(),
+            x: *x as u32,
+            y: *y as u32,
+            w: source.image.width(),
+            h: source.image.height(),
+        })
+        .collect();
+    let mut sheet = RgbaImage::new(sheet_width.max(1) as u32, sheet_height.max(1) as u32);
+
+    for (source, frame) in sources.iter().zip(frames.iter()) {
+        imageops::overlay(&mut sheet, &source.image.to_rgba8(), frame.x as i64, frame.y as i64);
+    }
+
+    Ok((sheet, frames))
+}
@@ -0,0 +1,206 @@
+use std::{ io::{ Read, Write }, path::{ Path, PathBuf } };
+
+use axum::extract::multipart::Field;
+use image::ImageReader;
+use reqwest::{ header::CONTENT_TYPE, Response };
+use tokio::{ fs::File, io::AsyncWriteExt, sync::Semaphore };
+use uuid::Uuid;
+
+use crate::utils::{
+    image_utils::{ decode_upload, run_image_task, DecodedImage },
+    progress_utils::UploadProgress,
+};
+
+// Enough of the file to sniff its type from the magic bytes
+const HEAD_SIZE: usize = 64;
+
+// A multipart field written to a temp file, removed again once dropped.
+pub struct SpooledFile {
+    path: PathBuf,
+    pub head: Vec<u8>,
+    pub size: u64,
+    // Content type the client sent along, checked against the magic bytes
+    pub declared_type: Option<String>,
+}
+
+impl Drop for SpooledFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+impl SpooledFile {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub async fn decode_image(
+        &self,
+        permits: &Semaphore,
+        max_pixels: u64
+    ) -> Result<DecodedImage, String> {
+        let path = self.path.clone();
+
+        run_image_task(permits, "decode", move || {
+            decode_upload(ImageReader::open(&path).map_err(|err| err.to_string())?, max_pixels)
+        }).await?
+    }
+
+    async fn create() -> Result<(SpooledFile, File), String> {
+        let path = std::env::temp_dir().join(format!("arkive-upload-{}", Uuid::new_v4()));
+        let file = File::create(&path).await;
+
+        if file.is_err() {
+            return Err(file.err().unwrap().to_string());
+        }
+
+        Ok((SpooledFile { path, head: vec![], size: 0, declared_type: None }, file.unwrap()))
+    }
+
+    async fn append(&mut self, file: &mut File, chunk: &[u8]) -> Result<(), String> {
+        if self.head.len() < HEAD_SIZE {
+            let missing = (HEAD_SIZE - self.head.len()).min(chunk.len());
+            self.head.extend_from_slice(&chunk[..missing]);
+        }
+
+        let res = file.write_all(chunk).await;
+
+        if res.is_err() {
+            return Err(res.err().unwrap().to_string());
+        }
+
+        self.size += chunk.len() as u64;
+
+        Ok(())
+    }
+}
+
+// Streams the field to disk chunk by chunk instead of buffering it in memory.
+pub async fn spool_field(
+    mut field: Field<'_>,
+    progress: &UploadProgress
+) -> Result<SpooledFile, String> {
+    let (mut spooled, mut file) = SpooledFile::create().await?;
+    spooled.declared_type = field.content_type().map(|content_type| content_type.to_owned());
+
+    loop {
+        let chunk = field.chunk().await;
+
+        if chunk.is_err() {
+            return Err(chunk.err().unwrap().to_string());
+        }
+
+        let chunk = chunk.unwrap();
+
+        if chunk.is_none() {
+            break;
+        }
+
+        let chunk = chunk.unwrap();
+
+        spooled.append(&mut file, &chunk).await?;
+        progress.received(chunk.len());
+    }
+
+    let res = file.flush().await;
+
+    if res.is_err() {
+        return Err(res.err().unwrap().to_string());
+    }
+
+    Ok(spooled)
+}
+
+// Same for the body of a download. Returns None once the body grows past `max_size`, the
+// announced length can't be trusted.
+pub async fn spool_response(
+    mut response: Response,
+    max_size: u64
+) -> Result<Option<SpooledFile>, String> {
+    let (mut spooled, mut file) = SpooledFile::create().await?;
+    spooled.declared_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .map(|content_type| content_type.to_owned());
+
+    loop {
+        let chunk = response.chunk().await;
+
+        if chunk.is_err() {
+            return Err(chunk.err().unwrap().to_string());
+        }
+
+        let chunk = chunk.unwrap();
+
+        if chunk.is_none() {
+            break;
+        }
+
+        let chunk = chunk.unwrap();
+
+        if spooled.size + (chunk.len() as u64) > max_size {
+            return Ok(None);
+        }
+
+        spooled.append(&mut file, &chunk).await?;
+    }
+
+    let res = file.flush().await;
+
+    if res.is_err() {
+        return Err(res.err().unwrap().to_string());
+    }
+
+    Ok(Some(spooled))
+}
+
+// Blocking variant for readers that are only usable from a blocking thread, like the entries
+// of an archive. Returns None once the data grows past `max_size`.
+pub fn spool_reader(reader: &mut impl Read, max_size: u64) -> Result<Option<SpooledFile>, String> {
+    let path = std::env::temp_dir().join(format!("arkive-upload-{}", Uuid::new_v4()));
+    let file = std::fs::File::create(&path);
+
+    if file.is_err() {
+        return Err(file.err().unwrap().to_string());
+    }
+
+    let mut file = file.unwrap();
+    let mut spooled = SpooledFile { path, head: vec![], size: 0, declared_type: None };
+    let mut buffer = vec![0u8; 64 * 1024];
+
+    loop {
+        let read = reader.read(&mut buffer);
+
+        if read.is_err() {
+            return Err(read.err().unwrap().to_string());
+        }
+
+        let read = read.unwrap();
+
+        if read == 0 {
+            break;
+        }
+
+        if spooled.size + (read as u64) > max_size {
+            return Ok(None);
+        }
+
+        let chunk = &buffer[..read];
+
+        if spooled.head.len() < HEAD_SIZE {
+            let missing = (HEAD_SIZE - spooled.head.len()).min(chunk.len());
+            spooled.head.extend_from_slice(&chunk[..missing]);
+        }
+
+        let res = file.write_all(chunk);
+
+        if res.is_err() {
+            return Err(res.err().unwrap().to_string());
+        }
+
+        spooled.size += read as u64;
+    }
+
+    Ok(Some(spooled))
+}
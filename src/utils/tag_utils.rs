@@ -0,0 +1,39 @@
+use deadpool_postgres::Object;
+use uuid::Uuid;
+
+use crate::enums::AppResponse;
+
+const MAX_TAG_LENGTH: usize = 64;
+
+// Tags are matched case-insensitively, so they're stored trimmed and lowercased.
+// Empty and overly long tags are dropped.
+pub fn normalize_tags<'a>(tags: impl Iterator<Item = &'a str>) -> Vec<String> {
+    let mut normalized: Vec<String> = tags
+        .map(|tag| tag.trim().to_lowercase())
+        .filter(|tag| !tag.is_empty() && tag.chars().count() <= MAX_TAG_LENGTH)
+        .collect();
+
+    normalized.sort();
+    normalized.dedup();
+
+    normalized
+}
+
+pub async fn get_asset_tags(client: &Object, id: &Uuid) -> Result<Vec<String>, AppResponse> {
+    let rows = client.query(
+        "SELECT tag FROM asset_tags WHERE image_id = $1 ORDER BY tag;",
+        &[&id]
+    ).await;
+
+    if rows.is_err() {
+        return Err(AppResponse::Error(rows.err().unwrap().to_string()));
+    }
+
+    Ok(
+        rows
+            .unwrap()
+            .iter()
+            .map(|row| row.get("tag"))
+            .collect()
+    )
+}
@@ -0,0 +1,143 @@
+use std::str::FromStr;
+
+use axum::{
+    extract::{ RawPathParams, Request, State },
+    middleware::Next,
+    response::{ IntoResponse, Response },
+};
+use uuid::Uuid;
+
+use crate::{
+    enums::AppResponse,
+    state::models::AppState,
+    utils::{ auth_utils::check_project_owner, db_utils::get_client, extractors::AuthenticatedUser },
+};
+
+// Returns false only when the image exists and belongs to a different project.
+// Ids without an images row (e.g. gateway entity images) are left to the handlers.
+pub async fn image_belongs_to_project(
+    state: &AppState,
+    image_id: &Uuid,
+    project_id: &Uuid
+) -> Result<bool, AppResponse> {
+    let client = get_client(&state.pool).await?;
+
+    let image = client.query_opt("SELECT project_id FROM images WHERE id = $1;", &[&image_id]).await;
+
+    if image.is_err() {
+        return Err(AppResponse::Error(image.err().unwrap().to_string()));
+    }
+
+    match image.unwrap() {
+        Some(row) => {
+            let image_project_id: Uuid = row.get("project_id");
+            Ok(&image_project_id == project_id)
+        }
+        None => Ok(true),
+    }
+}
+
+pub fn path_uuid(params: &Option<RawPathParams>, name: &str) -> Option<Result<Uuid, AppResponse>> {
+    let (_, value) = params
+        .as_ref()?
+        .iter()
+        .find(|(key, _)| *key == name)?;
+
+    Some(Uuid::from_str(value).map_err(|err| AppResponse::Error(format!("PATH ERROR - {}", err))))
+}
+
+// Verifies that every project scoped path parameter matches the project the caller's
+// token was issued for. AuthenticatedUser leaves the verified claims in the request
+// extensions for the handler.
+pub async fn tenant_middleware(
+    State(state): State<AppState>,
+    AuthenticatedUser(claims): AuthenticatedUser,
+    params: Option<RawPathParams>,
+    request: Request,
+    next: Next
+) -> Response {
+    if let Some(project_id) = path_uuid(&params, "project_id") {
+        if project_id.is_err() {
+            return project_id.err().unwrap().into_response();
+        }
+
+        if project_id.unwrap() != claims.project_id {
+            return AppResponse::Auth.into_response();
+        }
+    }
+
+    if let Some(id) = path_uuid(&params, "id") {
+        if id.is_err() {
+            return id.err().unwrap().into_response();
+        }
+
+        let belongs = image_belongs_to_project(&state, &id.unwrap(), &claims.project_id).await;
+
+        if belongs.is_err() {
+            return belongs.err().unwrap().into_response();
+        }
+
+        if !belongs.unwrap() {
+            return AppResponse::Auth.into_response();
+        }
+    }
+
+    return next.run(request).await;
+}
+
+// For routes that manage project wide settings, e.g. secrets shared with other services
+pub async fn owner_middleware(
+    State(state): State<AppState>,
+    AuthenticatedUser(claims): AuthenticatedUser,
+    request: Request,
+    next: Next
+) -> Response {
+    let is_owner = check_project_owner(&state, &claims).await;
+
+    if is_owner.is_err() {
+        return is_owner.err().unwrap().into_response();
+    }
+
+    if !is_owner.unwrap() {
+        return AppResponse::Auth.into_response();
+    }
+
+    return next.run(request).await;
+}
+
+// Public routes have no claims to compare against, so only the path itself is checked:
+// an image id must not be served under another project's path.
+pub async fn entity_project_middleware(
+    State(state): State<AppState>,
+    params: Option<RawPathParams>,
+    request: Request,
+    next: Next
+) -> Response {
+    let project_id = path_uuid(&params, "project_id");
+    let image_id = path_uuid(&params, "image_id");
+
+    if let (Some(project_id), Some(image_id)) = (project_id, image_id) {
+        if project_id.is_err() {
+            return project_id.err().unwrap().into_response();
+        }
+        if image_id.is_err() {
+            return image_id.err().unwrap().into_response();
+        }
+
+        let belongs = image_belongs_to_project(
+            &state,
+            &image_id.unwrap(),
+            &project_id.unwrap()
+        ).await;
+
+        if belongs.is_err() {
+            return belongs.err().unwrap().into_response();
+        }
+
+        if !belongs.unwrap() {
+            return AppResponse::Auth.into_response();
+        }
+    }
+
+    return next.run(request).await;
+}
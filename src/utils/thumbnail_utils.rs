@@ -0,0 +1,282 @@
+use std::time::{ Duration, Instant };
+
+use axum::{ body::Bytes, http::HeaderMap };
+use base64::prelude::*;
+use hmac::{ Hmac, Mac };
+use image::imageops::FilterType;
+use sha2::Sha512;
+use uuid::Uuid;
+
+use crate::{
+    enums::{ AppResponse, AssetVisibility, ImageType },
+    state::models::AppState,
+    storage::{ backend::PutOptions, StorageTarget },
+    utils::{
+        asset_utils::image_key,
+        auth_utils::constant_time_eq,
+        image_utils::{ encode_webp, load_oriented, run_image_task, EncodeOptions },
+        request_id_utils::ForwardRequestId,
+        s3_utils::get_object_bytes,
+    },
+};
+
+type HmacSha512 = Hmac<Sha512>;
+
+// Sizes the editor and wiki request most, generated up front by the prewarm job
+pub const THUMBNAIL_PRESETS: [(usize, usize); 3] = [
+    (160, 160),
+    (320, 320),
+    (640, 640),
+];
+
+// Widths of a srcset when the client doesn't ask for specific ones
+pub const SRCSET_WIDTHS: [usize; 4] = [320, 640, 1280, 1920];
+pub const SRCSET_MAX_WIDTHS: usize = 8;
+
+// Thumbnails resized here are only cached at the preset and srcset sizes. Any other box is
+// served the smallest of those that covers it (the largest when none does), so arbitrary
+// query strings can't each leave a new object behind.
+pub fn snap_thumbnail_size(width: usize, height: usize) -> (usize, usize) {
+    let sides = THUMBNAIL_PRESETS
+        .iter()
+        .map(|(width, height)| *width.max(height))
+        .chain(SRCSET_WIDTHS);
+    let requested = width.max(height);
+
+    let side = sides
+        .clone()
+        .filter(|side| *side >= requested)
+        .min()
+        .or(sides.max())
+        .unwrap();
+
+    (side, side)
+}
+
+// Locally generated thumbnails are capped so a crafted query can't make us allocate huge buffers
+const THUMBNAIL_MAX_SIZE: u32 = 2048;
+const THUMBNAIL_HEALTH_TTL: Duration = Duration::from_secs(30);
+const THUMBNAIL_HEALTH_TIMEOUT: Duration = Duration::from_secs(2);
+
+// The thumbnail service reads from the default bucket only, so for projects on a dedicated
+// bucket these URLs don't resolve and thumbnails are served by get_or_create_thumbnail.
+#[allow(clippy::too_many_arguments)]
+pub fn sign_thumbnail_url(
+    state: &AppState,
+    target: &StorageTarget,
+    project_id: &Uuid,
+    image_type: &ImageType,
+    image_id: &Uuid,
+    mime_type: &str,
+    width: usize,
+    height: usize
+) -> String {
+    let mut hmac = HmacSha512::new_from_slice(&state.config.thumbnail_secret.as_bytes()).unwrap();
+    let sized_url = format!(
+        "{}x{}/{}",
+        width,
+        height,
+        target.key(&image_key(project_id, image_type, image_id, mime_type))
+    );
+    hmac.update(&sized_url.as_bytes());
+
+    let res = hmac.finalize().into_bytes();
+
+    let base_64 = BASE64_STANDARD.encode(res).replace('+', "-").replace('/', "_");
+
+    format!("{}/{}/{}", &state.config.thumbnail_service_url, &base_64, &sized_url)
+}
+
+// The box of every srcset width. Heights follow the original's aspect ratio, without the
+// dimensions the box is square. Widths past the original are left out, they would only be
+// upscaled, but the smallest one is always kept so the set is never empty.
+pub fn srcset_sizes(widths: &[usize], dimensions: Option<(u32, u32)>) -> Vec<(usize, usize)> {
+    let mut widths: Vec<usize> = widths
+        .iter()
+        .map(|width| (*width).clamp(1, THUMBNAIL_MAX_SIZE as usize))
+        .collect();
+
+    widths.sort();
+    widths.dedup();
+
+    let smallest = widths.first().copied();
+
+    if let Some((original_width, _)) = dimensions {
+        widths.retain(|width| *width <= (original_width as usize));
+    }
+
+    if widths.is_empty() {
+        widths.extend(smallest);
+    }
+
+    widths
+        .into_iter()
+        .map(|width| {
+            let height = match dimensions {
+                Some((original_width, original_height)) if original_width > 0 => {
+                    ((width as u64) * (original_height as u64)).div_ceil(original_width as u64)
+                }
+                _ => width as u64,
+            };
+
+            (width, (height as usize).clamp(1, THUMBNAIL_MAX_SIZE as usize))
+        })
+        .collect()
+}
+
+// e.g. "https://.../320x180/... 320w, https://.../640x360/... 640w"
+pub fn srcset(urls: &[(usize, String)]) -> String {
+    urls.iter()
+        .map(|(width, url)| format!("{} {}w", url, width))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+pub const THUMBNAIL_SIGNATURE_HEADER: &str = "x-thumbnail-signature";
+
+// Callbacks are signed like the URLs, HMAC-SHA512 with the shared secret, URL safe base64, but
+// over the raw request body. Recording a variant twice changes nothing, so replays are harmless.
+pub fn sign_thumbnail_callback(secret: &str, body: &[u8]) -> String {
+    let mut hmac = HmacSha512::new_from_slice(secret.as_bytes()).unwrap();
+    hmac.update(body);
+
+    BASE64_STANDARD.encode(hmac.finalize().into_bytes()).replace('+', "-").replace('/', "_")
+}
+
+pub fn check_thumbnail_callback(headers: &HeaderMap, secret: &str, body: &[u8]) -> bool {
+    let signature = headers
+        .get(THUMBNAIL_SIGNATURE_HEADER)
+        .and_then(|value| value.to_str().ok());
+
+    if signature.is_none() || secret.is_empty() {
+        return false;
+    }
+
+    let expected = sign_thumbnail_callback(secret, body);
+
+    constant_time_eq(signature.unwrap().trim().as_bytes(), expected.as_bytes())
+}
+
+// Prefix of every locally generated thumbnail of an asset, whatever the size
+pub fn thumbnail_prefix(project_id: &Uuid, image_type: &ImageType, image_id: &Uuid) -> String {
+    format!("assets/{}/{}/thumbs/{}_", project_id, image_type, image_id)
+}
+
+pub fn thumbnail_key(
+    project_id: &Uuid,
+    image_type: &ImageType,
+    image_id: &Uuid,
+    width: usize,
+    height: usize
+) -> String {
+    format!("{}{}x{}.webp", thumbnail_prefix(project_id, image_type, image_id), width, height)
+}
+
+// The result is cached for a short while so an outage costs one probe, not one per request
+pub async fn thumbnail_service_available(state: &AppState) -> bool {
+    if let Some((checked_at, available)) = *state.thumbnail_health.lock().unwrap() {
+        if checked_at.elapsed() < THUMBNAIL_HEALTH_TTL {
+            return available;
+        }
+    }
+
+    let res = state.reqwest_client
+        .head(&state.config.thumbnail_service_url)
+        .timeout(THUMBNAIL_HEALTH_TIMEOUT)
+        .forward_request_id()
+        .send().await;
+
+    let available = match res {
+        Ok(res) => !res.status().is_server_error(),
+        Err(err) => {
+            tracing::error!("THUMBNAIL SERVICE UNAVAILABLE - {}", err);
+            false
+        }
+    };
+
+    *state.thumbnail_health.lock().unwrap() = Some((Instant::now(), available));
+
+    available
+}
+
+// Fallback for when the thumbnail service is down. Resizes the original to fit within
+// the requested box (see snap_thumbnail_size) and caches the result next to it, so it is only
+// generated once.
+#[allow(clippy::too_many_arguments)]
+pub async fn get_or_create_thumbnail(
+    state: &AppState,
+    target: &StorageTarget,
+    project_id: &Uuid,
+    image_type: &ImageType,
+    image_id: &Uuid,
+    mime_type: &str,
+    width: usize,
+    height: usize
+) -> Result<Bytes, AppResponse> {
+    let (width, height) = snap_thumbnail_size(width, height);
+    let key = thumbnail_key(project_id, image_type, image_id, width, height);
+    let cached = get_object_bytes(target, &key).await;
+
+    if cached.is_ok() {
+        return cached;
+    }
+
+    let original = get_object_bytes(
+        target,
+        &image_key(project_id, image_type, image_id, mime_type)
+    ).await?;
+
+    let width = (width as u32).clamp(1, THUMBNAIL_MAX_SIZE);
+    let height = (height as u32).clamp(1, THUMBNAIL_MAX_SIZE);
+    let max_pixels = state.config.max_image_pixels;
+
+    let data = run_image_task(&state.encode_permits, "thumbnail", move || {
+        let img = load_oriented(&original, max_pixels)?;
+
+        // Never upscale, a thumbnail bigger than the original is just a worse original
+        let img = if img.width() <= width && img.height() <= height {
+            img
+        } else {
+            img.resize(width, height, FilterType::Lanczos3)
+        };
+
+        Ok::<Vec<u8>, String>(encode_webp(img, &EncodeOptions::default()))
+    }).await;
+
+    if data.is_err() {
+        return Err(AppResponse::Error(data.err().unwrap()));
+    }
+
+    let data = data.unwrap();
+
+    if data.is_err() {
+        return Err(AppResponse::Error(data.err().unwrap()));
+    }
+
+    let data = data.unwrap();
+
+    let upload = target.put(&key, data.clone(), &PutOptions {
+        content_type: "image/webp",
+        cache_control: &state.config.cache_control.renditions,
+        visibility: AssetVisibility::Private,
+    }).await;
+
+    if upload.is_err() {
+        tracing::error!("ERROR CACHING THUMBNAIL - {}", upload.err().unwrap());
+    }
+
+    Ok(Bytes::from(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::snap_thumbnail_size;
+
+    #[test]
+    fn snaps_to_the_smallest_covering_size() {
+        assert_eq!(snap_thumbnail_size(160, 160), (160, 160));
+        assert_eq!(snap_thumbnail_size(161, 90), (320, 320));
+        assert_eq!(snap_thumbnail_size(100, 700), (1280, 1280));
+        assert_eq!(snap_thumbnail_size(2048, 2048), (1920, 1920));
+    }
+}
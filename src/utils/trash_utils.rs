@@ -0,0 +1,259 @@
+use std::collections::HashSet;
+
+use deadpool_postgres::Object;
+use serde_json::Value;
+use uuid::Uuid;
+
+use crate::{
+    enums::{ AppResponse, AssetJobOperation, AssetKind, AssetVisibility, ImageType, WebhookEvent },
+    jobs::{
+        acl_job::get_project_visibility,
+        asset_job::{ enqueue_jobs, notify_job_worker, AssetJob },
+    },
+    state::models::AppState,
+    storage::{ backend::CopyOptions, resolve_target, StorageTarget },
+    utils::{
+        asset_utils::asset_key,
+        dedup_utils::OBJECT_ID,
+        s3_utils::rendition_prefix,
+        sprite_utils::sprite_manifest_key,
+        thumbnail_utils::thumbnail_prefix,
+        webhook_utils::{ deleted_asset_data, emit_event },
+    },
+};
+
+// Trashed objects keep their original key under the trash/ prefix
+pub fn trash_key(key: &str) -> String {
+    format!("trash/{}", key)
+}
+
+// Where the object of a row lives, depending on whether it has been trashed
+pub fn stored_key(key: String, trashed: bool) -> String {
+    match trashed {
+        true => trash_key(&key),
+        false => key,
+    }
+}
+
+// Objects shared with a live row stay in place when a row is trashed, so whether a trashed
+// row's object is in the trash has to be looked up
+pub async fn is_in_trash(target: &StorageTarget, key: &str) -> bool {
+    target.head(&trash_key(key)).await.is_ok()
+}
+
+// Storage has no rename, so this is a copy followed by a delete. Content type and cache headers
+// travel with the copy, the visibility does not and has to be given explicitly.
+pub async fn move_object(
+    target: &StorageTarget,
+    from: &str,
+    to: &str,
+    visibility: AssetVisibility
+) -> Result<(), AppResponse> {
+    let copy = target.copy_from(target, from, to, &CopyOptions {
+        visibility,
+        content_type: None,
+        cache_control: None,
+    }).await;
+
+    if copy.is_err() {
+        return Err(AppResponse::Error(copy.err().unwrap()));
+    }
+
+    let del_res = target.delete(from).await;
+
+    if del_res.is_err() {
+        return Err(AppResponse::Error(del_res.err().unwrap()));
+    }
+
+    Ok(())
+}
+
+// Sprite sheets keep their manifest next to the sheet, it goes in and out of the trash with it.
+// Most images are no sheet, a missing manifest is not an error.
+pub async fn move_sprite_manifest(
+    target: &StorageTarget,
+    manifest: Option<String>,
+    to_trash: bool,
+    visibility: AssetVisibility
+) -> Result<(), AppResponse> {
+    if manifest.is_none() {
+        return Ok(());
+    }
+
+    let manifest = manifest.unwrap();
+    let (from, to) = match to_trash {
+        true => (manifest.clone(), trash_key(&manifest)),
+        false => (trash_key(&manifest), manifest),
+    };
+
+    if target.head(&from).await.is_err() {
+        return Ok(());
+    }
+
+    move_object(target, &from, &to, visibility).await
+}
+
+// The visibility an object gets back when it leaves the trash
+pub async fn live_visibility(
+    state: &AppState,
+    project_id: &Uuid,
+    pending: bool
+) -> Result<AssetVisibility, AppResponse> {
+    if pending {
+        return Ok(AssetVisibility::Private);
+    }
+
+    get_project_visibility(state, project_id).await
+}
+
+// Moves the objects into the trash and marks their rows as deleted. Renditions and thumbnails
+// are dropped since they can be regenerated after a restore. Objects a live row outside `ids`
+// still points at stay where they are. Returns the ids that were trashed; ids without a live
+// row (of `image_type`, when given) are skipped, so callers can tell them apart.
+pub async fn trash_assets(
+    state: &AppState,
+    client: &Object,
+    project_id: &Uuid,
+    image_type: Option<&ImageType>,
+    ids: &Vec<Uuid>
+) -> Result<Vec<Uuid>, AppResponse> {
+    let target = resolve_target(state, project_id).await?;
+
+    let rows = client.query(
+        &format!(
+            "SELECT id, type, kind, mime_type, pending, {} FROM images
+             WHERE id = ANY($1) AND project_id = $2 AND deleted_at IS NULL
+                AND ($3::\"ImageType\" IS NULL OR type = $3);",
+            OBJECT_ID
+        ),
+        &[&ids, &project_id, &image_type]
+    ).await;
+
+    if rows.is_err() {
+        return Err(AppResponse::Error(rows.err().unwrap().to_string()));
+    }
+
+    let rows = rows.unwrap();
+
+    let object_ids: Vec<Uuid> = rows
+        .iter()
+        .map(|row| row.get("object_id"))
+        .collect();
+
+    let shared = client.query(
+        &format!(
+            "SELECT DISTINCT {} FROM images
+             WHERE (id = ANY($1) OR object_id = ANY($1)) AND deleted_at IS NULL AND NOT (id = ANY($2));",
+            OBJECT_ID
+        ),
+        &[&object_ids, &ids]
+    ).await;
+
+    if shared.is_err() {
+        return Err(AppResponse::Error(shared.err().unwrap().to_string()));
+    }
+
+    // Shared objects are skipped up front, and each object is only moved once
+    let mut skipped: HashSet<Uuid> = shared
+        .unwrap()
+        .iter()
+        .map(|row| row.get("object_id"))
+        .collect();
+
+    let mut trashed: Vec<Uuid> = vec![];
+    let mut moved_keys: Vec<(String, Option<String>, bool)> = vec![];
+    let mut jobs: Vec<AssetJob> = vec![];
+    let mut events: Vec<Value> = vec![];
+
+    for row in rows {
+        let id: Uuid = row.get("id");
+        let object_id: Uuid = row.get("object_id");
+        let image_type: ImageType = row.get("type");
+        let kind: AssetKind = row.get("kind");
+        let mime_type: String = row.get("mime_type");
+        let pending: bool = row.get("pending");
+
+        if !skipped.insert(object_id) {
+            trashed.push(id);
+            events.push(deleted_asset_data(&id, &image_type, false));
+            continue;
+        }
+
+        let key = asset_key(project_id, &image_type, &kind, &object_id, &mime_type);
+        let moved = move_object(&target, &key, &trash_key(&key), AssetVisibility::Private).await;
+
+        if moved.is_err() {
+            tracing::error!("ERROR MOVING {} TO TRASH - {:?}", key, moved.err().unwrap());
+            skipped.remove(&object_id);
+            continue;
+        }
+
+        let manifest = sprite_manifest_key(project_id, &image_type, &kind, &object_id, &mime_type);
+        let moved = move_sprite_manifest(
+            &target,
+            manifest.clone(),
+            true,
+            AssetVisibility::Private
+        ).await;
+
+        if moved.is_err() {
+            tracing::error!(
+                "ERROR MOVING MANIFEST OF {} TO TRASH - {:?}",
+                key,
+                moved.err().unwrap()
+            );
+
+            // The sheet goes back too, the two are trashed together or not at all
+            let visibility = live_visibility(state, project_id, pending).await.unwrap_or(
+                AssetVisibility::Private
+            );
+            let _ = move_object(&target, &trash_key(&key), &key, visibility).await;
+            skipped.remove(&object_id);
+            continue;
+        }
+
+        trashed.push(id);
+        events.push(deleted_asset_data(&id, &image_type, false));
+        moved_keys.push((key, manifest, pending));
+        jobs.push(AssetJob {
+            operation: AssetJobOperation::DeletePrefix,
+            target: rendition_prefix(project_id, &image_type, &object_id),
+        });
+        jobs.push(AssetJob {
+            operation: AssetJobOperation::DeletePrefix,
+            target: thumbnail_prefix(project_id, &image_type, &object_id),
+        });
+    }
+
+    let res = client.execute(
+        "UPDATE images SET deleted_at = NOW() WHERE id = ANY($1);",
+        &[&trashed]
+    ).await;
+
+    if res.is_err() {
+        // Put the objects back so the rows still point at them
+        for (key, manifest, pending) in moved_keys {
+            let visibility = live_visibility(state, project_id, pending).await.unwrap_or(
+                AssetVisibility::Private
+            );
+            let _ = move_object(&target, &trash_key(&key), &key, visibility).await;
+            let _ = move_sprite_manifest(&target, manifest, false, visibility).await;
+        }
+
+        return Err(AppResponse::Error(res.err().unwrap().to_string()));
+    }
+
+    let enqueued = enqueue_jobs(client, &jobs).await;
+
+    if enqueued.is_err() {
+        tracing::error!("{:?}", enqueued.err().unwrap());
+    }
+
+    notify_job_worker(state);
+
+    for data in events {
+        emit_event(state, client, project_id, WebhookEvent::Deleted, data).await;
+    }
+
+    Ok(trashed)
+}
@@ -0,0 +1,109 @@
+use deadpool_postgres::{ GenericClient, Object };
+use serde::Serialize;
+use serde_json::{ json, Map, Value };
+use uuid::Uuid;
+
+use crate::enums::AppResponse;
+
+// Every Arkive entity that can show an asset. Boards reference images through their nodes, so
+// a board is listed once however many of its nodes use the image.
+const REFERENCES_QUERY: &str =
+    "SELECT 'documents' AS entity, id, title, image_id, NULL::UUID AS parent_id
+     FROM documents WHERE image_id = ANY($1)
+     UNION ALL
+     SELECT 'maps', id, title, image_id, NULL::UUID FROM maps WHERE image_id = ANY($1)
+     UNION ALL
+     SELECT 'map_layers', id, title, image_id, parent_id FROM map_layers WHERE image_id = ANY($1)
+     UNION ALL
+     SELECT DISTINCT 'boards', boards.id, boards.title, nodes.image_id, NULL::UUID
+     FROM nodes
+     JOIN boards ON boards.id = nodes.parent_id
+     WHERE nodes.image_id = ANY($1);";
+
+// Every table REFERENCES_QUERY reads, boards through their nodes
+const REFERENCING_TABLES: [&str; 4] = ["documents", "maps", "map_layers", "nodes"];
+
+#[derive(Serialize)]
+pub struct AssetReference {
+    #[serde(skip)]
+    pub entity: String,
+    pub id: Uuid,
+    pub title: Option<String>,
+    pub image_id: Uuid,
+    // The map a layer belongs to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent_id: Option<Uuid>,
+}
+
+pub async fn get_asset_references(
+    client: &Object,
+    ids: &Vec<Uuid>
+) -> Result<Vec<AssetReference>, AppResponse> {
+    let rows = client.query(REFERENCES_QUERY, &[&ids]).await;
+
+    if rows.is_err() {
+        return Err(AppResponse::Error(rows.err().unwrap().to_string()));
+    }
+
+    Ok(
+        rows
+            .unwrap()
+            .iter()
+            .map(|row| AssetReference {
+                entity: row.get("entity"),
+                id: row.get("id"),
+                title: row.get("title"),
+                image_id: row.get("image_id"),
+                parent_id: row.get("parent_id"),
+            })
+            .collect()
+    )
+}
+
+// Points everything that shows one of `ids` at `to` instead. Returns how many rows changed.
+pub async fn repoint_references(
+    client: &impl GenericClient,
+    ids: &Vec<Uuid>,
+    to: &Uuid
+) -> Result<u64, AppResponse> {
+    let mut repointed = 0;
+
+    for table in REFERENCING_TABLES {
+        let res = client.execute(
+            &format!("UPDATE {} SET image_id = $1 WHERE image_id = ANY($2);", table),
+            &[&to, &ids]
+        ).await;
+
+        if res.is_err() {
+            return Err(AppResponse::Error(res.err().unwrap().to_string()));
+        }
+
+        repointed += res.unwrap();
+    }
+
+    Ok(repointed)
+}
+
+// { "documents": [...], "maps": [...], ... }, every entity is present even without references
+pub fn group_references(references: Vec<AssetReference>) -> Value {
+    let mut grouped = Map::new();
+
+    for entity in ["documents", "maps", "map_layers", "boards"] {
+        grouped.insert(entity.to_owned(), json!([]));
+    }
+
+    for reference in references {
+        if let Some(Value::Array(list)) = grouped.get_mut(&reference.entity) {
+            list.push(json!(reference));
+        }
+    }
+
+    Value::Object(grouped)
+}
+
+pub fn referenced_conflict(references: Vec<AssetReference>) -> AppResponse {
+    AppResponse::Conflict(
+        "Some of the selected images are still in use.".to_owned(),
+        json!({ "references": group_references(references) })
+    )
+}
@@ -0,0 +1,147 @@
+use image::ImageFormat;
+use serde::Serialize;
+use serde_json::json;
+
+use crate::enums::{ AppResponse, ImageType };
+
+// Limits an image has to meet to be stored as the image type. They only apply to images,
+// other kinds are bounded by the upload size limits alone.
+pub struct ImageTypeRules {
+    pub max_width: u32,
+    pub max_height: u32,
+    // Size of the file as uploaded, not of the re-encoded object
+    pub max_size_bytes: u64,
+    pub allowed_formats: &'static [ImageFormat],
+}
+
+const IMAGE_FORMATS: [ImageFormat; 6] = [
+    ImageFormat::Png,
+    ImageFormat::Jpeg,
+    ImageFormat::WebP,
+    ImageFormat::Gif,
+    ImageFormat::Avif,
+    ImageFormat::Bmp,
+];
+
+// Maps are tiled and zoomed, animations and palette formats only get in the way there
+const MAP_IMAGE_FORMATS: [ImageFormat; 4] = [
+    ImageFormat::Png,
+    ImageFormat::Jpeg,
+    ImageFormat::WebP,
+    ImageFormat::Avif,
+];
+
+impl ImageTypeRules {
+    pub fn of(image_type: &ImageType) -> Self {
+        match image_type {
+            ImageType::Images =>
+                ImageTypeRules {
+                    max_width: 8192,
+                    max_height: 8192,
+                    max_size_bytes: 10_000_000,
+                    allowed_formats: &IMAGE_FORMATS,
+                },
+            // 16383 is the largest side WebP can encode
+            ImageType::MapImages =>
+                ImageTypeRules {
+                    max_width: 16383,
+                    max_height: 16383,
+                    max_size_bytes: 20_000_000,
+                    allowed_formats: &MAP_IMAGE_FORMATS,
+                },
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "rule", rename_all = "snake_case")]
+pub enum ValidationError {
+    MaxWidth {
+        limit: u32,
+        actual: u32,
+    },
+    MaxHeight {
+        limit: u32,
+        actual: u32,
+    },
+    MaxSizeBytes {
+        limit: u64,
+        actual: u64,
+    },
+    SourceFormat {
+        allowed: Vec<&'static str>,
+        actual: &'static str,
+    },
+}
+
+// What is known about an upload so far. Rules for the parts that aren't known yet are skipped,
+// e.g. presigned uploads are checked by format first and by size and dimensions once stored.
+#[derive(Default)]
+pub struct ImageFacts {
+    pub format: Option<ImageFormat>,
+    pub size_bytes: Option<u64>,
+    pub dimensions: Option<(u32, u32)>,
+}
+
+impl ImageFacts {
+    // Everything the start of the file tells, the dimensions come from the decoded image
+    pub fn from_head(head: &[u8], size_bytes: u64, dimensions: (u32, u32)) -> Self {
+        ImageFacts {
+            format: image::guess_format(head).ok(),
+            size_bytes: Some(size_bytes),
+            dimensions: Some(dimensions),
+        }
+    }
+}
+
+// Checks every rule instead of stopping at the first one, so the uploader sees all of them
+pub fn validate_image(
+    image_type: &ImageType,
+    facts: &ImageFacts
+) -> Result<(), Vec<ValidationError>> {
+    let rules = ImageTypeRules::of(image_type);
+    let mut errors: Vec<ValidationError> = vec![];
+
+    if let Some(format) = facts.format {
+        if !rules.allowed_formats.contains(&format) {
+            errors.push(ValidationError::SourceFormat {
+                allowed: rules.allowed_formats
+                    .iter()
+                    .map(|format| format.to_mime_type())
+                    .collect(),
+                actual: format.to_mime_type(),
+            });
+        }
+    }
+
+    if let Some(size_bytes) = facts.size_bytes {
+        if size_bytes > rules.max_size_bytes {
+            errors.push(ValidationError::MaxSizeBytes {
+                limit: rules.max_size_bytes,
+                actual: size_bytes,
+            });
+        }
+    }
+
+    if let Some((width, height)) = facts.dimensions {
+        if width > rules.max_width {
+            errors.push(ValidationError::MaxWidth { limit: rules.max_width, actual: width });
+        }
+
+        if height > rules.max_height {
+            errors.push(ValidationError::MaxHeight { limit: rules.max_height, actual: height });
+        }
+    }
+
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(errors),
+    }
+}
+
+pub fn validation_failed(image_type: &ImageType, errors: Vec<ValidationError>) -> AppResponse {
+    AppResponse::Invalid(
+        format!("The file does not meet the requirements for {}.", image_type),
+        json!({ "image_type": image_type.to_string(), "errors": errors })
+    )
+}
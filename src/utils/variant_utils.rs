@@ -0,0 +1,173 @@
+use deadpool_postgres::GenericClient;
+use image::imageops::FilterType;
+use uuid::Uuid;
+
+use crate::{
+    enums::{ AppResponse, AssetVisibility, ImageType },
+    state::models::AppState,
+    storage::{ backend::PutOptions, resolve_target },
+    utils::{
+        asset_utils::image_key,
+        db_utils::get_client,
+        image_utils::{ encode_webp, is_animated_webp, load_oriented, run_image_task, EncodeOptions },
+        s3_utils::get_object_bytes,
+    },
+};
+
+// Widths generated for every still image, only the ones narrower than the original
+pub const VARIANT_WIDTHS: [u32; 3] = [320, 768, 1600];
+
+// Variants outlive the trash, so a restored asset has them right away. They are removed with
+// the object once it's deleted for good.
+pub fn variant_prefix(project_id: &Uuid, image_type: &ImageType, id: &Uuid) -> String {
+    format!("assets/{}/{}/variants/{}_", project_id, image_type, id)
+}
+
+pub fn variant_key(project_id: &Uuid, image_type: &ImageType, id: &Uuid, width: u32) -> String {
+    format!("{}{}w.webp", variant_prefix(project_id, image_type, id), width)
+}
+
+// Smallest variant that covers the requested box, the client scales it down the rest of the way
+pub async fn find_variant(
+    client: &impl GenericClient,
+    object_id: &Uuid,
+    width: usize,
+    height: usize
+) -> Result<Option<String>, AppResponse> {
+    let row = client.query_opt(
+        "SELECT key FROM image_variants
+         WHERE object_id = $1 AND width >= $2 AND height >= $3
+         ORDER BY width
+         LIMIT 1;",
+        &[&object_id, &(width as i32), &(height as i32)]
+    ).await;
+
+    if row.is_err() {
+        return Err(AppResponse::Error(row.err().unwrap().to_string()));
+    }
+
+    Ok(row.unwrap().map(|row| row.get("key")))
+}
+
+// Forgets the variants of an object that is about to get new content, so none of the old ones
+// are served while the new ones are generated
+pub async fn clear_variants(
+    client: &impl GenericClient,
+    object_ids: &Vec<Uuid>
+) -> Result<(), AppResponse> {
+    let res = client.execute(
+        "DELETE FROM image_variants WHERE object_id = ANY($1);",
+        &[&object_ids]
+    ).await;
+
+    if res.is_err() {
+        return Err(AppResponse::Error(res.err().unwrap().to_string()));
+    }
+
+    Ok(())
+}
+
+async fn generate_variants(
+    state: &AppState,
+    project_id: &Uuid,
+    image_type: &ImageType,
+    object_id: &Uuid,
+    mime_type: &str
+) -> Result<usize, AppResponse> {
+    let target = resolve_target(state, project_id).await?;
+    let original = get_object_bytes(
+        &target,
+        &image_key(project_id, image_type, object_id, mime_type)
+    ).await?;
+
+    // Variants are stills, an animation keeps being resized on demand. AVIF can only be
+    // encoded here, not decoded.
+    if mime_type != "image/webp" || is_animated_webp(&original) {
+        return Ok(0);
+    }
+
+    let max_pixels = state.config.max_image_pixels;
+
+    let resized = run_image_task(&state.encode_permits, "variants", move || {
+        let img = load_oriented(&original, max_pixels)?;
+
+        let variants: Vec<(u32, u32, Vec<u8>)> = VARIANT_WIDTHS.iter()
+            .filter(|width| **width < img.width())
+            .map(|width| {
+                let variant = img.resize(*width, u32::MAX, FilterType::Lanczos3);
+                let height = variant.height();
+
+                (*width, height, encode_webp(variant, &EncodeOptions::default()))
+            })
+            .collect();
+
+        Ok::<Vec<(u32, u32, Vec<u8>)>, String>(variants)
+    }).await;
+
+    if resized.is_err() {
+        return Err(AppResponse::Error(resized.err().unwrap()));
+    }
+
+    let resized = resized.unwrap();
+
+    if resized.is_err() {
+        return Err(AppResponse::Error(resized.err().unwrap()));
+    }
+
+    let resized = resized.unwrap();
+    let client = get_client(&state.pool).await?;
+
+    for (width, height, data) in &resized {
+        let key = variant_key(project_id, image_type, object_id, *width);
+
+        // Same visibility as thumbnails, variants are only handed out as presigned URLs
+        let upload = target.put(&key, data.clone(), &PutOptions {
+            content_type: "image/webp",
+            cache_control: state.config.cache_control.for_image_type(image_type),
+            visibility: AssetVisibility::Private,
+        }).await;
+
+        if upload.is_err() {
+            return Err(AppResponse::Error(upload.err().unwrap()));
+        }
+
+        let res = client.execute(
+            "INSERT INTO image_variants (object_id, width, height, key) VALUES ($1, $2, $3, $4)
+             ON CONFLICT (object_id, width) DO UPDATE SET height = $3, key = $4;",
+            &[&object_id, &(*width as i32), &(*height as i32), &key]
+        ).await;
+
+        if res.is_err() {
+            return Err(AppResponse::Error(res.err().unwrap().to_string()));
+        }
+    }
+
+    Ok(resized.len())
+}
+
+// Generated in the background, the upload doesn't wait for the resizes. Until they are done
+// the thumbnail route falls back to resizing on demand.
+pub fn queue_variants(
+    state: &AppState,
+    project_id: Uuid,
+    image_type: ImageType,
+    object_id: Uuid,
+    mime_type: String
+) {
+    let task_state = state.clone();
+
+    state.tasks.spawn(async move {
+        let res = generate_variants(
+            &task_state,
+            &project_id,
+            &image_type,
+            &object_id,
+            &mime_type
+        ).await;
+
+        if res.is_err() {
+            let err = res.err().unwrap();
+            tracing::error!("ERROR GENERATING VARIANTS OF {} - {:?}", object_id, err);
+        }
+    });
+}
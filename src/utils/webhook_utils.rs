@@ -0,0 +1,170 @@
+use std::time::{ SystemTime, UNIX_EPOCH };
+
+use deadpool_postgres::{ GenericClient, Object };
+use hmac::{ Hmac, Mac };
+use serde_json::{ json, Value };
+use sha2::Sha256;
+use tokio_postgres::Row;
+use uuid::Uuid;
+
+use crate::{
+    enums::{ AppResponse, AssetKind, ImageType, WebhookEvent },
+    state::models::AppState,
+};
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Receivers recompute the signature over "<timestamp>.<body>" with the project's webhook secret
+// and reject stale timestamps to guard against replays
+pub const SIGNATURE_HEADER: &str = "x-arkive-signature";
+pub const TIMESTAMP_HEADER: &str = "x-arkive-timestamp";
+pub const EVENT_HEADER: &str = "x-arkive-event";
+pub const DELIVERY_HEADER: &str = "x-arkive-delivery";
+
+pub fn sign_payload(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut hmac = HmacSha256::new_from_slice(secret.as_bytes()).unwrap();
+    hmac.update(format!("{}.{}", timestamp, body).as_bytes());
+
+    format!("sha256={:x}", hmac.finalize().into_bytes())
+}
+
+// Two v4 UUIDs give 244 random bits from the OS generator
+pub fn generate_secret() -> String {
+    format!("whsec_{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+// Records a delivery for every webhook of the project subscribed to the event. Takes any client
+// so deliveries can be queued in the same transaction as the change they describe.
+pub async fn enqueue_event(
+    client: &impl GenericClient,
+    project_id: &Uuid,
+    event: WebhookEvent,
+    data: Value
+) -> Result<(), AppResponse> {
+    let occurred_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as i64)
+        .unwrap_or(0);
+
+    let payload =
+        json!({
+        "event": event,
+        "project_id": project_id,
+        "occurred_at": occurred_at,
+        "data": data,
+    });
+
+    let res = client.execute(
+        "INSERT INTO webhook_deliveries (webhook_id, event, payload)
+         SELECT id, $2, $3 FROM webhooks WHERE project_id = $1 AND $2 = ANY(events);",
+        &[&project_id, &event, &payload]
+    ).await;
+
+    if res.is_err() {
+        return Err(AppResponse::Error(res.err().unwrap().to_string()));
+    }
+
+    Ok(())
+}
+
+// Wakes the worker so freshly queued deliveries go out right away instead of on the next poll
+pub fn notify_webhook_worker(state: &AppState) {
+    state.webhook_notify.notify_one();
+}
+
+// Webhooks are best effort from the caller's point of view, a failure to queue is logged
+// instead of failing a change that already happened
+pub async fn emit_event(
+    state: &AppState,
+    client: &impl GenericClient,
+    project_id: &Uuid,
+    event: WebhookEvent,
+    data: Value
+) {
+    let res = enqueue_event(client, project_id, event, data).await;
+
+    if res.is_err() {
+        tracing::error!("ERROR QUEUEING WEBHOOK EVENT - {:?}", res.err().unwrap());
+        return;
+    }
+
+    notify_webhook_worker(state);
+}
+
+// Emits an event describing the asset's current row, does nothing when it no longer exists
+pub async fn emit_asset_event(state: &AppState, client: &Object, event: WebhookEvent, id: &Uuid) {
+    let row = client.query_opt(
+        "SELECT project_id, title, type, kind, mime_type, size_bytes, width, height, owner_id, pending
+         FROM images WHERE id = $1;",
+        &[&id]
+    ).await;
+
+    if row.is_err() {
+        tracing::error!("ERROR QUEUEING WEBHOOK EVENT - {}", row.err().unwrap());
+        return;
+    }
+
+    let row = row.unwrap();
+
+    if row.is_none() {
+        return;
+    }
+
+    let row = row.unwrap();
+    let project_id: Uuid = row.get("project_id");
+    let title: Option<String> = row.get("title");
+    let image_type: ImageType = row.get("type");
+    let kind: AssetKind = row.get("kind");
+    let mime_type: String = row.get("mime_type");
+    let size_bytes: Option<i64> = row.get("size_bytes");
+    let width: Option<i32> = row.get("width");
+    let height: Option<i32> = row.get("height");
+    let owner_id: Uuid = row.get("owner_id");
+    let pending: bool = row.get("pending");
+
+    let data =
+        json!({
+        "id": id,
+        "title": title,
+        "type": image_type.to_string(),
+        "kind": kind,
+        "mime_type": mime_type,
+        "size_bytes": size_bytes,
+        "width": width,
+        "height": height,
+        "owner_id": owner_id,
+        "pending": pending,
+    });
+
+    emit_event(state, client, &project_id, event, data).await;
+}
+
+pub fn deleted_asset_data(id: &Uuid, image_type: &ImageType, permanent: bool) -> Value {
+    json!({
+        "id": id,
+        "type": image_type.to_string(),
+        "permanent": permanent,
+    })
+}
+
+// Queues deleted events for rows returned by a deleting query (id plus deleted_asset_columns).
+// Trashed rows already sent theirs when they went to the trash.
+pub async fn enqueue_deleted_events(
+    client: &impl GenericClient,
+    rows: &[Row]
+) -> Result<(), AppResponse> {
+    for row in rows.iter().filter(|row| !row.get::<_, bool>("trashed")) {
+        let id: Uuid = row.get("id");
+        let project_id: Uuid = row.get("project_id");
+        let image_type: ImageType = row.get("type");
+
+        enqueue_event(
+            client,
+            &project_id,
+            WebhookEvent::Deleted,
+            deleted_asset_data(&id, &image_type, true)
+        ).await?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,355 @@
+use std::{ collections::HashSet, io::{ self, Write }, path::{ Component, Path } };
+
+use axum::body::{ Body, Bytes };
+use tokio::sync::mpsc::{ self, Receiver, Sender };
+use zip::ZipArchive;
+
+use crate::utils::stream_utils::{ spool_reader, SpooledFile };
+
+// A piece of a response body that's streamed from a channel
+pub type BodyChunk = Result<Bytes, io::Error>;
+
+// Write half of a streamed response body. Used from blocking threads, the zip
+// writer fills it while the response is being sent.
+pub struct ChannelWriter {
+    sender: Sender<BodyChunk>,
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.sender
+            .blocking_send(Ok(Bytes::copy_from_slice(buf)))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "CLIENT DISCONNECTED"))?;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+pub fn body_channel() -> (ChannelWriter, Body) {
+    let (sender, receiver): (Sender<BodyChunk>, Receiver<BodyChunk>) = mpsc::channel(16);
+
+    let stream = futures::stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|chunk| (chunk, receiver))
+    });
+
+    (ChannelWriter { sender }, Body::from_stream(stream))
+}
+
+const LOCAL_HEADER_SIGNATURE: u32 = 0x04034b50;
+const CENTRAL_HEADER_SIGNATURE: u32 = 0x02014b50;
+const ZIP64_END_SIGNATURE: u32 = 0x06064b50;
+const ZIP64_LOCATOR_SIGNATURE: u32 = 0x07064b50;
+const END_SIGNATURE: u32 = 0x06054b50;
+const UTF8_NAMES_FLAG: u16 = 0x0800;
+// Entries carry no modification time, 1980-01-01 is the earliest DOS date
+const DOS_DATE: u16 = (1 << 5) | 1;
+
+struct ArchiveEntry {
+    name: String,
+    crc: u32,
+    size: u32,
+    offset: u64,
+}
+
+// Minimal stored-only ZIP writer for non-seekable outputs. Each entry is written in one
+// go, so the CRC and sizes are known up front and no header has to be patched afterwards.
+// ZIP64 records are only emitted once offsets or the entry count outgrow the classic format.
+pub struct ZipStream<W: Write> {
+    inner: W,
+    offset: u64,
+    entries: Vec<ArchiveEntry>,
+}
+
+impl<W: Write> ZipStream<W> {
+    pub fn new(inner: W) -> Self {
+        ZipStream { inner, offset: 0, entries: vec![] }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.inner.write_all(buf)?;
+        self.offset += buf.len() as u64;
+
+        Ok(())
+    }
+
+    pub fn add_file(&mut self, name: &str, data: &[u8]) -> io::Result<()> {
+        if data.len() as u64 >= (u32::MAX as u64) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "FILE TOO LARGE FOR ARCHIVE"));
+        }
+
+        let entry = ArchiveEntry {
+            name: name.to_owned(),
+            crc: crc32fast::hash(data),
+            size: data.len() as u32,
+            offset: self.offset,
+        };
+
+        let mut header: Vec<u8> = Vec::with_capacity(30 + name.len());
+        header.extend(LOCAL_HEADER_SIGNATURE.to_le_bytes());
+        header.extend(20_u16.to_le_bytes());
+        header.extend(UTF8_NAMES_FLAG.to_le_bytes());
+        // Stored, no compression
+        header.extend(0_u16.to_le_bytes());
+        header.extend(0_u16.to_le_bytes());
+        header.extend(DOS_DATE.to_le_bytes());
+        header.extend(entry.crc.to_le_bytes());
+        header.extend(entry.size.to_le_bytes());
+        header.extend(entry.size.to_le_bytes());
+        header.extend((name.len() as u16).to_le_bytes());
+        header.extend(0_u16.to_le_bytes());
+        header.extend(name.as_bytes());
+
+        self.write(&header)?;
+        self.write(data)?;
+        self.entries.push(entry);
+
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        let directory_start = self.offset;
+        let entries = std::mem::take(&mut self.entries);
+
+        for entry in entries.iter() {
+            let needs_zip64 = entry.offset >= (u32::MAX as u64);
+            let version: u16 = if needs_zip64 { 45 } else { 20 };
+
+            let mut header: Vec<u8> = Vec::with_capacity(58 + entry.name.len());
+            header.extend(CENTRAL_HEADER_SIGNATURE.to_le_bytes());
+            header.extend(45_u16.to_le_bytes());
+            header.extend(version.to_le_bytes());
+            header.extend(UTF8_NAMES_FLAG.to_le_bytes());
+            header.extend(0_u16.to_le_bytes());
+            header.extend(0_u16.to_le_bytes());
+            header.extend(DOS_DATE.to_le_bytes());
+            header.extend(entry.crc.to_le_bytes());
+            header.extend(entry.size.to_le_bytes());
+            header.extend(entry.size.to_le_bytes());
+            header.extend((entry.name.len() as u16).to_le_bytes());
+            header.extend((if needs_zip64 { 12 } else { 0 } as u16).to_le_bytes());
+            // Comment length, disk number, internal and external attributes
+            header.extend([0u8; 10]);
+            header.extend((if needs_zip64 { u32::MAX } else { entry.offset as u32 }).to_le_bytes());
+            header.extend(entry.name.as_bytes());
+
+            if needs_zip64 {
+                header.extend(0x0001_u16.to_le_bytes());
+                header.extend(8_u16.to_le_bytes());
+                header.extend(entry.offset.to_le_bytes());
+            }
+
+            self.write(&header)?;
+        }
+
+        let directory_size = self.offset - directory_start;
+        let count = entries.len() as u64;
+
+        let needs_zip64 =
+            count >= (u16::MAX as u64) ||
+            directory_start >= (u32::MAX as u64) ||
+            directory_size >= (u32::MAX as u64);
+
+        let mut end: Vec<u8> = vec![];
+
+        if needs_zip64 {
+            let zip64_end_offset = self.offset;
+
+            end.extend(ZIP64_END_SIGNATURE.to_le_bytes());
+            end.extend(44_u64.to_le_bytes());
+            end.extend(45_u16.to_le_bytes());
+            end.extend(45_u16.to_le_bytes());
+            end.extend(0_u32.to_le_bytes());
+            end.extend(0_u32.to_le_bytes());
+            end.extend(count.to_le_bytes());
+            end.extend(count.to_le_bytes());
+            end.extend(directory_size.to_le_bytes());
+            end.extend(directory_start.to_le_bytes());
+
+            end.extend(ZIP64_LOCATOR_SIGNATURE.to_le_bytes());
+            end.extend(0_u32.to_le_bytes());
+            end.extend(zip64_end_offset.to_le_bytes());
+            end.extend(1_u32.to_le_bytes());
+        }
+
+        end.extend(END_SIGNATURE.to_le_bytes());
+        end.extend(0_u16.to_le_bytes());
+        end.extend(0_u16.to_le_bytes());
+        end.extend((count.min(u16::MAX as u64) as u16).to_le_bytes());
+        end.extend((count.min(u16::MAX as u64) as u16).to_le_bytes());
+        end.extend((directory_size.min(u32::MAX as u64) as u32).to_le_bytes());
+        end.extend((directory_start.min(u32::MAX as u64) as u32).to_le_bytes());
+        end.extend(0_u16.to_le_bytes());
+
+        self.write(&end)?;
+        self.inner.flush()?;
+
+        Ok(self.inner)
+    }
+}
+
+// Titles become file names, stripped of path separators and made unique within the archive.
+pub fn archive_file_name(title: &str, extension: &str, used: &mut HashSet<String>) -> String {
+    let base: String = title
+        .chars()
+        .map(|c| if c.is_alphanumeric() || c == ' ' || c == '-' || c == '_' { c } else { '_' })
+        .collect::<String>()
+        .trim()
+        .to_owned();
+    let base = if base.is_empty() { "untitled".to_owned() } else { base };
+
+    let mut name = format!("{}.{}", base, extension);
+    let mut counter = 1;
+
+    while used.contains(&name) {
+        name = format!("{} ({}).{}", base, counter, extension);
+        counter += 1;
+    }
+
+    used.insert(name.clone());
+
+    name
+}
+
+// One file of an uploaded archive. A file that couldn't be extracted keeps its error, so it
+// still shows up in the report.
+pub struct ExtractedEntry {
+    pub path: String,
+    pub title: String,
+    // Folders the file was in, outermost first
+    pub folders: Vec<String>,
+    pub file: Result<SpooledFile, String>,
+}
+
+// Folders and files that archivers add on their own, never assets
+fn is_archive_metadata(path: &Path) -> bool {
+    path.components().any(|component| {
+        match component {
+            Component::Normal(name) => {
+                let name = name.to_string_lossy();
+                name == "__MACOSX" || name.starts_with('.')
+            }
+            _ => false,
+        }
+    })
+}
+
+// Spools every file of the archive to disk. The announced sizes are only used to turn down
+// archives up front, the extracted bytes are counted as well since the headers can't be trusted.
+// Blocking, run it on a blocking thread.
+pub fn extract_archive(
+    archive: &Path,
+    max_entries: usize,
+    max_total_size: u64,
+    max_file_size: u64
+) -> Result<Vec<ExtractedEntry>, String> {
+    let file = std::fs::File::open(archive);
+
+    if file.is_err() {
+        return Err(file.err().unwrap().to_string());
+    }
+
+    let zip = ZipArchive::new(file.unwrap());
+
+    if zip.is_err() {
+        return Err(format!("INVALID ARCHIVE - {}", zip.err().unwrap()));
+    }
+
+    let mut zip = zip.unwrap();
+    let mut indexes: Vec<usize> = vec![];
+    let mut announced_size: u64 = 0;
+
+    for index in 0..zip.len() {
+        let entry = zip.by_index_raw(index);
+
+        if entry.is_err() {
+            return Err(format!("INVALID ARCHIVE - {}", entry.err().unwrap()));
+        }
+
+        let entry = entry.unwrap();
+
+        if entry.is_dir() || is_archive_metadata(Path::new(entry.name())) {
+            continue;
+        }
+
+        announced_size = announced_size.saturating_add(entry.size());
+        indexes.push(index);
+    }
+
+    if indexes.len() > max_entries {
+        return Err(format!("TOO MANY FILES IN ARCHIVE - {} (MAX {})", indexes.len(), max_entries));
+    }
+
+    if announced_size > max_total_size {
+        return Err("ARCHIVE TOO LARGE".to_owned());
+    }
+
+    let mut entries: Vec<ExtractedEntry> = vec![];
+    let mut extracted_size: u64 = 0;
+
+    for index in indexes {
+        let entry = zip.by_index(index);
+
+        if entry.is_err() {
+            return Err(format!("INVALID ARCHIVE - {}", entry.err().unwrap()));
+        }
+
+        let mut entry = entry.unwrap();
+        let path = entry.name().to_owned();
+
+        // Names that would escape the archive, like "../x.png", only make the report
+        let enclosed = entry.enclosed_name();
+
+        if enclosed.is_none() {
+            entries.push(ExtractedEntry {
+                title: path.clone(),
+                path,
+                folders: vec![],
+                file: Err("INVALID PATH".to_owned()),
+            });
+            continue;
+        }
+
+        let enclosed = enclosed.unwrap();
+
+        let title = enclosed
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or("untitled".to_owned());
+
+        let folders: Vec<String> = enclosed
+            .parent()
+            .map(|parent| {
+                parent
+                    .components()
+                    .map(|component| component.as_os_str().to_string_lossy().into_owned())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let remaining = max_total_size - extracted_size;
+        let spooled = spool_reader(&mut entry, max_file_size.min(remaining));
+
+        let file = match spooled {
+            Ok(Some(spooled)) => {
+                extracted_size += spooled.size;
+                Ok(spooled)
+            }
+            Ok(None) if remaining <= max_file_size => {
+                return Err("ARCHIVE TOO LARGE".to_owned());
+            }
+            Ok(None) => Err("FILE TOO LARGE".to_owned()),
+            Err(err) => {
+                tracing::error!("ERROR EXTRACTING ARCHIVE ENTRY - {} - {}", path, err);
+                Err("COULD NOT READ FILE".to_owned())
+            }
+        };
+
+        entries.push(ExtractedEntry { path, title, folders, file });
+    }
+
+    Ok(entries)
+}